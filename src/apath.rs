@@ -23,10 +23,11 @@ use std::ffi::OsStr;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::ops::Deref;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
 
-use serde::{Deserialize, Serialize};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
 
 /// An ordered archive path.
 ///
@@ -37,7 +38,10 @@ use serde::{Deserialize, Serialize};
 /// string ordering.
 ///
 /// Apaths must start with `/` and not end with `/` unless they have length 1.
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+///
+/// Apaths are checked when they're deserialized, so that an index naming `..` or an
+/// empty component can't be read.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct Apath(String);
 
 impl Apath {
@@ -67,6 +71,7 @@ impl Apath {
             c.push('/');
         }
         c.push_str(child_name);
+        debug_assert!(Apath::is_valid(&c), "invalid apath: {c:?}");
         Apath(c)
     }
 
@@ -84,6 +89,51 @@ impl Apath {
         }
     }
 
+    /// Return the apath of the directory containing this one, or None for the root.
+    #[must_use]
+    pub fn parent(&self) -> Option<Apath> {
+        match self.0.rfind('/') {
+            _ if self.0 == "/" => None,
+            Some(0) => Some(Apath::root()),
+            Some(i) => Some(Apath(self.0[..i].to_owned())),
+            None => unreachable!("apath {self:?} has no slash"),
+        }
+    }
+
+    /// Return the last component of this apath, or an empty string for the root.
+    #[must_use]
+    pub fn file_name(&self) -> &str {
+        let i = self.0.rfind('/').expect("apath has a slash");
+        &self.0[i + 1..]
+    }
+
+    /// Return the number of components in this apath: 0 for the root, 1 for a
+    /// child of the root, and so on.
+    #[must_use]
+    pub fn depth(&self) -> usize {
+        if self.0 == "/" {
+            0
+        } else {
+            self.0.matches('/').count()
+        }
+    }
+
+    /// If `prefix` is a parent of, or equal to, this apath, return the rest of the
+    /// path below it, without a leading slash.
+    ///
+    /// Returns an empty string if the apaths are equal, and None if `prefix` is not
+    /// a parent.
+    #[must_use]
+    pub fn strip_prefix(&self, prefix: &Apath) -> Option<&str> {
+        if !prefix.is_prefix_of(self) {
+            None
+        } else if prefix.0 == "/" {
+            Some(&self.0[1..])
+        } else {
+            Some(self.0[prefix.0.len()..].trim_start_matches('/'))
+        }
+    }
+
     /// Return a PathBuf for this Apath below a tree root directory.
     #[must_use]
     pub fn below<R: Into<PathBuf>>(&self, tree_root: R) -> PathBuf {
@@ -92,6 +142,19 @@ impl Apath {
         buf
     }
 
+    /// Return a PathBuf for this Apath below a tree root directory, or None if on this
+    /// platform it wouldn't be a path inside the root.
+    ///
+    /// Every valid apath is a relative path on Unix, but on Windows a component might
+    /// contain a backslash, or start with a drive letter such as `C:`.
+    #[must_use]
+    pub fn below_checked<R: Into<PathBuf>>(&self, tree_root: R) -> Option<PathBuf> {
+        Path::new(&self.0[1..])
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+            .then(|| self.below(tree_root))
+    }
+
     /// Construct an Apath for the root of the tree.
     #[must_use]
     pub fn root() -> Apath {
@@ -133,6 +196,17 @@ impl fmt::Display for ApathParseError {
     }
 }
 
+impl<'de> Deserialize<'de> for Apath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        if Apath::is_valid(&s) {
+            Ok(Apath(s))
+        } else {
+            Err(D::Error::custom(format!("invalid apath {s:?}")))
+        }
+    }
+}
+
 impl From<Apath> for String {
     fn from(a: Apath) -> String {
         a.0
@@ -369,6 +443,53 @@ mod test {
             .not());
     }
 
+    #[test]
+    fn parent() {
+        assert_eq!(Apath::root().parent(), None);
+        assert_eq!(Apath::from("/a").parent(), Some(Apath::root()));
+        assert_eq!(Apath::from("/a/b").parent(), Some(Apath::from("/a")));
+        assert_eq!(Apath::from("/a/b/c").parent(), Some(Apath::from("/a/b")));
+        assert_eq!(Apath::from("/a.b/.c").parent(), Some(Apath::from("/a.b")));
+    }
+
+    #[test]
+    fn file_name() {
+        assert_eq!(Apath::root().file_name(), "");
+        assert_eq!(Apath::from("/a").file_name(), "a");
+        assert_eq!(Apath::from("/a/bb").file_name(), "bb");
+        assert_eq!(Apath::from("/a/b/...").file_name(), "...");
+        let apath = Apath::from("/stuff/file.txt");
+        assert_eq!(
+            apath.parent().unwrap().append(apath.file_name()),
+            apath,
+            "parent and file_name recompose the apath"
+        );
+    }
+
+    #[test]
+    fn depth() {
+        assert_eq!(Apath::root().depth(), 0);
+        assert_eq!(Apath::from("/a").depth(), 1);
+        assert_eq!(Apath::from("/a/bb/c").depth(), 3);
+    }
+
+    #[test]
+    fn strip_prefix() {
+        let root = Apath::root();
+        assert_eq!(root.strip_prefix(&root), Some(""));
+        assert_eq!(Apath::from("/a").strip_prefix(&root), Some("a"));
+        assert_eq!(Apath::from("/a/b").strip_prefix(&root), Some("a/b"));
+        assert_eq!(Apath::from("/a/b").strip_prefix(&"/a".into()), Some("b"));
+        assert_eq!(
+            Apath::from("/a/b/c").strip_prefix(&"/a".into()),
+            Some("b/c")
+        );
+        assert_eq!(Apath::from("/a").strip_prefix(&"/a".into()), Some(""));
+        assert_eq!(Apath::from("/ab").strip_prefix(&"/a".into()), None);
+        assert_eq!(Apath::from("/a").strip_prefix(&"/a/b".into()), None);
+        assert_eq!(root.strip_prefix(&"/a".into()), None);
+    }
+
     #[test]
     pub fn invalid() {
         let invalid_cases = [
@@ -391,6 +512,38 @@ mod test {
         }
     }
 
+    #[test]
+    fn deserialize_rejects_invalid_apaths() {
+        for bad in [
+            r#""/../escape""#,
+            r#""/a/../../b""#,
+            r#""relative""#,
+            r#""/a//b""#,
+        ] {
+            assert!(serde_json::from_str::<Apath>(bad).is_err(), "{bad}");
+        }
+        let apath: Apath = serde_json::from_str(r#""/a/b""#).unwrap();
+        assert_eq!(apath, "/a/b");
+    }
+
+    #[test]
+    fn below_checked_rejects_paths_outside_the_root_on_this_platform() {
+        assert_eq!(
+            Apath::from("/a/b").below_checked("/root"),
+            Some(Apath::from("/a/b").below("/root"))
+        );
+        assert!(Apath::root().below_checked("/root").is_some());
+        let windows_escapes = ["/C:", "/C:/Windows", "/..\\escape", "/a/..\\..\\b"];
+        for apath in windows_escapes {
+            let checked = Apath::from(apath).below_checked("/root");
+            if cfg!(windows) {
+                assert_eq!(checked, None, "{apath:?}");
+            } else {
+                assert!(checked.is_some(), "{apath:?}");
+            }
+        }
+    }
+
     #[test]
     pub fn valid_and_ordered() {
         let ordered = [
@@ -13,7 +13,9 @@
 
 //! Archives holding backup material.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env;
+use std::num::NonZeroUsize;
 use std::path::Path;
 use std::sync::Arc;
 
@@ -22,14 +24,24 @@ use std::time::Instant;
 use itertools::Itertools;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 
+use crate::blockdir::Address;
+use crate::clock::{Clock, SystemClock};
+use crate::compress::zstd::Dictionary;
+use crate::index::{IndexHunkCache, IndexReadAhead};
 use crate::jsonio::{read_json, write_json};
 use crate::monitor::Monitor;
-use crate::transport::Transport;
+use crate::transport::{Transport, WriteMode};
 use crate::*;
 
 const HEADER_FILENAME: &str = "CONSERVE";
+/// Patterns excluded from every backup into the archive, one per line.
+const EXCLUDES_FILENAME: &str = "EXCLUDES";
+/// Archive-wide metadata set by the user, as a json object mapping keys to values.
+const METADATA_FILENAME: &str = "METADATA";
+/// A zstd dictionary used to compress blocks, if the header says there is one.
+const DICTIONARY_FILENAME: &str = "DICTIONARY";
 static BLOCK_DIR: &str = "d";
 
 /// An archive holding backup material.
@@ -40,11 +52,146 @@ pub struct Archive {
 
     /// Transport to the root directory of the archive.
     transport: Transport,
+
+    /// If enabled, parsed index hunks that can be reused across reads.
+    pub(crate) index_cache: Option<Arc<IndexHunkCache>>,
+
+    /// If set, how many index hunks to read ahead while iterating through an index.
+    pub(crate) index_read_ahead: Option<IndexReadAhead>,
+
+    /// Source of the times recorded in bands.
+    pub(crate) clock: Arc<dyn Clock>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ArchiveHeader {
     conserve_archive_version: String,
+    /// True if blocks and index hunks are passed through external commands, from
+    /// [Transport::external_filter].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    external_filter: bool,
+    /// True if new blocks are compressed with the zstd dictionary stored in
+    /// [DICTIONARY_FILENAME].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    block_dictionary: bool,
+}
+
+/// Details about one block, from [Archive::block_info].
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockInfo {
+    pub hash: BlockHash,
+    /// Size of the block file in the archive.
+    pub compressed_len: u64,
+    /// Size of the decompressed content, or None if it can't be decompressed.
+    pub uncompressed_len: Option<usize>,
+    /// True if the decompressed content has the expected hash.
+    pub hash_matches: bool,
+    /// All the files in all the bands that reference this block, in order.
+    pub references: Vec<BlockReference>,
+    /// The bands that reference this block, which would need to be deleted before
+    /// gc would remove it.
+    pub bands: BTreeSet<BandId>,
+}
+
+/// A file in a band that references a block.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct BlockReference {
+    pub band_id: BandId,
+    pub apath: Apath,
+}
+
+/// One address of file content in one band, from [Archive::all_referenced_addresses].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AddressReference {
+    pub band_id: BandId,
+    pub apath: Apath,
+    pub address: Address,
+}
+
+/// The space used by an archive, from [Archive::disk_usage].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DiskUsage {
+    /// Number of bands in the archive.
+    pub bands: usize,
+    /// Total size of the files in all bands, counting each version of each file.
+    pub logical_bytes: u64,
+    /// Number of blocks in the archive.
+    pub blocks: usize,
+    /// Total size of the compressed block files.
+    pub physical_bytes: u64,
+    /// How many times larger the logical size is than the physical size, from
+    /// deduplication and compression together; None if there are no blocks.
+    pub factor: Option<f64>,
+}
+
+/// Counts of the blocks present in two archives, from [Archive::compare_blocks].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockComparison {
+    /// Blocks only in the archive the comparison was called on.
+    pub only_in_first: usize,
+    /// Blocks only in the other archive.
+    pub only_in_second: usize,
+    /// Blocks present in both archives.
+    pub in_both: usize,
+}
+
+/// Return an error if any parent of `path` is an archive.
+fn check_not_inside_archive(path: &Path) -> Result<()> {
+    let Ok(path) = std::path::absolute(path) else {
+        return Ok(());
+    };
+    match path
+        .ancestors()
+        .skip(1)
+        .find(|ancestor| Archive::is_archive_path(ancestor))
+    {
+        Some(archive) => Err(Error::NewArchiveInsideArchive {
+            archive: archive.to_owned(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// The oldest archive format version that can be read by this version of Conserve.
+///
+/// Archives with versions from this up to [ARCHIVE_VERSION] can be opened.
+const OLDEST_READABLE_ARCHIVE_VERSION: &str = "0.6";
+
+/// Parse an archive format version like "0.6" into its major and minor numbers.
+fn parse_archive_version(version: &str) -> Option<(u32, u32)> {
+    let (major, minor) = version.split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Check that an archive with this format version can be read.
+///
+/// Archives written by a newer version of Conserve are refused before anything
+/// else in them is read, since their format might have changed in any way.
+fn check_archive_version(found: &str) -> Result<()> {
+    let unsupported = || Error::UnsupportedArchiveVersion {
+        version: found.to_owned(),
+    };
+    let parsed = parse_archive_version(found).ok_or_else(unsupported)?;
+    let current = parse_archive_version(ARCHIVE_VERSION).expect("Parse current archive version");
+    let oldest = parse_archive_version(OLDEST_READABLE_ARCHIVE_VERSION)
+        .expect("Parse oldest readable archive version");
+    if parsed > current {
+        Err(Error::ArchiveFormatTooNew {
+            found: found.to_owned(),
+            supported: ARCHIVE_VERSION.to_owned(),
+        })
+    } else if parsed < oldest {
+        Err(unsupported())
+    } else {
+        if parsed < current {
+            debug!(
+                version = found,
+                current = ARCHIVE_VERSION,
+                "Opening archive written in an older compatible format"
+            );
+        }
+        Ok(())
+    }
 }
 
 #[derive(Default, Debug)]
@@ -60,23 +207,68 @@ impl Archive {
     }
 
     /// Make a new archive in a new directory accessed by a Transport.
+    ///
+    /// On the local filesystem, this refuses to create an archive inside an existing
+    /// archive. Other transports can't see the parents of their directory, so this
+    /// isn't checked.
     pub fn create(transport: Transport) -> Result<Archive> {
+        Archive::create_inner(transport, None)
+    }
+
+    /// Make a new archive whose blocks are compressed with zstd using a dictionary,
+    /// for example from [train_block_dictionary], rather than with Snappy.
+    ///
+    /// The dictionary is stored in the archive and used for every block written to
+    /// it. Backups into the archive can't be read by older versions of Conserve.
+    pub fn create_with_block_dictionary(
+        transport: Transport,
+        dictionary: &[u8],
+    ) -> Result<Archive> {
+        let prepared = Dictionary::new(dictionary)?;
+        Archive::create_inner(transport, Some((dictionary, prepared)))
+    }
+
+    fn create_inner(
+        transport: Transport,
+        dictionary: Option<(&[u8], Dictionary)>,
+    ) -> Result<Archive> {
+        if let Some(path) = transport.local_path() {
+            check_not_inside_archive(&path)?;
+        }
         transport.create_dir("")?;
         let names = transport.list_dir("")?;
         if !names.files.is_empty() || !names.dirs.is_empty() {
             return Err(Error::NewArchiveDirectoryNotEmpty);
         }
-        let block_dir = Arc::new(BlockDir::create(transport.chdir(BLOCK_DIR))?);
+        let mut block_dir = BlockDir::create(transport.chdir(BLOCK_DIR))?;
+        if let Some((content, prepared)) = dictionary {
+            transport.write_file(DICTIONARY_FILENAME, content, WriteMode::CreateNew)?;
+            block_dir = block_dir.with_dictionary(Some(Arc::new(prepared)));
+        }
         let header = ArchiveHeader {
             conserve_archive_version: String::from(ARCHIVE_VERSION),
+            external_filter: transport.is_filtered(),
+            block_dictionary: block_dir.dictionary().is_some(),
         };
         write_json(&transport, HEADER_FILENAME, &header)?;
+        let block_dir = Arc::new(block_dir);
         Ok(Archive {
             block_dir,
             transport,
+            index_cache: None,
+            index_read_ahead: None,
+            clock: Arc::new(SystemClock),
         })
     }
 
+    /// True if a local directory seems to hold an archive, because it has an archive
+    /// header.
+    ///
+    /// This doesn't check that the header is valid.
+    pub fn is_archive_path(path: &Path) -> bool {
+        path.join(HEADER_FILENAME).is_file()
+    }
+
     /// Open an existing archive.
     ///
     /// Checks that the header is correct.
@@ -84,22 +276,227 @@ impl Archive {
         Archive::open(Transport::local(path))
     }
 
+    /// Open an existing archive accessed by a Transport.
+    ///
+    /// The transport can be made with [Transport::from_protocol] to keep the archive
+    /// in custom storage.
+    ///
+    /// Checks that the header is correct.
+    ///
+    /// If the `CONSERVE_BLOCK_CACHE` environment variable is set to a number, up to
+    /// that many blocks are cached in memory rather than [DEFAULT_BLOCK_CACHE_SIZE];
+    /// zero disables the cache. See [Archive::with_block_cache].
+    ///
+    /// Similarly, `CONSERVE_BLOCK_LIST_CONCURRENCY` limits the number of block
+    /// subdirectories listed at once. See [Archive::with_block_list_concurrency].
     pub fn open(transport: Transport) -> Result<Archive> {
         let header: ArchiveHeader =
             read_json(&transport, HEADER_FILENAME)?.ok_or(Error::NotAnArchive)?;
-        if header.conserve_archive_version != ARCHIVE_VERSION {
-            return Err(Error::UnsupportedArchiveVersion {
-                version: header.conserve_archive_version,
-            });
+        check_archive_version(&header.conserve_archive_version)?;
+        match (header.external_filter, transport.is_filtered()) {
+            (true, false) => return Err(Error::ArchiveNeedsExternalFilter),
+            (false, true) => return Err(Error::ArchiveNotExternallyFiltered),
+            _ => (),
+        }
+        let mut block_dir = BlockDir::open(transport.chdir(BLOCK_DIR));
+        if header.block_dictionary {
+            let dictionary = Dictionary::new(&transport.read_file(DICTIONARY_FILENAME)?)?;
+            debug!(id = dictionary.id(), "Read block dictionary");
+            block_dir = block_dir.with_dictionary(Some(Arc::new(dictionary)));
+        }
+        if let Ok(capacity) = env::var("CONSERVE_BLOCK_CACHE") {
+            match capacity.parse() {
+                Ok(capacity) => block_dir = block_dir.with_block_cache(capacity),
+                Err(_) => warn!(?capacity, "Ignoring invalid CONSERVE_BLOCK_CACHE"),
+            }
+        }
+        if let Ok(tasks) = env::var("CONSERVE_BLOCK_LIST_CONCURRENCY") {
+            match tasks.parse() {
+                Ok(tasks) => block_dir = block_dir.with_list_concurrency(tasks),
+                Err(_) => warn!(?tasks, "Ignoring invalid CONSERVE_BLOCK_LIST_CONCURRENCY"),
+            }
         }
-        let block_dir = Arc::new(BlockDir::open(transport.chdir(BLOCK_DIR)));
         debug!(?header, "Opened archive");
         Ok(Archive {
-            block_dir,
+            block_dir: Arc::new(block_dir),
             transport,
+            index_cache: None,
+            index_read_ahead: None,
+            clock: Arc::new(SystemClock),
         })
     }
 
+    /// Keep up to `capacity` parsed index hunks in memory, and reuse them for later
+    /// reads of the same bands through this archive or its clones.
+    ///
+    /// This speeds up repeatedly listing or restoring the same versions, for example
+    /// from a mounted archive. If `capacity` is zero, the cache is disabled.
+    pub fn with_index_cache(self, capacity: usize) -> Archive {
+        Archive {
+            index_cache: NonZeroUsize::new(capacity)
+                .map(|capacity| Arc::new(IndexHunkCache::new(capacity))),
+            ..self
+        }
+    }
+
+    /// While reading through an index, fetch up to `hunks` further index hunks in the
+    /// background.
+    ///
+    /// This hides the latency of each read from a remote archive during sequential
+    /// iteration, at the cost of keeping those hunks in memory. By default, hunks
+    /// are read one at a time as they're needed.
+    pub fn with_index_read_ahead(self, hunks: usize) -> Archive {
+        Archive {
+            index_read_ahead: IndexReadAhead::new(hunks),
+            ..self
+        }
+    }
+
+    /// Keep the content of up to `capacity` recently read or written blocks in
+    /// memory, rather than [DEFAULT_BLOCK_CACHE_SIZE].
+    ///
+    /// If `capacity` is zero, the cache is disabled, which saves memory when each
+    /// block is read only once, such as when validating a large archive.
+    pub fn with_block_cache(self, capacity: usize) -> Archive {
+        Archive {
+            block_dir: Arc::new(
+                BlockDir::with_store(self.block_dir.store())
+                    .with_block_cache(capacity)
+                    .with_settings_from(&self.block_dir),
+            ),
+            ..self
+        }
+    }
+
+    /// List up to `tasks` block subdirectories at once when listing all the blocks,
+    /// for example during gc or validation, rather than one per rayon thread.
+    ///
+    /// This is useful with transports that fail or slow down under many concurrent
+    /// requests. Zero restores the default.
+    pub fn with_block_list_concurrency(self, tasks: usize) -> Archive {
+        Archive {
+            block_dir: Arc::new(
+                BlockDir::with_store(self.block_dir.store())
+                    .with_block_cache(self.block_dir.block_cache_capacity())
+                    .with_settings_from(&self.block_dir)
+                    .with_list_concurrency(tasks),
+            ),
+            ..self
+        }
+    }
+
+    /// Keep blocks in `store` rather than in the archive's own block directory.
+    ///
+    /// The archive header, bands, and indexes are still read and written through the
+    /// archive's transport. The same store must be given every time the archive is
+    /// opened, or the blocks its bands refer to will be missing.
+    pub fn with_block_store(self, store: Arc<dyn BlockStore>) -> Archive {
+        Archive {
+            block_dir: Arc::new(
+                BlockDir::with_store(store)
+                    .with_block_cache(self.block_dir.block_cache_capacity())
+                    .with_settings_from(&self.block_dir),
+            ),
+            ..self
+        }
+    }
+
+    /// Use `clock` for the start and end times of new bands, rather than the system clock.
+    ///
+    /// This is mostly useful for tests that need exact times.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Archive {
+        Archive { clock, ..self }
+    }
+
+    /// Return the exclude patterns stored in the archive, which apply to every backup
+    /// into it as well as any given in [BackupOptions::exclude].
+    ///
+    /// If none have been set, returns an empty list.
+    pub fn exclude_patterns(&self) -> Result<Vec<String>> {
+        match self.transport.read_file(EXCLUDES_FILENAME) {
+            Ok(bytes) => Ok(crate::excludes::patterns_from_str(
+                std::str::from_utf8(&bytes).map_err(|_| Error::InvalidMetadata {
+                    details: format!("{EXCLUDES_FILENAME} is not valid UTF-8"),
+                })?,
+            )
+            .map(str::to_owned)
+            .collect()),
+            Err(err) if err.is_not_found() => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Replace the exclude patterns stored in the archive.
+    ///
+    /// As in an exclude file, blank lines and comments starting with `#` are skipped.
+    /// If no patterns remain, any stored patterns are removed.
+    pub fn set_exclude_patterns<I, S>(&self, patterns: I) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut content = String::new();
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            if pattern.contains('\n') {
+                return Err(Error::ExcludePatternHasNewline {
+                    pattern: pattern.to_owned(),
+                });
+            }
+            content.push_str(pattern);
+            content.push('\n');
+        }
+        let patterns: Vec<&str> = crate::excludes::patterns_from_str(&content).collect();
+        // Check they parse, so that later backups don't fail.
+        Exclude::from_strings(&patterns)?;
+        if patterns.is_empty() {
+            return match self.transport.remove_file(EXCLUDES_FILENAME) {
+                Err(err) if !err.is_not_found() => Err(err.into()),
+                _ => Ok(()),
+            };
+        }
+        let content = patterns
+            .iter()
+            .map(|p| format!("{p}\n"))
+            .collect::<String>();
+        self.transport
+            .write_file(EXCLUDES_FILENAME, content.as_bytes(), WriteMode::Overwrite)?;
+        Ok(())
+    }
+
+    /// Return all the metadata stored in the archive by [Archive::set_metadata].
+    pub fn metadata(&self) -> Result<BTreeMap<String, String>> {
+        Ok(read_json(&self.transport, METADATA_FILENAME)?.unwrap_or_default())
+    }
+
+    /// Return the value of one key in the archive's metadata.
+    ///
+    /// Returns [Error::MetadataKeyNotFound] if the key has no value.
+    pub fn get_metadata(&self, key: &str) -> Result<String> {
+        self.metadata()?
+            .remove(key)
+            .ok_or_else(|| Error::MetadataKeyNotFound {
+                key: key.to_owned(),
+            })
+    }
+
+    /// Set the value of a key in the archive's metadata, such as who manages the
+    /// archive or the policy for keeping backups in it.
+    ///
+    /// The metadata isn't used by Conserve itself. It's stored as json in a small file
+    /// at the top of the archive, which is replaced as a whole, so a concurrent
+    /// reader sees either the old or the new values. If two writers race, the last
+    /// one to finish wins.
+    pub fn set_metadata(&self, key: &str, value: &str) -> Result<()> {
+        let mut metadata = self.metadata()?;
+        metadata.insert(key.to_owned(), value.to_owned());
+        let mut json = serde_json::to_string_pretty(&metadata)?;
+        json.push('\n');
+        self.transport
+            .write_file(METADATA_FILENAME, json.as_bytes(), WriteMode::Overwrite)?;
+        Ok(())
+    }
+
     pub fn block_dir(&self) -> &BlockDir {
         &self.block_dir
     }
@@ -135,23 +532,86 @@ impl Archive {
         Ok(band_ids)
     }
 
+    /// List the ids of the bands chosen by a selection, in order.
+    ///
+    /// Bands in a range that don't exist, such as those that were deleted, are
+    /// skipped.
+    pub fn select_bands(&self, selection: BandsSelection) -> Result<Vec<BandId>> {
+        let band_ids = self.list_band_ids()?;
+        Ok(match selection {
+            BandsSelection::All => band_ids,
+            BandsSelection::Range(start, end) => band_ids
+                .into_iter()
+                .filter(|band_id| (start..=end).contains(band_id))
+                .collect(),
+            BandsSelection::Latest(count) => {
+                band_ids[band_ids.len().saturating_sub(count)..].to_vec()
+            }
+        })
+    }
+
     pub(crate) fn transport(&self) -> &Transport {
         &self.transport
     }
 
-    pub fn resolve_band_id(&self, band_selection: BandSelectionPolicy) -> Result<BandId> {
+    /// Find the band selected by a policy.
+    ///
+    /// All commands that select a band do so through this, so that they fail the
+    /// same way: with [Error::ArchiveEmpty] if the archive has no bands,
+    /// [Error::NoCompleteBands] if a complete band is needed and all are incomplete,
+    /// and [Error::BandNotFound] if a specified band doesn't exist.
+    pub fn resolve_band(&self, band_selection: BandSelectionPolicy) -> Result<BandId> {
+        let last_band_id = self.last_band_id()?.ok_or(Error::ArchiveEmpty)?;
         match band_selection {
             BandSelectionPolicy::LatestClosed => self
                 .last_complete_band()?
                 .map(|band| band.id())
                 .ok_or(Error::NoCompleteBands),
-            BandSelectionPolicy::Specified(band_id) => Ok(band_id),
-            BandSelectionPolicy::Latest => self.last_band_id()?.ok_or(Error::ArchiveEmpty),
+            BandSelectionPolicy::Specified(band_id) => {
+                if self.band_exists(band_id)? {
+                    Ok(band_id)
+                } else {
+                    Err(Error::BandNotFound { band_id })
+                }
+            }
+            BandSelectionPolicy::Latest | BandSelectionPolicy::LatestIncludingIncomplete => {
+                Ok(last_band_id)
+            }
         }
     }
 
+    /// Find the band selected by a policy.
+    #[deprecated(note = "Use Archive::resolve_band")]
+    pub fn resolve_band_id(&self, band_selection: BandSelectionPolicy) -> Result<BandId> {
+        self.resolve_band(band_selection)
+    }
+
+    /// Open the stored tree of the band selected by a policy, as resolved by
+    /// [Archive::resolve_band].
     pub fn open_stored_tree(&self, band_selection: BandSelectionPolicy) -> Result<StoredTree> {
-        StoredTree::open(self, self.resolve_band_id(band_selection)?)
+        StoredTree::open(self, self.resolve_band(band_selection)?)
+    }
+
+    /// Iterate every complete band in the archive, in order, opened as a tree.
+    ///
+    /// Bands are opened as the iterator reaches them. Incomplete bands are skipped,
+    /// and a band that can't be opened yields an error.
+    pub fn stored_trees(&self) -> Result<impl Iterator<Item = Result<(BandId, StoredTree)>>> {
+        let archive = self.clone();
+        Ok(self
+            .list_band_ids()?
+            .into_iter()
+            .filter_map(move |band_id| {
+                let tree = match StoredTree::open(&archive, band_id) {
+                    Ok(tree) => tree,
+                    Err(err) => return Some(Err(err)),
+                };
+                match tree.is_closed() {
+                    Ok(true) => Some(Ok((band_id, tree))),
+                    Ok(false) => None,
+                    Err(err) => Some(Err(err)),
+                }
+            }))
     }
 
     /// Return an iterator of valid band ids in this archive, in arbitrary order.
@@ -194,18 +654,168 @@ impl Archive {
         band_ids: &[BandId],
         monitor: Arc<dyn Monitor>,
     ) -> Result<HashSet<BlockHash>> {
-        let archive = self.clone();
         let task = monitor.start_task("Find referenced blocks".to_string());
+        task.set_total(band_ids.len());
         Ok(band_ids
             .par_iter()
-            .map(move |band_id| Band::open(&archive, *band_id).expect("Failed to open band"))
-            .flat_map_iter(|band| band.index().iter_entries())
-            .flat_map_iter(|entry| entry.addrs)
-            .map(|addr| addr.hash)
-            .inspect(|_| {
+            .map(|band_id| {
+                let band = Band::open(self, *band_id).expect("Failed to open band");
+                let band_task = task.start_subtask(format!("Band {band_id}"));
+                let hashes: HashSet<BlockHash> = band
+                    .index()
+                    .iter_entries()
+                    .flat_map(|entry| entry.addrs)
+                    .map(|addr| addr.hash)
+                    .inspect(|_| band_task.increment(1))
+                    .collect();
+                drop(band_task);
+                task.increment(1);
+                hashes
+            })
+            .reduce(HashSet::new, |mut a, b| {
+                a.extend(b);
+                a
+            }))
+    }
+
+    /// Iterate every address of file content in every band, in band order and then
+    /// in apath order within each band.
+    ///
+    /// Bands are opened and their index hunks read only as the iterator reaches them,
+    /// so this can be used on large archives without holding all the indexes in
+    /// memory. A band that can't be opened yields an error, and iteration continues
+    /// with the next band.
+    pub fn all_referenced_addresses(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<AddressReference>>> {
+        let archive = self.clone();
+        Ok(self.list_band_ids()?.into_iter().flat_map(
+            move |band_id| -> Box<dyn Iterator<Item = _>> {
+                match Band::open(&archive, band_id) {
+                    Ok(band) => Box::new(band.index().iter_entries().flat_map(move |entry| {
+                        let apath = entry.apath;
+                        entry.addrs.into_iter().map(move |address| {
+                            Ok(AddressReference {
+                                band_id,
+                                apath: apath.clone(),
+                                address,
+                            })
+                        })
+                    })),
+                    Err(err) => Box::new(std::iter::once(Err(err))),
+                }
+            },
+        ))
+    }
+
+    /// Describe one block: its size, whether its content is intact, and which files
+    /// reference it.
+    ///
+    /// This reads every index in the archive, so is slow on large archives.
+    pub fn block_info(&self, hash: &BlockHash, monitor: Arc<dyn Monitor>) -> Result<BlockInfo> {
+        let (compressed, decompressed) = self.block_dir.read_block_uncached(hash)?;
+        let (uncompressed_len, hash_matches) = match decompressed {
+            Ok(content) => (
+                Some(content.len()),
+                BlockHash::hash_bytes(&content) == *hash,
+            ),
+            Err(err) => {
+                monitor.error(err);
+                (None, false)
+            }
+        };
+        let task = monitor.start_task("Find block references".to_string());
+        let band_ids = self.list_band_ids()?;
+        task.set_total(band_ids.len());
+        let mut references: Vec<BlockReference> = band_ids
+            .par_iter()
+            .map(|band_id| -> Result<Vec<BlockReference>> {
+                let references = Band::open(self, *band_id)?
+                    .index()
+                    .iter_entries()
+                    .filter(|entry| entry.addrs.iter().any(|addr| addr.hash == *hash))
+                    .map(|entry| BlockReference {
+                        band_id: *band_id,
+                        apath: entry.apath,
+                    })
+                    .collect();
+                task.increment(1);
+                Ok(references)
+            })
+            .collect::<Result<Vec<Vec<BlockReference>>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+        references.sort_unstable();
+        let bands = references.iter().map(|r| r.band_id).collect();
+        Ok(BlockInfo {
+            hash: hash.clone(),
+            compressed_len: compressed.len() as u64,
+            uncompressed_len,
+            hash_matches,
+            references,
+            bands,
+        })
+    }
+
+    /// Find the bands whose index references a block, including incomplete bands.
+    ///
+    /// Each band's index is read only until the first reference is found, but in the
+    /// worst case this reads every index in the archive.
+    pub fn bands_referencing(
+        &self,
+        hash: &BlockHash,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<BTreeSet<BandId>> {
+        let task = monitor.start_task("Find bands referencing block".to_string());
+        let band_ids = self.list_band_ids()?;
+        task.set_total(band_ids.len());
+        let referencing = band_ids
+            .par_iter()
+            .map(|band_id| -> Result<Option<BandId>> {
+                let found = Band::open(self, *band_id)?
+                    .index()
+                    .iter_entries()
+                    .any(|entry| entry.addrs.iter().any(|addr| addr.hash == *hash));
                 task.increment(1);
+                Ok(found.then_some(*band_id))
             })
-            .collect())
+            .collect::<Result<Vec<Option<BandId>>>>()?;
+        Ok(referencing.into_iter().flatten().collect())
+    }
+
+    /// Measure the space used by blocks in the archive, compared to the size of all
+    /// the files stored in it.
+    ///
+    /// This reads every index in the archive, and the metadata of every block.
+    pub fn disk_usage(&self, monitor: Arc<dyn Monitor>) -> Result<DiskUsage> {
+        let band_ids = self.list_band_ids()?;
+        let task = monitor.start_task("Measure stored files".to_string());
+        task.set_total(band_ids.len());
+        let logical_bytes = band_ids
+            .par_iter()
+            .map(|band_id| -> Result<u64> {
+                let bytes = Band::open(self, *band_id)?
+                    .index()
+                    .iter_entries()
+                    .flat_map(|entry| entry.addrs)
+                    .map(|addr| addr.len)
+                    .sum();
+                task.increment(1);
+                Ok(bytes)
+            })
+            .collect::<Result<Vec<u64>>>()?
+            .into_iter()
+            .sum();
+        drop(task);
+        let (blocks, physical_bytes) = self.block_dir.compressed_size_total(monitor)?;
+        Ok(DiskUsage {
+            bands: band_ids.len(),
+            logical_bytes,
+            blocks,
+            physical_bytes,
+            factor: (physical_bytes > 0).then(|| logical_bytes as f64 / physical_bytes as f64),
+        })
     }
 
     /// Returns an iterator of blocks that are present and referenced by no index.
@@ -220,6 +830,25 @@ impl Archive {
             .filter(move |h| !referenced.contains(h)))
     }
 
+    /// Count which blocks are present in only this archive, only `other`, or both.
+    ///
+    /// This lists the blocks in both archives, without reading them, so it shows how
+    /// many blocks would be copied to bring either archive up to date with the other.
+    pub fn compare_blocks(
+        &self,
+        other: &Archive,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<BlockComparison> {
+        let first: HashSet<BlockHash> = self.block_dir.blocks(monitor.clone())?.collect();
+        let second: HashSet<BlockHash> = other.block_dir.blocks(monitor)?.collect();
+        let in_both = first.intersection(&second).count();
+        Ok(BlockComparison {
+            only_in_first: first.len() - in_both,
+            only_in_second: second.len() - in_both,
+            in_both,
+        })
+    }
+
     /// Delete bands, and the blocks that they reference.
     ///
     /// If `delete_band_ids` is empty, this deletes no bands, but will delete any garbage
@@ -234,6 +863,11 @@ impl Archive {
         let start = Instant::now();
 
         // TODO: No need to lock for dry_run.
+        let _write_lock = if options.break_lock {
+            WriteLock::break_lock(self)?
+        } else {
+            WriteLock::acquire(self)?
+        };
         let delete_guard = if options.break_lock {
             gc_lock::GarbageCollectionLock::break_lock(self)?
         } else {
@@ -283,6 +917,9 @@ impl Archive {
                 stats.deleted_band_count += 1;
                 task.increment(1);
             }
+            if !delete_band_ids.is_empty() {
+                band_manifest::update_or_warn(self);
+            }
 
             let task = monitor.start_task("Delete blocks".to_string());
             task.set_total(unref_count);
@@ -303,19 +940,41 @@ impl Archive {
 
     /// Walk the archive to check all invariants.
     ///
-    /// If problems are found, they are emitted as `warn` or `error` level
-    /// tracing messages. This function only returns an error if validation
-    /// stops due to a fatal error.
-    pub fn validate(&self, options: &ValidateOptions, monitor: Arc<dyn Monitor>) -> Result<()> {
+    /// Problems found are reported as errors to the monitor, and also returned in
+    /// the report. This function only returns an error if validation stops due to a
+    /// fatal error.
+    pub fn validate(
+        &self,
+        options: &ValidateOptions,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<ValidationReport> {
+        let problems = Arc::new(validate::ReportProblems::new(monitor));
+        self.validate_into(options, problems.clone())?;
+        Ok(problems.report())
+    }
+
+    fn validate_into(
+        &self,
+        options: &ValidateOptions,
+        problems: Arc<validate::ReportProblems>,
+    ) -> Result<()> {
+        let monitor: Arc<dyn Monitor> = problems.clone();
         self.validate_archive_dir(monitor.clone())?;
 
         debug!("List bands...");
-        let band_ids = self.list_band_ids()?;
+        let band_ids = self.select_bands(options.bands)?;
         debug!("Check {} bands...", band_ids.len());
 
         // 1. Walk all indexes, collecting a list of (block_hash6, min_length)
         //    values referenced by all the indexes.
-        let referenced_lens = validate::validate_bands(self, &band_ids, monitor.clone())?;
+        let referenced_lens = validate::validate_bands(self, &band_ids, options, problems)?;
+
+        if options.remove_empty_blocks {
+            let _lock = WriteLock::acquire(self)?;
+            for hash in self.block_dir.remove_empty_blocks(monitor.clone())? {
+                info!(%hash, "Removed empty block file");
+            }
+        }
 
         if options.skip_block_hashes {
             // 3a. Check that all referenced blocks are present, without spending time reading their
@@ -324,18 +983,29 @@ impl Archive {
             // TODO: Check for unexpected files or directories in the blockdir.
             let present_blocks: HashSet<BlockHash> =
                 self.block_dir.blocks(monitor.clone())?.collect();
-            for hash in referenced_lens.keys() {
+            for hash in referenced_lens.keys().sorted() {
                 if !present_blocks.contains(hash) {
                     monitor.error(Error::BlockMissing { hash: hash.clone() })
                 }
             }
         } else {
             // 2. Check the hash of all blocks are correct, and remember how long
-            //    the uncompressed data is.
-            let block_lengths: HashMap<BlockHash, usize> =
-                self.block_dir.validate(monitor.clone())?;
+            //    the uncompressed data is. If only some bands are being checked,
+            //    check only the blocks they reference.
+            let block_lengths: HashMap<BlockHash, usize> = if options.bands == BandsSelection::All {
+                self.block_dir.validate(monitor.clone())?
+            } else {
+                let present_blocks: HashSet<BlockHash> =
+                    self.block_dir.blocks(monitor.clone())?.collect();
+                let blocks = referenced_lens
+                    .keys()
+                    .filter(|hash| present_blocks.contains(hash))
+                    .cloned()
+                    .collect();
+                self.block_dir.validate_blocks(blocks, monitor.clone())
+            };
             // 3b. Check that all referenced ranges are inside the present data.
-            for (hash, referenced_len) in referenced_lens {
+            for (hash, referenced_len) in referenced_lens.into_iter().sorted() {
                 if let Some(&actual_len) = block_lengths.get(&hash) {
                     if referenced_len > actual_len as u64 {
                         monitor.error(Error::BlockTooShort {
@@ -376,6 +1046,10 @@ impl Archive {
         for name in list_dir.files {
             if !name.eq_ignore_ascii_case(HEADER_FILENAME)
                 && !name.eq_ignore_ascii_case(crate::gc_lock::GC_LOCK)
+                && !name.eq_ignore_ascii_case(crate::write_lock::WRITE_LOCK_FILENAME)
+                && !name.eq_ignore_ascii_case(EXCLUDES_FILENAME)
+                && !name.eq_ignore_ascii_case(METADATA_FILENAME)
+                && !name.eq_ignore_ascii_case(crate::band_manifest::BANDS_MANIFEST_FILENAME)
                 && !name.eq_ignore_ascii_case(".DS_Store")
             {
                 // TODO: The whole path not just the filename
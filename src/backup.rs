@@ -14,60 +14,250 @@
 //! Make a backup by walking a source directory and copying the contents
 //! into an archive.
 
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fmt;
-use std::io::prelude::*;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, SeekFrom};
 use std::mem::take;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use blake2_rfc::blake2b::Blake2b;
 use bytes::BytesMut;
 use derive_more::{Add, AddAssign};
 use itertools::Itertools;
-use tracing::{trace, warn};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace, warn};
 
 use crate::blockdir::Address;
 use crate::change::Change;
+use crate::chunk::Chunker;
 use crate::counters::Counter;
-use crate::io::read_with_retries;
+use crate::entry::KindMeta;
 use crate::monitor::Monitor;
 use crate::stats::{write_compressed_size, write_count, write_duration, write_size};
 use crate::stitch::IterStitchedIndexHunks;
 use crate::*;
 
+/// Default for [BackupOptions::max_entries_per_hunk].
+pub const DEFAULT_MAX_ENTRIES_PER_HUNK: usize = 100_000;
+
+/// Backup warns if less than this many bytes are available for the archive.
+///
+/// The size of the new data isn't known until the source has been read, so this is
+/// only a rough guard against running out of space partway through.
+pub const LOW_SPACE_WARNING_BYTES: u64 = 1 << 30;
+
 /// Configuration of how to make a backup.
 pub struct BackupOptions<'cb> {
     /// Exclude these globs from the backup.
+    ///
+    /// The archive's own exclude patterns are also applied, unless
+    /// `ignore_archive_excludes` is set. Patterns here can only exclude more paths:
+    /// they can't re-include paths excluded by the archive.
     pub exclude: Exclude,
 
+    /// If set, back up only files, symlinks, and other entries that aren't
+    /// directories if they match these globs.
+    ///
+    /// All directories not excluded are still stored, so that the included entries
+    /// inside them can be reached and restored. Excludes take precedence: an entry
+    /// matching both is not stored.
+    pub include: Option<Exclude>,
+
+    /// Back up only these paths within the source directory, and the directories
+    /// containing them, rather than everything in it.
+    ///
+    /// Paths may be relative to the source directory, or absolute paths inside it.
+    /// Listed paths that don't exist are skipped with a warning.
+    pub source_paths: Option<Vec<PathBuf>>,
+
+    /// Don't apply the exclude patterns stored in the archive, from
+    /// [Archive::exclude_patterns].
+    pub ignore_archive_excludes: bool,
+
+    /// Write an index hunk after this many entries; must be at least 1.
+    ///
+    /// Smaller hunks mean less is lost when a backup is interrupted, and allow more
+    /// parallelism when reading the index; larger hunks have less overhead.
     pub max_entries_per_hunk: usize,
 
+    /// How to compress the index hunks of the new band, independently of how blocks
+    /// are compressed.
+    ///
+    /// Bands whose index is compressed with zstd are marked with the `index_zstd`
+    /// format flag, so that older versions of Conserve refuse to read them rather
+    /// than failing part way through.
+    pub index_compression: IndexCompression,
+
     /// Call this callback as each entry is successfully stored.
     pub change_callback: Option<ChangeCallback<'cb>>,
 
+    /// The largest block to store from one file, unless a rule in `block_size_rules`
+    /// matches the file.
     pub max_block_size: usize,
 
+    /// Use a different maximum block size for files matching some patterns: for
+    /// example, larger blocks for media files, which rarely change in place and have
+    /// many blocks, or smaller ones for source code, which deduplicates better in
+    /// smaller pieces.
+    ///
+    /// The first matching rule applies; files matching no rule use `max_block_size`.
+    /// Files no larger than `small_file_cap` are still combined into shared blocks.
+    pub block_size_rules: Vec<BlockSizeRule>,
+
+    /// How to split files larger than one block.
+    pub chunking: Chunking,
+
     /// Combine files smaller than this into a single block.
     pub small_file_cap: u64,
 
     /// Record the user/group owners on Unix.
     pub owner: bool,
+
+    /// While storing a file larger than one block, record the blocks stored so far in the
+    /// band, so that if the backup is interrupted the next backup needn't read them again.
+    ///
+    /// This costs one small extra write per block.
+    pub checkpoint_large_files: bool,
+
+    /// When new data has the same hash as a block already in the archive, read the
+    /// existing block and check it has the same content, rather than trusting the hash.
+    ///
+    /// Existing blocks found to be corrupt are stored again.
+    pub verify_dedup: bool,
+
+    /// Store a block of a large file that's a prefix of a block already stored, such
+    /// as the end of a file that was truncated since the last backup, as a reference
+    /// into the existing block rather than as a new block.
+    ///
+    /// This keeps an in-memory index of the start of each block stored or matched
+    /// during the backup, and reads the blocks of the previous version of each
+    /// changed file that aren't matched exactly, so it costs memory and reads.
+    pub dedup_prefixes: bool,
+
+    /// Warn about apaths longer than this many bytes, which might not be restorable
+    /// on some filesystems.
+    pub max_path_len: Option<usize>,
+
+    /// Warn about apaths nested more than this many directories deep.
+    pub max_path_depth: Option<usize>,
+
+    /// Skip entries that exceed `max_path_len` or `max_path_depth`, reporting an
+    /// error rather than a warning.
+    pub strict_paths: bool,
+
+    /// Back up at most this many files, directories, and other entries from each
+    /// source directory: the first by name. The rest are skipped with a warning,
+    /// and counted in [Counter::OverflowEntries].
+    ///
+    /// This doesn't apply to `source_paths`.
+    pub max_files_per_dir: Option<usize>,
+
+    /// Warn about symlinks whose target doesn't exist, and count them in
+    /// [Counter::DanglingSymlinks]. They're still stored.
+    pub warn_dangling_symlinks: bool,
+
+    /// Break the archive's write lock, if it's held, before starting.
+    ///
+    /// Use this only if you're confident the process that took the lock has
+    /// terminated.
+    pub break_lock: bool,
+
+    /// Don't warn if the storage holding the archive has less than
+    /// [LOW_SPACE_WARNING_BYTES] available.
+    pub ignore_space_check: bool,
+
+    /// Record the path of the source directory in the band, as well as the Conserve
+    /// version and hostname that are always recorded.
+    pub record_source_path: bool,
+
+    /// Record whether each directory was empty in the source, in
+    /// [IndexEntry::empty_in_source], so that a directory stored with no children
+    /// because everything in it was excluded can be told apart from one that was
+    /// really empty. This costs an extra read of each directory.
+    pub record_empty_dirs: bool,
+
+    /// If this becomes true, for example from a signal handler, stop the backup
+    /// cleanly before the next entry.
+    ///
+    /// The index entries stored so far are written out, and the band is left
+    /// incomplete and marked as interrupted, so the next backup needn't store those
+    /// files again. The backup then returns [Error::BackupInterrupted].
+    pub stop_requested: Option<&'cb AtomicBool>,
+}
+
+impl BackupOptions<'_> {
+    /// The maximum block size for a file, from the first matching rule in
+    /// `block_size_rules` or otherwise `max_block_size`.
+    fn max_block_size_for(&self, apath: &Apath) -> usize {
+        self.block_size_rules
+            .iter()
+            .find(|rule| rule.files.matches(apath))
+            .map_or(self.max_block_size, |rule| rule.max_block_size)
+    }
 }
 
 impl Default for BackupOptions<'_> {
     fn default() -> BackupOptions<'static> {
         BackupOptions {
             exclude: Exclude::nothing(),
-            max_entries_per_hunk: 100_000,
+            include: None,
+            ignore_archive_excludes: false,
+            source_paths: None,
+            max_entries_per_hunk: DEFAULT_MAX_ENTRIES_PER_HUNK,
+            index_compression: IndexCompression::Snappy,
             change_callback: None,
             max_block_size: 20 << 20,
+            block_size_rules: Vec::new(),
+            chunking: Chunking::Fixed,
             small_file_cap: 1 << 20,
             owner: true,
+            checkpoint_large_files: false,
+            verify_dedup: false,
+            dedup_prefixes: false,
+            max_path_len: None,
+            max_path_depth: None,
+            strict_paths: false,
+            max_files_per_dir: None,
+            warn_dangling_symlinks: false,
+            break_lock: false,
+            ignore_space_check: false,
+            record_source_path: false,
+            record_empty_dirs: false,
+            stop_requested: None,
         }
     }
 }
 
+/// A maximum block size for files matching some patterns, in
+/// [BackupOptions::block_size_rules].
+#[derive(Clone, Debug)]
+pub struct BlockSizeRule {
+    /// Files the rule applies to, matched with the same syntax as exclude patterns.
+    pub files: Exclude,
+    pub max_block_size: usize,
+}
+
+impl BlockSizeRule {
+    /// Make a rule for files matching any of some glob patterns, such as `*.mp4`.
+    pub fn new<I, S>(patterns: I, max_block_size: usize) -> Result<BlockSizeRule>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        assert!(max_block_size > 0);
+        Ok(BlockSizeRule {
+            files: Exclude::from_strings(patterns)?,
+            max_block_size,
+        })
+    }
+}
+
 // This causes us to walk the source tree twice, which is probably an acceptable option
 // since it's nice to see realistic overall progress. We could keep all the entries
 // in memory, and maybe we should, but it might get unreasonably big.
@@ -82,6 +272,10 @@ impl Default for BackupOptions<'_> {
 
 /// Backup a source directory into a new band in the archive.
 ///
+/// If the archive is on the local filesystem, this refuses to back up a source inside
+/// the archive, or a source containing the archive unless the archive is excluded.
+/// Overlap with a remote archive can't be detected.
+///
 /// Returns statistics about what was copied.
 pub fn backup(
     archive: &Archive,
@@ -90,19 +284,55 @@ pub fn backup(
     monitor: Arc<dyn Monitor>,
 ) -> Result<BackupStats> {
     let start = Instant::now();
-    let mut writer = BackupWriter::begin(archive, options, monitor.clone())?;
+    let exclude = if options.ignore_archive_excludes {
+        options.exclude.clone()
+    } else {
+        options
+            .exclude
+            .clone()
+            .union(Exclude::from_strings(archive.exclude_patterns()?)?)
+    };
+    check_source_and_archive_overlap(archive, source_path, &exclude)?;
+    let source_tree =
+        LiveTree::open(source_path)?.with_max_files_per_dir(options.max_files_per_dir);
+    if !options.ignore_space_check {
+        warn_if_low_space(archive);
+    }
+    let mut writer = BackupWriter::begin(archive, source_path, options, monitor.clone())?;
     let mut stats = BackupStats::default();
-    let source_tree = LiveTree::open(source_path)?;
+    let mut interrupted = false;
 
     let task = monitor.start_task("Backup".to_string());
 
-    let entry_iter =
-        source_tree.iter_entries(Apath::root(), options.exclude.clone(), monitor.clone())?;
-    for entry_group in entry_iter.chunks(options.max_entries_per_hunk).into_iter() {
+    let entry_iter: Box<dyn Iterator<Item = EntryValue>> = match &options.source_paths {
+        Some(paths) => Box::new(source_tree.listed_entries(paths, &exclude).into_iter()),
+        None => Box::new(source_tree.iter_entries(Apath::root(), exclude, monitor.clone())?),
+    };
+    'groups: for entry_group in entry_iter.chunks(options.max_entries_per_hunk).into_iter() {
         for mut entry in entry_group {
+            if options
+                .stop_requested
+                .is_some_and(|stop| stop.load(Relaxed))
+            {
+                interrupted = true;
+                break 'groups;
+            }
+            if let Some(include) = &options.include {
+                if entry.kind() != Kind::Dir && !include.matches_kind(entry.apath(), entry.kind()) {
+                    continue;
+                }
+            }
             if !options.owner {
                 entry.owner.clear();
             }
+            if let Err(err) = check_path_limits(entry.apath(), options) {
+                if options.strict_paths {
+                    monitor.error(err);
+                    stats.errors += 1;
+                    continue;
+                }
+                warn!("{err}");
+            }
             match writer.copy_entry(&entry, &source_tree, options, monitor.clone()) {
                 Err(err) => {
                     monitor.error(err);
@@ -127,7 +357,21 @@ pub fn backup(
         }
         writer.flush_group(monitor.clone())?;
     }
-    stats += writer.finish(monitor.clone())?;
+    if interrupted {
+        let band_id = writer.interrupt(archive, monitor.clone())?;
+        return Err(Error::BackupInterrupted { band_id });
+    }
+    stats += writer.finish(archive, monitor.clone())?;
+    if stats.unknown_kind > 0 {
+        warn!(
+            block_devices = stats.block_devices,
+            char_devices = stats.char_devices,
+            fifos = stats.fifos,
+            sockets = stats.sockets,
+            "Skipped {} special files that can't be backed up",
+            stats.unknown_kind,
+        );
+    }
     stats.elapsed = start.elapsed();
     let block_stats = &archive.block_dir.stats;
     stats.read_blocks = block_stats.read_blocks.load(Relaxed);
@@ -137,6 +381,83 @@ pub fn backup(
     Ok(stats)
 }
 
+/// Return an error if an apath exceeds the length or depth limits in the options.
+fn check_path_limits(apath: &Apath, options: &BackupOptions) -> Result<()> {
+    if let Some(limit) = options.max_path_len {
+        if apath.len() > limit {
+            return Err(Error::PathTooLong {
+                apath: apath.clone(),
+                len: apath.len(),
+                limit,
+            });
+        }
+    }
+    if let Some(limit) = options.max_path_depth {
+        if apath.depth() > limit {
+            return Err(Error::PathTooDeep {
+                apath: apath.clone(),
+                depth: apath.depth(),
+                limit,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Warn if the archive's storage is nearly full, so the backup might fail before
+/// it completes.
+fn warn_if_low_space(archive: &Archive) {
+    let Some(available) = archive.transport().available_space() else {
+        return;
+    };
+    debug!(available, "Space available for the archive");
+    if available < LOW_SPACE_WARNING_BYTES {
+        warn!(
+            "Only {} available for the archive: the backup may run out of space",
+            bytes_to_human_mb(available)
+        );
+    }
+}
+
+/// Return an error if backing up `source_path` would copy the archive into itself.
+fn check_source_and_archive_overlap(
+    archive: &Archive,
+    source_path: &Path,
+    exclude: &Exclude,
+) -> Result<()> {
+    let Some(archive_path) = archive.transport().local_path() else {
+        return Ok(());
+    };
+    // If either can't be resolved, opening the source or writing the archive will
+    // report a better error.
+    let (Ok(archive_path), Ok(source_path)) =
+        (archive_path.canonicalize(), source_path.canonicalize())
+    else {
+        return Ok(());
+    };
+    if source_path.starts_with(&archive_path) {
+        return Err(Error::BackupSourceInsideArchive { source_path });
+    }
+    let Some(relpath) = archive_path
+        .strip_prefix(&source_path)
+        .ok()
+        .and_then(Path::to_str)
+    else {
+        return Ok(());
+    };
+    let archive_apath = Apath::from(format!("/{}", relpath.replace('\\', "/")));
+    let mut apath = Some(archive_apath.clone());
+    while let Some(a) = apath {
+        if exclude.matches_kind(&a, Kind::Dir) {
+            return Ok(());
+        }
+        apath = a.parent();
+    }
+    Err(Error::ArchiveInsideBackupSource {
+        apath: archive_apath,
+    })
+}
+
 /// Accepts files to write in the archive (in apath order.)
 struct BackupWriter {
     band: Band,
@@ -148,7 +469,51 @@ struct BackupWriter {
     /// stored files have changed.
     basis_index: crate::index::IndexEntryIter<crate::stitch::IterStitchedIndexHunks>,
 
+    /// A large file that was partly stored when the basis band was interrupted.
+    resume: Option<PartialFile>,
+
     file_combiner: FileCombiner,
+
+    /// Blocks that file content might be a prefix of, if
+    /// [BackupOptions::dedup_prefixes] is set.
+    prefix_index: Option<PrefixIndex>,
+
+    /// Held until the backup is finished, to keep out other writers.
+    _write_lock: WriteLock,
+}
+
+/// The blocks stored so far from a file too large to fit in one block.
+///
+/// This is written into the band while the file is being stored. If the backup is
+/// interrupted, and the file hasn't changed, the next backup can start from the end
+/// of these blocks rather than reading the whole file again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PartialFile {
+    apath: Apath,
+    mtime: i64,
+    mtime_nanos: u32,
+    /// Size of the whole source file.
+    size: u64,
+    /// Addresses of the leading blocks already stored.
+    addrs: Vec<Address>,
+}
+
+impl PartialFile {
+    fn new(source_entry: &EntryValue) -> PartialFile {
+        let mtime = source_entry.mtime();
+        PartialFile {
+            apath: source_entry.apath().clone(),
+            mtime: mtime.unix_timestamp(),
+            mtime_nanos: mtime.nanosecond(),
+            size: source_entry.size().expect("source entry has a size"),
+            addrs: Vec::new(),
+        }
+    }
+
+    /// Total length of the blocks already stored.
+    fn stored_len(&self) -> u64 {
+        self.addrs.iter().map(|addr| addr.len).sum()
+    }
 }
 
 impl BackupWriter {
@@ -157,13 +522,32 @@ impl BackupWriter {
     /// This currently makes a new top-level band.
     pub fn begin(
         archive: &Archive,
+        source_path: &Path,
         options: &BackupOptions,
         monitor: Arc<dyn Monitor>,
     ) -> Result<Self> {
         if gc_lock::GarbageCollectionLock::is_locked(archive)? {
             return Err(Error::GarbageCollectionLockHeld);
         }
-        let basis_index = if let Some(basis_band_id) = archive.last_band_id()? {
+        let write_lock = if options.break_lock {
+            WriteLock::break_lock(archive)?
+        } else {
+            WriteLock::acquire(archive)?
+        };
+        let basis_band_id = archive.last_band_id()?;
+        let resume = basis_band_id.and_then(|band_id| {
+            match Band::open(archive, band_id).and_then(|band| band.read_partial_file()) {
+                Ok(resume) => resume,
+                Err(err) => {
+                    warn!(
+                        ?err,
+                        "Failed to read partially stored file from the last band"
+                    );
+                    None
+                }
+            }
+        });
+        let basis_index = if let Some(basis_band_id) = basis_band_id {
             IterStitchedIndexHunks::new(archive, basis_band_id, monitor)
         } else {
             IterStitchedIndexHunks::empty(archive, monitor)
@@ -171,7 +555,27 @@ impl BackupWriter {
         .iter_entries(Apath::root(), Exclude::nothing());
 
         // Create the new band only after finding the basis band!
-        let band = Band::create(archive)?;
+        let recorded_source_path = options
+            .record_source_path
+            .then(|| {
+                source_path
+                    .canonicalize()
+                    .unwrap_or_else(|_| source_path.to_owned())
+            })
+            .map(|path| path.to_string_lossy().into_owned());
+        let mut format_flags = band::flags::DEFAULT.to_vec();
+        if options.index_compression == IndexCompression::Zstd {
+            format_flags.push(Cow::Borrowed(band::flags::INDEX_ZSTD));
+        }
+        if archive.block_dir.dictionary().is_some() {
+            format_flags.push(Cow::Borrowed(band::flags::BLOCK_DICTIONARY));
+        }
+        let band = Band::create_for_backup(
+            archive,
+            &format_flags,
+            recorded_source_path,
+            Some(options.chunking),
+        )?;
         let index_builder = band.index_builder();
         Ok(BackupWriter {
             band,
@@ -179,16 +583,36 @@ impl BackupWriter {
             block_dir: archive.block_dir.clone(),
             stats: BackupStats::default(),
             basis_index,
-            file_combiner: FileCombiner::new(archive.block_dir.clone(), options.max_block_size),
+            resume,
+            file_combiner: FileCombiner::new(
+                archive.block_dir.clone(),
+                options.max_block_size,
+                options.verify_dedup,
+            ),
+            prefix_index: options.dedup_prefixes.then(PrefixIndex::default),
+            _write_lock: write_lock,
         })
     }
 
-    fn finish(self, monitor: Arc<dyn Monitor>) -> Result<BackupStats> {
+    /// Close the band, and update the archive's manifest of bands while still
+    /// holding the write lock.
+    fn finish(self, archive: &Archive, monitor: Arc<dyn Monitor>) -> Result<BackupStats> {
         let hunks = self.index_builder.finish(monitor)?;
         self.band.close(hunks as u64)?;
+        band_manifest::update_or_warn(archive);
         Ok(BackupStats { ..self.stats })
     }
 
+    /// Write out everything pending and mark the band as interrupted, leaving it
+    /// incomplete, and update the manifest of bands while still holding the write
+    /// lock. Returns the id of the band.
+    fn interrupt(mut self, archive: &Archive, monitor: Arc<dyn Monitor>) -> Result<BandId> {
+        self.flush_group(monitor)?;
+        self.band.mark_interrupted()?;
+        band_manifest::update_or_warn(archive);
+        Ok(self.band.id())
+    }
+
     /// Write out any pending data blocks, and then the pending index entries.
     fn flush_group(&mut self, monitor: Arc<dyn Monitor>) -> Result<()> {
         let (stats, mut entries) = self.file_combiner.drain(monitor.clone())?;
@@ -211,14 +635,28 @@ impl BackupWriter {
     ) -> Result<Option<EntryChange>> {
         // TODO: Emit deletions for entries in the basis not present in the source.
         match entry.kind() {
-            Kind::Dir => self.copy_dir(entry, monitor.as_ref()),
+            Kind::Dir => self.copy_dir(entry, source, options, monitor.as_ref()),
             Kind::File => self.copy_file(entry, source, options, monitor.clone()),
-            Kind::Symlink => self.copy_symlink(entry, monitor.as_ref()),
+            Kind::Symlink => self.copy_symlink(entry, source, options, monitor.as_ref()),
             Kind::Unknown => {
-                self.stats.unknown_kind += 1;
                 // TODO: Perhaps eventually we could backup and restore pipes,
-                // sockets, etc. Or at least count them. For now, silently skip.
+                // sockets, etc. For now, count and skip them, with one warning at
+                // the end of the backup.
                 // https://github.com/sourcefrog/conserve/issues/82
+                let special = match entry.kind_meta {
+                    KindMeta::Unknown { special } => special,
+                    _ => SpecialKind::Other,
+                };
+                debug!(apath = %entry.apath(), ?special, "Skip special file");
+                monitor.count(Counter::SpecialFiles, 1);
+                self.stats.unknown_kind += 1;
+                match special {
+                    SpecialKind::BlockDevice => self.stats.block_devices += 1,
+                    SpecialKind::CharDevice => self.stats.char_devices += 1,
+                    SpecialKind::Fifo => self.stats.fifos += 1,
+                    SpecialKind::Socket => self.stats.sockets += 1,
+                    SpecialKind::Other => (),
+                }
                 Ok(None)
             }
         }
@@ -227,12 +665,28 @@ impl BackupWriter {
     fn copy_dir(
         &mut self,
         source_entry: &EntryValue,
+        source: &LiveTree,
+        options: &BackupOptions,
         monitor: &dyn Monitor,
     ) -> Result<Option<EntryChange>> {
         monitor.count(Counter::Dirs, 1);
         self.stats.directories += 1;
-        self.index_builder
-            .push_entry(IndexEntry::metadata_from(source_entry));
+        let empty_in_source = if options.record_empty_dirs {
+            let path = source_entry.apath().below(source.path());
+            match fs::read_dir(&path) {
+                Ok(mut children) => Some(children.next().is_none()),
+                Err(err) => {
+                    warn!("Can't read directory {path:?} to check if it's empty: {err}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        self.index_builder.push_entry(IndexEntry {
+            empty_in_source,
+            ..IndexEntry::metadata_from(source_entry)
+        });
         Ok(None) // TODO: Emit the actual change.
     }
 
@@ -253,6 +707,7 @@ impl BackupWriter {
                     self.stats.unmodified_files += 1;
                     let new_entry = IndexEntry {
                         addrs: basis_entry.addrs.clone(),
+                        content_hash: basis_entry.content_hash.clone(),
                         ..IndexEntry::metadata_from(source_entry)
                     };
                     let change = if new_entry == basis_entry {
@@ -271,6 +726,9 @@ impl BackupWriter {
                 }
             } else {
                 self.stats.modified_files += 1;
+                if let Some(prefix_index) = &mut self.prefix_index {
+                    prefix_index.add_unread(&basis_entry.addrs);
+                }
                 Some(EntryChange::changed(&basis_entry, source_entry))
             }
         } else {
@@ -290,16 +748,11 @@ impl BackupWriter {
                     .push_file(source_entry, &mut source_file, monitor.clone())?;
                 monitor.count(Counter::SmallFiles, 1);
             } else {
-                let addrs = store_file_content(
-                    apath,
-                    &mut source_file,
-                    &self.block_dir,
-                    &mut self.stats,
-                    options.max_block_size,
-                    monitor.clone(),
-                )?;
+                let (addrs, content_hash) =
+                    self.store_large_file(source_entry, &mut source_file, options, monitor)?;
                 self.index_builder.push_entry(IndexEntry {
                     addrs,
+                    content_hash,
                     ..IndexEntry::metadata_from(source_entry)
                 });
             }
@@ -307,15 +760,82 @@ impl BackupWriter {
         Ok(result)
     }
 
+    /// Store a file too large to be combined with others, skipping over any leading
+    /// blocks that were stored by an interrupted backup.
+    ///
+    /// Returns the addresses of the content and, unless storage was resumed, its hash.
+    fn store_large_file(
+        &mut self,
+        source_entry: &EntryValue,
+        source_file: &mut File,
+        options: &BackupOptions,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<(Vec<Address>, Option<BlockHash>)> {
+        let apath = source_entry.apath();
+        let mut partial = PartialFile::new(source_entry);
+        if self.resume.as_ref().is_some_and(|resume| {
+            resume.apath == partial.apath
+                && resume.mtime == partial.mtime
+                && resume.mtime_nanos == partial.mtime_nanos
+                && resume.size == partial.size
+        }) {
+            let resume = self.resume.take().expect("resume is present");
+            if all_blocks_present(&resume.addrs, &self.block_dir, &monitor) {
+                let stored_len = resume.stored_len();
+                source_file
+                    .seek(SeekFrom::Start(stored_len))
+                    .map_err(|source| Error::ReadSourceFile {
+                        path: apath.to_string().into(),
+                        source,
+                    })?;
+                debug!(%apath, stored_len, "Resume storing partially stored file");
+                self.stats.resumed_bytes += stored_len;
+                partial.addrs = resume.addrs;
+            }
+        }
+        let checkpoint = (options.checkpoint_large_files
+            && partial.size > options.max_block_size_for(apath) as u64)
+            .then_some(&self.band);
+        let result = store_file_content(
+            source_file,
+            &self.block_dir,
+            &mut self.stats,
+            options,
+            partial,
+            checkpoint,
+            self.prefix_index.as_mut(),
+            monitor,
+        );
+        if let Some(prefix_index) = &mut self.prefix_index {
+            prefix_index.clear_unread();
+        }
+        result
+    }
+
     fn copy_symlink(
         &mut self,
         source_entry: &EntryValue,
+        from_tree: &LiveTree,
+        options: &BackupOptions,
         monitor: &dyn Monitor,
     ) -> Result<Option<EntryChange>> {
         monitor.count(Counter::Symlinks, 1);
         let target = source_entry.symlink_target();
         self.stats.symlinks += 1;
         assert!(target.is_some());
+        if options.warn_dangling_symlinks {
+            // Following the link resolves a relative target from the link's directory.
+            let path = from_tree.relative_path(source_entry.apath());
+            if let Err(err) = fs::metadata(path) {
+                if err.kind() == io::ErrorKind::NotFound {
+                    let apath = source_entry.apath();
+                    let target = target.unwrap_or_default();
+                    warn!("Symlink {apath} points to {target:?}, which doesn't exist");
+                    monitor.count(Counter::DanglingSymlinks, 1);
+                    self.stats.dangling_symlinks += 1;
+                }
+            }
+        }
         self.index_builder
             .push_entry(IndexEntry::metadata_from(source_entry));
         // TODO: Emit the actual change.
@@ -335,35 +855,91 @@ fn all_blocks_present(
         .all(|hash| block_dir.contains(hash, monitor.clone()).unwrap_or(false))
 }
 
+/// Store the rest of a file's content, following the blocks already in `partial`.
+///
+/// If `checkpoint` is given, the blocks stored so far are recorded in that band after
+/// each block, and the record is removed once the whole file is stored.
+///
+/// If `prefix_index` is given, blocks that are a prefix of one already stored are
+/// stored as a reference into it.
+///
+/// Returns the addresses of the whole file, and the hash of its content if `partial`
+/// started out with no blocks and so all the content was read here.
+#[allow(clippy::too_many_arguments)]
 fn store_file_content(
-    apath: &Apath,
     from_file: &mut dyn Read,
     block_dir: &BlockDir,
     stats: &mut BackupStats,
-    max_block_size: usize,
+    options: &BackupOptions,
+    mut partial: PartialFile,
+    checkpoint: Option<&Band>,
+    mut prefix_index: Option<&mut PrefixIndex>,
     monitor: Arc<dyn Monitor>,
-) -> Result<Vec<Address>> {
-    let mut addresses = Vec::<Address>::with_capacity(1);
-    loop {
-        let buffer = read_with_retries(max_block_size, from_file).map_err(|source| {
-            Error::ReadSourceFile {
-                path: apath.to_string().into(),
-                source,
-            }
-        })?;
-        if buffer.is_empty() {
-            break;
-        }
-        let buffer = buffer.freeze();
+) -> Result<(Vec<Address>, Option<BlockHash>)> {
+    let apath = &partial.apath;
+    let mut checkpointed = false;
+    let mut hasher = partial
+        .addrs
+        .is_empty()
+        .then(|| Blake2b::new(BLAKE_HASH_SIZE_BYTES));
+    let max_block_size = options.max_block_size_for(apath);
+    let mut chunker = Chunker::new(from_file, options.chunking, max_block_size);
+    while let Some(buffer) = chunker
+        .next_block()
+        .map_err(|source| Error::ReadSourceFile {
+            path: apath.to_string().into(),
+            source,
+        })?
+    {
         monitor.count(Counter::FileBytes, buffer.len());
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&buffer);
+        }
         let len = buffer.len() as u64;
-        let hash = block_dir.store_or_deduplicate(buffer, stats, monitor.clone())?;
-        addresses.push(Address {
-            hash,
-            start: 0,
-            len,
-        });
+        let prefix_addr = match prefix_index.as_deref_mut() {
+            Some(prefix_index) => prefix_index.find(&buffer, block_dir, monitor.clone())?,
+            None => None,
+        };
+        if let Some(addr) = prefix_addr {
+            trace!(%apath, hash = %addr.hash, len, "Block is a prefix of an existing block");
+            stats.deduplicated_blocks += 1;
+            stats.deduplicated_bytes += len;
+            stats.prefix_deduplicated_blocks += 1;
+            monitor.count(Counter::DeduplicatedBlocks, 1);
+            monitor.count(Counter::DeduplicatedBlockBytes, buffer.len());
+            partial.addrs.push(addr);
+        } else {
+            let hash = block_dir.store_or_deduplicate(
+                buffer.clone(),
+                options.verify_dedup,
+                stats,
+                monitor.clone(),
+            )?;
+            if let Some(prefix_index) = prefix_index.as_deref_mut() {
+                prefix_index.add(&hash, &buffer);
+            }
+            partial.addrs.push(Address {
+                hash,
+                start: 0,
+                len,
+            });
+        }
+        if let Some(band) = checkpoint {
+            match band.write_partial_file(&partial) {
+                Ok(()) => checkpointed = true,
+                Err(err) => warn!(?err, %apath, "Failed to record partially stored file"),
+            }
+        }
+    }
+    if let (Some(band), true) = (checkpoint, checkpointed) {
+        if let Err(err) = band.remove_partial_file() {
+            warn!(?err, %apath, "Failed to remove record of partially stored file");
+        }
     }
+    let addresses = partial.addrs;
+    let content_hash = hasher
+        .filter(|_| !addresses.is_empty())
+        .map(|hasher| BlockHash::from(hasher.finalize()));
     match addresses.len() {
         0 => {
             // This doesn't duplicate the call to monitor.count above, because
@@ -381,7 +957,102 @@ fn store_file_content(
             stats.multi_block_files += 1
         }
     }
-    Ok(addresses)
+    Ok((addresses, content_hash))
+}
+
+/// The length of the start of a block by which [PrefixIndex] finds it.
+///
+/// Blocks no longer than this aren't matched, which keeps the index small and
+/// avoids reading blocks to save only a few bytes.
+const PREFIX_KEY_LEN: usize = 4096;
+
+/// Finds blocks that begin with some content, so that content that's a prefix of
+/// a stored block can be stored as a reference into that block.
+#[derive(Default)]
+struct PrefixIndex {
+    /// Hashes of blocks longer than [PREFIX_KEY_LEN], keyed by the hash of
+    /// their first [PREFIX_KEY_LEN] bytes.
+    by_key: HashMap<BlockHash, Vec<BlockHash>>,
+
+    /// Blocks of the previous version of the file being stored, which are read
+    /// and indexed only if some content doesn't match an existing block exactly.
+    unread: Vec<BlockHash>,
+}
+
+impl PrefixIndex {
+    /// Add a block whose content is in memory.
+    fn add(&mut self, hash: &BlockHash, content: &[u8]) {
+        self.unread.retain(|h| h != hash);
+        if content.len() > PREFIX_KEY_LEN {
+            let hashes = self
+                .by_key
+                .entry(BlockHash::hash_bytes(&content[..PREFIX_KEY_LEN]))
+                .or_default();
+            if !hashes.contains(hash) {
+                hashes.push(hash.clone());
+            }
+        }
+    }
+
+    /// Remember the blocks of the previous version of a file, to be read if needed.
+    fn add_unread(&mut self, addrs: &[Address]) {
+        for addr in addrs {
+            if !self.unread.contains(&addr.hash) {
+                self.unread.push(addr.hash.clone());
+            }
+        }
+    }
+
+    /// Forget the blocks of the previous file that weren't needed.
+    fn clear_unread(&mut self) {
+        self.unread.clear();
+    }
+
+    /// Find a stored block that is longer than `content` and starts with it.
+    ///
+    /// Content that's exactly the same as an existing block isn't matched here, so
+    /// that it's deduplicated as a whole block.
+    fn find(
+        &mut self,
+        content: &[u8],
+        block_dir: &BlockDir,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<Option<Address>> {
+        if content.len() < PREFIX_KEY_LEN
+            || block_dir.contains(&BlockHash::hash_bytes(content), monitor.clone())?
+        {
+            return Ok(None);
+        }
+        for hash in take(&mut self.unread) {
+            match block_dir.get_block_content(&hash, monitor.clone()) {
+                Ok(block) => self.add(&hash, &block),
+                Err(err) => debug!(?err, %hash, "Failed to read block of previous version"),
+            }
+        }
+        let Some(candidates) = self
+            .by_key
+            .get(&BlockHash::hash_bytes(&content[..PREFIX_KEY_LEN]))
+        else {
+            return Ok(None);
+        };
+        for hash in candidates {
+            let block = match block_dir.get_block_content(hash, monitor.clone()) {
+                Ok(block) => block,
+                Err(err) => {
+                    debug!(?err, %hash, "Failed to read candidate block");
+                    continue;
+                }
+            };
+            if block.len() > content.len() && block.starts_with(content) {
+                return Ok(Some(Address {
+                    hash: hash.clone(),
+                    start: 0,
+                    len: content.len() as u64,
+                }));
+            }
+        }
+        Ok(None)
+    }
 }
 
 /// Combines multiple small files into a single block.
@@ -397,6 +1068,7 @@ struct FileCombiner {
     stats: BackupStats,
     block_dir: Arc<BlockDir>,
     max_block_size: usize,
+    verify_dedup: bool,
 }
 
 /// A file in the process of being written into a combined block.
@@ -414,7 +1086,7 @@ struct QueuedFile {
 }
 
 impl FileCombiner {
-    fn new(block_dir: Arc<BlockDir>, max_block_size: usize) -> FileCombiner {
+    fn new(block_dir: Arc<BlockDir>, max_block_size: usize, verify_dedup: bool) -> FileCombiner {
         FileCombiner {
             block_dir,
             buf: BytesMut::new(),
@@ -422,6 +1094,7 @@ impl FileCombiner {
             finished: Vec::new(),
             stats: BackupStats::default(),
             max_block_size,
+            verify_dedup,
         }
     }
 
@@ -450,6 +1123,7 @@ impl FileCombiner {
         }
         let hash = self.block_dir.store_or_deduplicate(
             take(&mut self.buf).freeze(),
+            self.verify_dedup,
             &mut self.stats,
             monitor,
         )?;
@@ -508,7 +1182,10 @@ impl FileCombiner {
         self.queue.push(QueuedFile {
             start,
             len,
-            entry: index_entry,
+            entry: IndexEntry {
+                content_hash: Some(BlockHash::hash_bytes(&self.buf[start..])),
+                ..index_entry
+            },
         });
         // TODO: This can overrun by one small file; it would be better to check
         // in advance and perhaps start a new combined block that it will fit inside.
@@ -540,8 +1217,15 @@ pub struct BackupStats {
     // TODO: Include source file bytes, including unmodified files.
     pub files: usize,
     pub symlinks: usize,
+    /// Symlinks whose target doesn't exist, if [BackupOptions::warn_dangling_symlinks] is set.
+    pub dangling_symlinks: usize,
     pub directories: usize,
+    /// Special files, such as devices, FIFOs, and sockets, that were skipped.
     pub unknown_kind: usize,
+    pub block_devices: usize,
+    pub char_devices: usize,
+    pub fifos: usize,
+    pub sockets: usize,
 
     pub unmodified_files: usize,
     pub modified_files: usize,
@@ -550,6 +1234,9 @@ pub struct BackupStats {
     /// Files that were previously stored and that have been stored again because
     /// some of their blocks were damaged.
     pub replaced_damaged_blocks: usize,
+    /// Existing blocks that were found to be corrupt when verifying deduplication,
+    /// and that were stored again.
+    pub replaced_corrupt_blocks: usize,
 
     /// Bytes that matched an existing block.
     pub deduplicated_bytes: u64,
@@ -561,11 +1248,16 @@ pub struct BackupStats {
     pub written_blocks: usize,
     /// Blocks containing combined small files.
     pub combined_blocks: usize,
+    /// Blocks stored as a reference to the start of a longer existing block,
+    /// included in `deduplicated_blocks`.
+    pub prefix_deduplicated_blocks: usize,
 
     pub empty_files: usize,
     pub small_combined_files: usize,
     pub single_block_files: usize,
     pub multi_block_files: usize,
+    /// Bytes of large files that were stored by an interrupted backup, and so weren't read again.
+    pub resumed_bytes: u64,
 
     pub errors: usize,
 
@@ -583,8 +1275,16 @@ impl fmt::Display for BackupStats {
         write_count(w, "  modified files", self.modified_files);
         write_count(w, "  new files", self.new_files);
         write_count(w, "symlinks", self.symlinks);
+        // These are only counted if BackupOptions::warn_dangling_symlinks is set.
+        if self.dangling_symlinks > 0 {
+            write_count(w, "  dangling symlinks", self.dangling_symlinks);
+        }
         write_count(w, "directories", self.directories);
-        write_count(w, "unsupported file kind", self.unknown_kind);
+        write_count(w, "special files skipped:", self.unknown_kind);
+        write_count(w, "  block devices", self.block_devices);
+        write_count(w, "  character devices", self.char_devices);
+        write_count(w, "  FIFOs", self.fifos);
+        write_count(w, "  sockets", self.sockets);
         writeln!(w).unwrap();
 
         write_count(w, "files stored:", self.new_files + self.modified_files);
@@ -592,10 +1292,17 @@ impl fmt::Display for BackupStats {
         write_count(w, "  small combined files", self.small_combined_files);
         write_count(w, "  single block files", self.single_block_files);
         write_count(w, "  multi-block files", self.multi_block_files);
+        write_size(w, "  resumed from interrupted backup", self.resumed_bytes);
         writeln!(w).unwrap();
 
         write_count(w, "data blocks deduplicated:", self.deduplicated_blocks);
         write_size(w, "  saved", self.deduplicated_bytes);
+        write_count(
+            w,
+            "  prefixes of longer blocks",
+            self.prefix_deduplicated_blocks,
+        );
+        write_count(w, "  corrupt and replaced", self.replaced_corrupt_blocks);
         writeln!(w).unwrap();
 
         write_count(w, "new data blocks written:", self.written_blocks);
@@ -618,3 +1325,96 @@ impl fmt::Display for BackupStats {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::*;
+    use crate::monitor::test::TestMonitor;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    /// A reader that always fails, as if the source disappeared part way through.
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("simulated interruption"))
+        }
+    }
+
+    #[test]
+    fn resume_large_file_after_interrupted_backup() {
+        const BLOCK_SIZE: usize = 1000;
+        let src = TreeFixture::new();
+        let content: Vec<u8> = (0..10 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        src.create_file_with_contents("big", &content);
+        let options = BackupOptions {
+            max_block_size: BLOCK_SIZE,
+            small_file_cap: 100,
+            checkpoint_large_files: true,
+            ..Default::default()
+        };
+        let source_entry = src
+            .live_tree()
+            .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+            .unwrap()
+            .find(|entry| entry.apath() == "/big")
+            .unwrap();
+
+        // Store the first four blocks and then fail, leaving the band incomplete.
+        let af = ScratchArchive::new();
+        let monitor = TestMonitor::arc();
+        let mut writer = BackupWriter::begin(&af, src.path(), &options, monitor.clone()).unwrap();
+        let mut interrupted_source = File::open(src.path().join("big"))
+            .unwrap()
+            .take(4 * BLOCK_SIZE as u64)
+            .chain(FailingReader);
+        store_file_content(
+            &mut interrupted_source,
+            &writer.block_dir,
+            &mut writer.stats,
+            &BackupOptions {
+                max_block_size: BLOCK_SIZE,
+                ..Default::default()
+            },
+            PartialFile::new(&source_entry),
+            Some(&writer.band),
+            None,
+            monitor,
+        )
+        .unwrap_err();
+        let partial = writer.band.read_partial_file().unwrap().unwrap();
+        assert_eq!(partial.stored_len(), 4 * BLOCK_SIZE as u64);
+        drop(writer);
+
+        let monitor = TestMonitor::arc();
+        let stats = backup(&af, src.path(), &options, monitor.clone()).unwrap();
+        assert_eq!(stats.resumed_bytes, 4 * BLOCK_SIZE as u64);
+        monitor.assert_counter(Counter::FileBytes, 6 * BLOCK_SIZE);
+        let band = Band::open(&af, BandId::new(&[1])).unwrap();
+        assert_eq!(band.read_partial_file().unwrap(), None);
+
+        // A backup from scratch reads the whole file.
+        let fresh = ScratchArchive::new();
+        let fresh_monitor = TestMonitor::arc();
+        backup(&fresh, src.path(), &options, fresh_monitor.clone()).unwrap();
+        fresh_monitor.assert_counter(Counter::FileBytes, 10 * BLOCK_SIZE);
+
+        // The resumed backup stored the same content.
+        let resumed_entry = band
+            .index()
+            .iter_entries()
+            .find(|entry| entry.apath == "/big")
+            .unwrap();
+        let mut restored = Vec::new();
+        for addr in &resumed_entry.addrs {
+            restored.extend_from_slice(
+                &af.block_dir()
+                    .read_address(addr, TestMonitor::arc())
+                    .unwrap(),
+            );
+        }
+        assert_eq!(restored, content);
+    }
+}
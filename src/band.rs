@@ -22,22 +22,35 @@
 //! StoredTree rather than the Band itself.
 
 use std::borrow::Cow;
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
 
 use crate::transport::Transport;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 use tracing::{debug, warn};
+use url::Url;
 
+use crate::backup::PartialFile;
+use crate::clock::Clock;
+use crate::index::{IndexHunkCache, IndexReadAhead};
 use crate::jsonio::{read_json, write_json};
 use crate::misc::remove_item;
 use crate::monitor::Monitor;
-use crate::transport::ListDir;
+use crate::transport::{ListDir, WriteMode};
 use crate::*;
 
 static INDEX_DIR: &str = "i";
 
+/// Directory in the band where a rewritten index is written by [crate::compact]
+/// before it replaces the old index.
+static STAGED_INDEX_DIR: &str = "i.compact";
+
+/// File written in the staged index directory once the staged index is complete.
+static STAGED_INDEX_COMPLETE_FILENAME: &str = "COMPLETE";
+
 /// Per-band format flags.
 pub mod flags {
     use std::borrow::Cow;
@@ -45,8 +58,16 @@ pub mod flags {
     /// Default flags for newly created bands.
     pub static DEFAULT: &[Cow<'static, str>] = &[];
 
-    /// All the flags understood by this version of Conserve.
-    pub static SUPPORTED: &[&str] = &[];
+    /// Index hunks may be compressed with zstd rather than Snappy.
+    pub const INDEX_ZSTD: &str = "index_zstd";
+
+    /// Blocks may be compressed with zstd using the archive's block dictionary,
+    /// rather than Snappy.
+    pub const BLOCK_DICTIONARY: &str = "block_dictionary";
+
+    /// All the flags understood by this version of Conserve, whether required or
+    /// optional.
+    pub static SUPPORTED: &[&str] = &[INDEX_ZSTD, BLOCK_DICTIONARY];
 }
 
 /// Describes how to select a band from an archive.
@@ -56,10 +77,57 @@ pub enum BandSelectionPolicy {
     LatestClosed,
     /// Open the latest band, regardless of whether it's complete.
     Latest,
+    /// Open the latest band, even if it's incomplete because the backup was interrupted.
+    ///
+    /// This selects the same band as [BandSelectionPolicy::Latest], but makes clear
+    /// that it may be incomplete. Entries missing from the end of an incomplete band's
+    /// index are filled in from earlier bands, so the tree may be a mix of the
+    /// interrupted backup and the previous one.
+    LatestIncludingIncomplete,
     /// Open the band with the specified id.
     Specified(BandId),
 }
 
+/// Describes how to select a set of bands from an archive, for operations such as
+/// validation that can look at many bands.
+///
+/// Selections can be parsed from strings like `b5..b8` or `latest:3`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum BandsSelection {
+    /// All the bands in the archive.
+    #[default]
+    All,
+    /// Bands with ids from the first through the second, including both.
+    Range(BandId, BandId),
+    /// The most recent bands, up to this many.
+    Latest(usize),
+}
+
+impl FromStr for BandsSelection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidBandsSelection {
+            selection: s.to_owned(),
+        };
+        if let Some(count) = s.strip_prefix("latest:") {
+            count
+                .parse()
+                .map(BandsSelection::Latest)
+                .map_err(|_| invalid())
+        } else if let Some((start, end)) = s.split_once("..") {
+            let start: BandId = start.parse().map_err(|_| invalid())?;
+            let end: BandId = end.parse().map_err(|_| invalid())?;
+            if start > end {
+                return Err(Error::ReversedBandsRange { start, end });
+            }
+            Ok(BandsSelection::Range(start, end))
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
 fn band_version_requirement() -> semver::VersionReq {
     semver::VersionReq::parse(&format!("<={}", crate::VERSION)).unwrap()
 }
@@ -80,6 +148,15 @@ pub struct Band {
 
     /// Deserialized band head info.
     head: Head,
+
+    /// The archive's cache of index hunks, if enabled.
+    index_cache: Option<Arc<IndexHunkCache>>,
+
+    /// How far to read ahead through the index, from the archive.
+    index_read_ahead: Option<IndexReadAhead>,
+
+    /// The archive's source of the current time, used when the band is closed.
+    clock: Arc<dyn Clock>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -95,6 +172,32 @@ struct Head {
     /// referenced data correctly.
     #[serde(default)]
     format_flags: Vec<Cow<'static, str>>,
+
+    /// Format flags for features used by this band that a reader can safely ignore,
+    /// such as metadata that isn't needed to restore file content correctly.
+    ///
+    /// Readers that don't understand them warn and carry on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    optional_format_flags: Vec<Cow<'static, str>>,
+
+    /// Version of Conserve that wrote this band, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    conserve_version: Option<String>,
+
+    /// Name of the host that wrote this band, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+
+    /// Source directory that was backed up, if it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_path: Option<String>,
+
+    /// How files were split into blocks, if the band was written by a backup.
+    ///
+    /// This is only informational: blocks can be read without knowing how they
+    /// were chosen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunking: Option<String>,
 }
 
 /// Format of the on-disk tail file.
@@ -109,7 +212,15 @@ struct Tail {
     index_hunk_count: Option<u64>,
 }
 
+/// Format of the marker file written when a backup is stopped on request.
+#[derive(Debug, Serialize, Deserialize)]
+struct Interrupted {
+    /// Seconds since the Unix epoch when the backup stopped.
+    interrupted_time: i64,
+}
+
 /// Readonly summary info about a band, from `Band::get_info`.
+#[derive(Clone, Debug, Serialize)]
 pub struct Info {
     pub id: BandId,
     pub is_closed: bool,
@@ -122,6 +233,34 @@ pub struct Info {
 
     /// Number of hunks present in the index, if that is known.
     pub index_hunk_count: Option<u64>,
+
+    /// Version of Conserve that wrote this band, or None if it was written by an
+    /// older version that didn't record it.
+    pub conserve_version: Option<String>,
+
+    /// Name of the host that wrote this band, if it was recorded.
+    pub hostname: Option<String>,
+
+    /// Source directory that was backed up, if [crate::BackupOptions::record_source_path]
+    /// was set.
+    pub source_path: Option<String>,
+
+    /// How files were split into blocks, as named by [crate::Chunking::name], if it was
+    /// recorded.
+    pub chunking: Option<String>,
+}
+
+/// True the first time this is called for a band directory in this process.
+///
+/// Bands are opened many times by some operations, so this keeps warnings about them
+/// from being repeated.
+fn first_open_in_process(transport: &Transport) -> bool {
+    static OPENED: OnceLock<Mutex<HashSet<Url>>> = OnceLock::new();
+    OPENED
+        .get_or_init(Default::default)
+        .lock()
+        .expect("Lock opened bands")
+        .insert(transport.url().clone())
 }
 
 // TODO: Maybe merge Band with StoredTree and/or with the Index classes? The distinction seems
@@ -137,6 +276,17 @@ impl Band {
     pub fn create_with_flags(
         archive: &Archive,
         format_flags: &[Cow<'static, str>],
+    ) -> Result<Band> {
+        Band::create_for_backup(archive, format_flags, None, None)
+    }
+
+    /// Make a new band, recording the source directory it backs up and how files
+    /// are split into blocks.
+    pub(crate) fn create_for_backup(
+        archive: &Archive,
+        format_flags: &[Cow<'static, str>],
+        source_path: Option<String>,
+        chunking: Option<Chunking>,
     ) -> Result<Band> {
         format_flags
             .iter()
@@ -153,16 +303,25 @@ impl Band {
             Some("23.2.0".to_owned())
         };
         let head = Head {
-            start_time: OffsetDateTime::now_utc().unix_timestamp(),
+            start_time: archive.clock.now().unix_timestamp(),
             band_format_version,
             format_flags: format_flags.into(),
+            optional_format_flags: Vec::new(),
+            conserve_version: Some(crate::VERSION.to_owned()),
+            hostname: whoami::fallible::hostname().ok(),
+            source_path,
+            chunking: chunking.map(|chunking| chunking.name().to_owned()),
         };
         write_json(&transport, BAND_HEAD_FILENAME, &head)?;
-        Ok(Band {
+        let band = Band {
             band_id,
             head,
             transport,
-        })
+            index_cache: archive.index_cache.clone(),
+            index_read_ahead: archive.index_read_ahead.clone(),
+            clock: archive.clock.clone(),
+        };
+        Ok(band)
     }
 
     /// Mark this band closed: no more blocks should be written after this.
@@ -171,13 +330,35 @@ impl Band {
             &self.transport,
             BAND_TAIL_FILENAME,
             &Tail {
-                end_time: OffsetDateTime::now_utc().unix_timestamp(),
+                end_time: self.clock.now().unix_timestamp(),
                 index_hunk_count: Some(index_hunk_count),
             },
         )
         .map_err(Error::from)
     }
 
+    /// Record that the backup writing this band was stopped on request, after writing
+    /// out the index entries it had reached, rather than crashing or being killed.
+    ///
+    /// The band is left incomplete.
+    pub(crate) fn mark_interrupted(&self) -> Result<()> {
+        write_json(
+            &self.transport,
+            BAND_INTERRUPTED_FILENAME,
+            &Interrupted {
+                interrupted_time: self.clock.now().unix_timestamp(),
+            },
+        )
+        .map_err(Error::from)
+    }
+
+    /// True if the backup writing this band was stopped on request.
+    pub fn is_interrupted(&self) -> Result<bool> {
+        self.transport
+            .is_file(BAND_INTERRUPTED_FILENAME)
+            .map_err(Error::from)
+    }
+
     /// Open the band with the given id.
     pub fn open(archive: &Archive, band_id: BandId) -> Result<Band> {
         let transport = archive.transport().chdir(&band_id.to_string());
@@ -208,16 +389,34 @@ impl Band {
                 unsupported_flags,
             });
         }
+        let unsupported_optional_flags = head
+            .optional_format_flags
+            .iter()
+            .filter(|f| !flags::SUPPORTED.contains(&f.as_ref()))
+            .collect_vec();
+        if !unsupported_optional_flags.is_empty() && first_open_in_process(&transport) {
+            warn!(
+                %band_id,
+                "Band uses optional format flags {unsupported_optional_flags:?} that aren't understood by this version of Conserve; the features they mark will be ignored"
+            );
+        }
         Ok(Band {
             band_id: band_id.to_owned(),
             head,
             transport,
+            index_cache: archive.index_cache.clone(),
+            index_read_ahead: archive.index_read_ahead.clone(),
+            clock: archive.clock.clone(),
         })
     }
 
     /// Delete a band.
     pub fn delete(archive: &Archive, band_id: BandId) -> Result<()> {
         // TODO: Count how many files were deleted, and the total size?
+        if let Some(cache) = &archive.index_cache {
+            // A later band might be created with the same id.
+            cache.remove_band(band_id);
+        }
         archive
             .transport()
             .remove_dir_all(&band_id.to_string())
@@ -250,13 +449,101 @@ impl Band {
         &self.head.format_flags
     }
 
+    /// Flags for features used by this band that readers can safely ignore.
+    pub fn optional_format_flags(&self) -> &[Cow<'static, str>] {
+        &self.head.optional_format_flags
+    }
+
     pub fn index_builder(&self) -> IndexWriter {
-        IndexWriter::new(self.transport.chdir(INDEX_DIR))
+        IndexWriter::new(self.transport.chdir(INDEX_DIR)).with_compression(self.index_compression())
+    }
+
+    /// How new index hunks in this band are compressed, from its format flags.
+    pub fn index_compression(&self) -> IndexCompression {
+        if self
+            .head
+            .format_flags
+            .iter()
+            .any(|f| f == flags::INDEX_ZSTD)
+        {
+            IndexCompression::Zstd
+        } else {
+            IndexCompression::Snappy
+        }
     }
 
     /// Get read-only access to the index of this band.
     pub fn index(&self) -> IndexRead {
-        IndexRead::open(self.transport.chdir(INDEX_DIR))
+        let index = IndexRead::open(self.transport.chdir(INDEX_DIR))
+            .with_read_ahead(self.index_read_ahead.clone());
+        if let Some(cache) = &self.index_cache {
+            index.with_cache(self.band_id, cache.clone())
+        } else {
+            index
+        }
+    }
+
+    /// Start writing a rewritten index for this band alongside the current index,
+    /// discarding any incomplete one left by an earlier attempt.
+    pub(crate) fn stage_index(&self) -> Result<IndexWriter> {
+        self.discard_staged_index()?;
+        self.transport.create_dir(STAGED_INDEX_DIR)?;
+        Ok(IndexWriter::new(self.transport.chdir(STAGED_INDEX_DIR))
+            .with_compression(self.index_compression()))
+    }
+
+    /// Remove a staged index, if there is one.
+    pub(crate) fn discard_staged_index(&self) -> Result<()> {
+        match self.transport.remove_dir_all(STAGED_INDEX_DIR) {
+            Err(err) if !err.is_not_found() => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// True if a staged index was completely written, but hasn't yet replaced the
+    /// current index.
+    pub(crate) fn has_complete_staged_index(&self) -> Result<bool> {
+        self.transport
+            .is_file(&format!(
+                "{STAGED_INDEX_DIR}/{STAGED_INDEX_COMPLETE_FILENAME}"
+            ))
+            .map_err(Error::from)
+    }
+
+    /// Replace the current index with the completely written staged index, and record
+    /// its number of hunks in the tail if the band is closed.
+    ///
+    /// If this is interrupted, the staged index is kept, and this can be called again
+    /// to finish the job.
+    pub(crate) fn install_staged_index(&self) -> Result<usize> {
+        let staged = self.transport.chdir(STAGED_INDEX_DIR);
+        staged.write_file(STAGED_INDEX_COMPLETE_FILENAME, b"", WriteMode::Overwrite)?;
+        match self.transport.remove_dir_all(INDEX_DIR) {
+            Err(err) if !err.is_not_found() => return Err(err.into()),
+            _ => (),
+        }
+        self.transport.create_dir(INDEX_DIR)?;
+        let index = self.transport.chdir(INDEX_DIR);
+        let mut hunk_count = 0;
+        for subdir in staged.list_dir("")?.dirs {
+            index.create_dir(&subdir)?;
+            for name in staged.list_dir(&subdir)?.files {
+                let relpath = format!("{subdir}/{name}");
+                index.write_file(&relpath, &staged.read_file(&relpath)?, WriteMode::CreateNew)?;
+                hunk_count += 1;
+            }
+        }
+        if let Some(tail) = read_json::<Tail>(&self.transport, BAND_TAIL_FILENAME)? {
+            let mut json = serde_json::to_string(&Tail {
+                index_hunk_count: Some(hunk_count as u64),
+                ..tail
+            })?;
+            json.push('\n');
+            self.transport
+                .write_file(BAND_TAIL_FILENAME, json.as_bytes(), WriteMode::Overwrite)?;
+        }
+        self.discard_staged_index()?;
+        Ok(hunk_count)
     }
 
     /// Return info about the state of this band.
@@ -284,9 +571,35 @@ impl Band {
             start_time,
             end_time,
             index_hunk_count: tail_option.as_ref().and_then(|tail| tail.index_hunk_count),
+            conserve_version: self.head.conserve_version.clone(),
+            hostname: self.head.hostname.clone(),
+            source_path: self.head.source_path.clone(),
+            chunking: self.head.chunking.clone(),
         })
     }
 
+    /// Record how much of a large file has been stored so far, replacing any previous record.
+    pub(crate) fn write_partial_file(&self, partial: &PartialFile) -> Result<()> {
+        let mut json = serde_json::to_string(partial)?;
+        json.push('\n');
+        self.transport
+            .write_file(BAND_PARTIAL_FILENAME, json.as_bytes(), WriteMode::Overwrite)
+            .map_err(Error::from)
+    }
+
+    /// Read the record of a large file that was being stored when this band was
+    /// interrupted, if there is one.
+    pub(crate) fn read_partial_file(&self) -> Result<Option<PartialFile>> {
+        read_json(&self.transport, BAND_PARTIAL_FILENAME).map_err(Error::from)
+    }
+
+    /// Remove the record of a partially stored file, after the whole file is stored.
+    pub(crate) fn remove_partial_file(&self) -> Result<()> {
+        self.transport
+            .remove_file(BAND_PARTIAL_FILENAME)
+            .map_err(Error::from)
+    }
+
     pub fn validate(&self, monitor: Arc<dyn Monitor>) -> Result<()> {
         let ListDir { mut files, dirs } = self.transport.list_dir("")?;
         if !files.contains(&BAND_HEAD_FILENAME.to_string()) {
@@ -296,6 +609,8 @@ impl Band {
         }
         remove_item(&mut files, &BAND_HEAD_FILENAME);
         remove_item(&mut files, &BAND_TAIL_FILENAME);
+        remove_item(&mut files, &BAND_PARTIAL_FILENAME);
+        remove_item(&mut files, &BAND_INTERRUPTED_FILENAME);
         for unexpected in files {
             warn!(path = ?unexpected, "Unexpected file in band directory");
         }
@@ -314,6 +629,7 @@ mod tests {
 
     use serde_json::json;
 
+    use crate::clock::FixedClock;
     use crate::test_fixtures::ScratchArchive;
 
     use super::*;
@@ -351,6 +667,36 @@ mod tests {
         assert!(dur < Duration::from_secs(5));
     }
 
+    #[test]
+    fn band_times_come_from_archive_clock() {
+        let af = ScratchArchive::new();
+        let start_time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let clock = Arc::new(FixedClock::new(start_time));
+        let archive = Archive::open_path(af.path())
+            .unwrap()
+            .with_clock(clock.clone());
+
+        let band = Band::create(&archive).unwrap();
+        clock.advance(Duration::from_secs(90));
+        band.close(0).unwrap();
+
+        let info = Band::open(&archive, band.id()).unwrap().get_info().unwrap();
+        assert_eq!(info.start_time, start_time);
+        assert_eq!(info.end_time, Some(start_time + Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn band_records_conserve_version_and_hostname() {
+        let af = ScratchArchive::new();
+        let band = Band::create(&af).unwrap();
+        band.close(0).unwrap();
+
+        let info = Band::open(&af, band.id()).unwrap().get_info().unwrap();
+        assert_eq!(info.conserve_version.as_deref(), Some(crate::VERSION));
+        assert_eq!(info.hostname, whoami::fallible::hostname().ok());
+        assert_eq!(info.source_path, None);
+    }
+
     #[test]
     fn delete_band() {
         let af = ScratchArchive::new();
@@ -363,6 +709,32 @@ mod tests {
         assert!(!af.transport().is_file("b0000/BANDHEAD").unwrap());
     }
 
+    #[test]
+    fn parse_bands_selection() {
+        assert_eq!(
+            BandsSelection::from_str("b5..b8").unwrap(),
+            BandsSelection::Range(BandId::new(&[5]), BandId::new(&[8]))
+        );
+        assert_eq!(
+            BandsSelection::from_str("latest:3").unwrap(),
+            BandsSelection::Latest(3)
+        );
+        for bad in ["", "b5", "b5..", "5..8", "latest:", "latest:x"] {
+            assert!(
+                matches!(
+                    BandsSelection::from_str(bad),
+                    Err(Error::InvalidBandsSelection { selection }) if selection == bad
+                ),
+                "{bad:?} should not parse"
+            );
+        }
+        let err = BandsSelection::from_str("b8..b5").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid range of backups: b0008 is after b0005"
+        );
+    }
+
     #[test]
     fn unsupported_band_version() {
         let af = ScratchArchive::new();
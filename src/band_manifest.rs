@@ -0,0 +1,185 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A manifest at the top of the archive listing all the bands and their start and end times.
+//!
+//! Reading every band head and tail is slow on high-latency transports like S3; with
+//! the manifest, `versions` needs only one listing of the archive directory and one
+//! read.
+//!
+//! The manifest is only a cache: the band directories remain authoritative. If the
+//! manifest is missing, can't be parsed, or doesn't list exactly the bands in the
+//! archive directory, it's ignored. It's only rewritten by operations that hold the
+//! archive's [WriteLock], so reading the archive never writes to it.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tracing::{debug, warn};
+
+use crate::band::Info;
+use crate::jsonio::read_json;
+use crate::transport::WriteMode;
+use crate::*;
+
+/// Name of the manifest file in the archive directory.
+pub(crate) static BANDS_MANIFEST_FILENAME: &str = "BANDS";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    bands: Vec<ManifestBand>,
+}
+
+/// Summary of one band, as stored in the manifest.
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestBand {
+    id: String,
+    start_time: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    end_time: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index_hunk_count: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    conserve_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunking: Option<String>,
+}
+
+impl ManifestBand {
+    fn from_info(info: &Info) -> ManifestBand {
+        ManifestBand {
+            id: info.id.to_string(),
+            start_time: info.start_time.unix_timestamp(),
+            end_time: info.end_time.map(OffsetDateTime::unix_timestamp),
+            index_hunk_count: info.index_hunk_count,
+            conserve_version: info.conserve_version.clone(),
+            hostname: info.hostname.clone(),
+            source_path: info.source_path.clone(),
+            chunking: info.chunking.clone(),
+        }
+    }
+
+    fn to_info(&self) -> Option<Info> {
+        Some(Info {
+            id: self.id.parse().ok()?,
+            is_closed: self.end_time.is_some(),
+            start_time: OffsetDateTime::from_unix_timestamp(self.start_time).ok()?,
+            end_time: match self.end_time {
+                Some(end_time) => Some(OffsetDateTime::from_unix_timestamp(end_time).ok()?),
+                None => None,
+            },
+            index_hunk_count: self.index_hunk_count,
+            conserve_version: self.conserve_version.clone(),
+            hostname: self.hostname.clone(),
+            source_path: self.source_path.clone(),
+            chunking: self.chunking.clone(),
+        })
+    }
+}
+
+/// Read the manifest, returning summaries of the bands it lists in order, if it
+/// lists exactly the bands now in the archive.
+///
+/// Returns None if the manifest doesn't exist, can't be parsed, or is stale, for
+/// example because an older version of Conserve created or deleted a band without
+/// updating it.
+///
+/// Bands that weren't closed when the manifest was written might have been closed
+/// since, so callers should read their current state from the band.
+pub(crate) fn read(archive: &Archive) -> Option<Vec<Info>> {
+    let infos = read_unchecked(archive)?;
+    match archive.list_band_ids() {
+        Ok(band_ids) if band_ids.iter().eq(infos.iter().map(|info| &info.id)) => Some(infos),
+        Ok(_) => {
+            debug!("Band manifest is stale");
+            None
+        }
+        Err(err) => {
+            warn!(?err, "Failed to list bands to check the band manifest");
+            None
+        }
+    }
+}
+
+/// Read the manifest without checking whether it lists the bands now in the archive.
+fn read_unchecked(archive: &Archive) -> Option<Vec<Info>> {
+    let manifest: Manifest = match read_json(archive.transport(), BANDS_MANIFEST_FILENAME) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => return None,
+        Err(err) => {
+            warn!(?err, "Failed to read band manifest; ignoring it");
+            return None;
+        }
+    };
+    let Some(mut infos) = manifest
+        .bands
+        .iter()
+        .map(ManifestBand::to_info)
+        .collect::<Option<Vec<Info>>>()
+    else {
+        warn!("Invalid band in band manifest; ignoring it");
+        return None;
+    };
+    infos.sort_unstable_by_key(|info| info.id);
+    Some(infos)
+}
+
+/// Rewrite the manifest to match the bands now in the archive directory.
+///
+/// Summaries of bands that were already closed are taken from the old manifest, even
+/// if it's stale, since closed bands don't change; everything else is read from the
+/// band.
+///
+/// This must only be called while holding the archive's [WriteLock].
+fn update(archive: &Archive) -> Result<Vec<Info>> {
+    let mut closed: HashMap<BandId, Info> = read_unchecked(archive)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|info| info.is_closed)
+        .map(|info| (info.id, info))
+        .collect();
+    let infos = archive
+        .list_band_ids()?
+        .into_iter()
+        .map(|band_id| match closed.remove(&band_id) {
+            Some(info) => Ok(info),
+            None => Band::open(archive, band_id)?.get_info(),
+        })
+        .collect::<Result<Vec<Info>>>()?;
+    let manifest = Manifest {
+        bands: infos.iter().map(ManifestBand::from_info).collect(),
+    };
+    let mut json = serde_json::to_string(&manifest)?;
+    json.push('\n');
+    archive.transport().write_file(
+        BANDS_MANIFEST_FILENAME,
+        json.as_bytes(),
+        WriteMode::Overwrite,
+    )?;
+    Ok(infos)
+}
+
+/// Update the manifest after the archive changed, logging rather than returning any error
+/// since the manifest is only a cache.
+///
+/// This must only be called while holding the archive's [WriteLock].
+pub(crate) fn update_or_warn(archive: &Archive) {
+    if let Err(err) = update(archive) {
+        warn!(?err, "Failed to update band manifest");
+    }
+}
@@ -22,6 +22,7 @@ use crate::errors::Error;
 
 /// Identifier for a band within an archive, eg 'b0001'.
 #[derive(Debug, PartialEq, Clone, Copy, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(into = "String")]
 pub struct BandId(u32);
 
 impl BandId {
@@ -81,6 +82,12 @@ impl From<u32> for BandId {
     }
 }
 
+impl From<BandId> for String {
+    fn from(band_id: BandId) -> String {
+        band_id.to_string()
+    }
+}
+
 impl fmt::Display for BandId {
     /// Returns the string representation of this BandId.
     ///
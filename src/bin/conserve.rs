@@ -14,22 +14,32 @@
 //! Command-line entry point for Conserve backups.
 
 use std::cell::RefCell;
+use std::ffi::OsString;
 use std::fs::{File, OpenOptions};
-use std::io::{self, BufWriter, Write};
+use std::io::{self, BufWriter, Read, Write};
+use std::num::NonZeroUsize;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use clap::builder::{styling, Styles};
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use conserve::change::Change;
+use conserve::monitor::events::EventMonitor;
 use rayon::prelude::ParallelIterator;
-use time::UtcOffset;
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime, UtcOffset};
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn, Level};
 
 use crate::transport::Transport;
-use conserve::termui::{enable_tracing, TermUiMonitor, TraceTimeStyle};
+use conserve::termui::{
+    enable_tracing, set_color_choice, ColorChoice, TermUiMonitor, TraceTimeStyle,
+    DEFAULT_PROGRESS_INTERVAL,
+};
 use conserve::*;
 
 /// Local timezone offset, calculated once at startup, to avoid issues about
@@ -55,10 +65,31 @@ struct Args {
     #[arg(long, short = 'P', global = true)]
     no_progress: bool,
 
+    /// Minimum time between redraws of the progress bars, in milliseconds.
+    ///
+    /// A longer interval produces less output on slow terminals.
+    #[arg(long, global = true, value_name = "MS", default_value_t = DEFAULT_PROGRESS_INTERVAL.as_millis() as u64)]
+    progress_interval: u64,
+
+    /// Rather than drawing progress bars, write snapshots of progress to stderr as
+    /// lines of json, for a wrapper that shows its own progress.
+    #[arg(long, global = true)]
+    progress_json: bool,
+
+    /// If the command fails, print the error to stderr as a line of json with a stable
+    /// `code` for the kind of error, as well as the `message`.
+    #[arg(long, global = true)]
+    error_json: bool,
+
     /// Show debug trace to stdout.
     #[arg(long, short = 'D', global = true)]
     debug: bool,
 
+    /// When to color messages and help: by default, only on a color terminal and
+    /// if `NO_COLOR` is not set.
+    #[arg(long, value_enum, global = true, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
     /// Control timestamps prefixes on stderr.
     #[arg(long, value_enum, global = true, default_value_t = TraceTimeStyle::None)]
     trace_time: TraceTimeStyle,
@@ -67,11 +98,98 @@ struct Args {
     #[arg(long, global = true)]
     log_json: Option<PathBuf>,
 
+    /// Format for json printed to stdout; implies `--json` for commands that have it.
+    ///
+    /// By default `ls` and `diff` print one value per line, and `debug` commands print
+    /// indented json. `--changes-json` and `--log-json` files are always one value per line.
+    #[arg(long, value_enum, global = true)]
+    json_format: Option<JsonFormat>,
+
+    /// Write progress, counters, and errors as json events to this Unix socket,
+    /// named pipe, or file, for a GUI or other program driving Conserve.
+    #[arg(long, global = true, value_name = "PATH")]
+    events_socket: Option<PathBuf>,
+
+    /// Use at most this many threads for work done in parallel, such as reading blocks
+    /// and validating bands. By default, one per CPU.
+    #[arg(long, global = true, env = "CONSERVE_THREADS", value_name = "N")]
+    threads: Option<NonZeroUsize>,
+
+    #[command(flatten)]
+    filter: FilterArgs,
+
     /// Write metrics to this file: deprecated and ignored.
     #[arg(long, global = true, hide = true)]
     metrics_json: Option<PathBuf>,
 }
 
+/// External commands to filter archive content, for example to encrypt it.
+#[derive(Debug, clap::Args)]
+struct FilterArgs {
+    /// Pass blocks and index hunks through this shell command, reading stdin and
+    /// writing stdout, as they're written to the archive: for example to encrypt them.
+    ///
+    /// Archives written with this can only be read with `--decrypt-command`.
+    #[arg(
+        long,
+        global = true,
+        env = "CONSERVE_ENCRYPT_COMMAND",
+        value_name = "COMMAND",
+        requires = "decrypt_command"
+    )]
+    encrypt_command: Option<String>,
+
+    /// Pass blocks and index hunks through this shell command as they're read,
+    /// to undo `--encrypt-command`.
+    #[arg(
+        long,
+        global = true,
+        env = "CONSERVE_DECRYPT_COMMAND",
+        value_name = "COMMAND",
+        requires = "encrypt_command"
+    )]
+    decrypt_command: Option<String>,
+}
+
+impl FilterArgs {
+    /// Make a transport to an archive location, filtered through the commands if
+    /// they're set.
+    fn transport(&self, location: &str) -> Result<Transport> {
+        let transport = Transport::new(location)?;
+        match (&self.encrypt_command, &self.decrypt_command) {
+            (Some(encrypt), Some(decrypt)) => Ok(transport.external_filter(encrypt, decrypt)),
+            _ => Ok(transport),
+        }
+    }
+}
+
+/// Options to report how much of the archive's index was read.
+#[derive(Debug, clap::Args)]
+struct IndexStatsArgs {
+    /// Print statistics about reading the index when finished.
+    #[arg(long)]
+    stats: bool,
+
+    /// Write statistics about reading the index to this json file.
+    #[arg(long, value_name = "PATH")]
+    stats_json: Option<PathBuf>,
+}
+
+impl IndexStatsArgs {
+    /// Print or write the index reads counted by the monitor, if requested.
+    fn report(&self, monitor: &TermUiMonitor) -> Result<()> {
+        let stats = IndexReadStats::from_counters(monitor.counters());
+        if self.stats {
+            monitor.clear_progress_bars();
+            info!("{stats}");
+        }
+        if let Some(path) = &self.stats_json {
+            serde_json::to_writer_pretty(File::create(path)?, &stats)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Subcommand)]
 enum Command {
     /// Copy source directory into an archive.
@@ -80,6 +198,10 @@ enum Command {
         archive: String,
         /// Source directory to copy from.
         source: PathBuf,
+        /// Create the archive first if there's nothing at its location, or only an
+        /// empty directory.
+        #[arg(long)]
+        init_if_missing: bool,
         /// Write a list of changes to this file.
         #[arg(long)]
         changes_json: Option<PathBuf>,
@@ -88,17 +210,135 @@ enum Command {
         verbose: bool,
         #[arg(long, short)]
         exclude: Vec<String>,
-        /// Read a list of globs to exclude from this file.
+        /// Read a list of globs to exclude from this file, or `-` for stdin.
         #[arg(long, short = 'E')]
         exclude_from: Vec<String>,
+        /// Back up only files and symlinks matching this glob, and the directories
+        /// that might contain them; may be repeated. Excludes take precedence.
+        #[arg(long, short = 'I', value_name = "GLOB")]
+        include: Vec<String>,
         /// Don't print statistics after the backup completes.
         #[arg(long)]
         no_stats: bool,
         /// Show permissions, owner, and group in verbose output.
         #[arg(long, short = 'l')]
         long_listing: bool,
+        /// Flush each file written to a local archive to disk, so that the backup
+        /// survives a crash or power loss as soon as it completes. This is slower.
+        #[arg(long)]
+        durable: bool,
+        /// Record progress through large files as they're stored, so that if the backup is
+        /// interrupted the next backup can skip the part already stored.
+        #[arg(long)]
+        checkpoint_large_files: bool,
+        /// When new data matches the hash of a block already in the archive, read the
+        /// existing block and check its content, storing it again if it's corrupt.
+        #[arg(long)]
+        verify_dedup: bool,
+        /// Store parts of large files that are a prefix of an existing block, such as
+        /// the end of a truncated file, as a reference into that block. This uses
+        /// more memory, and reads the old blocks of changed files.
+        #[arg(long)]
+        dedup_prefixes: bool,
+        /// Warn about paths longer than this many bytes, which might not be restorable
+        /// on some filesystems.
+        #[arg(long, value_name = "BYTES")]
+        max_path_len: Option<usize>,
+        /// Warn about paths nested more than this many directories deep.
+        #[arg(long, value_name = "DEPTH")]
+        max_path_depth: Option<usize>,
+        /// Skip paths exceeding `--max-path-len` or `--max-path-depth`, as errors.
+        #[arg(long)]
+        strict_paths: bool,
+        /// Back up at most this many entries from each directory, the first by name,
+        /// skipping the rest with a warning.
+        #[arg(long, value_name = "N")]
+        max_files_per_dir: Option<usize>,
+        /// Warn about symlinks whose target doesn't exist. They're still stored.
+        #[arg(long)]
+        warn_dangling_symlinks: bool,
+        /// Don't apply the exclude patterns stored in the archive by `set-excludes`.
+        #[arg(long)]
+        no_archive_excludes: bool,
+        /// Record the absolute path of the source directory in the backup.
+        #[arg(long)]
+        record_source_path: bool,
+        /// Record whether each directory was empty in the source, to distinguish it
+        /// from a directory whose contents were all excluded.
+        #[arg(long)]
+        record_empty_dirs: bool,
+        /// Break the archive lock left behind by an interrupted backup, delete, or gc,
+        /// and then back up.
+        #[arg(long)]
+        break_lock: bool,
+        /// Don't warn if the archive's filesystem is nearly full.
+        #[arg(long)]
+        ignore_space_check: bool,
+        /// Back up only the paths listed on stdin, and the directories containing them.
+        ///
+        /// Paths are separated by newlines, or by NULs if there are any, as from
+        /// `find -print0`. They may be relative to the source directory, or absolute.
+        #[arg(long)]
+        stdin_paths: bool,
+        /// Back up only the paths stored in this existing backup, and read them from
+        /// the source as they are now. Paths that no longer exist are skipped with a
+        /// warning.
+        #[arg(long, value_name = "BACKUP", conflicts_with = "stdin_paths")]
+        paths_from_backup: Option<BandId>,
+        /// How to split large files into blocks: `content-defined` chunking
+        /// deduplicates better when data is inserted into or removed from files.
+        #[arg(long, value_enum, default_value_t)]
+        chunking: Chunking,
+        /// How to compress the index: `zstd` makes it much smaller, but the backup
+        /// can't be read by older versions of Conserve.
+        #[arg(long, value_enum, default_value_t)]
+        index_compression: IndexCompression,
+        /// Write an index hunk after this many entries. Smaller hunks lose less work
+        /// if the backup is interrupted; larger hunks have less overhead.
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = DEFAULT_MAX_ENTRIES_PER_HUNK,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        )]
+        entries_per_hunk: usize,
+    },
+
+    /// List files added, changed, or deleted between two backups.
+    Changed {
+        /// Path or URL of an existing archive.
+        archive: String,
+        /// The earlier backup to compare from.
+        #[arg(long, short)]
+        since: BandId,
+        /// The later backup to compare to: by default, the latest.
+        #[arg(long, short)]
+        backup: Option<BandId>,
+        #[arg(long, short)]
+        exclude: Vec<String>,
+        #[arg(long, short = 'E')]
+        exclude_from: Vec<String>,
+
+        /// Print the changes as json.
+        #[arg(long, short)]
+        json: bool,
+    },
+
+    /// Rewrite the latest backup's index into fewer hunks, or with --all, rewrite
+    /// every backup's index and pack small blocks together.
+    Compact {
+        /// Archive to compact.
+        archive: String,
+        /// Rewrite all backups and repack small blocks, not only the latest backup.
+        #[arg(long)]
+        all: bool,
+        #[arg(long)]
+        no_stats: bool,
     },
 
+    #[command(subcommand)]
+    Config(Config),
+
     #[command(subcommand)]
     Debug(Debug),
 
@@ -112,13 +352,23 @@ enum Command {
         /// Don't actually delete, just check what could be deleted.
         #[arg(long)]
         dry_run: bool,
-        /// Break a lock left behind by a previous interrupted gc operation, and then gc.
+        /// Break locks left behind by a previous interrupted gc, delete, or backup,
+        /// and then gc.
         #[arg(long)]
         break_lock: bool,
         #[arg(long)]
         no_stats: bool,
     },
 
+    /// Look for common problems in an archive, and suggest how to fix them.
+    ///
+    /// This doesn't change the archive, and is much quicker than `validate`, but less
+    /// thorough. Exits with status 2 if any problems are found.
+    Doctor {
+        /// Path or URL of an existing archive.
+        archive: String,
+    },
+
     /// Compare a stored tree to a source directory.
     Diff {
         /// Path or URL of an existing archive.
@@ -132,18 +382,77 @@ enum Command {
         exclude: Vec<String>,
         #[arg(long, short = 'E')]
         exclude_from: Vec<String>,
+        /// Don't apply the exclude patterns stored in the archive by `set-excludes`.
+        #[arg(long)]
+        no_archive_excludes: bool,
         #[arg(long)]
         include_unchanged: bool,
+        /// Also compare the content of files whose metadata is unchanged.
+        #[arg(long)]
+        verify_content: bool,
 
         /// Print the diff as json.
         #[arg(long, short)]
         json: bool,
+
+        #[command(flatten)]
+        index_stats: IndexStatsArgs,
+    },
+
+    /// List the files in a stored tree with a hash of each one's content, so that a
+    /// restored tree can be checked without Conserve.
+    ///
+    /// Hashes are BLAKE2b-512 of the whole file, written in the format of `b2sum`, so
+    /// they can be checked by running `b2sum -c` from the top of the restored tree.
+    /// With `--json`, each entry also has the file size.
+    ExportManifest {
+        /// Path or URL of an existing archive.
+        archive: String,
+        /// Select the version from the archive: by default, the latest.
+        #[arg(long, short)]
+        backup: Option<BandId>,
+        #[arg(long, short)]
+        exclude: Vec<String>,
+        #[arg(long, short = 'E')]
+        exclude_from: Vec<String>,
+        /// List only this subdirectory; paths are still relative to the top of the tree.
+        #[arg(long = "only", short = 'i')]
+        only_subtree: Option<Apath>,
+        /// Print the manifest as json.
+        #[arg(long, short)]
+        json: bool,
+    },
+
+    /// Find files and directories whose path matches a pattern in every backup
+    /// version, for example to locate a file whose directory was renamed.
+    ///
+    /// A pattern containing `*`, `?`, `[`, or `{` matches like an exclude glob;
+    /// otherwise every path containing the pattern matches.
+    Find {
+        /// Path or URL of an existing archive.
+        archive: String,
+        /// Glob or substring to match against paths.
+        pattern: String,
+        /// Print matches as json.
+        #[arg(long, short)]
+        json: bool,
     },
 
     /// Create a new archive.
     Init {
         /// Path for new archive.
         archive: String,
+        /// Exclude this glob from every backup into the archive; may be repeated.
+        #[arg(long, short)]
+        exclude: Vec<String>,
+        /// Read globs to exclude from every backup from this file, or `-` for stdin.
+        #[arg(long, short = 'E')]
+        exclude_from: Vec<String>,
+        /// Compress blocks with a zstd dictionary trained from a sample of the files
+        /// in this directory, which helps when backing up many small, similar files.
+        /// Backups can't be read by older versions of Conserve.
+        #[arg(long, value_name = "SOURCE")]
+        train_dictionary: Option<PathBuf>,
     },
 
     /// Delete blocks unreferenced by any index.
@@ -153,7 +462,8 @@ enum Command {
         /// Don't actually delete, just check what could be deleted.
         #[arg(long)]
         dry_run: bool,
-        /// Break a lock left behind by a previous interrupted gc operation, and then gc.
+        /// Break locks left behind by a previous interrupted gc, delete, or backup,
+        /// and then gc.
         #[arg(long)]
         break_lock: bool,
         #[arg(long)]
@@ -171,6 +481,14 @@ enum Command {
         #[arg(long, short = 'E')]
         exclude_from: Vec<String>,
 
+        /// List only this subdirectory.
+        #[arg(long = "only", short = 'i')]
+        only_subtree: Option<Apath>,
+
+        /// Match exclude patterns relative to the `--only` subtree rather than the top of the tree.
+        #[arg(long, requires = "only_subtree")]
+        relative_excludes: bool,
+
         /// Print entries as json.
         #[arg(long, short)]
         json: bool,
@@ -178,6 +496,13 @@ enum Command {
         /// Show permissions, owner, and group.
         #[arg(short = 'l')]
         long_listing: bool,
+
+        /// List only entries of this kind; may be repeated.
+        #[arg(long, value_enum)]
+        kind: Vec<Kind>,
+
+        #[command(flatten)]
+        index_stats: IndexStatsArgs,
     },
 
     /// Mount the archive as a filesystem.
@@ -213,7 +538,8 @@ enum Command {
     /// Copy a stored tree to a restore directory.
     Restore {
         archive: String,
-        destination: PathBuf,
+        #[arg(required_unless_present = "cas_export")]
+        destination: Option<PathBuf>,
         #[arg(long, short)]
         backup: Option<BandId>,
         /// Write a list of restored files to this json file.
@@ -229,11 +555,111 @@ enum Command {
         exclude_from: Vec<String>,
         #[arg(long = "only", short = 'i')]
         only_subtree: Option<Apath>,
-        #[arg(long)]
+        /// Match exclude patterns relative to the `--only` subtree rather than the top of the tree.
+        #[arg(long, requires = "only_subtree")]
+        relative_excludes: bool,
+        #[arg(long, conflicts_with = "stats")]
         no_stats: bool,
         /// Show permissions, owner, and group in verbose output.
         #[arg(long, short = 'l')]
         long_listing: bool,
+        /// Give yourself permission to read and write every restored file, and to list
+        /// every restored directory, while keeping the other stored permission bits.
+        #[arg(long)]
+        readable: bool,
+        /// Restore only entries of this kind, and the directories containing them;
+        /// may be repeated.
+        #[arg(long, value_enum)]
+        kind: Vec<Kind>,
+        /// Restore only files and symlinks stored with an mtime after this time, as a
+        /// date like 2024-06-01 (midnight UTC) or an RFC 3339 timestamp. Directories are
+        /// still restored.
+        #[arg(long, value_name = "DATE", value_parser = parse_date_time)]
+        newer_than: Option<OffsetDateTime>,
+        /// Restore only files and symlinks stored with an mtime before this time.
+        #[arg(long, value_name = "DATE", value_parser = parse_date_time)]
+        older_than: Option<OffsetDateTime>,
+        /// Stop after restoring this many files, for example to spot-check a backup.
+        #[arg(long, value_name = "N")]
+        limit_files: Option<usize>,
+        /// Stop before restoring a file that would take the total restored over this
+        /// many bytes.
+        #[arg(long, value_name = "BYTES")]
+        limit_bytes: Option<u64>,
+        /// Refuse to restore paths, including the destination, longer than this many
+        /// bytes, as well as any that are too long for the destination filesystem.
+        #[arg(long, value_name = "BYTES")]
+        max_path_len: Option<usize>,
+        /// Give files stored as owned by user OLD to user NEW instead; may be repeated.
+        #[arg(long, value_name = "OLD=NEW", value_parser = parse_name_mapping)]
+        map_owner: Vec<(String, String)>,
+        /// Give files stored as owned by group OLD to group NEW instead; may be repeated.
+        #[arg(long, value_name = "OLD=NEW", value_parser = parse_name_mapping)]
+        map_group: Vec<(String, String)>,
+        /// Don't set the stored owners, leaving restored files owned by the user
+        /// running the restore.
+        #[arg(long, visible_alias = "no-ownership", conflicts_with_all = ["map_owner", "map_group"])]
+        owner_current_user: bool,
+        /// Don't set the stored permissions, leaving restored files with the default
+        /// permissions for new files.
+        #[arg(long, conflicts_with_all = ["readable", "default_mode"])]
+        no_permissions: bool,
+        /// Give restored files with no stored permissions, from archives written by old
+        /// versions, this octal mode, such as 644. Directories also get search
+        /// permission wherever this gives read permission.
+        #[arg(long, value_name = "MODE", value_parser = parse_octal_mode)]
+        default_mode: Option<u32>,
+        /// Don't write anything, but check that all the blocks needed to restore the
+        /// selected files are present and intact; exits with status 2 if any aren't.
+        #[arg(long, conflicts_with = "force_overwrite")]
+        check_only: bool,
+        /// With `--check-only`, only check that blocks are present, without reading them.
+        #[arg(long, requires = "check_only")]
+        quick: bool,
+        /// Restore directories, symlinks, and empty files with their stored permissions,
+        /// owners, and mtimes, without reading or writing the content of any file.
+        #[arg(long, conflicts_with_all = ["check_only", "update", "force_overwrite"])]
+        metadata_only: bool,
+        /// Set the mtime of restored files and directories stored with an mtime in the
+        /// future to the time the restore started.
+        #[arg(long)]
+        no_future_mtimes: bool,
+        /// Update a destination that already holds an earlier copy, writing only files
+        /// whose size or mtime differ from those stored.
+        #[arg(long, conflicts_with_all = ["force_overwrite", "check_only"])]
+        update: bool,
+        /// With `--update`, also compare the content of files that look unchanged.
+        #[arg(long, requires = "update")]
+        verify_content: bool,
+        /// With `--update`, delete files in the destination that aren't in the backup,
+        /// other than excluded files.
+        #[arg(long, requires = "update")]
+        delete: bool,
+        /// Rather than restoring files, write each distinct block they use once into
+        /// this empty directory, named by its hash, with a `manifest.json` listing the
+        /// parts of blocks that make up each file.
+        #[arg(long, value_name = "DIR", conflicts_with_all = ["destination", "check_only", "metadata_only", "update", "force_overwrite"])]
+        cas_export: Option<PathBuf>,
+
+        #[command(flatten)]
+        index_stats: IndexStatsArgs,
+    },
+
+    /// Replace the exclude patterns stored in an archive, which apply to every backup
+    /// into it as well as those given to `backup`.
+    ///
+    /// With no patterns, print the stored patterns without changing them.
+    SetExcludes {
+        /// Path or URL of an existing archive.
+        archive: String,
+        #[arg(long, short)]
+        exclude: Vec<String>,
+        /// Read globs to exclude from this file, or `-` for stdin.
+        #[arg(long, short = 'E')]
+        exclude_from: Vec<String>,
+        /// Remove all the stored patterns.
+        #[arg(long, conflicts_with_all = ["exclude", "exclude_from"])]
+        clear: bool,
     },
 
     /// Show the total size of files in a stored tree or source directory, with exclusions.
@@ -249,6 +675,29 @@ enum Command {
         exclude: Vec<String>,
         #[arg(long, short = 'E')]
         exclude_from: Vec<String>,
+
+        /// Measure only this subdirectory.
+        #[arg(long = "only", short = 'i')]
+        only_subtree: Option<Apath>,
+
+        /// Print the size measured so far every this many seconds, before the total.
+        #[arg(long, value_name = "SECONDS")]
+        subtotal_interval: Option<u64>,
+    },
+
+    /// Show how much space an archive's blocks use, compared to the size of all the
+    /// files backed up in it.
+    Stats {
+        /// Path of the archive to read.
+        archive: String,
+
+        /// Count in bytes, not megabytes.
+        #[arg(long)]
+        bytes: bool,
+
+        /// Print the result as json.
+        #[arg(long, short)]
+        json: bool,
     },
 
     /// Check that an archive is internally consistent.
@@ -259,8 +708,19 @@ enum Command {
         /// Skip reading and checking the content of data blocks.
         #[arg(long, short = 'q')]
         quick: bool,
+        /// Delete block files of length zero, left by interrupted writes, so that the
+        /// next backup stores their content again.
+        #[arg(long)]
+        remove_empty_blocks: bool,
+        /// Check only these backups, and the blocks they use: a range like `b5..b8`,
+        /// including both ends, or the latest few like `latest:3`.
+        #[arg(long, value_name = "SELECTION")]
+        bands: Option<BandsSelection>,
         #[arg(long)]
         no_stats: bool,
+        /// Print a report of the problems found, and whether the archive is ok, as json.
+        #[arg(long, short)]
+        json: bool,
     },
 
     /// List backup versions in an archive.
@@ -278,6 +738,12 @@ enum Command {
         /// Show times in UTC.
         #[arg(long)]
         utc: bool,
+        /// Show the source directory of backups made with `--record-source-path`.
+        #[arg(long, conflicts_with = "short")]
+        source: bool,
+        /// Print versions as json, including which Conserve version and host wrote them.
+        #[arg(long, short, conflicts_with_all = ["short", "sizes"])]
+        json: bool,
     },
 }
 
@@ -299,6 +765,25 @@ struct StoredTreeOrSource {
     backup: Option<BandId>,
 }
 
+/// Read or write metadata stored in the archive, such as who manages it.
+#[derive(Debug, Subcommand)]
+enum Config {
+    /// Print the value of a key; fails if it's not set.
+    Get {
+        /// Path or URL of the archive.
+        archive: String,
+        key: String,
+    },
+
+    /// Set the value of a key.
+    Set {
+        /// Path or URL of the archive.
+        archive: String,
+        key: String,
+        value: String,
+    },
+}
+
 /// Show debugging information.
 #[derive(Debug, Subcommand)]
 enum Debug {
@@ -310,13 +795,64 @@ enum Debug {
         /// Backup version number.
         #[arg(long, short)]
         backup: Option<BandId>,
+
+        /// Dump only this hunk of the index, such as `5`, or a range of hunks such
+        /// as `5..10`, which includes 5 but not 10.
+        #[arg(long, value_name = "N[..M]", value_parser = parse_hunk_range)]
+        hunk: Option<Range<u32>>,
     },
 
     /// List all blocks.
     Blocks { archive: String },
 
+    /// Show the size and integrity of one block, and which files reference it.
+    BlockInfo {
+        /// Path of the archive to read.
+        archive: String,
+
+        /// Hash of the block, or a prefix of it that matches only one block.
+        hash: String,
+
+        /// Print the result as json.
+        #[arg(long, short)]
+        json: bool,
+    },
+
+    /// Re-read one block and check its content has the right hash, without
+    /// validating the rest of the archive.
+    VerifyBlock {
+        /// Path of the archive to read.
+        archive: String,
+
+        /// Hash of the block, or a prefix of it that matches only one block.
+        hash: String,
+
+        /// Print the result as json.
+        #[arg(long, short)]
+        json: bool,
+    },
+
+    /// Count the blocks present in only one of two archives, or in both.
+    BlockDiff {
+        /// Path of the first archive.
+        first: String,
+
+        /// Path of the second archive.
+        second: String,
+
+        /// Print the result as json.
+        #[arg(long, short)]
+        json: bool,
+    },
+
     /// List all blocks referenced by any band.
-    Referenced { archive: String },
+    Referenced {
+        archive: String,
+        /// List only the blocks used by these backups: a range like `b5..b8`,
+        /// including both ends, or the latest few like `latest:3`.
+        #[arg(long, value_name = "SELECTION")]
+        bands: Option<BandsSelection>,
+    },
 
     /// List garbage blocks referenced by no band.
     Unreferenced { archive: String },
@@ -335,41 +871,161 @@ impl std::process::Termination for ExitCode {
 }
 
 impl Command {
-    fn run(&self, monitor: Arc<TermUiMonitor>) -> Result<ExitCode> {
+    fn run(
+        &self,
+        monitor: Arc<TermUiMonitor>,
+        json_format: Option<JsonFormat>,
+        filter: &FilterArgs,
+    ) -> Result<ExitCode> {
         let mut stdout = io::stdout();
         match self {
             Command::Backup {
                 archive,
                 changes_json,
+                checkpoint_large_files,
+                verify_dedup,
+                dedup_prefixes,
+                max_path_len,
+                max_path_depth,
+                strict_paths,
+                max_files_per_dir,
+                warn_dangling_symlinks,
+                no_archive_excludes,
+                record_source_path,
+                record_empty_dirs,
+                break_lock,
+                ignore_space_check,
+                chunking,
+                index_compression,
+                entries_per_hunk,
+                stdin_paths,
+                paths_from_backup,
+                durable,
                 exclude,
                 exclude_from,
+                include,
+                init_if_missing,
                 long_listing,
                 no_stats,
                 source,
                 verbose,
             } => {
-                let options = BackupOptions {
+                // Check the options before opening, and perhaps creating, the archive.
+                let mut options = BackupOptions {
                     exclude: Exclude::from_patterns_and_files(exclude, exclude_from)?,
+                    include: if include.is_empty() {
+                        None
+                    } else {
+                        Some(Exclude::from_strings(include)?)
+                    },
                     change_callback: make_change_callback(
                         *verbose,
                         *long_listing,
                         &changes_json.as_deref(),
                     )?,
+                    checkpoint_large_files: *checkpoint_large_files,
+                    verify_dedup: *verify_dedup,
+                    dedup_prefixes: *dedup_prefixes,
+                    max_path_len: *max_path_len,
+                    max_path_depth: *max_path_depth,
+                    strict_paths: *strict_paths,
+                    max_files_per_dir: *max_files_per_dir,
+                    warn_dangling_symlinks: *warn_dangling_symlinks,
+                    ignore_archive_excludes: *no_archive_excludes,
+                    record_source_path: *record_source_path,
+                    record_empty_dirs: *record_empty_dirs,
+                    break_lock: *break_lock,
+                    ignore_space_check: *ignore_space_check,
+                    chunking: *chunking,
+                    index_compression: *index_compression,
+                    max_entries_per_hunk: *entries_per_hunk,
+                    source_paths: if *stdin_paths {
+                        Some(read_stdin_paths()?)
+                    } else {
+                        None
+                    },
+                    stop_requested: Some(&STOP_REQUESTED),
                     ..Default::default()
                 };
-                let stats = backup(
-                    &Archive::open(Transport::new(archive)?)?,
-                    source,
-                    &options,
-                    monitor,
-                )?;
+                let mut transport = filter.transport(archive)?;
+                if *durable {
+                    transport = transport.durable();
+                }
+                let archive = match Archive::open(transport.clone()) {
+                    // Creating the archive fails if the location isn't empty, so this
+                    // won't overwrite anything else.
+                    Err(Error::NotAnArchive) if *init_if_missing => {
+                        info!("Creating new archive in {archive:?}");
+                        Archive::create(transport)?
+                    }
+                    result => result?,
+                };
+                if let Some(band_id) = paths_from_backup {
+                    options.source_paths =
+                        Some(read_backup_paths(&archive, *band_id, monitor.clone())?);
+                }
+                stop_cleanly_on_interrupt();
+                let stats = backup(&archive, source, &options, monitor)?;
                 if !no_stats {
                     info!("Backup complete.\n{stats}");
                 }
             }
+            Command::Changed {
+                archive,
+                since,
+                backup,
+                exclude,
+                exclude_from,
+                json,
+            } => {
+                let archive = Archive::open(filter.transport(archive)?)?;
+                let old = archive.open_stored_tree(BandSelectionPolicy::Specified(*since))?;
+                let new = archive.open_stored_tree(band_selection_policy_from_opt(backup))?;
+                let options = DiffOptions {
+                    exclude: Exclude::from_patterns_and_files(exclude, exclude_from)?,
+                    include_unchanged: false,
+                    verify_content: false,
+                };
+                let changes = diff_stored_trees(&old, &new, &options, monitor.clone())?;
+                if *json || json_format.is_some() {
+                    show::write_json_seq(changes, json_format.unwrap_or_default(), &mut stdout)?;
+                } else {
+                    let mut bw = BufWriter::new(stdout);
+                    for change in changes {
+                        writeln!(bw, "{change}")?;
+                    }
+                }
+            }
+            Command::Compact {
+                archive,
+                all,
+                no_stats,
+            } => {
+                let archive = Archive::open(filter.transport(archive)?)?;
+                let options = CompactOptions {
+                    all_bands: *all,
+                    ..Default::default()
+                };
+                let stats = compact(&archive, &options, monitor)?;
+                if !no_stats {
+                    info!(%stats);
+                }
+            }
+            Command::Config(Config::Get { archive, key }) => {
+                let archive = Archive::open(filter.transport(archive)?)?;
+                println!("{}", archive.get_metadata(key)?);
+            }
+            Command::Config(Config::Set {
+                archive,
+                key,
+                value,
+            }) => {
+                let archive = Archive::open(filter.transport(archive)?)?;
+                archive.set_metadata(key, value)?;
+            }
             Command::Debug(Debug::Blocks { archive }) => {
                 let mut bw = BufWriter::new(stdout);
-                for hash in Archive::open(Transport::new(archive)?)?
+                for hash in Archive::open(filter.transport(archive)?)?
                     .block_dir()
                     .blocks(monitor)?
                     .collect::<Vec<BlockHash>>()
@@ -378,21 +1034,163 @@ impl Command {
                     writeln!(bw, "{hash}")?;
                 }
             }
-            Command::Debug(Debug::Index { archive, backup }) => {
-                let st = stored_tree_from_opt(archive, backup)?;
-                show::show_index_json(st.band(), &mut stdout)?;
+            Command::Debug(Debug::VerifyBlock {
+                archive,
+                hash,
+                json,
+            }) => {
+                let archive = Archive::open(filter.transport(archive)?)?;
+                let block_dir = archive.block_dir();
+                let hash = block_dir.resolve_prefix(hash, monitor.clone())?;
+                let verification = block_dir.verify_block(&hash, monitor.clone())?;
+                if *json || json_format.is_some() {
+                    show::write_json_value(
+                        &verification,
+                        json_format.unwrap_or(JsonFormat::Pretty),
+                        &mut stdout,
+                    )?;
+                } else {
+                    writeln!(stdout, "hash: {hash}")?;
+                    writeln!(
+                        stdout,
+                        "compressed size: {} bytes",
+                        verification.compressed_len
+                    )?;
+                    match verification.uncompressed_len {
+                        Some(len) => writeln!(stdout, "uncompressed size: {len} bytes")?,
+                        None => writeln!(stdout, "uncompressed size: can't decompress")?,
+                    }
+                    match &verification.actual_hash {
+                        _ if verification.is_ok() => writeln!(stdout, "OK")?,
+                        Some(actual) => writeln!(
+                            stdout,
+                            "MISMATCH: expected hash {hash}, actual hash {actual}"
+                        )?,
+                        None => writeln!(stdout, "MISMATCH: content can't be decompressed")?,
+                    }
+                }
+                if !verification.is_ok() {
+                    return Ok(ExitCode::NonFatalErrors);
+                }
+            }
+            Command::Debug(Debug::BlockInfo {
+                archive,
+                hash,
+                json,
+            }) => {
+                let archive = Archive::open(filter.transport(archive)?)?;
+                let hash = archive.block_dir().resolve_prefix(hash, monitor.clone())?;
+                let info = archive.block_info(&hash, monitor)?;
+                if *json || json_format.is_some() {
+                    show::write_json_value(
+                        &info,
+                        json_format.unwrap_or(JsonFormat::Pretty),
+                        &mut stdout,
+                    )?;
+                } else {
+                    writeln!(stdout, "hash: {}", info.hash)?;
+                    writeln!(stdout, "compressed size: {} bytes", info.compressed_len)?;
+                    match info.uncompressed_len {
+                        Some(len) => writeln!(stdout, "uncompressed size: {len} bytes")?,
+                        None => writeln!(stdout, "uncompressed size: can't decompress")?,
+                    }
+                    writeln!(
+                        stdout,
+                        "hash matches content: {}",
+                        if info.hash_matches { "yes" } else { "NO" }
+                    )?;
+                    writeln!(
+                        stdout,
+                        "referenced by bands: {}",
+                        info.bands
+                            .iter()
+                            .map(BandId::to_string)
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    )?;
+                    writeln!(stdout, "referenced by:")?;
+                    for reference in &info.references {
+                        writeln!(stdout, "  {} {}", reference.band_id, reference.apath)?;
+                    }
+                }
+            }
+            Command::Debug(Debug::Index {
+                archive,
+                backup,
+                hunk,
+            }) => {
+                let st = stored_tree_from_opt(archive, backup, filter)?;
+                let json_format = json_format.unwrap_or(JsonFormat::Pretty);
+                if let Some(hunks) = hunk {
+                    let band_id = st.band().id();
+                    let mut index = st.band().index();
+                    let available = index.hunks_available()?;
+                    let not_found = |hunk_number| Error::IndexHunkNotFound {
+                        band_id,
+                        hunk_number,
+                        hunk_count: available.len(),
+                    };
+                    // Check them all first, so that nothing is printed for a bad range.
+                    if let Some(missing) = hunks.clone().find(|h| !available.contains(h)) {
+                        return Err(not_found(missing));
+                    }
+                    let mut entries = Vec::new();
+                    for hunk_number in hunks.clone() {
+                        entries.extend(
+                            index
+                                .read_hunk(hunk_number)?
+                                .ok_or_else(|| not_found(hunk_number))?,
+                        );
+                    }
+                    show::write_json_seq(entries, json_format, &mut stdout)?;
+                } else {
+                    show::write_json_seq(
+                        st.band().index().iter_entries(),
+                        json_format,
+                        &mut stdout,
+                    )?;
+                }
+            }
+            Command::Debug(Debug::BlockDiff {
+                first,
+                second,
+                json,
+            }) => {
+                let first = Archive::open(filter.transport(first)?)?;
+                let second = Archive::open(filter.transport(second)?)?;
+                let comparison = first.compare_blocks(&second, monitor)?;
+                if *json || json_format.is_some() {
+                    show::write_json_value(
+                        &comparison,
+                        json_format.unwrap_or(JsonFormat::Pretty),
+                        &mut stdout,
+                    )?;
+                } else {
+                    writeln!(
+                        stdout,
+                        "only in first archive: {} blocks",
+                        comparison.only_in_first
+                    )?;
+                    writeln!(
+                        stdout,
+                        "only in second archive: {} blocks",
+                        comparison.only_in_second
+                    )?;
+                    writeln!(stdout, "in both: {} blocks", comparison.in_both)?;
+                }
             }
-            Command::Debug(Debug::Referenced { archive }) => {
+            Command::Debug(Debug::Referenced { archive, bands }) => {
                 let mut bw = BufWriter::new(stdout);
-                let archive = Archive::open(Transport::new(archive)?)?;
-                for hash in archive.referenced_blocks(&archive.list_band_ids()?, monitor)? {
+                let archive = Archive::open(filter.transport(archive)?)?;
+                let band_ids = archive.select_bands(bands.unwrap_or_default())?;
+                for hash in archive.referenced_blocks(&band_ids, monitor)? {
                     writeln!(bw, "{hash}")?;
                 }
             }
             Command::Debug(Debug::Unreferenced { archive }) => {
                 print!(
                     "{}",
-                    Archive::open(Transport::new(archive)?)?
+                    Archive::open(filter.transport(archive)?)?
                         .unreferenced_blocks(monitor)?
                         .map(|hash| format!("{}\n", hash))
                         .collect::<Vec<String>>()
@@ -406,7 +1204,7 @@ impl Command {
                 break_lock,
                 no_stats,
             } => {
-                let stats = Archive::open(Transport::new(archive)?)?.delete_bands(
+                let stats = Archive::open(filter.transport(archive)?)?.delete_bands(
                     backup,
                     &DeleteOptions {
                         dry_run: *dry_run,
@@ -425,23 +1223,106 @@ impl Command {
                 backup,
                 exclude,
                 exclude_from,
+                no_archive_excludes,
                 include_unchanged,
+                verify_content,
                 json,
+                index_stats,
             } => {
-                let st = stored_tree_from_opt(archive, backup)?;
+                let archive = match Archive::open(filter.transport(archive)?) {
+                    // Explain the likely mistake, rather than just saying it's not an archive.
+                    Err(Error::NotAnArchive) if Archive::is_archive_path(source) => {
+                        return Err(Error::DiffArgumentsSwapped {
+                            archive: archive.clone(),
+                            source_path: source.clone(),
+                        });
+                    }
+                    Err(Error::NotAnArchive) => {
+                        return Err(Error::DiffArchiveIsNotAnArchive {
+                            archive: archive.clone(),
+                        });
+                    }
+                    result => result?,
+                };
+                let st = archive.open_stored_tree(band_selection_policy_from_opt(backup))?;
+                if Archive::is_archive_path(source) {
+                    return Err(Error::DiffSourceIsAnArchive {
+                        source_path: source.clone(),
+                    });
+                }
                 let lt = LiveTree::open(source)?;
+                let mut exclude = Exclude::from_patterns_and_files(exclude, exclude_from)?;
+                // Compare only what a backup would have stored.
+                if !no_archive_excludes {
+                    exclude = exclude.union(Exclude::from_strings(archive.exclude_patterns()?)?);
+                }
                 let options = DiffOptions {
-                    exclude: Exclude::from_patterns_and_files(exclude, exclude_from)?,
+                    exclude,
                     include_unchanged: *include_unchanged,
+                    verify_content: *verify_content,
                 };
-                let mut bw = BufWriter::new(stdout);
-                for change in diff(&st, &lt, &options, monitor.clone())? {
-                    if *json {
-                        serde_json::to_writer(&mut bw, &change)?;
-                    } else {
+                let changes = diff(&st, &lt, &options, monitor.clone())?;
+                if *json || json_format.is_some() {
+                    show::write_json_seq(changes, json_format.unwrap_or_default(), &mut stdout)?;
+                } else {
+                    let mut bw = BufWriter::new(stdout);
+                    for change in changes {
                         writeln!(bw, "{change}")?;
                     }
                 }
+                index_stats.report(&monitor)?;
+            }
+            Command::Doctor { archive } => {
+                let problems = doctor(&Archive::open(filter.transport(archive)?)?)?;
+                monitor.clear_progress_bars();
+                if problems.is_empty() {
+                    println!("No problems found.");
+                } else {
+                    for problem in &problems {
+                        println!("{problem}.\n    {}", problem.advice());
+                    }
+                    return Ok(ExitCode::NonFatalErrors);
+                }
+            }
+            Command::ExportManifest {
+                archive,
+                backup,
+                exclude,
+                exclude_from,
+                only_subtree,
+                json,
+            } => {
+                let st = stored_tree_from_opt(archive, backup, filter)?;
+                let entries = content_manifest(
+                    &st,
+                    only_subtree.clone().unwrap_or_else(Apath::root),
+                    Exclude::from_patterns_and_files(exclude, exclude_from)?,
+                    monitor.clone(),
+                )?;
+                if *json || json_format.is_some() {
+                    show::write_json_seq(entries, json_format.unwrap_or_default(), &mut stdout)?;
+                } else {
+                    let mut bw = BufWriter::new(stdout);
+                    for entry in entries {
+                        writeln!(bw, "{entry}")?;
+                    }
+                }
+            }
+            Command::Find {
+                archive,
+                pattern,
+                json,
+            } => {
+                let archive = Archive::open(filter.transport(archive)?)?;
+                let matches = find(&archive, pattern, monitor.clone())?;
+                if *json || json_format.is_some() {
+                    show::write_json_seq(matches, json_format.unwrap_or_default(), &mut stdout)?;
+                } else {
+                    let mut bw = BufWriter::new(stdout);
+                    for found in matches {
+                        writeln!(bw, "{found}")?;
+                    }
+                }
             }
             Command::Gc {
                 archive,
@@ -449,7 +1330,7 @@ impl Command {
                 break_lock,
                 no_stats,
             } => {
-                let archive = Archive::open(Transport::new(archive)?)?;
+                let archive = Archive::open(filter.transport(archive)?)?;
                 let stats = archive.delete_bands(
                     &[],
                     &DeleteOptions {
@@ -462,8 +1343,25 @@ impl Command {
                     info!(%stats);
                 }
             }
-            Command::Init { archive } => {
-                Archive::create(Transport::new(archive)?)?;
+            Command::Init {
+                archive,
+                exclude,
+                exclude_from,
+                train_dictionary,
+            } => {
+                let patterns = read_exclude_patterns(exclude, exclude_from)?;
+                let transport = filter.transport(archive)?;
+                let new_archive = if let Some(source) = train_dictionary {
+                    let dictionary = train_block_dictionary(
+                        source,
+                        Exclude::from_strings(&patterns)?,
+                        monitor.clone(),
+                    )?;
+                    Archive::create_with_block_dictionary(transport, &dictionary)?
+                } else {
+                    Archive::create(transport)?
+                };
+                new_archive.set_exclude_patterns(patterns)?;
                 debug!("Created new archive in {archive:?}");
             }
             Command::Ls {
@@ -471,32 +1369,41 @@ impl Command {
                 stos,
                 exclude,
                 exclude_from,
+                only_subtree,
+                relative_excludes,
                 long_listing,
+                kind,
+                index_stats,
             } => {
-                let exclude = Exclude::from_patterns_and_files(exclude, exclude_from)?;
+                let subtree = only_subtree.clone().unwrap_or_else(Apath::root);
+                let mut exclude = Exclude::from_patterns_and_files(exclude, exclude_from)?;
+                if *relative_excludes {
+                    exclude = exclude.relative_to(subtree.clone());
+                }
                 let entry_iter: Box<dyn Iterator<Item = EntryValue>> =
                     if let Some(archive) = &stos.archive {
-                        // TODO: Option for subtree.
                         Box::new(
-                            stored_tree_from_opt(archive, &stos.backup)?
-                                .iter_entries(Apath::root(), exclude, monitor.clone())?
+                            stored_tree_from_opt(archive, &stos.backup, filter)?
+                                .iter_entries(subtree, exclude, monitor.clone())?
                                 .map(|it| it.into()),
                         )
                     } else {
-                        Box::new(LiveTree::open(stos.source.clone().unwrap())?.iter_entries(
-                            Apath::root(),
-                            exclude,
-                            monitor.clone(),
-                        )?)
+                        // Special files such as FIFOs can't be backed up, so aren't listed.
+                        Box::new(
+                            LiveTree::open(stos.source.clone().unwrap())?
+                                .iter_entries(subtree, exclude, monitor.clone())?
+                                .filter(|entry| entry.kind() != Kind::Unknown),
+                        )
                     };
+                let entry_iter =
+                    entry_iter.filter(|entry| kind.is_empty() || kind.contains(&entry.kind()));
                 monitor.clear_progress_bars();
-                if *json {
-                    for entry in entry_iter {
-                        println!("{}", serde_json::ser::to_string(&entry)?);
-                    }
+                if *json || json_format.is_some() {
+                    show::write_json_seq(entry_iter, json_format.unwrap_or_default(), &mut stdout)?;
                 } else {
                     show::show_entry_names(entry_iter, &mut stdout, *long_listing)?;
                 }
+                index_stats.report(&monitor)?;
             }
             #[cfg(windows)]
             Command::Mount {
@@ -506,7 +1413,7 @@ impl Command {
             } => {
                 use std::io::Read;
 
-                let archive = Archive::open(Transport::new(archive)?)?;
+                let archive = Archive::open(filter.transport(archive)?)?;
                 let options = MountOptions { clean: *cleanup };
                 let projection = match mount(archive, destination, options) {
                     Ok(handle) => handle,
@@ -544,14 +1451,76 @@ impl Command {
                 exclude,
                 exclude_from,
                 only_subtree,
+                relative_excludes,
                 long_listing,
                 no_stats,
+                readable,
+                kind,
+                newer_than,
+                older_than,
+                limit_files,
+                limit_bytes,
+                max_path_len,
+                map_owner,
+                map_group,
+                owner_current_user,
+                no_permissions,
+                default_mode,
+                check_only,
+                quick,
+                metadata_only,
+                no_future_mtimes,
+                update,
+                verify_content,
+                delete,
+                cas_export: cas_export_dir,
+                index_stats,
             } => {
                 let band_selection = band_selection_policy_from_opt(backup);
-                let archive = Archive::open(Transport::new(archive)?)?;
-                let _ = no_stats; // accepted but ignored; we never currently print stats
+                let archive = Archive::open(filter.transport(archive)?)?;
+                let mut exclude = Exclude::from_patterns_and_files(exclude, exclude_from)?;
+                if let (true, Some(subtree)) = (relative_excludes, only_subtree) {
+                    exclude = exclude.relative_to(subtree.clone());
+                }
+                if let Some(cas_export_dir) = cas_export_dir {
+                    let st = archive.open_stored_tree(band_selection)?;
+                    let stats = cas_export(
+                        &st,
+                        cas_export_dir,
+                        only_subtree.clone().unwrap_or_else(Apath::root),
+                        exclude,
+                        monitor.clone(),
+                    )?;
+                    if !no_stats {
+                        info!("Export complete.\n{stats}");
+                    }
+                    index_stats.report(&monitor)?;
+                    return Ok(ExitCode::Success);
+                }
+                let destination = destination
+                    .as_deref()
+                    .expect("destination is required without --cas-export");
+                if !*check_only {
+                    // Any error opening the band is reported by the restore itself.
+                    if let Ok(band_info) = archive
+                        .open_stored_tree(band_selection.clone())
+                        .and_then(|st| st.band().get_info())
+                    {
+                        if let Some(source_path) = &band_info.source_path {
+                            let start_time = band_info
+                                .start_time
+                                .to_offset(*LOCAL_OFFSET.read().unwrap())
+                                .format(&Rfc3339)
+                                .expect("Format start time");
+                            info!(
+                                "Restoring backup {} of {source_path:?} taken at {start_time}",
+                                band_info.id
+                            );
+                        }
+                    }
+                }
                 let options = RestoreOptions {
-                    exclude: Exclude::from_patterns_and_files(exclude, exclude_from)?,
+                    exclude,
                     only_subtree: only_subtree.clone(),
                     band_selection,
                     overwrite: *force_overwrite,
@@ -560,39 +1529,163 @@ impl Command {
                         *long_listing,
                         &changes_json.as_deref(),
                     )?,
+                    readable: *readable,
+                    kinds: (!kind.is_empty()).then(|| kind.clone()),
+                    newer_than: *newer_than,
+                    older_than: *older_than,
+                    limit_files: *limit_files,
+                    limit_bytes: *limit_bytes,
+                    max_path_len: *max_path_len,
+                    owner_map: OwnerMap {
+                        users: map_owner.iter().cloned().collect(),
+                        groups: map_group.iter().cloned().collect(),
+                        current_user: *owner_current_user,
+                    },
+                    default_mode: *default_mode,
+                    skip_permissions: *no_permissions,
+                    check_only: *check_only,
+                    quick: *quick,
+                    metadata_only: *metadata_only,
+                    clamp_mtime_to_now: *no_future_mtimes,
+                    update: *update,
+                    verify_content: *verify_content,
+                    delete: *delete,
                 };
-                restore(&archive, destination, &options, monitor)?;
-                debug!("Restore complete");
+                let stats = restore(&archive, destination, &options, monitor.clone())?;
+                if !no_stats {
+                    if *check_only {
+                        info!("Check complete.\n{stats}");
+                    } else {
+                        info!("Restore complete.\n{stats}");
+                    }
+                }
+                index_stats.report(&monitor)?;
+            }
+            Command::SetExcludes {
+                archive,
+                exclude,
+                exclude_from,
+                clear,
+            } => {
+                let archive = Archive::open(filter.transport(archive)?)?;
+                let patterns = read_exclude_patterns(exclude, exclude_from)?;
+                if patterns.is_empty() && !clear {
+                    for pattern in archive.exclude_patterns()? {
+                        println!("{pattern}");
+                    }
+                } else {
+                    archive.set_exclude_patterns(patterns)?;
+                }
             }
             Command::Size {
                 stos,
                 bytes,
                 exclude,
                 exclude_from,
+                only_subtree,
+                subtotal_interval,
             } => {
+                let subtree = only_subtree.clone().unwrap_or_else(Apath::root);
                 let exclude = Exclude::from_patterns_and_files(exclude, exclude_from)?;
+                let format_size = |size: u64| {
+                    if *bytes {
+                        size.to_string()
+                    } else {
+                        conserve::bytes_to_human_mb(size)
+                    }
+                };
+                let interval = subtotal_interval.map_or(Duration::MAX, Duration::from_secs);
+                let mut print_subtotal = |subtotal: &TreeSize| {
+                    monitor.clear_progress_bars();
+                    println!(
+                        "{} so far, in {} files",
+                        format_size(subtotal.file_bytes),
+                        subtotal.files
+                    );
+                };
                 let size = if let Some(archive) = &stos.archive {
-                    stored_tree_from_opt(archive, &stos.backup)?
-                        .size(exclude, monitor.clone())?
-                        .file_bytes
+                    stored_tree_from_opt(archive, &stos.backup, filter)?.size_with_subtotals(
+                        subtree,
+                        exclude,
+                        monitor.clone(),
+                        interval,
+                        &mut print_subtotal,
+                    )?
                 } else {
-                    LiveTree::open(stos.source.as_ref().unwrap())?
-                        .size(exclude, monitor.clone())?
-                        .file_bytes
+                    LiveTree::open(stos.source.as_ref().unwrap())?.size_with_subtotals(
+                        subtree,
+                        exclude,
+                        monitor.clone(),
+                        interval,
+                        &mut print_subtotal,
+                    )?
                 };
                 monitor.clear_progress_bars();
-                if *bytes {
-                    println!("{size}");
+                println!("{}", format_size(size.file_bytes));
+            }
+            Command::Stats {
+                archive,
+                bytes,
+                json,
+            } => {
+                let archive = Archive::open(filter.transport(archive)?)?;
+                let usage = archive.disk_usage(monitor.clone())?;
+                monitor.clear_progress_bars();
+                if *json || json_format.is_some() {
+                    show::write_json_value(
+                        &usage,
+                        json_format.unwrap_or(JsonFormat::Pretty),
+                        &mut stdout,
+                    )?;
                 } else {
-                    println!("{}", conserve::bytes_to_human_mb(size));
+                    let format_size = |size: u64| {
+                        if *bytes {
+                            size.to_string()
+                        } else {
+                            conserve::bytes_to_human_mb(size)
+                        }
+                    };
+                    writeln!(stdout, "bands: {}", usage.bands)?;
+                    writeln!(stdout, "logical size: {}", format_size(usage.logical_bytes))?;
+                    writeln!(stdout, "blocks: {}", usage.blocks)?;
+                    writeln!(
+                        stdout,
+                        "physical size: {}",
+                        format_size(usage.physical_bytes)
+                    )?;
+                    match usage.factor {
+                        Some(factor) => {
+                            writeln!(stdout, "dedup and compression factor: {factor:.2}")?
+                        }
+                        None => writeln!(stdout, "dedup and compression factor: none")?,
+                    }
                 }
             }
-            Command::Validate { archive, quick, .. } => {
+            Command::Validate {
+                archive,
+                quick,
+                remove_empty_blocks,
+                bands,
+                json,
+                ..
+            } => {
                 let options = ValidateOptions {
                     skip_block_hashes: *quick,
+                    remove_empty_blocks: *remove_empty_blocks,
+                    bands: bands.unwrap_or_default(),
+                    ..Default::default()
                 };
-                Archive::open(Transport::new(archive)?)?.validate(&options, monitor.clone())?;
-                if monitor.error_count() != 0 {
+                let report = Archive::open(filter.transport(archive)?)?
+                    .validate(&options, monitor.clone())?;
+                if *json || json_format.is_some() {
+                    monitor.clear_progress_bars();
+                    show::write_json_value(
+                        &report,
+                        json_format.unwrap_or(JsonFormat::Pretty),
+                        &mut stdout,
+                    )?;
+                }
+                if !report.ok {
                     warn!("Archive has some problems.");
                 } else {
                     info!("Archive is OK.");
@@ -604,19 +1697,27 @@ impl Command {
                 newest,
                 sizes,
                 utc,
+                source,
+                json,
             } => {
                 let timezone = if *utc {
                     None
                 } else {
                     Some(*LOCAL_OFFSET.read().unwrap())
                 };
-                let archive = Archive::open(Transport::new(archive)?)?;
+                let archive = Archive::open(filter.transport(archive)?)?;
                 let options = ShowVersionsOptions {
                     newest_first: *newest,
                     tree_size: *sizes,
                     timezone,
                     start_time: !*short,
                     backup_duration: !*short,
+                    source_path: *source,
+                    json: if *json || json_format.is_some() {
+                        Some(json_format.unwrap_or_default())
+                    } else {
+                        None
+                    },
                 };
                 conserve::show_versions(&archive, &options, monitor)?;
             }
@@ -625,8 +1726,147 @@ impl Command {
     }
 }
 
-fn stored_tree_from_opt(archive_location: &str, backup: &Option<BandId>) -> Result<StoredTree> {
-    let archive = Archive::open(Transport::new(archive_location)?)?;
+/// Parse an `OLD=NEW` pair of user or group names.
+fn parse_name_mapping(s: &str) -> std::result::Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+            Ok((old.to_owned(), new.to_owned()))
+        }
+        _ => Err(format!("expected OLD=NEW, not {s:?}")),
+    }
+}
+
+/// Parse a hunk number, or a half-open range of them like `5..10`.
+fn parse_hunk_range(s: &str) -> std::result::Result<Range<u32>, String> {
+    let parse = |n: &str| {
+        n.parse::<u32>()
+            .map_err(|_| format!("expected a hunk number or range such as 5..10, not {s:?}"))
+    };
+    let range = match s.split_once("..") {
+        Some((start, end)) => parse(start)?..parse(end)?,
+        None => {
+            let hunk = parse(s)?;
+            hunk..hunk.saturating_add(1)
+        }
+    };
+    if range.is_empty() {
+        return Err(format!("hunk range {s:?} is empty"));
+    }
+    Ok(range)
+}
+
+/// Parse a date like `2024-06-01`, meaning midnight UTC, or an RFC 3339 timestamp.
+fn parse_date_time(s: &str) -> std::result::Result<OffsetDateTime, String> {
+    OffsetDateTime::parse(s, &Rfc3339)
+        .or_else(|_| {
+            Date::parse(s, format_description!("[year]-[month]-[day]"))
+                .map(|date| date.midnight().assume_utc())
+        })
+        .map_err(|_| format!("expected a date such as 2024-06-01 or an RFC 3339 time, not {s:?}"))
+}
+
+/// Parse a Unix permission mode given in octal, such as `644`.
+fn parse_octal_mode(s: &str) -> std::result::Result<u32, String> {
+    match u32::from_str_radix(s.trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("expected an octal mode such as 644, not {s:?}")),
+    }
+}
+
+/// Collect exclude patterns from the command line and from files, to store in an archive.
+fn read_exclude_patterns(exclude: &[String], exclude_from: &[String]) -> Result<Vec<String>> {
+    if exclude_from.iter().filter(|path| *path == "-").count() > 1 {
+        return Err(Error::ExcludeFromStdinRepeated);
+    }
+    let mut patterns = exclude.to_vec();
+    for path in exclude_from {
+        let content = if path == "-" {
+            io::read_to_string(io::stdin())?
+        } else {
+            std::fs::read_to_string(path)?
+        };
+        patterns.extend(content.lines().map(str::to_owned));
+    }
+    Ok(patterns)
+}
+
+/// Set by the first Ctrl-C during a backup, to ask it to stop cleanly.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Make the first Ctrl-C set [STOP_REQUESTED] rather than killing the process; a
+/// second one exits immediately.
+#[cfg(unix)]
+fn stop_cleanly_on_interrupt() {
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+    extern "C" fn handle_interrupt(_signal: nix::libc::c_int) {
+        if STOP_REQUESTED.swap(true, Ordering::SeqCst) {
+            // Safety: _exit is async-signal-safe, unlike exit.
+            unsafe { nix::libc::_exit(130) };
+        }
+    }
+
+    let action = SigAction::new(
+        SigHandler::Handler(handle_interrupt),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // Safety: the handler only uses an atomic and _exit, which are safe in a signal handler.
+    if let Err(err) = unsafe { sigaction(Signal::SIGINT, &action) } {
+        warn!(?err, "Failed to install interrupt handler");
+    }
+}
+
+#[cfg(not(unix))]
+fn stop_cleanly_on_interrupt() {}
+
+/// Read a list of paths from stdin, separated by NULs if there are any, or otherwise
+/// by newlines.
+///
+/// On Unix, paths needn't be valid UTF-8.
+fn read_stdin_paths() -> Result<Vec<PathBuf>> {
+    let mut content = Vec::new();
+    io::stdin().read_to_end(&mut content)?;
+    let separator = if content.contains(&0) { 0 } else { b'\n' };
+    content
+        .split(|&b| b == separator)
+        .filter(|path| !path.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(std::ffi::OsStr::from_bytes(bytes).into())
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf> {
+    String::from_utf8(bytes.to_owned())
+        .map(PathBuf::from)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err).into())
+}
+
+/// List the paths of all the entries in a backup, relative to the source directory.
+fn read_backup_paths(
+    archive: &Archive,
+    band_id: BandId,
+    monitor: Arc<TermUiMonitor>,
+) -> Result<Vec<PathBuf>> {
+    Ok(archive
+        .open_stored_tree(BandSelectionPolicy::Specified(band_id))?
+        .iter_entries(Apath::root(), Exclude::nothing(), monitor)?
+        .map(|entry| PathBuf::from(entry.apath.trim_start_matches('/')))
+        .collect())
+}
+
+fn stored_tree_from_opt(
+    archive_location: &str,
+    backup: &Option<BandId>,
+    filter: &FilterArgs,
+) -> Result<StoredTree> {
+    let archive = Archive::open(filter.transport(archive_location)?)?;
     let policy = band_selection_policy_from_opt(backup);
     archive.open_stored_tree(policy)
 }
@@ -692,16 +1932,58 @@ fn main() -> Result<ExitCode> {
     // problems with loading it when threads are running.
     *LOCAL_OFFSET.write().unwrap() =
         UtcOffset::current_local_offset().expect("get local time offset");
-    let args = Args::parse();
+    // Help and usage errors are printed while parsing, so the color choice is needed
+    // before the arguments are parsed.
+    let args = Args::from_arg_matches(
+        &Args::command()
+            .color(color_choice_from_args(std::env::args_os()))
+            .get_matches(),
+    )
+    .unwrap_or_else(|err| err.exit());
+    if let Command::Backup {
+        stdin_paths: true,
+        exclude_from,
+        ..
+    } = &args.command
+    {
+        if exclude_from.iter().any(|path| path == "-") {
+            Args::command()
+                .color(color_choice_from_args(std::env::args_os()))
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "the argument '--stdin-paths' cannot be used with '--exclude-from -': \
+                    both read from stdin",
+                )
+                .exit();
+        }
+    }
+    set_color_choice(args.color);
     let start_time = Instant::now();
     let console_level = if args.debug {
         Level::TRACE
     } else {
         Level::INFO
     };
-    let monitor = Arc::new(TermUiMonitor::new(!args.no_progress));
+    let progress_interval = Duration::from_millis(args.progress_interval);
+    let mut monitor = if args.progress_json {
+        TermUiMonitor::new_with_json_progress(progress_interval)
+    } else {
+        TermUiMonitor::new_with_interval(!args.no_progress, progress_interval)
+    };
+    if let Some(events_path) = &args.events_socket {
+        monitor = monitor.with_events(EventMonitor::open(events_path)?);
+    }
+    let monitor = Arc::new(monitor);
     let _flush_tracing = enable_tracing(&monitor, &args.trace_time, console_level, &args.log_json);
-    let result = args.command.run(monitor.clone());
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.get())
+            .build_global()
+            .expect("Configure global thread pool");
+    }
+    let result = args
+        .command
+        .run(monitor.clone(), args.json_format, &args.filter);
     debug!(elapsed = ?start_time.elapsed());
     if let Some(metrics_path) = args.metrics_json {
         serde_json::to_writer_pretty(
@@ -714,6 +1996,11 @@ fn main() -> Result<ExitCode> {
         )?;
     }
     match result {
+        Err(err) if args.error_json => {
+            let json = serde_json::json!({"code": err.code(), "message": format!("{err:#}")});
+            eprintln!("{json}");
+            Ok(ExitCode::Failure)
+        }
         Err(err) => {
             error!("{err:#}");
             Ok(ExitCode::Failure)
@@ -723,6 +2010,47 @@ fn main() -> Result<ExitCode> {
     }
 }
 
+/// Find the value of `--color` in the command line, before it's fully parsed.
+///
+/// If it's not given or not valid, colors are chosen automatically, and any error is
+/// reported when the arguments are parsed.
+fn color_choice_from_args<I: IntoIterator<Item = OsString>>(args: I) -> ColorChoice {
+    let mut args = args.into_iter().skip(1);
+    let mut choice = None;
+    while let Some(arg) = args.next() {
+        let Some(arg) = arg.to_str() else {
+            continue;
+        };
+        let value = match arg {
+            "--" => break,
+            "--color" => args.next().and_then(|v| v.into_string().ok()),
+            _ => arg.strip_prefix("--color=").map(str::to_owned),
+        };
+        if let Some(value) = value {
+            choice = ColorChoice::from_str(&value, true).ok();
+        }
+    }
+    choice.unwrap_or(ColorChoice::Auto)
+}
+
+#[test]
+fn color_choice_is_found_before_parsing() {
+    let choice = |args: &[&str]| color_choice_from_args(args.iter().map(OsString::from));
+    assert_eq!(choice(&["conserve", "--help"]), ColorChoice::Auto);
+    assert_eq!(
+        choice(&["conserve", "--color", "never", "ls"]),
+        ColorChoice::Never
+    );
+    assert_eq!(
+        choice(&["conserve", "ls", "--color=always"]),
+        ColorChoice::Always
+    );
+    assert_eq!(
+        choice(&["conserve", "ls", "--", "--color=always"]),
+        ColorChoice::Auto
+    );
+}
+
 #[test]
 fn verify_clap() {
     use clap::CommandFactory;
@@ -0,0 +1,75 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Train a zstd dictionary for compressing blocks, from a sample of the files that
+//! will be backed up.
+//!
+//! A shared dictionary helps most when a tree has many small, similar files, such
+//! as source code or logs, each of which is too small to compress well on its own.
+
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+use tracing::{debug, warn};
+
+use crate::compress::zstd::train_dictionary;
+use crate::monitor::Monitor;
+use crate::*;
+
+/// Make dictionaries of up to this many bytes, which is the zstd default.
+const MAX_DICTIONARY_SIZE: usize = 110 << 10;
+
+/// Take up to this many bytes from the start of each file as a sample.
+const MAX_SAMPLE_LEN: u64 = 64 << 10;
+
+/// Stop sampling after this many bytes in total.
+const MAX_TOTAL_SAMPLES_LEN: usize = 100 << 20;
+
+/// Train a dictionary for [Archive::create_with_block_dictionary] from the start of
+/// each file in `source`, or as many as fit in the sample limit.
+///
+/// Files that can't be read are skipped with a warning. Training fails if there
+/// are too few files to learn from.
+pub fn train_block_dictionary(
+    source: &Path,
+    exclude: Exclude,
+    monitor: Arc<dyn Monitor>,
+) -> Result<Vec<u8>> {
+    let tree = LiveTree::open(source)?;
+    let task = monitor.start_task("Sample files".to_string());
+    let mut samples = Vec::new();
+    let mut total_len = 0;
+    for entry in tree.iter_entries(Apath::root(), exclude, monitor.clone())? {
+        if entry.kind() != Kind::File || entry.size() == Some(0) {
+            continue;
+        }
+        let mut sample = Vec::new();
+        if let Err(err) = tree
+            .open_file(&entry)
+            .and_then(|file| Ok(file.take(MAX_SAMPLE_LEN).read_to_end(&mut sample)?))
+        {
+            warn!(apath = %entry.apath(), ?err, "Failed to read sample for dictionary");
+            continue;
+        }
+        total_len += sample.len();
+        samples.push(sample);
+        task.increment(1);
+        if total_len >= MAX_TOTAL_SAMPLES_LEN {
+            break;
+        }
+    }
+    drop(task);
+    debug!(samples = samples.len(), total_len, "Train block dictionary");
+    train_dictionary(&samples, MAX_DICTIONARY_SIZE)
+}
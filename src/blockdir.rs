@@ -20,8 +20,13 @@
 //! and which range of uncompressed bytes.
 //!
 //! The structure is: archive > blockdir > subdir > file.
+//!
+//! Blocks are kept in a [BlockStore], which by default is a directory accessed through
+//! a [Transport], but can be replaced by [Archive::with_block_store].
 
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::num::NonZeroUsize;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::{Arc, RwLock};
@@ -35,9 +40,10 @@ use tracing::{instrument, trace};
 use transport::WriteMode;
 
 use crate::compress::snappy::{Compressor, Decompressor};
+use crate::compress::zstd::{self, Dictionary};
 use crate::counters::Counter;
 use crate::monitor::Monitor;
-use crate::transport::{ListDir, Transport};
+use crate::transport::{ListDir, Transport, TEMP_FILE_PREFIX};
 use crate::*;
 
 // const BLOCKDIR_FILE_NAME_LEN: usize = crate::BLAKE_HASH_SIZE_BYTES * 2;
@@ -45,6 +51,11 @@ use crate::*;
 /// Take this many characters from the block hash to form the subdirectory name.
 const SUBDIR_NAME_CHARS: usize = 3;
 
+/// By default, cache the content of this many blocks in memory.
+// TODO: Change to a cache that tracks the size of stored blocks?
+// As a safe conservative value, 100 blocks of 20MB each would be 2GB.
+pub const DEFAULT_BLOCK_CACHE_SIZE: usize = 100;
+
 /// Points to some compressed data inside the block dir.
 ///
 /// Identifiers are: which file contains it, at what (pre-compression) offset,
@@ -63,15 +74,225 @@ pub struct Address {
     pub len: u64,
 }
 
+/// Storage for compressed blocks, identified by the hash of their uncompressed content.
+///
+/// [BlockDir] compresses, caches, and checks the content of blocks, and keeps them in
+/// a store. The default, [TransportBlockStore], keeps each block in a file within
+/// the archive. Other implementations can keep blocks elsewhere, such as in a
+/// database or another content-addressed store, while the rest of the archive stays
+/// on its transport: see [Archive::with_block_store].
+///
+/// Errors should have an [ErrorKind](transport::ErrorKind) of
+/// [NotFound](transport::ErrorKind::NotFound) when a block isn't present.
+pub trait BlockStore: Send + Sync {
+    /// Read the compressed content of a block.
+    fn get(&self, hash: &BlockHash) -> transport::Result<Bytes>;
+
+    /// Read the compressed content of a block, passing it to `check`, which returns
+    /// true if the content is valid.
+    ///
+    /// A store holding more than one copy of blocks can fall back to another copy if
+    /// `check` returns false. By default, this just reads the block once.
+    fn get_verified(
+        &self,
+        hash: &BlockHash,
+        check: &mut dyn FnMut(&Bytes) -> bool,
+    ) -> transport::Result<Bytes> {
+        let content = self.get(hash)?;
+        check(&content);
+        Ok(content)
+    }
+
+    /// Store the compressed content of a block.
+    ///
+    /// With [WriteMode::CreateNew], the store may return an error of kind
+    /// [AlreadyExists](transport::ErrorKind::AlreadyExists) if the block is already
+    /// present; with [WriteMode::Overwrite] the stored block is replaced.
+    fn put(&self, hash: &BlockHash, compressed: &[u8], mode: WriteMode) -> transport::Result<()>;
+
+    /// Return the length of the compressed content of a stored block.
+    fn compressed_size(&self, hash: &BlockHash) -> transport::Result<u64>;
+
+    /// True if a block is stored, with some content.
+    ///
+    /// A block of length zero, as might be left by an interrupted write, isn't
+    /// valid compressed data, so it's treated as absent and stored again.
+    fn contains(&self, hash: &BlockHash) -> transport::Result<bool> {
+        match self.compressed_size(hash) {
+            Ok(len) => Ok(len > 0),
+            Err(err) if err.is_not_found() => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// List the stored blocks whose hex hash starts with `prefix`, ignoring case, in
+    /// arbitrary order.
+    ///
+    /// The empty prefix lists all blocks. Failures to list some part of the store may
+    /// be reported to the monitor and skipped.
+    fn list(&self, prefix: &str, monitor: Arc<dyn Monitor>) -> transport::Result<Vec<BlockHash>>;
+
+    /// Delete a stored block.
+    fn delete(&self, hash: &BlockHash) -> transport::Result<()>;
+
+    /// Find anything in the store that isn't a block.
+    ///
+    /// Returns the names of temporary files left behind by interrupted writes, and
+    /// separately of anything else unexpected, both sorted. By default nothing is
+    /// found.
+    fn stray_files(&self) -> transport::Result<(Vec<String>, Vec<String>)> {
+        Ok((Vec::new(), Vec::new()))
+    }
+}
+
+/// The default [BlockStore], keeping each block in a file named by its hash, within
+/// a subdirectory named by the first few characters of the hash.
+#[derive(Clone, Debug)]
+pub struct TransportBlockStore {
+    transport: Transport,
+}
+
+impl TransportBlockStore {
+    pub fn new(transport: Transport) -> TransportBlockStore {
+        TransportBlockStore { transport }
+    }
+
+    /// Return the block subdirectories, in arbitrary order.
+    ///
+    /// Unexpected names are logged and skipped.
+    fn subdirs(&self) -> transport::Result<Vec<String>> {
+        let ListDir { mut dirs, .. } = self.transport.list_dir("")?;
+        dirs.retain(|dirname| {
+            if dirname.len() == SUBDIR_NAME_CHARS {
+                true
+            } else {
+                warn!("Unexpected subdirectory in blockdir: {dirname:?}");
+                false
+            }
+        });
+        Ok(dirs)
+    }
+}
+
+impl BlockStore for TransportBlockStore {
+    fn get(&self, hash: &BlockHash) -> transport::Result<Bytes> {
+        self.transport.read_file(&block_relpath(hash))
+    }
+
+    fn get_verified(
+        &self,
+        hash: &BlockHash,
+        check: &mut dyn FnMut(&Bytes) -> bool,
+    ) -> transport::Result<Bytes> {
+        self.transport
+            .read_file_verified(&block_relpath(hash), check)
+    }
+
+    fn put(&self, hash: &BlockHash, compressed: &[u8], mode: WriteMode) -> transport::Result<()> {
+        self.transport
+            .create_dir(subdir_relpath(&hash.to_string()))?;
+        self.transport
+            .write_file(&block_relpath(hash), compressed, mode)
+    }
+
+    fn compressed_size(&self, hash: &BlockHash) -> transport::Result<u64> {
+        Ok(self.transport.metadata(&block_relpath(hash))?.len)
+    }
+
+    fn contains(&self, hash: &BlockHash) -> transport::Result<bool> {
+        match self.transport.metadata(&block_relpath(hash)) {
+            Ok(metadata) => Ok(metadata.kind == Kind::File && metadata.len > 0),
+            Err(err) if err.is_not_found() => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn list(&self, prefix: &str, monitor: Arc<dyn Monitor>) -> transport::Result<Vec<BlockHash>> {
+        let prefix = prefix.to_ascii_lowercase();
+        let single_subdir = prefix.len() >= SUBDIR_NAME_CHARS;
+        let subdirs = if single_subdir {
+            vec![subdir_relpath(&prefix).to_owned()]
+        } else {
+            let mut subdirs = self.subdirs()?;
+            subdirs.retain(|name| name.starts_with(&prefix));
+            subdirs
+        };
+        let task = monitor.start_task("List block subdir".to_string());
+        task.set_total(subdirs.len());
+        let lists = subdirs
+            .into_par_iter()
+            .map(|subdir_name| {
+                let r = self.transport.list_dir(&subdir_name);
+                task.increment(1);
+                monitor.count(Counter::BlockSubdirsListed, 1);
+                r
+            })
+            .collect::<Vec<_>>();
+        let mut hashes = Vec::new();
+        for list in lists {
+            match list {
+                Ok(ListDir { files, .. }) => hashes.extend(
+                    files
+                        .into_iter()
+                        // drop any invalid names, including temp files
+                        .filter_map(|name| name.parse::<BlockHash>().ok())
+                        .filter(|hash| hash.starts_with(&prefix)),
+                ),
+                Err(err) if single_subdir && err.is_not_found() => {}
+                // If only one subdirectory could hold the blocks, failing to list it
+                // is an error rather than just missing some blocks.
+                Err(err) if single_subdir => return Err(err),
+                Err(source) => monitor.error(Error::ListBlocks { source }),
+            }
+        }
+        Ok(hashes)
+    }
+
+    fn delete(&self, hash: &BlockHash) -> transport::Result<()> {
+        self.transport.remove_file(&block_relpath(hash))
+    }
+
+    fn stray_files(&self) -> transport::Result<(Vec<String>, Vec<String>)> {
+        let mut temp_files = Vec::new();
+        let mut unexpected = Vec::new();
+        let ListDir { files, dirs } = self.transport.list_dir("")?;
+        unexpected.extend(files);
+        for dirname in dirs {
+            if dirname.len() != SUBDIR_NAME_CHARS {
+                unexpected.push(dirname);
+                continue;
+            }
+            let ListDir { files, dirs } = self.transport.list_dir(&dirname)?;
+            for name in files {
+                if name.starts_with(TEMP_FILE_PREFIX) {
+                    temp_files.push(format!("{dirname}/{name}"));
+                } else if name.parse::<BlockHash>().is_err() || !name.starts_with(&dirname) {
+                    unexpected.push(format!("{dirname}/{name}"));
+                }
+            }
+            unexpected.extend(dirs.into_iter().map(|name| format!("{dirname}/{name}")));
+        }
+        temp_files.sort();
+        unexpected.sort();
+        Ok((temp_files, unexpected))
+    }
+}
+
 /// A readable, writable directory within a band holding data blocks.
-#[derive(Debug)]
 pub struct BlockDir {
-    transport: Transport,
+    store: Arc<dyn BlockStore>,
     pub stats: BlockDirStats,
     // TODO: There are fancier caches and they might help, but this one works, and Stretto did not work for me.
-    cache: RwLock<LruCache<BlockHash, Bytes>>,
+    /// Recently read or written block content, or None if caching is disabled.
+    cache: Option<RwLock<LruCache<BlockHash, Bytes>>>,
     /// Presence means that we know that this block exists, even if we don't have its content.
     exists: RwLock<LruCache<BlockHash, ()>>,
+    /// If set, a pool that limits how many listings of the store run at once, or by
+    /// default one per thread in the global rayon pool.
+    list_pool: Option<Arc<rayon::ThreadPool>>,
+    /// If set, new blocks are compressed with zstd using this dictionary, rather than
+    /// with Snappy.
+    dictionary: Option<Arc<Dictionary>>,
 }
 
 /// Returns the transport-relative subdirectory name.
@@ -79,6 +300,26 @@ fn subdir_relpath(block_hash: &str) -> &str {
     &block_hash[..SUBDIR_NAME_CHARS]
 }
 
+/// The result of re-reading one block, from [BlockDir::verify_block].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockVerification {
+    /// The hash by which the block is stored, which its content should have.
+    pub hash: BlockHash,
+    /// Size of the block file in the archive.
+    pub compressed_len: u64,
+    /// Size of the decompressed content, or None if it can't be decompressed.
+    pub uncompressed_len: Option<usize>,
+    /// Hash of the decompressed content, or None if it can't be decompressed.
+    pub actual_hash: Option<BlockHash>,
+}
+
+impl BlockVerification {
+    /// True if the block's content has the expected hash.
+    pub fn is_ok(&self) -> bool {
+        self.actual_hash.as_ref() == Some(&self.hash)
+    }
+}
+
 /// Return the transport-relative file for a given hash.
 pub fn block_relpath(hash: &BlockHash) -> String {
     let hash_hex = hash.to_string();
@@ -86,56 +327,178 @@ pub fn block_relpath(hash: &BlockHash) -> String {
 }
 
 impl BlockDir {
+    /// Open a block directory.
+    ///
+    /// This doesn't read anything: blocks are looked up as they're needed, and
+    /// remembered in a bounded cache.
     pub fn open(transport: Transport) -> BlockDir {
-        /// Cache this many blocks in memory.
-        // TODO: Change to a cache that tracks the size of stored blocks?
-        // As a safe conservative value, 100 blocks of 20MB each would be 2GB.
-        const BLOCK_CACHE_SIZE: usize = 100;
+        BlockDir::with_store(Arc::new(TransportBlockStore::new(transport)))
+    }
 
+    /// Open a block directory keeping its blocks in a given store.
+    pub fn with_store(store: Arc<dyn BlockStore>) -> BlockDir {
         /// Remember the existence of this many blocks, even if we don't have their content.
         const EXISTENCE_CACHE_SIZE: usize = (64 << 20) / BLAKE_HASH_SIZE_BYTES;
 
         BlockDir {
-            transport,
+            store,
             stats: BlockDirStats::default(),
-            cache: RwLock::new(LruCache::new(BLOCK_CACHE_SIZE.try_into().unwrap())),
+            cache: None,
             exists: RwLock::new(LruCache::new(EXISTENCE_CACHE_SIZE.try_into().unwrap())),
+            list_pool: None,
+            dictionary: None,
+        }
+        .with_block_cache(DEFAULT_BLOCK_CACHE_SIZE)
+    }
+
+    /// Keep the content of up to `capacity` recently used blocks in memory.
+    ///
+    /// If `capacity` is zero, the cache is disabled, and every read of a block
+    /// reads it from the store. This saves memory when blocks are unlikely to be
+    /// read more than once, such as when validating a large archive.
+    pub fn with_block_cache(self, capacity: usize) -> BlockDir {
+        BlockDir {
+            cache: NonZeroUsize::new(capacity).map(|capacity| RwLock::new(LruCache::new(capacity))),
+            ..self
+        }
+    }
+
+    /// The number of blocks whose content may be cached.
+    pub(crate) fn block_cache_capacity(&self) -> usize {
+        self.cache
+            .as_ref()
+            .map_or(0, |cache| cache.read().expect("Lock cache").cap().get())
+    }
+
+    /// When listing blocks, list up to `tasks` subdirectories of the store at once,
+    /// rather than one per rayon thread.
+    ///
+    /// This avoids overwhelming transports that handle many concurrent requests
+    /// badly. If `tasks` is zero, the default is used.
+    pub fn with_list_concurrency(self, tasks: usize) -> BlockDir {
+        let list_pool = NonZeroUsize::new(tasks).map(|tasks| {
+            Arc::new(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(tasks.get())
+                    .build()
+                    .expect("Failed to build thread pool"),
+            )
+        });
+        BlockDir { list_pool, ..self }
+    }
+
+    /// Use the same listing concurrency and dictionary as `other`, for a block dir
+    /// rebuilt with a different store or cache.
+    pub(crate) fn with_settings_from(self, other: &BlockDir) -> BlockDir {
+        BlockDir {
+            list_pool: other.list_pool.clone(),
+            dictionary: other.dictionary.clone(),
+            ..self
+        }
+    }
+
+    /// List blocks in the store, within the concurrency limit if there is one.
+    fn list_store(
+        &self,
+        prefix: &str,
+        monitor: Arc<dyn Monitor>,
+    ) -> transport::Result<Vec<BlockHash>> {
+        match &self.list_pool {
+            None => self.store.list(prefix, monitor),
+            Some(pool) => pool.install(|| self.store.list(prefix, monitor)),
+        }
+    }
+
+    /// Return the cached content of a block, if any.
+    fn cache_get(&self, hash: &BlockHash) -> Option<Bytes> {
+        self.cache
+            .as_ref()?
+            .write()
+            .expect("Lock cache")
+            .get(hash)
+            .cloned()
+    }
+
+    fn cache_put(&self, hash: &BlockHash, content: Bytes) {
+        if let Some(cache) = &self.cache {
+            cache
+                .write()
+                .expect("Lock cache")
+                .put(hash.clone(), content);
         }
     }
 
+    /// The store holding this directory's blocks.
+    pub(crate) fn store(&self) -> Arc<dyn BlockStore> {
+        self.store.clone()
+    }
+
     pub fn create(transport: Transport) -> Result<BlockDir> {
         transport.create_dir("")?;
         Ok(BlockDir::open(transport))
     }
 
+    /// Compress new blocks with zstd using this dictionary, and use it to read blocks
+    /// that were compressed with it.
+    pub(crate) fn with_dictionary(self, dictionary: Option<Arc<Dictionary>>) -> BlockDir {
+        BlockDir { dictionary, ..self }
+    }
+
+    /// The dictionary set by [BlockDir::with_dictionary], if any.
+    pub(crate) fn dictionary(&self) -> Option<Arc<Dictionary>> {
+        self.dictionary.clone()
+    }
+
+    /// Compress the content of a new block.
+    fn compress(&self, content: &[u8]) -> Result<Bytes> {
+        match &self.dictionary {
+            Some(dictionary) => dictionary.compress(content),
+            None => Compressor::new().compress(content),
+        }
+    }
+
+    /// Decompress a stored block, which may be compressed with Snappy or zstd.
+    fn decompress(&self, compressed: &[u8]) -> Result<Bytes> {
+        if zstd::is_zstd(compressed) {
+            zstd::decompress_with_dictionary(compressed, self.dictionary.as_deref())
+        } else {
+            Decompressor::new().decompress(compressed)
+        }
+    }
+
     /// Store block data, if it's not already present, and return the hash.
     ///
     /// The block data must be less than the maximum block size.
+    ///
+    /// If `verify_dedup` is true and a block with the same hash is already stored, its
+    /// content is read back and compared. A corrupt existing block is reported and
+    /// replaced; a valid block with different content is a hash collision, and an error.
     pub(crate) fn store_or_deduplicate(
         &self,
         block_data: Bytes,
+        verify_dedup: bool,
         stats: &mut BackupStats,
         monitor: Arc<dyn Monitor>,
     ) -> Result<BlockHash> {
         let hash = BlockHash::hash_bytes(&block_data);
         let uncomp_len = block_data.len() as u64;
+        let mut write_mode = WriteMode::CreateNew;
         if self.contains(&hash, monitor.clone())? {
-            stats.deduplicated_blocks += 1;
-            stats.deduplicated_bytes += uncomp_len;
-            monitor.count(Counter::DeduplicatedBlocks, 1);
-            monitor.count(Counter::DeduplicatedBlockBytes, block_data.len());
-            return Ok(hash);
+            if !verify_dedup || self.stored_block_matches(&hash, &block_data)? {
+                stats.deduplicated_blocks += 1;
+                stats.deduplicated_bytes += uncomp_len;
+                monitor.count(Counter::DeduplicatedBlocks, 1);
+                monitor.count(Counter::DeduplicatedBlockBytes, block_data.len());
+                return Ok(hash);
+            }
+            monitor.error(Error::DeduplicatedBlockCorrupt { hash: hash.clone() });
+            stats.replaced_corrupt_blocks += 1;
+            write_mode = WriteMode::Overwrite;
         }
-        let compressed = Compressor::new().compress(&block_data)?;
+        let compressed = self.compress(&block_data)?;
         monitor.count(Counter::BlockWriteUncompressedBytes, block_data.len());
         let comp_len: u64 = compressed.len().try_into().unwrap();
-        let hex_hash = hash.to_string();
-        let relpath = block_relpath(&hash);
-        self.transport.create_dir(subdir_relpath(&hex_hash))?;
-        match self
-            .transport
-            .write_file(&relpath, &compressed, WriteMode::CreateNew)
-        {
+        match self.store.put(&hash, &compressed, write_mode) {
             Ok(()) => {}
             Err(err) if err.kind() == transport::ErrorKind::AlreadyExists => {
                 // let's assume the contents are correct
@@ -151,14 +514,39 @@ impl BlockDir {
         monitor.count(Counter::BlockWrites, 1);
         monitor.count(Counter::BlockWriteCompressedBytes, compressed.len());
         // Only update caches after everything succeeded
-        self.cache
-            .write()
-            .expect("Lock cache")
-            .put(hash.clone(), block_data);
+        self.cache_put(&hash, block_data);
         self.exists.write().unwrap().push(hash.clone(), ());
         Ok(hash)
     }
 
+    /// Check whether the stored block with this hash has exactly the given content.
+    ///
+    /// Returns false if the stored block is corrupt, and an error if it's a valid
+    /// block with different content.
+    fn stored_block_matches(&self, hash: &BlockHash, content: &[u8]) -> Result<bool> {
+        if let Some(cached) = self.cache_get(hash) {
+            // Cached content was either just written or was checked against its hash when read.
+            if cached.as_ref() == content {
+                return Ok(true);
+            }
+            return Err(Error::BlockHashCollision { hash: hash.clone() });
+        }
+        let stored = match self.read_block_uncached(hash) {
+            Ok((_, Ok(stored))) => stored,
+            Ok((_, Err(err))) | Err(err) => {
+                warn!(?err, %hash, "Failed to read existing block to verify deduplication");
+                return Ok(false);
+            }
+        };
+        if stored.as_ref() == content {
+            Ok(true)
+        } else if BlockHash::hash_bytes(&stored) == *hash {
+            Err(Error::BlockHashCollision { hash: hash.clone() })
+        } else {
+            Ok(false)
+        }
+    }
+
     /// True if the named block is present and apparently in this blockdir.
     ///
     /// Empty block files should never normally occur, because the index doesn't
@@ -168,7 +556,10 @@ impl BlockDir {
     /// So, these are specifically treated as missing, so there's a chance to heal
     /// them later.
     pub fn contains(&self, hash: &BlockHash, monitor: Arc<dyn Monitor>) -> Result<bool> {
-        if self.cache.read().expect("Lock cache").contains(hash)
+        if self
+            .cache
+            .as_ref()
+            .is_some_and(|cache| cache.read().expect("Lock cache").contains(hash))
             || self.exists.read().unwrap().contains(hash)
         {
             monitor.count(Counter::BlockExistenceCacheHit, 1);
@@ -176,23 +567,31 @@ impl BlockDir {
             return Ok(true);
         }
         monitor.count(Counter::BlockExistenceCacheMiss, 1);
-        match self.transport.metadata(&block_relpath(hash)) {
-            Err(err) if err.is_not_found() => Ok(false),
+        match self.store.contains(hash) {
             Err(err) => {
                 warn!(?err, ?hash, "Error checking presence of block");
                 Err(err.into())
             }
-            Ok(metadata) if metadata.kind == Kind::File && metadata.len > 0 => {
+            Ok(true) => {
                 self.exists.write().unwrap().put(hash.clone(), ());
                 Ok(true)
             }
-            Ok(_) => Ok(false),
+            Ok(false) => Ok(false),
         }
     }
 
     /// Returns the compressed on-disk size of a block.
     pub fn compressed_size(&self, hash: &BlockHash) -> Result<u64> {
-        Ok(self.transport.metadata(&block_relpath(hash))?.len)
+        Ok(self.store.compressed_size(hash)?)
+    }
+
+    /// Return the number of blocks, and the total size of their compressed files.
+    pub fn compressed_size_total(&self, monitor: Arc<dyn Monitor>) -> Result<(usize, u64)> {
+        let sizes = self
+            .blocks(monitor)?
+            .map(|hash| self.compressed_size(&hash))
+            .collect::<Result<Vec<u64>>>()?;
+        Ok((sizes.len(), sizes.iter().sum()))
     }
 
     /// Read back some content addressed by an [Address] (a block hash, start and end).
@@ -217,25 +616,27 @@ impl BlockDir {
     /// Checks that the hash is correct with the contents.
     #[instrument(skip(self, monitor))]
     pub fn get_block_content(&self, hash: &BlockHash, monitor: Arc<dyn Monitor>) -> Result<Bytes> {
-        if let Some(hit) = self.cache.write().expect("Lock cache").get(hash) {
+        if let Some(hit) = self.cache_get(hash) {
             monitor.count(Counter::BlockContentCacheHit, 1);
             self.stats.cache_hit.fetch_add(1, Relaxed);
             trace!("Block cache hit");
-            return Ok(hit.clone());
+            return Ok(hit);
         }
         monitor.count(Counter::BlockContentCacheMiss, 1);
-        let mut decompressor = Decompressor::new();
-        let block_relpath = block_relpath(hash);
-        let compressed_bytes = self.transport.read_file(&block_relpath)?;
-        let decompressed_bytes = decompressor.decompress(&compressed_bytes)?;
-        let actual_hash = BlockHash::hash_bytes(&decompressed_bytes);
-        if actual_hash != *hash {
+        // Check the content as it's read, so that a store holding another copy can
+        // fall back to it.
+        let mut decompressed = None;
+        let compressed_bytes = self.store.get_verified(hash, &mut |compressed_bytes| {
+            decompressed = self
+                .decompress(compressed_bytes)
+                .ok()
+                .filter(|content| BlockHash::hash_bytes(content) == *hash);
+            decompressed.is_some()
+        })?;
+        let Some(decompressed_bytes) = decompressed else {
             return Err(Error::BlockCorrupt { hash: hash.clone() });
-        }
-        self.cache
-            .write()
-            .expect("Lock cache")
-            .put(hash.clone(), decompressed_bytes.clone());
+        };
+        self.cache_put(hash, decompressed_bytes.clone());
         self.exists.write().unwrap().put(hash.clone(), ());
         self.stats.read_blocks.fetch_add(1, Relaxed);
         monitor.count(Counter::BlockReads, 1);
@@ -253,28 +654,120 @@ impl BlockDir {
         Ok(decompressed_bytes)
     }
 
+    /// Read a block directly from storage, bypassing the cache, returning the compressed
+    /// bytes and the result of decompressing them.
+    ///
+    /// Unlike [BlockDir::get_block_content], this doesn't check the content matches the hash.
+    pub(crate) fn read_block_uncached(&self, hash: &BlockHash) -> Result<(Bytes, Result<Bytes>)> {
+        let compressed_bytes = self.store.get(hash).map_err(|err| {
+            if err.is_not_found() {
+                Error::BlockMissing { hash: hash.clone() }
+            } else {
+                Error::from(err)
+            }
+        })?;
+        let decompressed = self.decompress(&compressed_bytes);
+        Ok((compressed_bytes, decompressed))
+    }
+
+    /// Read one block from storage, bypassing the cache, and check its content has the
+    /// expected hash.
+    ///
+    /// A block that can't be read at all is an error. A block that can't be
+    /// decompressed is reported to the monitor, and has no actual hash.
+    pub fn verify_block(
+        &self,
+        hash: &BlockHash,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<BlockVerification> {
+        let (compressed, decompressed) = self.read_block_uncached(hash)?;
+        let (uncompressed_len, actual_hash) = match decompressed {
+            Ok(content) => (Some(content.len()), Some(BlockHash::hash_bytes(&content))),
+            Err(err) => {
+                monitor.error(err);
+                (None, None)
+            }
+        };
+        Ok(BlockVerification {
+            hash: hash.clone(),
+            compressed_len: compressed.len() as u64,
+            uncompressed_len,
+            actual_hash,
+        })
+    }
+
     pub fn delete_block(&self, hash: &BlockHash) -> Result<()> {
-        self.cache.write().expect("Lock cache").pop(hash);
+        if let Some(cache) = &self.cache {
+            cache.write().expect("Lock cache").pop(hash);
+        }
         self.exists.write().unwrap().pop(hash);
-        self.transport
-            .remove_file(&block_relpath(hash))
-            .map_err(Error::from)
+        self.store.delete(hash).map_err(Error::from)
     }
 
-    /// Return an iterator of block subdirectories, in arbitrary order.
+    /// Delete block files of length zero, returning their hashes, sorted.
     ///
-    /// Errors, other than failure to open the directory at all, are logged and discarded.
-    fn subdirs(&self) -> Result<Vec<String>> {
-        let ListDir { mut dirs, .. } = self.transport.list_dir("")?;
-        dirs.retain(|dirname| {
-            if dirname.len() == SUBDIR_NAME_CHARS {
-                true
-            } else {
-                warn!("Unexpected subdirectory in blockdir: {dirname:?}");
-                false
-            }
-        });
-        Ok(dirs)
+    /// These can be left by an interrupted write and are never valid, so they're
+    /// treated as missing and their content is stored again by a later backup.
+    /// Block files with any content, even if it's corrupt, are left alone.
+    pub fn remove_empty_blocks(&self, monitor: Arc<dyn Monitor>) -> Result<Vec<BlockHash>> {
+        let mut empty: Vec<BlockHash> = self
+            .blocks(monitor.clone())?
+            .filter(|hash| match self.store.compressed_size(hash) {
+                Ok(len) => len == 0,
+                Err(source) => {
+                    monitor.error(Error::ListBlocks { source });
+                    false
+                }
+            })
+            .collect();
+        empty.sort();
+        for hash in &empty {
+            self.delete_block(hash)?;
+        }
+        Ok(empty)
+    }
+
+    /// Find files and directories in the blockdir that aren't blocks in their
+    /// expected subdirectory.
+    ///
+    /// Returns the relative paths of temporary files left behind by interrupted
+    /// writes, and separately of anything else unexpected, both sorted.
+    pub(crate) fn stray_files(&self) -> Result<(Vec<String>, Vec<String>)> {
+        Ok(self.store.stray_files()?)
+    }
+
+    /// Find the one block whose hash starts with `prefix`, like an abbreviated git
+    /// commit id.
+    ///
+    /// A full-length hash is returned without checking that the block exists. For a
+    /// prefix of at least the subdirectory name length, only that subdirectory is
+    /// listed; shorter prefixes list the whole block directory.
+    pub fn resolve_prefix(&self, prefix: &str, monitor: Arc<dyn Monitor>) -> Result<BlockHash> {
+        if prefix.is_empty()
+            || prefix.len() > BLAKE_HASH_SIZE_BYTES * 2
+            || !prefix.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(Error::InvalidBlockHashPrefix {
+                prefix: prefix.to_owned(),
+            });
+        }
+        if let Ok(hash) = prefix.parse() {
+            return Ok(hash);
+        }
+        let mut candidates = self
+            .list_store(prefix, monitor)
+            .map_err(|source| Error::ListBlocks { source })?;
+        candidates.sort();
+        match candidates.len() {
+            0 => Err(Error::NoBlockWithPrefix {
+                prefix: prefix.to_owned(),
+            }),
+            1 => Ok(candidates.remove(0)),
+            _ => Err(Error::AmbiguousBlockHashPrefix {
+                prefix: prefix.to_owned(),
+                candidates,
+            }),
+        }
     }
 
     /// Return all the blocknames in the blockdir, in arbitrary order.
@@ -282,28 +775,7 @@ impl BlockDir {
         &self,
         monitor: Arc<dyn Monitor>,
     ) -> Result<impl ParallelIterator<Item = BlockHash>> {
-        let transport = self.transport.clone();
-        let task = monitor.start_task("List block subdir".to_string());
-        let subdirs = self.subdirs()?;
-        task.set_total(subdirs.len());
-        Ok(subdirs
-            .into_par_iter()
-            .map(move |subdir_name| {
-                let r = transport.list_dir(&subdir_name);
-                task.increment(1);
-                r
-            })
-            .filter_map(move |iter_or| match iter_or {
-                Err(source) => {
-                    monitor.error(Error::ListBlocks { source });
-                    None
-                }
-                Ok(ListDir { files, .. }) => Some(files),
-            })
-            .flatten()
-            .filter_map(|name| // drop any invalid names, including temp files
-                // TODO: Report errors on bad names?
-                name.parse().ok()))
+        Ok(self.list_store("", monitor)?.into_par_iter())
     }
 
     /// Check format invariants of the BlockDir.
@@ -319,10 +791,20 @@ impl BlockDir {
         let blocks = self
             .blocks(monitor.clone())?
             .collect::<HashSet<BlockHash>>();
+        Ok(self.validate_blocks(blocks, monitor))
+    }
+
+    /// Check the hashes of some blocks that are present, and return the length of the
+    /// uncompressed content of each good one.
+    pub(crate) fn validate_blocks(
+        &self,
+        blocks: HashSet<BlockHash>,
+        monitor: Arc<dyn Monitor>,
+    ) -> HashMap<BlockHash, usize> {
         debug!("Check {} blocks", blocks.len());
         let task = monitor.start_task("Validate blocks".to_string());
         task.set_total(blocks.len());
-        let block_lens = blocks
+        blocks
             .into_par_iter()
             .flat_map(
                 |hash| match self.get_block_content(&hash, monitor.clone()) {
@@ -336,8 +818,15 @@ impl BlockDir {
                     }
                 },
             )
-            .collect();
-        Ok(block_lens)
+            .collect()
+    }
+}
+
+impl fmt::Debug for BlockDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockDir")
+            .field("stats", &self.stats)
+            .finish_non_exhaustive()
     }
 }
 
@@ -359,6 +848,175 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn resolve_unique_and_ambiguous_prefixes() {
+        let tempdir = TempDir::new().unwrap();
+        let blockdir = BlockDir::open(Transport::local(tempdir.path()));
+        let monitor = TestMonitor::arc();
+        let hash = blockdir
+            .store_or_deduplicate(
+                Bytes::from("stuff"),
+                false,
+                &mut BackupStats::default(),
+                monitor.clone(),
+            )
+            .unwrap();
+        let hex = hash.to_string();
+        assert_eq!(
+            blockdir.resolve_prefix(&hex[..8], monitor.clone()).unwrap(),
+            hash
+        );
+        assert_eq!(
+            blockdir
+                .resolve_prefix(&hex[..8].to_ascii_uppercase(), monitor.clone())
+                .unwrap(),
+            hash
+        );
+        assert_eq!(
+            blockdir.resolve_prefix(&hex[..2], monitor.clone()).unwrap(),
+            hash
+        );
+
+        // Add another block file whose name shares the first 8 digits.
+        let other = format!("{}{}", &hex[..8], "0".repeat(120));
+        write(tempdir.path().join(&hex[..3]).join(&other), b"").unwrap();
+        let err = blockdir
+            .resolve_prefix(&hex[..8], monitor.clone())
+            .unwrap_err();
+        let Error::AmbiguousBlockHashPrefix { candidates, .. } = &err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(candidates.len(), 2);
+        assert!(candidates.contains(&hash));
+        assert!(err.to_string().contains(&other));
+        assert_eq!(
+            blockdir.resolve_prefix(&hex[..9], monitor.clone()).unwrap(),
+            hash
+        );
+
+        let no_match = if hex.starts_with('f') { "0000" } else { "ffff" };
+        assert!(matches!(
+            blockdir.resolve_prefix(no_match, monitor.clone()),
+            Err(Error::NoBlockWithPrefix { .. })
+        ));
+        assert!(matches!(
+            blockdir.resolve_prefix("xyz", monitor.clone()),
+            Err(Error::InvalidBlockHashPrefix { .. })
+        ));
+    }
+
+    /// Counts reads from a wrapped store.
+    struct CountingStore {
+        inner: TransportBlockStore,
+        gets: AtomicUsize,
+    }
+
+    impl BlockStore for CountingStore {
+        fn get(&self, hash: &BlockHash) -> transport::Result<Bytes> {
+            self.gets.fetch_add(1, Relaxed);
+            self.inner.get(hash)
+        }
+
+        fn put(
+            &self,
+            hash: &BlockHash,
+            compressed: &[u8],
+            mode: WriteMode,
+        ) -> transport::Result<()> {
+            self.inner.put(hash, compressed, mode)
+        }
+
+        fn compressed_size(&self, hash: &BlockHash) -> transport::Result<u64> {
+            self.inner.compressed_size(hash)
+        }
+
+        fn list(
+            &self,
+            prefix: &str,
+            monitor: Arc<dyn Monitor>,
+        ) -> transport::Result<Vec<BlockHash>> {
+            self.inner.list(prefix, monitor)
+        }
+
+        fn delete(&self, hash: &BlockHash) -> transport::Result<()> {
+            self.inner.delete(hash)
+        }
+    }
+
+    #[test]
+    fn disabled_block_cache_reads_from_store_every_time() {
+        let store = Arc::new(CountingStore {
+            inner: TransportBlockStore::new(Transport::memory()),
+            gets: AtomicUsize::new(0),
+        });
+        let blockdir = BlockDir::with_store(store.clone()).with_block_cache(0);
+        let content = Bytes::from("stuff");
+        let hash = blockdir
+            .store_or_deduplicate(
+                content.clone(),
+                false,
+                &mut BackupStats::default(),
+                TestMonitor::arc(),
+            )
+            .unwrap();
+
+        let monitor = TestMonitor::arc();
+        for _ in 0..2 {
+            let retrieved = blockdir.get_block_content(&hash, monitor.clone()).unwrap();
+            assert_eq!(retrieved, content);
+        }
+        assert_eq!(store.gets.load(Relaxed), 2);
+        assert_eq!(monitor.get_counter(Counter::BlockContentCacheHit), 0);
+        assert_eq!(monitor.get_counter(Counter::BlockContentCacheMiss), 2);
+        assert_eq!(blockdir.stats.cache_hit.load(Relaxed), 0);
+
+        // With the default cache, only the first read reaches the store.
+        let blockdir = BlockDir::with_store(store.clone());
+        blockdir
+            .get_block_content(&hash, TestMonitor::arc())
+            .unwrap();
+        blockdir
+            .get_block_content(&hash, TestMonitor::arc())
+            .unwrap();
+        assert_eq!(store.gets.load(Relaxed), 3);
+    }
+
+    #[test]
+    fn transport_block_store_keeps_blocks_in_subdirectories() {
+        let transport = Transport::memory();
+        let store = TransportBlockStore::new(transport.clone());
+        let monitor = TestMonitor::arc();
+        let hash = BlockHash::hash_bytes(b"stuff");
+        assert!(!store.contains(&hash).unwrap());
+        assert!(store.get(&hash).unwrap_err().is_not_found());
+        assert_eq!(store.list("", monitor.clone()).unwrap(), []);
+
+        store
+            .put(&hash, b"compressed", WriteMode::CreateNew)
+            .unwrap();
+        assert_eq!(
+            transport.read_file(&block_relpath(&hash)).unwrap().as_ref(),
+            b"compressed"
+        );
+        assert!(store.contains(&hash).unwrap());
+        assert_eq!(store.compressed_size(&hash).unwrap(), 10);
+        assert_eq!(store.get(&hash).unwrap().as_ref(), b"compressed");
+        let hex = hash.to_string();
+        for prefix in ["", &hex[..1], &hex[..3], &hex[..10].to_ascii_uppercase()] {
+            assert_eq!(
+                store.list(prefix, monitor.clone()).unwrap(),
+                vec![hash.clone()]
+            );
+        }
+        let other = if hex.starts_with('f') { "0000" } else { "ffff" };
+        assert_eq!(store.list(other, monitor.clone()).unwrap(), []);
+
+        store.delete(&hash).unwrap();
+        assert!(!store.contains(&hash).unwrap());
+        assert_eq!(store.list("", monitor.clone()).unwrap(), []);
+        monitor.assert_no_errors();
+    }
+
     #[test]
     fn empty_block_file_counts_as_not_present() {
         // Due to an interruption or system crash we might end up with a block
@@ -369,7 +1027,7 @@ mod test {
         let mut stats = BackupStats::default();
         let monitor = TestMonitor::arc();
         let hash = blockdir
-            .store_or_deduplicate(Bytes::from("stuff"), &mut stats, monitor.clone())
+            .store_or_deduplicate(Bytes::from("stuff"), false, &mut stats, monitor.clone())
             .unwrap();
         assert_eq!(monitor.get_counter(Counter::BlockWrites), 1);
         assert_eq!(monitor.get_counter(Counter::DeduplicatedBlocks), 0);
@@ -392,6 +1050,34 @@ mod test {
         assert_eq!(monitor.get_counter(Counter::BlockExistenceCacheMiss), 1);
     }
 
+    #[test]
+    fn remove_empty_blocks_leaves_nonempty_blocks() {
+        let tempdir = TempDir::new().unwrap();
+        let blockdir = BlockDir::open(Transport::local(tempdir.path()));
+        let mut stats = BackupStats::default();
+        let monitor = TestMonitor::arc();
+        let empty_hash = blockdir
+            .store_or_deduplicate(Bytes::from("stuff"), false, &mut stats, monitor.clone())
+            .unwrap();
+        let good_hash = blockdir
+            .store_or_deduplicate(Bytes::from("other"), false, &mut stats, monitor.clone())
+            .unwrap();
+        let empty_path = tempdir.path().join(block_relpath(&empty_hash));
+        write(&empty_path, b"").unwrap();
+
+        assert_eq!(
+            blockdir.remove_empty_blocks(monitor.clone()).unwrap(),
+            [empty_hash]
+        );
+        monitor.assert_no_errors();
+        assert!(!empty_path.exists());
+        assert!(blockdir.contains(&good_hash, monitor.clone()).unwrap());
+        assert!(blockdir
+            .remove_empty_blocks(monitor.clone())
+            .unwrap()
+            .is_empty());
+    }
+
     #[test]
     fn temp_files_are_not_returned_as_blocks() {
         let tempdir = TempDir::new().unwrap();
@@ -415,7 +1101,7 @@ mod test {
         let mut stats = BackupStats::default();
         let content = Bytes::from("stuff");
         let hash = blockdir
-            .store_or_deduplicate(content.clone(), &mut stats, TestMonitor::arc())
+            .store_or_deduplicate(content.clone(), false, &mut stats, TestMonitor::arc())
             .unwrap();
         assert_eq!(blockdir.stats.cache_hit.load(Relaxed), 0);
 
@@ -446,7 +1132,7 @@ mod test {
         let content = Bytes::from("stuff");
         let monitor = TestMonitor::arc();
         let hash = blockdir
-            .store_or_deduplicate(content.clone(), &mut stats, monitor.clone())
+            .store_or_deduplicate(content.clone(), false, &mut stats, monitor.clone())
             .unwrap();
 
         // reopen
@@ -471,4 +1157,48 @@ mod test {
         assert_eq!(monitor.get_counter(Counter::BlockContentCacheHit), 0);
         assert_eq!(blockdir.stats.cache_hit.load(Relaxed), 2); // hit again
     }
+
+    #[test]
+    fn corrupt_block_read_from_mirror_secondary() {
+        let (primary, secondary) = (Transport::memory(), Transport::memory());
+        let blockdir = BlockDir::create(Transport::mirror(&primary, &secondary)).unwrap();
+        let content = Bytes::from("stuff");
+        let hash = blockdir
+            .store_or_deduplicate(
+                content.clone(),
+                false,
+                &mut BackupStats::default(),
+                TestMonitor::arc(),
+            )
+            .unwrap();
+        // Replace the primary copy with validly compressed data that has the wrong hash,
+        // so that only the hash check can notice.
+        let relpath = block_relpath(&hash);
+        let wrong = Compressor::new().compress(b"wrong").unwrap();
+        primary
+            .write_file(&relpath, &wrong, WriteMode::Overwrite)
+            .unwrap();
+
+        let blockdir = BlockDir::open(Transport::mirror(&primary, &secondary));
+        let retrieved = blockdir
+            .get_block_content(&hash, TestMonitor::arc())
+            .unwrap();
+        assert_eq!(retrieved, content);
+        assert_eq!(
+            primary.read_file(&relpath).unwrap(),
+            secondary.read_file(&relpath).unwrap()
+        );
+
+        // Damaged in both copies, it's reported as corrupt.
+        for transport in [&primary, &secondary] {
+            transport
+                .write_file(&relpath, &wrong, WriteMode::Overwrite)
+                .unwrap();
+        }
+        let blockdir = BlockDir::open(Transport::mirror(&primary, &secondary));
+        assert!(matches!(
+            blockdir.get_block_content(&hash, TestMonitor::arc()),
+            Err(Error::BlockCorrupt { .. })
+        ));
+    }
 }
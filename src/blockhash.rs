@@ -40,6 +40,12 @@ impl BlockHash {
     pub fn hash_bytes(bytes: &[u8]) -> Self {
         BlockHash::from(blake2b(BLAKE_HASH_SIZE_BYTES, &[], bytes))
     }
+
+    /// True if the hex form of this hash starts with `prefix`, ignoring case.
+    pub fn starts_with(&self, prefix: &str) -> bool {
+        let hex = self.to_string();
+        prefix.len() <= hex.len() && hex[..prefix.len()].eq_ignore_ascii_case(prefix)
+    }
 }
 
 #[derive(Debug)]
@@ -53,6 +59,8 @@ impl Display for BlockHashParseError {
     }
 }
 
+impl std::error::Error for BlockHashParseError {}
+
 impl FromStr for BlockHash {
     type Err = BlockHashParseError;
 
@@ -0,0 +1,156 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Export the content of a stored tree as a directory of blocks named by their
+//! hashes, plus a manifest of which parts of which blocks make up each file.
+//!
+//! This exposes the archive's deduplicated block layout to other tools, without
+//! reconstructing the files. Each block is written once, uncompressed, however
+//! many files refer to it.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::blockdir::Address;
+use crate::io::{directory_is_empty, ensure_dir_exists};
+use crate::monitor::Monitor;
+use crate::stats::{write_count, write_size};
+use crate::*;
+
+/// The name of the manifest file in an export directory.
+pub const CAS_EXPORT_MANIFEST: &str = "manifest.json";
+
+/// One file in the manifest of a content-addressed export.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct CasExportFile {
+    pub apath: Apath,
+    /// The parts of blocks whose concatenated content is the content of the file.
+    pub addrs: Vec<Address>,
+}
+
+/// Counts of what was written by [cas_export].
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct CasExportStats {
+    pub files: usize,
+    /// Distinct blocks written to the export directory.
+    pub blocks: usize,
+    /// Total uncompressed size of the blocks written.
+    pub block_bytes: u64,
+    /// Files left out of the export because their content couldn't be read.
+    pub errors: usize,
+}
+
+impl fmt::Display for CasExportStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_count(w, "files", self.files);
+        write_count(w, "blocks", self.blocks);
+        write_size(w, "  block bytes", self.block_bytes);
+        write_count(w, "errors", self.errors);
+        Ok(())
+    }
+}
+
+/// Write each distinct block used by the files of a stored tree into an empty
+/// `destination` directory, named by its hash, and write a [CAS_EXPORT_MANIFEST]
+/// listing a [CasExportFile] for each file.
+///
+/// Files whose blocks can't be read are reported to the monitor as errors and
+/// left out of the manifest.
+pub fn cas_export(
+    tree: &StoredTree,
+    destination: &Path,
+    subtree: Apath,
+    exclude: Exclude,
+    monitor: Arc<dyn Monitor>,
+) -> Result<CasExportStats> {
+    ensure_dir_exists(destination)?;
+    if !directory_is_empty(destination)? {
+        return Err(Error::DestinationNotEmpty);
+    }
+    let block_dir = tree.block_dir();
+    let mut stats = CasExportStats::default();
+    let mut written: HashSet<BlockHash> = HashSet::new();
+    let mut files = Vec::new();
+    for entry in tree.iter_entries(subtree, exclude, monitor.clone())? {
+        if entry.kind() != Kind::File {
+            continue;
+        }
+        match export_blocks(
+            &entry,
+            block_dir,
+            destination,
+            &mut written,
+            &mut stats,
+            monitor.clone(),
+        ) {
+            Ok(()) => {
+                stats.files += 1;
+                files.push(CasExportFile {
+                    apath: entry.apath,
+                    addrs: entry.addrs,
+                });
+            }
+            Err(err) => {
+                monitor.error(err);
+                stats.errors += 1;
+            }
+        }
+    }
+    let manifest_path = destination.join(CAS_EXPORT_MANIFEST);
+    let write_manifest = || -> std::io::Result<()> {
+        let mut f = BufWriter::new(File::create(&manifest_path)?);
+        serde_json::to_writer_pretty(&mut f, &files)?;
+        writeln!(f)?;
+        f.flush()
+    };
+    write_manifest().map_err(|source| Error::RestoreFile {
+        path: manifest_path.clone(),
+        source,
+    })?;
+    Ok(stats)
+}
+
+/// Write any blocks used by this file that aren't already in the export.
+fn export_blocks(
+    entry: &IndexEntry,
+    block_dir: &BlockDir,
+    destination: &Path,
+    written: &mut HashSet<BlockHash>,
+    stats: &mut CasExportStats,
+    monitor: Arc<dyn Monitor>,
+) -> Result<()> {
+    for addr in &entry.addrs {
+        if written.contains(&addr.hash) {
+            continue;
+        }
+        let bytes = block_dir
+            .get_block_content(&addr.hash, monitor.clone())
+            .map_err(|source| Error::RestoreFileBlock {
+                apath: entry.apath.clone(),
+                hash: addr.hash.clone(),
+                source: Box::new(source),
+            })?;
+        let path = destination.join(addr.hash.to_string());
+        fs::write(&path, &bytes).map_err(|source| Error::RestoreFile { path, source })?;
+        stats.blocks += 1;
+        stats.block_bytes += bytes.len() as u64;
+        written.insert(addr.hash.clone());
+    }
+    Ok(())
+}
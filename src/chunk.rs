@@ -0,0 +1,234 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Split file content into blocks.
+//!
+//! Blocks are addressed only by their hash, so the way files were split doesn't
+//! need to be known to read them back: this only affects how well new content
+//! deduplicates against blocks already in the archive.
+
+use std::io::{self, Read};
+
+use bytes::{Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::io::read_with_retries;
+
+/// How to split large files into blocks.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Chunking {
+    /// Blocks of exactly the maximum block size, except for the last.
+    ///
+    /// This is fast, but inserting or removing bytes in a file shifts all the
+    /// following blocks, so that none of them match blocks already stored.
+    #[default]
+    Fixed,
+    /// Blocks whose boundaries are chosen from a rolling hash of the content, so that
+    /// after an insertion or deletion the blocks soon line up again with those
+    /// previously stored.
+    ///
+    /// Blocks average a quarter of the maximum block size.
+    ContentDefined,
+}
+
+impl Chunking {
+    /// The name of this mode, as recorded in the band head.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Chunking::Fixed => "fixed",
+            Chunking::ContentDefined => "content-defined",
+        }
+    }
+}
+
+/// Reads a file and yields its content as a series of blocks.
+pub(crate) struct Chunker<'a> {
+    from: &'a mut dyn Read,
+    chunking: Chunking,
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    /// Data read from the file and not yet returned.
+    buf: BytesMut,
+    eof: bool,
+}
+
+impl<'a> Chunker<'a> {
+    pub fn new(from: &'a mut dyn Read, chunking: Chunking, max_size: usize) -> Chunker<'a> {
+        assert!(max_size > 0);
+        let avg_size = (max_size / 4).max(1);
+        Chunker {
+            from,
+            chunking,
+            min_size: avg_size / 4,
+            avg_size,
+            max_size,
+            buf: BytesMut::new(),
+            eof: false,
+        }
+    }
+
+    /// Return the next block of the file, or None at the end of the file.
+    pub fn next_block(&mut self) -> io::Result<Option<Bytes>> {
+        match self.chunking {
+            Chunking::Fixed => {
+                let buf = read_with_retries(self.max_size, self.from)?;
+                Ok((!buf.is_empty()).then(|| buf.freeze()))
+            }
+            Chunking::ContentDefined => {
+                if !self.eof && self.buf.len() < self.max_size {
+                    let want = self.max_size - self.buf.len();
+                    let more = read_with_retries(want, self.from)?;
+                    self.eof = more.len() < want;
+                    if self.buf.is_empty() {
+                        self.buf = more;
+                    } else {
+                        self.buf.extend_from_slice(&more);
+                    }
+                }
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                let len = cut_point(&self.buf, self.min_size, self.avg_size, self.max_size);
+                Ok(Some(self.buf.split_to(len).freeze()))
+            }
+        }
+    }
+}
+
+/// Return the length of the first content-defined block in `data`.
+///
+/// This uses a gear hash with normalized chunking, as in FastCDC: boundaries are
+/// less likely before the average size and more likely after it, so that block
+/// sizes cluster around the average. If no boundary is found, the block is all of
+/// `data`, up to `max_size`.
+fn cut_point(data: &[u8], min_size: usize, avg_size: usize, max_size: usize) -> usize {
+    let end = data.len().min(max_size);
+    if end <= min_size {
+        return end;
+    }
+    let bits = avg_size.max(2).ilog2();
+    let mask_small = mask(bits + 1);
+    let mask_large = mask(bits.saturating_sub(1));
+    let normal_end = avg_size.clamp(min_size, end);
+    let mut hash: u64 = 0;
+    for (i, &byte) in data.iter().enumerate().take(end).skip(min_size) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        let mask = if i < normal_end {
+            mask_small
+        } else {
+            mask_large
+        };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// A mask selecting the top `bits` bits of the hash, which depend on the most
+/// bytes of preceding content.
+fn mask(bits: u32) -> u64 {
+    match bits {
+        0 => 0,
+        bits => !0u64 << (64 - bits.min(64)),
+    }
+}
+
+/// Random values for each byte, mixed into the rolling hash.
+///
+/// Changing these would change where blocks are split, so new backups wouldn't
+/// deduplicate against blocks stored by earlier content-defined backups.
+const GEAR: [u64; 256] = gear_table();
+
+/// Generate the gear table with splitmix64, so it's reproducible without being
+/// spelled out.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pseudo_random(len: usize) -> Vec<u8> {
+        let mut state: u32 = 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn blocks(mut data: &[u8], chunking: Chunking, max_size: usize) -> Vec<Bytes> {
+        let mut chunker = Chunker::new(&mut data, chunking, max_size);
+        let mut blocks = Vec::new();
+        while let Some(block) = chunker.next_block().unwrap() {
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn content_defined_blocks_reassemble_the_input_within_size_limits() {
+        let data = pseudo_random(1 << 20);
+        let max_size = 64 << 10;
+        let blocks = blocks(&data, Chunking::ContentDefined, max_size);
+        assert_eq!(blocks.concat(), data);
+        assert!(blocks.iter().all(|b| b.len() <= max_size));
+        // Blocks are mostly cut by content, not at the maximum size.
+        assert!(blocks.len() > 2 * data.len() / max_size, "{}", blocks.len());
+    }
+
+    #[test]
+    fn content_defined_blocks_resynchronize_after_insertion() {
+        let data = pseudo_random(1 << 20);
+        let mut shifted = b"inserted".to_vec();
+        shifted.extend_from_slice(&data);
+        let before = blocks(&data, Chunking::ContentDefined, 64 << 10);
+        let after = blocks(&shifted, Chunking::ContentDefined, 64 << 10);
+        let changed = after.iter().filter(|b| !before.contains(b)).count();
+        assert!(changed <= 2, "{changed} of {} blocks changed", after.len());
+    }
+
+    #[test]
+    fn fixed_blocks_are_the_maximum_size() {
+        let data = pseudo_random(100_000);
+        let blocks = blocks(&data, Chunking::Fixed, 30_000);
+        assert_eq!(
+            blocks.iter().map(|b| b.len()).collect::<Vec<_>>(),
+            [30_000, 30_000, 30_000, 10_000]
+        );
+    }
+
+    #[test]
+    fn empty_input_has_no_blocks() {
+        assert!(blocks(&[], Chunking::ContentDefined, 1000).is_empty());
+        assert!(blocks(&[], Chunking::Fixed, 1000).is_empty());
+    }
+}
@@ -0,0 +1,65 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Sources of the current time, so that tests can control the times recorded in bands.
+
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use time::OffsetDateTime;
+
+/// A source of the current time.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> OffsetDateTime;
+}
+
+/// The real system clock.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
+
+/// A clock that stays at a given time until it's explicitly changed, for tests.
+#[derive(Debug)]
+pub struct FixedClock {
+    now: Mutex<OffsetDateTime>,
+}
+
+impl FixedClock {
+    pub fn new(now: OffsetDateTime) -> FixedClock {
+        FixedClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    /// Set the time that will be returned from now on.
+    pub fn set(&self, now: OffsetDateTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    /// Move the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> OffsetDateTime {
+        *self.now.lock().unwrap()
+    }
+}
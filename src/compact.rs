@@ -0,0 +1,286 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Rewrite an archive into a tidier form without changing the trees it holds:
+//! indexes with fewer, fuller hunks, and small blocks packed together.
+//!
+//! Each band's new index is written alongside the old one and then swapped in, so
+//! an interrupted compaction can be run again to finish. Small blocks are deleted
+//! only after every index that refers to them has been rewritten to use the packed
+//! blocks instead.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use tracing::debug;
+
+use crate::backup::BackupStats;
+use crate::monitor::Monitor;
+use crate::stats::{write_count, write_duration};
+use crate::*;
+
+/// Options for [compact].
+#[derive(Debug, Clone)]
+pub struct CompactOptions {
+    /// Rewrite every band, rather than only the latest.
+    ///
+    /// Small blocks are only repacked when every band is rewritten, since all the
+    /// indexes that refer to them must be updated.
+    pub all_bands: bool,
+
+    /// Put up to this many entries in each rewritten index hunk.
+    pub max_entries_per_hunk: usize,
+
+    /// Pack together blocks with less than this many bytes of content.
+    pub small_block_size: usize,
+
+    /// Make packed blocks of up to this many bytes.
+    pub max_block_size: usize,
+}
+
+impl Default for CompactOptions {
+    fn default() -> Self {
+        CompactOptions {
+            all_bands: false,
+            max_entries_per_hunk: backup::DEFAULT_MAX_ENTRIES_PER_HUNK,
+            small_block_size: 1 << 20,
+            max_block_size: 20 << 20,
+        }
+    }
+}
+
+/// Counts of what was done by [compact].
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct CompactStats {
+    /// Bands whose indexes were rewritten.
+    pub bands_rewritten: usize,
+    /// Hunks in the rewritten indexes, before they were rewritten.
+    pub index_hunks_before: usize,
+    /// Hunks in the rewritten indexes, afterwards.
+    pub index_hunks_after: usize,
+    /// Small blocks whose content was moved into packed blocks, and then deleted.
+    pub small_blocks_repacked: usize,
+    /// New blocks holding the content of the small blocks.
+    pub packed_blocks_written: usize,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for CompactStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_count(w, "bands rewritten", self.bands_rewritten);
+        write_count(w, "  index hunks before", self.index_hunks_before);
+        write_count(w, "  index hunks after", self.index_hunks_after);
+        write_count(w, "small blocks repacked", self.small_blocks_repacked);
+        write_count(w, "  packed blocks written", self.packed_blocks_written);
+        write_duration(w, "elapsed", self.elapsed)?;
+        Ok(())
+    }
+}
+
+/// Where the content of a small block was moved to: the packed block, and the
+/// offset of the small block's content within it.
+type Repacked = HashMap<BlockHash, (BlockHash, u64)>;
+
+/// Rewrite the indexes of the latest band, or all bands, into full hunks, and if
+/// all bands are rewritten, pack small blocks together.
+///
+/// This holds the archive's write lock, so it can't run at the same time as a
+/// backup or gc, and readers may see an incomplete index if they run at the same time.
+/// If it's interrupted, running it again finishes the job.
+pub fn compact(
+    archive: &Archive,
+    options: &CompactOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<CompactStats> {
+    let start = Instant::now();
+    let _lock = WriteLock::acquire(archive)?;
+    // Repacking deletes blocks, so also keep out older versions of Conserve that
+    // only know about the gc lock.
+    let _gc_lock = if options.all_bands {
+        Some(GarbageCollectionLock::new(archive)?)
+    } else {
+        None
+    };
+    let mut stats = CompactStats::default();
+    // Finish installing indexes staged by an interrupted compaction first, because
+    // they may refer to packed blocks that will themselves be repacked below.
+    for band_id in archive.list_band_ids()? {
+        let band = Band::open(archive, band_id)?;
+        if band.has_complete_staged_index()? {
+            debug!(%band_id, "Finish installing index staged by an earlier compaction");
+            stats.bands_rewritten += 1;
+            stats.index_hunks_after += band.install_staged_index()?;
+        }
+    }
+    let band_ids = if options.all_bands {
+        archive.list_band_ids()?
+    } else {
+        archive.last_band_id()?.into_iter().collect()
+    };
+    let repacked = if options.all_bands {
+        repack_small_blocks(archive, &band_ids, options, &mut stats, monitor.clone())?
+    } else {
+        Repacked::new()
+    };
+    let task = monitor.start_task("Rewrite indexes".to_string());
+    task.set_total(band_ids.len());
+    for band_id in &band_ids {
+        let band = Band::open(archive, *band_id)?;
+        rewrite_index(&band, &repacked, options, &mut stats, monitor.clone())?;
+        task.increment(1);
+    }
+    drop(task);
+    if stats.bands_rewritten > 0 {
+        // The manifest holds the hunk counts of closed bands, which have changed.
+        match archive
+            .transport()
+            .remove_file(band_manifest::BANDS_MANIFEST_FILENAME)
+        {
+            Err(err) if !err.is_not_found() => return Err(err.into()),
+            _ => band_manifest::update_or_warn(archive),
+        }
+    }
+    // Every index that referred to the small blocks now refers to the packed blocks.
+    for hash in repacked.keys() {
+        if let Err(err) = archive.block_dir().delete_block(hash) {
+            monitor.error(err);
+        }
+    }
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+/// Write the content of small blocks referenced by these bands into packed blocks,
+/// and return where each one's content now is.
+fn repack_small_blocks(
+    archive: &Archive,
+    band_ids: &[BandId],
+    options: &CompactOptions,
+    stats: &mut CompactStats,
+    monitor: Arc<dyn Monitor>,
+) -> Result<Repacked> {
+    // The end of the referenced content of each block, which is a lower bound on its
+    // length, so that only blocks that may be small need to be read.
+    let mut referenced_lens: BTreeMap<BlockHash, u64> = BTreeMap::new();
+    for band_id in band_ids {
+        for entry in Band::open(archive, *band_id)?.index().iter_entries() {
+            for addr in entry.addrs {
+                let len = referenced_lens.entry(addr.hash).or_default();
+                *len = (*len).max(addr.start + addr.len);
+            }
+        }
+    }
+    let block_dir = archive.block_dir();
+    let mut small_blocks = Vec::new();
+    for (hash, referenced_len) in referenced_lens {
+        if referenced_len < options.small_block_size as u64 {
+            let content = block_dir.get_block_content(&hash, monitor.clone())?;
+            if content.len() < options.small_block_size {
+                small_blocks.push((hash, content));
+            }
+        }
+    }
+    debug!(small_blocks = small_blocks.len());
+
+    let mut repacked = Repacked::new();
+    let mut backup_stats = BackupStats::default();
+    let mut pack_start = 0;
+    while pack_start < small_blocks.len() {
+        let mut pack_len = 0;
+        let mut pack_end = pack_start;
+        while pack_end < small_blocks.len()
+            && pack_len + small_blocks[pack_end].1.len() <= options.max_block_size
+        {
+            pack_len += small_blocks[pack_end].1.len();
+            pack_end += 1;
+        }
+        let pack = &small_blocks[pack_start..pack_end.max(pack_start + 1)];
+        pack_start += pack.len();
+        if pack.len() < 2 {
+            continue;
+        }
+        let mut content = BytesMut::with_capacity(pack_len);
+        let mut offsets = Vec::with_capacity(pack.len());
+        for (hash, block_content) in pack {
+            offsets.push((hash.clone(), content.len() as u64));
+            content.extend_from_slice(block_content);
+        }
+        let packed_hash = block_dir.store_or_deduplicate(
+            content.freeze(),
+            false,
+            &mut backup_stats,
+            monitor.clone(),
+        )?;
+        stats.packed_blocks_written += 1;
+        stats.small_blocks_repacked += pack.len();
+        for (hash, offset) in offsets {
+            repacked.insert(hash, (packed_hash.clone(), offset));
+        }
+    }
+    Ok(repacked)
+}
+
+/// Rewrite the index of one band into full hunks, pointing to the packed blocks,
+/// unless that would change nothing.
+fn rewrite_index(
+    band: &Band,
+    repacked: &Repacked,
+    options: &CompactOptions,
+    stats: &mut CompactStats,
+    monitor: Arc<dyn Monitor>,
+) -> Result<()> {
+    let band_id = band.id();
+    let mut index = band.index();
+    let old_hunks = index.hunks_available()?;
+    let mut writer = band.stage_index()?;
+    let mut pending = Vec::new();
+    let mut changed_addresses = false;
+    for &hunk_number in &old_hunks {
+        let entries = index
+            .read_hunk(hunk_number)?
+            .ok_or(Error::IndexHunkNotFound {
+                band_id,
+                hunk_number,
+                hunk_count: old_hunks.len(),
+            })?;
+        for mut entry in entries {
+            for addr in &mut entry.addrs {
+                if let Some((packed_hash, offset)) = repacked.get(&addr.hash) {
+                    addr.hash = packed_hash.clone();
+                    addr.start += offset;
+                    changed_addresses = true;
+                }
+            }
+            pending.push(entry);
+        }
+        while pending.len() >= options.max_entries_per_hunk {
+            let rest = pending.split_off(options.max_entries_per_hunk);
+            writer.append_entries(&mut pending);
+            writer.finish_hunk(monitor.clone())?;
+            pending = rest;
+        }
+    }
+    writer.append_entries(&mut pending);
+    let new_hunk_count = writer.finish(monitor)?;
+    if !changed_addresses && new_hunk_count >= old_hunks.len() {
+        debug!(%band_id, "Index is already compact");
+        return band.discard_staged_index();
+    }
+    stats.bands_rewritten += 1;
+    stats.index_hunks_before += old_hunks.len();
+    stats.index_hunks_after += band.install_staged_index()?;
+    Ok(())
+}
@@ -12,3 +12,4 @@
 
 //! Data compression algorithms.
 pub mod snappy;
+pub mod zstd;
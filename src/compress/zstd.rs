@@ -0,0 +1,209 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Zstandard compression glue.
+
+use std::fmt;
+use std::io::{self, Read};
+use std::num::NonZeroU32;
+
+use ::zstd::bulk;
+use ::zstd::dict::{DecoderDictionary, EncoderDictionary};
+use ::zstd::stream::read::Decoder;
+use ::zstd::zstd_safe;
+use bytes::Bytes;
+
+use crate::{Error, Result};
+
+/// The first bytes of every zstd frame.
+///
+/// Unframed Snappy data can never start with these bytes, because its first
+/// element after the length would be a copy with nothing to copy from.
+const MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// True if this data starts like a zstd frame.
+pub(crate) fn is_zstd(input: &[u8]) -> bool {
+    input.starts_with(&MAGIC)
+}
+
+/// Compress bytes into a single zstd frame, at the default level.
+pub(crate) fn compress(input: &[u8]) -> Result<Bytes> {
+    ::zstd::bulk::compress(input, ::zstd::DEFAULT_COMPRESSION_LEVEL)
+        .map(Bytes::from)
+        .map_err(|source| Error::ZstdCompressionError { source })
+}
+
+/// Decompress zstd frames.
+pub(crate) fn decompress(input: &[u8]) -> Result<Bytes> {
+    ::zstd::decode_all(input)
+        .map(Bytes::from)
+        .map_err(|source| Error::ZstdCompressionError { source })
+}
+
+/// A zstd dictionary, prepared for compressing and decompressing.
+pub(crate) struct Dictionary {
+    id: NonZeroU32,
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+impl Dictionary {
+    /// Prepare a dictionary from its serialized form, as returned by [train_dictionary].
+    pub(crate) fn new(content: &[u8]) -> Result<Dictionary> {
+        let id = zstd_safe::get_dict_id_from_dict(content).ok_or_else(|| {
+            Error::ZstdCompressionError {
+                source: io::Error::new(io::ErrorKind::InvalidData, "Not a zstd dictionary"),
+            }
+        })?;
+        Ok(Dictionary {
+            id,
+            encoder: EncoderDictionary::copy(content, ::zstd::DEFAULT_COMPRESSION_LEVEL),
+            decoder: DecoderDictionary::copy(content),
+        })
+    }
+
+    /// The id of this dictionary, which is recorded in each frame compressed with it.
+    pub(crate) fn id(&self) -> NonZeroU32 {
+        self.id
+    }
+
+    /// Compress bytes into a single zstd frame using this dictionary.
+    pub(crate) fn compress(&self, input: &[u8]) -> Result<Bytes> {
+        bulk::Compressor::with_prepared_dictionary(&self.encoder)
+            .and_then(|mut compressor| compressor.compress(input))
+            .map(Bytes::from)
+            .map_err(|source| Error::ZstdCompressionError { source })
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dictionary").field("id", &self.id).finish()
+    }
+}
+
+/// Train a dictionary of up to `max_size` bytes from samples of the data it'll
+/// compress.
+pub(crate) fn train_dictionary<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> Result<Vec<u8>> {
+    ::zstd::dict::from_samples(samples, max_size)
+        .map_err(|source| Error::ZstdCompressionError { source })
+}
+
+/// Decompress a zstd frame, using `dictionary` if the frame was compressed with one.
+///
+/// It's an error if the frame needs a dictionary other than the one given.
+pub(crate) fn decompress_with_dictionary(
+    input: &[u8],
+    dictionary: Option<&Dictionary>,
+) -> Result<Bytes> {
+    let Some(frame_dict_id) = zstd_safe::get_dict_id_from_frame(input) else {
+        return decompress(input);
+    };
+    let map_err = |source| Error::ZstdCompressionError { source };
+    let dictionary = dictionary
+        .filter(|dictionary| dictionary.id == frame_dict_id)
+        .ok_or_else(|| {
+            map_err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Data was compressed with unavailable zstd dictionary {frame_dict_id}"),
+            ))
+        })?;
+    let mut out = Vec::new();
+    Decoder::with_prepared_dictionary(input, &dictionary.decoder)
+        .and_then(|mut decoder| decoder.read_to_end(&mut out))
+        .map_err(map_err)?;
+    Ok(out.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compress::snappy;
+
+    #[test]
+    fn compress_decompress() {
+        let input = b"hello world, hello world, hello world, hello world";
+        let comp = compress(input).unwrap();
+        assert!(is_zstd(&comp));
+        assert_eq!(decompress(&comp).unwrap(), &input[..]);
+    }
+
+    #[test]
+    fn snappy_is_not_zstd() {
+        for input in [&b""[..], b"hello", b"hello world, hello world, hello world"] {
+            assert!(!is_zstd(
+                &snappy::Compressor::new().compress(input).unwrap()
+            ));
+        }
+    }
+
+    /// Samples of many small, similar, files.
+    fn similar_samples() -> Vec<String> {
+        (0..1000)
+            .map(|i| {
+                format!(
+                    "2024-01-{:02} 12:{:02}:00 INFO server{} request handled path=/api/v1/items/{i} status=200 duration_ms={}\n",
+                    i % 28 + 1,
+                    i % 60,
+                    i % 7,
+                    i * 37 % 1000
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dictionary_compress_decompress() {
+        let samples = similar_samples();
+        let dictionary = Dictionary::new(&train_dictionary(&samples, 4096).unwrap()).unwrap();
+        let input = samples[17].as_bytes();
+        let with_dict = dictionary.compress(input).unwrap();
+        assert!(is_zstd(&with_dict));
+        assert_eq!(
+            zstd_safe::get_dict_id_from_frame(&with_dict),
+            Some(dictionary.id())
+        );
+        assert!(with_dict.len() < compress(input).unwrap().len());
+        assert_eq!(
+            decompress_with_dictionary(&with_dict, Some(&dictionary)).unwrap(),
+            input
+        );
+        // Frames without a dictionary are read whether or not a dictionary is given.
+        let without_dict = compress(input).unwrap();
+        assert_eq!(zstd_safe::get_dict_id_from_frame(&without_dict), None);
+        assert_eq!(
+            decompress_with_dictionary(&without_dict, Some(&dictionary)).unwrap(),
+            input
+        );
+        assert!(matches!(
+            decompress_with_dictionary(&with_dict, None),
+            Err(Error::ZstdCompressionError { .. })
+        ));
+    }
+
+    #[test]
+    fn not_a_dictionary() {
+        assert!(matches!(
+            Dictionary::new(b"not a dictionary"),
+            Err(Error::ZstdCompressionError { .. })
+        ));
+    }
+
+    #[test]
+    fn decompress_garbage_fails() {
+        assert!(matches!(
+            decompress(b"\x28\xb5\x2f\xfdgarbage"),
+            Err(Error::ZstdCompressionError { .. })
+        ));
+    }
+}
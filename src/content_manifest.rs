@@ -0,0 +1,135 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! List the files in a stored tree with a hash of each one's whole content, so that
+//! a restored tree can be checked with standard tools rather than Conserve.
+//!
+//! The hash is BLAKE2b-512, the same as is used to name blocks, of the concatenated
+//! content of the file's blocks. In text form the manifest is in the format of
+//! `b2sum`, so it can be checked from the top of the restored tree with `b2sum -c`.
+
+use std::fmt;
+use std::sync::Arc;
+
+use blake2_rfc::blake2b::Blake2b;
+use serde::Serialize;
+
+use crate::monitor::Monitor;
+use crate::*;
+
+/// One file in a content manifest.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct ManifestEntry {
+    pub apath: Apath,
+    /// Length of the file content in bytes.
+    pub size: u64,
+    /// BLAKE2b-512 hash of the whole content of the file.
+    pub blake2b: BlockHash,
+}
+
+impl fmt::Display for ManifestEntry {
+    /// Format as a line of `b2sum` output, with the path relative to the top of the
+    /// tree.
+    ///
+    /// As in `b2sum`, if the path contains a backslash or newline, they're escaped and
+    /// the line starts with a backslash.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = self.apath.trim_start_matches('/');
+        if path.contains(['\\', '\n']) {
+            let escaped = path.replace('\\', "\\\\").replace('\n', "\\n");
+            write!(f, "\\{}  {escaped}", self.blake2b)
+        } else {
+            write!(f, "{}  {path}", self.blake2b)
+        }
+    }
+}
+
+/// Iterate the files in a stored tree with hashes of their content, in apath order.
+///
+/// This reads the content of every file whose index entry doesn't record the hash of
+/// its content. Files whose content can't be read are reported to the monitor as
+/// errors and left out.
+pub fn content_manifest(
+    tree: &StoredTree,
+    subtree: Apath,
+    exclude: Exclude,
+    monitor: Arc<dyn Monitor>,
+) -> Result<impl Iterator<Item = ManifestEntry> + '_> {
+    let block_dir = tree.block_dir();
+    Ok(tree
+        .iter_entries(subtree, exclude, monitor.clone())?
+        .filter(|entry| entry.kind() == Kind::File)
+        .filter_map(
+            move |entry| match hash_file(&entry, block_dir, monitor.clone()) {
+                Ok(manifest_entry) => Some(manifest_entry),
+                Err(err) => {
+                    monitor.error(err);
+                    None
+                }
+            },
+        ))
+}
+
+/// Find the hash of a stored file's content, from the index if it's recorded there,
+/// or otherwise by reading its blocks.
+pub(crate) fn hash_file(
+    entry: &IndexEntry,
+    block_dir: &BlockDir,
+    monitor: Arc<dyn Monitor>,
+) -> Result<ManifestEntry> {
+    if let Some(content_hash) = &entry.content_hash {
+        return Ok(ManifestEntry {
+            apath: entry.apath.clone(),
+            size: entry.addrs.iter().map(|addr| addr.len).sum(),
+            blake2b: content_hash.clone(),
+        });
+    }
+    let mut hasher = Blake2b::new(BLAKE_HASH_SIZE_BYTES);
+    let mut size = 0;
+    for addr in &entry.addrs {
+        let bytes = block_dir
+            .read_address(addr, monitor.clone())
+            .map_err(|source| Error::RestoreFileBlock {
+                apath: entry.apath.clone(),
+                hash: addr.hash.clone(),
+                source: Box::new(source),
+            })?;
+        hasher.update(&bytes);
+        size += bytes.len() as u64;
+    }
+    Ok(ManifestEntry {
+        apath: entry.apath.clone(),
+        size,
+        blake2b: BlockHash::from(hasher.finalize()),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display_escapes_like_b2sum() {
+        let blake2b = BlockHash::hash_bytes(b"");
+        let entry = |apath: &str| ManifestEntry {
+            apath: apath.into(),
+            size: 0,
+            blake2b: blake2b.clone(),
+        };
+        assert_eq!(entry("/a/b").to_string(), format!("{blake2b}  a/b"));
+        assert_eq!(
+            entry("/back\\slash\nnewline").to_string(),
+            format!("\\{blake2b}  back\\\\slash\\nnewline")
+        );
+    }
+}
@@ -28,6 +28,13 @@ pub enum Counter {
     Dirs,
     /// Number of symlinks processed.
     Symlinks,
+    /// Number of symlinks whose target doesn't exist, if checked.
+    DanglingSymlinks,
+    /// Number of special files, such as FIFOs and devices, that were skipped.
+    SpecialFiles,
+    /// Number of entries skipped because their directory has more entries than
+    /// the limit.
+    OverflowEntries,
     /// Number of entries (files etc) that are unchanged from the basis backup.
     EntriesUnchanged,
     /// Number of entries changed since the basis backup.
@@ -74,14 +81,34 @@ pub enum Counter {
     IndexWriteUncompressedBytes,
     /// Total compressed bytes in index hunks written.
     IndexWriteCompressedBytes,
+    /// Number of index hunks read from the archive.
+    IndexReads,
+    /// Total uncompressed bytes in index hunks read.
+    IndexReadUncompressedBytes,
+    /// Total compressed bytes in index hunks read.
+    IndexReadCompressedBytes,
+    /// Number of index hunks found in memory rather than read again.
+    IndexReadCacheHits,
+    /// Number of index hunks that couldn't be read or parsed.
+    IndexReadErrors,
+    /// Number of block subdirectories listed.
+    BlockSubdirsListed,
 }
 
 /// Counter values, identified by a [Counter].
-#[derive(Default)]
 pub struct Counters {
     counters: [AtomicUsize; Counter::COUNT],
 }
 
+impl Default for Counters {
+    fn default() -> Self {
+        // Arrays longer than 32 don't implement Default.
+        Counters {
+            counters: std::array::from_fn(|_| AtomicUsize::new(0)),
+        }
+    }
+}
+
 impl Counters {
     /// Increase the value for a given counter by an amount.
     pub fn count(&self, counter: Counter, increment: usize) {
@@ -107,6 +134,17 @@ impl Counters {
     }
 }
 
+impl Clone for Counters {
+    /// Copy the current values of all counters.
+    fn clone(&self) -> Self {
+        let counters = Counters::default();
+        for (c, v) in self.iter() {
+            counters.set(c, v);
+        }
+        counters
+    }
+}
+
 impl Debug for Counters {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut s = f.debug_struct("Counters");
@@ -163,6 +201,16 @@ mod test {
             .all(|(c, v)| (c == Counter::Files) == (v == 2)));
     }
 
+    #[test]
+    fn clone_copies_values() {
+        let counters = Counters::default();
+        counters.count(Counter::Files, 2);
+        let copy = counters.clone();
+        counters.count(Counter::Files, 1);
+        assert_eq!(copy.get(Counter::Files), 2);
+        assert_eq!(counters.get(Counter::Files), 3);
+    }
+
     #[test]
     fn debug_form() {
         let counters = Counters::default();
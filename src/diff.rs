@@ -15,10 +15,14 @@
 //!
 //! See also [conserve::show_diff] to format the diff as text.
 
+use std::io::{self, Read};
 use std::sync::Arc;
 
+use blake2_rfc::blake2b::Blake2b;
 use readahead_iterator::IntoReadahead;
 
+use crate::content_manifest::hash_file;
+use crate::merge::MatchedEntries;
 use crate::monitor::Monitor;
 use crate::*;
 
@@ -26,8 +30,13 @@ use crate::*;
 pub struct DiffOptions {
     pub exclude: Exclude,
     pub include_unchanged: bool,
+    /// When comparing to a live tree, also compare the content of files whose
+    /// metadata is unchanged.
+    ///
+    /// The live file is always read, but the stored file's blocks are only read if
+    /// its index entry doesn't record the hash of its content.
+    pub verify_content: bool,
     // TODO: An option to filter to a subtree?
-    // TODO: Optionally compare all the content?
 }
 
 impl Default for DiffOptions {
@@ -35,19 +44,22 @@ impl Default for DiffOptions {
         DiffOptions {
             exclude: Exclude::nothing(),
             include_unchanged: false,
+            verify_content: false,
         }
     }
 }
 
 /// Generate an iter of per-entry diffs between two trees.
+///
+/// Changes are returned in apath order. Entries that can't be read from either
+/// tree are logged and left out, rather than ending the iteration.
 pub fn diff(
     st: &StoredTree,
     lt: &LiveTree,
     options: &DiffOptions,
     monitor: Arc<dyn Monitor>,
-) -> Result<impl Iterator<Item = EntryChange>> {
+) -> Result<Diff> {
     let readahead = 1000;
-    let include_unchanged: bool = options.include_unchanged; // Copy out to avoid lifetime problems in the callback
     let ait = st
         .iter_entries(Apath::root(), options.exclude.clone(), monitor.clone())?
         .readahead(readahead);
@@ -55,7 +67,117 @@ pub fn diff(
         .iter_entries(Apath::root(), options.exclude.clone(), monitor.clone())?
         .filter(|le| le.kind() != Kind::Unknown)
         .readahead(readahead);
-    Ok(MergeTrees::new(ait, bit)
-        .map(|me| me.to_entry_change())
-        .filter(move |c: &EntryChange| include_unchanged || !c.change.is_unchanged()))
+    if !options.verify_content {
+        return Ok(Diff::merge(ait, bit, options.include_unchanged));
+    }
+    let block_dir = st.block_dir.clone();
+    let lt = lt.clone();
+    let changes = MergeTrees::new(ait, bit).map(move |me| {
+        let change = me.to_entry_change();
+        match me {
+            MatchedEntries::Both(se, le)
+                if change.change.is_unchanged() && se.kind() == Kind::File =>
+            {
+                match content_matches(&se, &le, &lt, &block_dir, monitor.clone()) {
+                    Ok(true) => change,
+                    Ok(false) => EntryChange::changed(&se, &le),
+                    Err(err) => {
+                        monitor.error(err);
+                        change
+                    }
+                }
+            }
+            _ => change,
+        }
+    });
+    Ok(Diff::new(changes, options.include_unchanged))
+}
+
+/// True if a live file has the same content as a stored file.
+fn content_matches(
+    stored: &IndexEntry,
+    live: &EntryValue,
+    lt: &LiveTree,
+    block_dir: &BlockDir,
+    monitor: Arc<dyn Monitor>,
+) -> Result<bool> {
+    let stored_hash = hash_file(stored, block_dir, monitor)?.blake2b;
+    let mut file = lt.open_file(live)?;
+    let mut hasher = Blake2b::new(BLAKE_HASH_SIZE_BYTES);
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let len = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(source) => {
+                return Err(Error::ReadSourceFile {
+                    path: lt.relative_path(live.apath()),
+                    source,
+                })
+            }
+        };
+        hasher.update(&buf[..len]);
+    }
+    Ok(BlockHash::from(hasher.finalize()) == stored_hash)
+}
+
+/// Generate an iter of per-entry diffs between two stored trees, such as the
+/// changes made by the backups after `old` up to and including `new`.
+///
+/// Entries only in `old` are reported as deleted, and those only in `new` as added.
+pub fn diff_stored_trees(
+    old: &StoredTree,
+    new: &StoredTree,
+    options: &DiffOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<Diff> {
+    let readahead = 1000;
+    let ait = old
+        .iter_entries(Apath::root(), options.exclude.clone(), monitor.clone())?
+        .readahead(readahead);
+    let bit = new
+        .iter_entries(Apath::root(), options.exclude.clone(), monitor.clone())?
+        .readahead(readahead);
+    Ok(Diff::merge(ait, bit, options.include_unchanged))
+}
+
+/// An iterator of the changes between two trees, returned by [diff].
+///
+/// This is an ordinary [Iterator], so changes can be filtered or collected with
+/// the usual adaptors, and it can be stored in a struct by name.
+pub struct Diff {
+    changes: Box<dyn Iterator<Item = EntryChange>>,
+}
+
+impl Diff {
+    fn merge<AE, BE, AIT, BIT>(ait: AIT, bit: BIT, include_unchanged: bool) -> Diff
+    where
+        AE: EntryTrait + 'static,
+        BE: EntryTrait + 'static,
+        AIT: Iterator<Item = AE> + 'static,
+        BIT: Iterator<Item = BE> + 'static,
+    {
+        Diff::new(
+            MergeTrees::new(ait, bit).map(|me| me.to_entry_change()),
+            include_unchanged,
+        )
+    }
+
+    fn new(changes: impl Iterator<Item = EntryChange> + 'static, include_unchanged: bool) -> Diff {
+        Diff {
+            changes: Box::new(
+                changes
+                    .filter(move |c: &EntryChange| include_unchanged || !c.change.is_unchanged()),
+            ),
+        }
+    }
+}
+
+impl Iterator for Diff {
+    type Item = EntryChange;
+
+    fn next(&mut self) -> Option<EntryChange> {
+        self.changes.next()
+    }
 }
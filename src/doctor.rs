@@ -0,0 +1,242 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Look for common problems in an archive, and suggest how to fix them.
+//!
+//! This only reads the archive, and is much quicker than validating it, because it
+//! doesn't read the indexes or blocks.
+
+use std::fmt;
+
+use itertools::Itertools;
+use time::OffsetDateTime;
+
+use crate::band::Info;
+use crate::*;
+
+/// A problem found by [doctor].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Problem {
+    /// The archive is locked for garbage collection.
+    GcLocked,
+    /// The archive is locked by a process writing to it.
+    WriteLocked { holder: LockHolder },
+    /// The most recent backup is incomplete.
+    LastBandIncomplete { band_id: BandId },
+    /// A band started earlier than the band before it.
+    BandTimesOutOfOrder {
+        band_id: BandId,
+        start_time: OffsetDateTime,
+        previous_band_id: BandId,
+        previous_start_time: OffsetDateTime,
+    },
+    /// Temporary files are left in the block directory.
+    TempFiles { paths: Vec<String> },
+    /// Files or directories in the block directory that aren't blocks in their
+    /// expected subdirectory.
+    UnexpectedBlockDirFiles { paths: Vec<String> },
+}
+
+impl Problem {
+    /// Describe how to fix the problem.
+    pub fn advice(&self) -> String {
+        match self {
+            Problem::GcLocked => "If no `conserve gc` or `conserve delete` is running, \
+                the lock was left by one that was interrupted: break it by running \
+                `conserve gc --break-lock`."
+                .to_owned(),
+            Problem::WriteLocked { .. } => "If that process is no longer running, the lock \
+                was left by an interrupted backup, delete, or gc: break it by running \
+                `conserve backup --break-lock` or `conserve gc --break-lock`."
+                .to_owned(),
+            Problem::LastBandIncomplete { band_id } => format!(
+                "If no backup is running, the last one was interrupted: run another \
+                backup, which will skip files already stored. Until a backup completes, \
+                `conserve gc` and `conserve delete` will refuse to run. Files stored so \
+                far can be restored with `conserve restore --backup {band_id}`."
+            ),
+            Problem::BandTimesOutOfOrder { .. } => {
+                "The system clock was probably wrong when one of these backups was made. \
+                Backups are ordered by their ids, so this is harmless, but check the clock."
+                    .to_owned()
+            }
+            Problem::TempFiles { .. } => {
+                "These were left by an interrupted write. They're not used, and \
+                can be deleted when no backup is running."
+                    .to_owned()
+            }
+            Problem::UnexpectedBlockDirFiles { .. } => {
+                "These aren't written by Conserve. Check how they got there, and then \
+                move them out of the archive. Run `conserve validate` to check nothing \
+                is missing."
+                    .to_owned()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::GcLocked => write!(f, "Archive is locked for garbage collection"),
+            Problem::WriteLocked { holder } => write!(f, "Archive is locked by {holder}"),
+            Problem::LastBandIncomplete { band_id } => {
+                write!(f, "The last backup, {band_id}, is incomplete")
+            }
+            Problem::BandTimesOutOfOrder {
+                band_id,
+                start_time,
+                previous_band_id,
+                previous_start_time,
+            } => write!(
+                f,
+                "Backup {band_id} started at {start_time}, before the previous backup \
+                {previous_band_id} at {previous_start_time}"
+            ),
+            Problem::TempFiles { paths } => write!(
+                f,
+                "{} temporary files in the block directory: {}",
+                paths.len(),
+                list_some(paths)
+            ),
+            Problem::UnexpectedBlockDirFiles { paths } => write!(
+                f,
+                "{} unexpected files in the block directory: {}",
+                paths.len(),
+                list_some(paths)
+            ),
+        }
+    }
+}
+
+/// Format the first few of a possibly long list of paths.
+fn list_some(paths: &[String]) -> String {
+    const SHOW: usize = 5;
+    let mut s = paths.iter().take(SHOW).join(", ");
+    if paths.len() > SHOW {
+        s.push_str(", ...");
+    }
+    s
+}
+
+/// Check an archive for common problems, returning any that are found.
+///
+/// Errors reading the archive are returned as errors rather than problems.
+pub fn doctor(archive: &Archive) -> Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+    if GarbageCollectionLock::is_locked(archive)? {
+        problems.push(Problem::GcLocked);
+    }
+    if let Some(holder) = WriteLock::holder(archive)? {
+        problems.push(Problem::WriteLocked { holder });
+    }
+    let infos = match band_manifest::read(archive) {
+        Some(infos) => infos,
+        None => archive
+            .list_band_ids()?
+            .into_iter()
+            .map(|band_id| Band::open(archive, band_id)?.get_info())
+            .collect::<Result<Vec<Info>>>()?,
+    };
+    if let Some(last) = infos.last() {
+        // The manifest might be out of date about whether the last band is complete.
+        if !archive.band_is_closed(last.id)? {
+            problems.push(Problem::LastBandIncomplete { band_id: last.id });
+        }
+    }
+    for pair in infos.windows(2) {
+        if pair[1].start_time < pair[0].start_time {
+            problems.push(Problem::BandTimesOutOfOrder {
+                band_id: pair[1].id,
+                start_time: pair[1].start_time,
+                previous_band_id: pair[0].id,
+                previous_start_time: pair[0].start_time,
+            });
+        }
+    }
+    let (temp_files, unexpected) = archive.block_dir().stray_files()?;
+    if !temp_files.is_empty() {
+        problems.push(Problem::TempFiles { paths: temp_files });
+    }
+    if !unexpected.is_empty() {
+        problems.push(Problem::UnexpectedBlockDirFiles { paths: unexpected });
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::monitor::test::TestMonitor;
+    use crate::test_fixtures::ScratchArchive;
+    use crate::transport::local::new_temp_file;
+
+    #[test]
+    fn temp_file_left_by_interrupted_write_is_temporary() {
+        let af = ScratchArchive::new();
+        let hash = af
+            .block_dir()
+            .store_or_deduplicate(
+                Bytes::from("stuff"),
+                false,
+                &mut BackupStats::default(),
+                TestMonitor::arc(),
+            )
+            .unwrap();
+        // Start replacing the block, as when a corrupt block is overwritten, but stop
+        // before the new content is renamed into place, as if the process was killed.
+        let subdir = hash.to_string()[..3].to_owned();
+        let (_file, temp_path) = new_temp_file(&af.path().join("d").join(&subdir))
+            .unwrap()
+            .keep()
+            .unwrap();
+        let temp_name = temp_path.file_name().unwrap().to_str().unwrap();
+
+        assert_eq!(
+            doctor(&af).unwrap(),
+            [Problem::TempFiles {
+                paths: vec![format!("{subdir}/{temp_name}")]
+            }]
+        );
+    }
+
+    #[test]
+    fn band_started_before_previous_band() {
+        let af = ScratchArchive::new();
+        let start_time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let clock = Arc::new(FixedClock::new(start_time));
+        let archive = Archive::open_path(af.path())
+            .unwrap()
+            .with_clock(clock.clone());
+        Band::create(&archive).unwrap().close(0).unwrap();
+        clock.set(start_time - Duration::from_secs(3600));
+        Band::create(&archive).unwrap().close(0).unwrap();
+
+        assert_eq!(
+            doctor(&archive).unwrap(),
+            [Problem::BandTimesOutOfOrder {
+                band_id: BandId::new(&[1]),
+                start_time: start_time - Duration::from_secs(3600),
+                previous_band_id: BandId::zero(),
+                previous_start_time: start_time,
+            }]
+        );
+    }
+}
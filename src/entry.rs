@@ -41,10 +41,17 @@ pub trait EntryTrait: Debug {
 #[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 #[serde(tag = "kind")]
 pub enum KindMeta {
-    File { size: u64 },
+    File {
+        size: u64,
+    },
     Dir,
-    Symlink { target: String },
-    Unknown,
+    Symlink {
+        target: String,
+    },
+    /// A special file that can't be stored.
+    Unknown {
+        special: SpecialKind,
+    },
 }
 
 impl From<&KindMeta> for Kind {
@@ -53,7 +60,7 @@ impl From<&KindMeta> for Kind {
             KindMeta::Dir => Kind::Dir,
             KindMeta::File { .. } => Kind::File,
             KindMeta::Symlink { .. } => Kind::Symlink,
-            KindMeta::Unknown => Kind::Unknown,
+            KindMeta::Unknown { .. } => Kind::Unknown,
         }
     }
 }
@@ -17,20 +17,42 @@ use std::borrow::Cow;
 use std::io;
 use std::path::PathBuf;
 
+use itertools::Itertools;
 use thiserror::Error;
 
 use crate::*;
 
 /// Conserve specific error.
 #[non_exhaustive]
-#[derive(Debug, Error)]
+#[derive(Debug, Error, strum_macros::IntoStaticStr)]
 pub enum Error {
     #[error("Block file {hash:?} corrupt: does not have the expected hash")]
     BlockCorrupt { hash: BlockHash },
 
+    #[error("Existing block {hash} is corrupt; storing it again")]
+    DeduplicatedBlockCorrupt { hash: BlockHash },
+
+    #[error("Hash collision: stored block {hash} differs from new content with the same hash")]
+    BlockHashCollision { hash: BlockHash },
+
     #[error("Referenced block {hash} is missing")]
     BlockMissing { hash: BlockHash },
 
+    #[error("Invalid block hash prefix {prefix:?}: expected up to 128 hex digits")]
+    InvalidBlockHashPrefix { prefix: String },
+
+    #[error("No block hash starts with {prefix:?}")]
+    NoBlockWithPrefix { prefix: String },
+
+    #[error(
+        "Block hash prefix {prefix:?} is ambiguous; it matches: {}",
+        candidates.iter().join(", ")
+    )]
+    AmbiguousBlockHashPrefix {
+        prefix: String,
+        candidates: Vec<BlockHash>,
+    },
+
     #[error("Block {hash} is too short: actual len {actual_len}, referenced len {referenced_len}")]
     BlockTooShort {
         hash: BlockHash,
@@ -47,6 +69,20 @@ pub enum Error {
     #[error("Not a Conserve archive (no CONSERVE header found)")]
     NotAnArchive,
 
+    #[error("{archive:?} is not a Conserve archive, but {source_path:?} is: give the archive first, then the source directory")]
+    DiffArgumentsSwapped {
+        archive: String,
+        source_path: PathBuf,
+    },
+
+    #[error(
+        "{archive:?} is not a Conserve archive: diff compares an archive to a source directory"
+    )]
+    DiffArchiveIsNotAnArchive { archive: String },
+
+    #[error("{source_path:?} is a Conserve archive, not a source directory: diff compares an archive to a source directory")]
+    DiffSourceIsAnArchive { source_path: PathBuf },
+
     #[error(
         "Archive version {:?} is not supported by Conserve {}",
         version,
@@ -54,6 +90,20 @@ pub enum Error {
     )]
     UnsupportedArchiveVersion { version: String },
 
+    #[error(
+        "Archive format version {found:?} is newer than version {supported:?} supported by Conserve {}; upgrade Conserve to read it",
+        crate::version()
+    )]
+    ArchiveFormatTooNew { found: String, supported: String },
+
+    #[error("Archive content is filtered through external commands, which must be configured to read or write it")]
+    ArchiveNeedsExternalFilter,
+
+    #[error(
+        "Archive was not written with external filter commands, so they can't be used with it"
+    )]
+    ArchiveNotExternallyFiltered,
+
     #[error("Unsupported band version {version:?} in {band_id}")]
     UnsupportedBandVersion { band_id: BandId, version: String },
 
@@ -72,12 +122,32 @@ pub enum Error {
     #[error("Destination directory is not empty")]
     DestinationNotEmpty,
 
+    #[error("Restoring only metadata can't overwrite existing files, which would be left empty")]
+    MetadataOnlyOverwrite,
+
     #[error("Directory for new archive is not empty")]
     NewArchiveDirectoryNotEmpty,
 
+    #[error("Can't create an archive inside the existing archive {archive:?}")]
+    NewArchiveInsideArchive { archive: PathBuf },
+
+    #[error("Backup source {source_path:?} is inside the archive")]
+    BackupSourceInsideArchive { source_path: PathBuf },
+
+    #[error("Backup source contains the archive at {apath}; exclude it to back up this source")]
+    ArchiveInsideBackupSource { apath: Apath },
+
     #[error("Invalid backup version number {:?}", version)]
     InvalidVersion { version: String },
 
+    #[error(
+        "Invalid selection of backups {selection:?}: expected a range like b5..b8, or latest:N"
+    )]
+    InvalidBandsSelection { selection: String },
+
+    #[error("Invalid range of backups: {start} is after {end}")]
+    ReversedBandsRange { start: BandId, end: BandId },
+
     #[error("Band {band_id} head file missing")]
     BandHeadMissing { band_id: BandId },
 
@@ -96,6 +166,21 @@ pub enum Error {
     #[error("A backup was created while the garbage collection lock was held; CHECK ARCHIVE NOW")]
     GarbageCollectionLockHeldDuringBackup,
 
+    #[error("Backup interrupted: {band_id} is incomplete, but the next backup won't need to store its files again")]
+    BackupInterrupted { band_id: BandId },
+
+    #[error("Archive is locked by {holder}")]
+    ArchiveLocked { holder: LockHolder },
+
+    #[error("Exclude patterns can be read from stdin only once")]
+    ExcludeFromStdinRepeated,
+
+    #[error("Exclude pattern {pattern:?} contains a newline")]
+    ExcludePatternHasNewline { pattern: String },
+
+    #[error("No value is set for {key:?} in the archive metadata")]
+    MetadataKeyNotFound { key: String },
+
     #[error(transparent)]
     ParseGlob {
         #[from]
@@ -121,21 +206,28 @@ pub enum Error {
     #[error("Band not found: {band_id}")]
     BandNotFound { band_id: BandId },
 
+    #[error("Index hunk {hunk_number} not found in {band_id}, which has {hunk_count} hunks")]
+    IndexHunkNotFound {
+        band_id: BandId,
+        hunk_number: u32,
+        hunk_count: usize,
+    },
+
     #[error("Failed to list bands: {source}")]
     ListBands { source: io::Error },
 
     #[error("Failed to read source file {path:?}: {source}")]
     ReadSourceFile { path: PathBuf, source: io::Error },
 
-    #[error("Unsupported source file kind: {path:?}")]
-    UnsupportedSourceKind { path: PathBuf },
-
     #[error("Unsupported symlink encoding: {path:?}")]
     UnsupportedTargetEncoding { path: PathBuf },
 
     #[error("Failed to read source tree {path:?}: {source}")]
     ListSourceTree { path: PathBuf, source: io::Error },
 
+    #[error("Source tree {path:?} is a symlink that can't be resolved: {source}")]
+    BrokenSourceSymlink { path: PathBuf, source: io::Error },
+
     #[error("Failed to restore file {path:?}: {source}")]
     RestoreFile { path: PathBuf, source: io::Error },
 
@@ -149,6 +241,39 @@ pub enum Error {
         source: Box<Error>,
     },
 
+    #[error(
+        "Can't restore {apath} because the destination doesn't distinguish it from {existing}"
+    )]
+    RestoreCaseCollision { apath: Apath, existing: Apath },
+
+    #[error("Path {apath} is {len} bytes long, more than the limit of {limit}")]
+    PathTooLong {
+        apath: Apath,
+        len: usize,
+        limit: usize,
+    },
+
+    #[error("Path {apath} is {depth} directories deep, more than the limit of {limit}")]
+    PathTooDeep {
+        apath: Apath,
+        depth: usize,
+        limit: usize,
+    },
+
+    #[error("Can't restore {path:?}: the path is {len} bytes long, more than the destination's limit of {limit}")]
+    RestorePathTooLong {
+        path: PathBuf,
+        len: usize,
+        limit: usize,
+    },
+
+    #[error("Can't restore {path:?}: the name is {len} bytes long, more than the destination's limit of {limit}")]
+    RestoreNameTooLong {
+        path: PathBuf,
+        len: usize,
+        limit: usize,
+    },
+
     #[error("Failed to restore directory {path:?}: {source}")]
     RestoreDirectory { path: PathBuf, source: io::Error },
 
@@ -161,6 +286,15 @@ pub enum Error {
     #[error("Failed to restore modification time on {path:?}: {source}")]
     RestoreModificationTime { path: PathBuf, source: io::Error },
 
+    #[error("Can't restore {apath}: it's not a relative path on this platform")]
+    RestoreUnsafeApath { apath: Apath },
+
+    #[error("Can't restore {apath}: {path:?} is outside the destination, through a symlink")]
+    RestoreOutsideDestination { apath: Apath, path: PathBuf },
+
+    #[error("Failed to remove {path:?} from the restore destination: {source}")]
+    RestoreRemove { path: PathBuf, source: io::Error },
+
     #[error("Unsupported URL scheme {:?}", scheme)]
     UrlScheme { scheme: String },
 
@@ -193,6 +327,9 @@ pub enum Error {
         source: snap::Error,
     },
 
+    #[error("Zstd compression error: {source}")]
+    ZstdCompressionError { source: io::Error },
+
     #[error(transparent)]
     Transport {
         #[from]
@@ -207,6 +344,98 @@ pub enum Error {
     },
 }
 
+impl Error {
+    /// A stable code for this kind of error, such as `DESTINATION_NOT_EMPTY`, so that
+    /// programs can tell errors apart without parsing the messages.
+    ///
+    /// Codes are upper case words separated by underscores. They won't change in
+    /// future releases, although new codes may be added.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::BlockCorrupt { .. } => "BLOCK_CORRUPT",
+            Error::DeduplicatedBlockCorrupt { .. } => "DEDUPLICATED_BLOCK_CORRUPT",
+            Error::BlockHashCollision { .. } => "BLOCK_HASH_COLLISION",
+            Error::BlockMissing { .. } => "BLOCK_MISSING",
+            Error::InvalidBlockHashPrefix { .. } => "INVALID_BLOCK_HASH_PREFIX",
+            Error::NoBlockWithPrefix { .. } => "NO_BLOCK_WITH_PREFIX",
+            Error::AmbiguousBlockHashPrefix { .. } => "AMBIGUOUS_BLOCK_HASH_PREFIX",
+            Error::BlockTooShort { .. } => "BLOCK_TOO_SHORT",
+            Error::ListBlocks { .. } => "LIST_BLOCKS",
+            Error::NotAnArchive => "NOT_AN_ARCHIVE",
+            Error::DiffArgumentsSwapped { .. } => "DIFF_ARGUMENTS_SWAPPED",
+            Error::DiffArchiveIsNotAnArchive { .. } => "DIFF_ARCHIVE_IS_NOT_AN_ARCHIVE",
+            Error::DiffSourceIsAnArchive { .. } => "DIFF_SOURCE_IS_AN_ARCHIVE",
+            Error::UnsupportedArchiveVersion { .. } => "UNSUPPORTED_ARCHIVE_VERSION",
+            Error::ArchiveFormatTooNew { .. } => "ARCHIVE_FORMAT_TOO_NEW",
+            Error::ArchiveNeedsExternalFilter => "ARCHIVE_NEEDS_EXTERNAL_FILTER",
+            Error::ArchiveNotExternallyFiltered => "ARCHIVE_NOT_EXTERNALLY_FILTERED",
+            Error::UnsupportedBandVersion { .. } => "UNSUPPORTED_BAND_VERSION",
+            Error::ArchiveEmpty => "ARCHIVE_EMPTY",
+            Error::NoCompleteBands => "NO_COMPLETE_BANDS",
+            Error::UnsupportedBandFormatFlags { .. } => "UNSUPPORTED_BAND_FORMAT_FLAGS",
+            Error::DestinationNotEmpty => "DESTINATION_NOT_EMPTY",
+            Error::MetadataOnlyOverwrite => "METADATA_ONLY_OVERWRITE",
+            Error::NewArchiveDirectoryNotEmpty => "NEW_ARCHIVE_DIRECTORY_NOT_EMPTY",
+            Error::NewArchiveInsideArchive { .. } => "NEW_ARCHIVE_INSIDE_ARCHIVE",
+            Error::BackupSourceInsideArchive { .. } => "BACKUP_SOURCE_INSIDE_ARCHIVE",
+            Error::ArchiveInsideBackupSource { .. } => "ARCHIVE_INSIDE_BACKUP_SOURCE",
+            Error::InvalidVersion { .. } => "INVALID_VERSION",
+            Error::InvalidBandsSelection { .. } => "INVALID_BANDS_SELECTION",
+            Error::ReversedBandsRange { .. } => "REVERSED_BANDS_RANGE",
+            Error::BandHeadMissing { .. } => "BAND_HEAD_MISSING",
+            Error::DeleteWithIncompleteBackup { .. } => "DELETE_WITH_INCOMPLETE_BACKUP",
+            Error::DeleteWithConcurrentActivity => "DELETE_WITH_CONCURRENT_ACTIVITY",
+            Error::GarbageCollectionLockHeld => "GARBAGE_COLLECTION_LOCK_HELD",
+            Error::GarbageCollectionLockHeldDuringBackup => {
+                "GARBAGE_COLLECTION_LOCK_HELD_DURING_BACKUP"
+            }
+            Error::BackupInterrupted { .. } => "BACKUP_INTERRUPTED",
+            Error::ArchiveLocked { .. } => "ARCHIVE_LOCKED",
+            Error::ExcludeFromStdinRepeated => "EXCLUDE_FROM_STDIN_REPEATED",
+            Error::ExcludePatternHasNewline { .. } => "EXCLUDE_PATTERN_HAS_NEWLINE",
+            Error::MetadataKeyNotFound { .. } => "METADATA_KEY_NOT_FOUND",
+            Error::ParseGlob { .. } => "PARSE_GLOB",
+            Error::DeserializeJson { .. } => "DESERIALIZE_JSON",
+            Error::SerializeJson { .. } => "SERIALIZE_JSON",
+            Error::InvalidMetadata { .. } => "INVALID_METADATA",
+            Error::BandNotFound { .. } => "BAND_NOT_FOUND",
+            Error::IndexHunkNotFound { .. } => "INDEX_HUNK_NOT_FOUND",
+            Error::ListBands { .. } => "LIST_BANDS",
+            Error::ReadSourceFile { .. } => "READ_SOURCE_FILE",
+            Error::UnsupportedTargetEncoding { .. } => "UNSUPPORTED_TARGET_ENCODING",
+            Error::ListSourceTree { .. } => "LIST_SOURCE_TREE",
+            Error::BrokenSourceSymlink { .. } => "BROKEN_SOURCE_SYMLINK",
+            Error::RestoreFile { .. } => "RESTORE_FILE",
+            Error::RestoreSymlink { .. } => "RESTORE_SYMLINK",
+            Error::RestoreFileBlock { .. } => "RESTORE_FILE_BLOCK",
+            Error::RestoreCaseCollision { .. } => "RESTORE_CASE_COLLISION",
+            Error::PathTooLong { .. } => "PATH_TOO_LONG",
+            Error::PathTooDeep { .. } => "PATH_TOO_DEEP",
+            Error::RestorePathTooLong { .. } => "RESTORE_PATH_TOO_LONG",
+            Error::RestoreNameTooLong { .. } => "RESTORE_NAME_TOO_LONG",
+            Error::RestoreDirectory { .. } => "RESTORE_DIRECTORY",
+            Error::RestoreOwnership { .. } => "RESTORE_OWNERSHIP",
+            Error::RestorePermissions { .. } => "RESTORE_PERMISSIONS",
+            Error::RestoreModificationTime { .. } => "RESTORE_MODIFICATION_TIME",
+            Error::RestoreUnsafeApath { .. } => "RESTORE_UNSAFE_APATH",
+            Error::RestoreOutsideDestination { .. } => "RESTORE_OUTSIDE_DESTINATION",
+            Error::RestoreRemove { .. } => "RESTORE_REMOVE",
+            Error::UrlScheme { .. } => "URL_SCHEME",
+            Error::UnexpectedFile { .. } => "UNEXPECTED_FILE",
+            Error::NotImplemented => "NOT_IMPLEMENTED",
+            Error::MountDestinationExists => "MOUNT_DESTINATION_EXISTS",
+            Error::MountDestinationDoesNotExists => "MOUNT_DESTINATION_DOES_NOT_EXIST",
+            Error::IOError { .. } => "IO_ERROR",
+            Error::SetOwner { .. } => "SET_OWNER",
+            Error::SnapCompressionError { .. } => "SNAP_COMPRESSION_ERROR",
+            Error::ZstdCompressionError { .. } => "ZSTD_COMPRESSION_ERROR",
+            Error::Transport { .. } => "TRANSPORT",
+            #[cfg(windows)]
+            Error::Projection { .. } => "PROJECTION",
+        }
+    }
+}
+
 impl From<jsonio::Error> for Error {
     fn from(value: jsonio::Error) -> Self {
         match value {
@@ -235,6 +464,33 @@ mod test {
         );
     }
 
+    #[test]
+    fn codes_are_stable() {
+        assert_eq!(
+            Error::BlockCorrupt {
+                hash: BlockHash::hash_bytes(b"hello"),
+            }
+            .code(),
+            "BLOCK_CORRUPT"
+        );
+        assert_eq!(Error::NotAnArchive.code(), "NOT_AN_ARCHIVE");
+        assert_eq!(Error::DestinationNotEmpty.code(), "DESTINATION_NOT_EMPTY");
+        assert_eq!(
+            Error::BandNotFound {
+                band_id: BandId::zero()
+            }
+            .code(),
+            "BAND_NOT_FOUND"
+        );
+        assert_eq!(
+            Error::IOError {
+                source: io::Error::from(io::ErrorKind::NotFound)
+            }
+            .code(),
+            "IO_ERROR"
+        );
+    }
+
     #[test]
     fn restore_to_nonexistent_directory() {
         // Hopefully, constructing the error from the `io::ErrorKind` will give consistent
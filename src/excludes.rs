@@ -18,9 +18,15 @@
 //! Patterns that start with a slash match only against full paths from the top
 //! of the tree. Patterns that do not start with a slash match the suffix of the
 //! path.
+//!
+//! Patterns that end with a slash match only directories, and everything inside
+//! them, as in `.gitignore`: `logs/` excludes a directory called `logs` but not a
+//! file of that name. Combined with a leading slash, such as `/*/logs/`, they match
+//! directories at a particular depth.
 
 use std::borrow::Cow;
 use std::fs;
+use std::io::{self, Read};
 use std::iter::empty;
 use std::path::Path;
 
@@ -31,7 +37,13 @@ use super::*;
 /// Describes which files to exclude from a backup, restore, etc.
 #[derive(Clone, Debug)]
 pub struct Exclude {
-    globset: GlobSet,
+    /// Paths matching any of these are excluded.
+    globsets: Vec<GlobSet>,
+    /// Directories matching any of these are excluded. (Their children are matched
+    /// by `globsets`.)
+    dir_globsets: Vec<GlobSet>,
+    /// Patterns match relative to this directory.
+    root: Apath,
     // TODO: Control of matching cachedir.
 }
 
@@ -47,6 +59,8 @@ impl Exclude {
     }
 
     /// Build from a list of exclusion patterns and a list of files containing more patterns.
+    ///
+    /// A file named `-` is read from stdin. It may be given at most once.
     pub fn from_patterns_and_files<I1, A, I2, P>(exclude: I1, exclude_from: I2) -> Result<Exclude>
     where
         I1: IntoIterator<Item = A>,
@@ -55,43 +69,117 @@ impl Exclude {
         P: AsRef<Path>,
     {
         let mut gsb = GlobSetBuilder::new();
+        let mut dir_gsb = GlobSetBuilder::new();
         for pat in exclude {
-            add_pattern(&mut gsb, pat.as_ref())?;
+            add_pattern(&mut gsb, &mut dir_gsb, pat.as_ref())?;
+        }
+        let exclude_from: Vec<P> = exclude_from.into_iter().collect();
+        if exclude_from
+            .iter()
+            .filter(|path| path.as_ref() == Path::new("-"))
+            .count()
+            > 1
+        {
+            return Err(Error::ExcludeFromStdinRepeated);
         }
         for path in exclude_from {
-            add_patterns_from_file(&mut gsb, path.as_ref())?;
+            let path = path.as_ref();
+            if path == Path::new("-") {
+                let mut patterns = String::new();
+                io::stdin().lock().read_to_string(&mut patterns)?;
+                add_patterns_from_str(&mut gsb, &mut dir_gsb, &patterns)?;
+            } else {
+                add_patterns_from_str(&mut gsb, &mut dir_gsb, &fs::read_to_string(path)?)?;
+            }
         }
         Ok(Exclude {
-            globset: gsb.build()?,
+            globsets: vec![gsb.build()?],
+            dir_globsets: vec![dir_gsb.build()?],
+            root: Apath::root(),
         })
     }
 
     /// Exclude nothing, even items that might be excluded by default.
     pub fn nothing() -> Exclude {
         Exclude {
-            globset: GlobSet::empty(),
+            globsets: Vec::new(),
+            dir_globsets: Vec::new(),
+            root: Apath::root(),
         }
     }
 
-    /// True if this apath should be excluded.
+    /// Also exclude everything excluded by `other`.
+    ///
+    /// The patterns from `other` are interpreted relative to this exclude's root.
+    #[must_use]
+    pub fn union(mut self, other: Exclude) -> Exclude {
+        self.globsets.extend(other.globsets);
+        self.dir_globsets.extend(other.dir_globsets);
+        self
+    }
+
+    /// Interpret the patterns relative to a subdirectory rather than the top of the tree.
+    ///
+    /// For example, with a root of `/home/me`, the pattern `/junk` matches `/home/me/junk`.
+    /// Nothing outside the root is excluded.
+    #[must_use]
+    pub fn relative_to(self, root: Apath) -> Exclude {
+        Exclude { root, ..self }
+    }
+
+    /// True if this apath should be excluded, when it's not a directory.
+    ///
+    /// Patterns that match only directories don't match the path itself, but do
+    /// match anything inside a directory they match.
     pub fn matches<'a, A>(&self, apath: &'a A) -> bool
     where
         &'a A: Into<Apath> + 'a,
         A: ?Sized,
     {
-        let apath: Apath = apath.into();
-        self.globset.is_match(apath)
+        self.is_match(apath.into(), false)
+    }
+
+    /// True if an entry of this kind at this apath should be excluded.
+    pub fn matches_kind<'a, A>(&self, apath: &'a A, kind: Kind) -> bool
+    where
+        &'a A: Into<Apath> + 'a,
+        A: ?Sized,
+    {
+        self.is_match(apath.into(), kind == Kind::Dir)
+    }
+
+    fn is_match(&self, apath: Apath, is_dir: bool) -> bool {
+        let relpath = if self.root == Apath::root() {
+            apath.to_string()
+        } else if let Some(relpath) = apath.strip_prefix(&self.root) {
+            format!("/{relpath}")
+        } else {
+            return false;
+        };
+        self.globsets.iter().any(|gs| gs.is_match(&relpath))
+            || (is_dir && self.dir_globsets.iter().any(|gs| gs.is_match(&relpath)))
     }
 }
 
 /// Add one pattern with Conserve's semantics.
-fn add_pattern(gsb: &mut GlobSetBuilder, pattern: &str) -> Result<()> {
+///
+/// Patterns ending in a slash match directories, which are added to `dir_gsb`,
+/// and their children, which are added to `gsb`.
+fn add_pattern(
+    gsb: &mut GlobSetBuilder,
+    dir_gsb: &mut GlobSetBuilder,
+    pattern: &str,
+) -> Result<()> {
+    let (pattern, dir_only) = match pattern.strip_suffix('/') {
+        Some(dir_pattern) if !dir_pattern.is_empty() => (dir_pattern, true),
+        _ => (pattern, false),
+    };
     let pattern: Cow<str> = if pattern.starts_with('/') {
         Cow::Borrowed(pattern)
     } else {
         Cow::Owned(format!("**/{pattern}"))
     };
-    gsb.add(
+    (if dir_only { dir_gsb } else { &mut *gsb }).add(
         GlobBuilder::new(&pattern)
             .literal_separator(true)
             .build()
@@ -106,15 +194,25 @@ fn add_pattern(gsb: &mut GlobSetBuilder, pattern: &str) -> Result<()> {
     Ok(())
 }
 
-fn add_patterns_from_file(gsb: &mut GlobSetBuilder, path: &Path) -> Result<()> {
-    for pat in fs::read_to_string(path)?
+/// Add patterns from the contents of an exclude file, one per line.
+fn add_patterns_from_str(
+    gsb: &mut GlobSetBuilder,
+    dir_gsb: &mut GlobSetBuilder,
+    patterns: &str,
+) -> Result<()> {
+    for pat in patterns_from_str(patterns) {
+        add_pattern(gsb, dir_gsb, pat)?;
+    }
+    Ok(())
+}
+
+/// Split the contents of an exclude file into patterns, skipping blank lines and
+/// comments starting with `#`.
+pub(crate) fn patterns_from_str(patterns: &str) -> impl Iterator<Item = &str> {
+    patterns
         .lines()
         .map(str::trim)
         .filter(|s| !s.starts_with('#') && !s.is_empty())
-    {
-        add_pattern(gsb, pat)?;
-    }
-    Ok(())
 }
 
 #[cfg(test)]
@@ -174,4 +272,57 @@ mod tests {
         let exclude = Exclude::nothing();
         assert!(!exclude.matches("/a"));
     }
+
+    #[test]
+    fn relative_to_subtree() {
+        let exclude = Exclude::from_strings(["/junk", "*.o"])
+            .unwrap()
+            .relative_to("/home/me".into());
+        assert!(exclude.matches("/home/me/junk"));
+        assert!(exclude.matches("/home/me/junk/inner"));
+        assert!(exclude.matches("/home/me/src/a.o"));
+        assert!(!exclude.matches("/home/me"));
+        assert!(!exclude.matches("/home/me/src/junk"));
+        assert!(!exclude.matches("/junk"));
+        assert!(!exclude.matches("/a.o"));
+        assert!(!exclude.matches("/home/meat/junk"));
+    }
+
+    #[test]
+    fn union_excludes_paths_matched_by_either() {
+        let exclude = Exclude::from_strings(["*.tmp"])
+            .unwrap()
+            .union(Exclude::from_strings(["/node_modules"]).unwrap());
+        assert!(exclude.matches("/a.tmp"));
+        assert!(exclude.matches("/node_modules/x.js"));
+        assert!(!exclude.matches("/src/node_modules"));
+        assert!(!exclude.matches("/a.txt"));
+        assert!(Exclude::nothing()
+            .union(Exclude::from_strings(["*.tmp"]).unwrap())
+            .matches("/b/c.tmp"));
+    }
+
+    #[test]
+    fn trailing_slash_matches_only_directories() {
+        let exclude = Exclude::from_strings(["logs/", "/*/cache/"]).unwrap();
+        assert!(exclude.matches_kind("/logs", Kind::Dir));
+        assert!(exclude.matches_kind("/src/logs", Kind::Dir));
+        assert!(!exclude.matches_kind("/logs", Kind::File));
+        assert!(!exclude.matches("/logs"));
+        assert!(exclude.matches("/logs/today.txt"));
+        assert!(exclude.matches_kind("/logs/old", Kind::Dir));
+
+        // Anchored to a depth of two.
+        assert!(exclude.matches_kind("/a/cache", Kind::Dir));
+        assert!(exclude.matches("/a/cache/x"));
+        assert!(!exclude.matches_kind("/cache", Kind::Dir));
+        assert!(!exclude.matches_kind("/a/b/cache", Kind::Dir));
+        assert!(!exclude.matches_kind("/a/cache", Kind::File));
+    }
+
+    #[test]
+    fn stdin_may_only_be_read_once() {
+        let result = Exclude::from_patterns_and_files(["*.tmp"], ["-", "-"]);
+        assert!(matches!(result, Err(Error::ExcludeFromStdinRepeated)));
+    }
 }
@@ -0,0 +1,117 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Find entries matching a pattern in every band of an archive, for example to
+//! locate a file whose directory was renamed between backups.
+
+use std::fmt;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::monitor::Monitor;
+use crate::*;
+
+/// An entry found by [find].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FindMatch {
+    pub band_id: BandId,
+    pub apath: Apath,
+    pub kind: Kind,
+}
+
+impl fmt::Display for FindMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.band_id, self.apath)
+    }
+}
+
+/// Matches apaths against a pattern given to [find].
+enum Matcher {
+    Glob(Exclude),
+    Substring(String),
+}
+
+impl Matcher {
+    fn new(pattern: &str) -> Result<Matcher> {
+        if pattern.contains(['*', '?', '[', '{']) {
+            Ok(Matcher::Glob(Exclude::from_strings([pattern])?))
+        } else {
+            Ok(Matcher::Substring(pattern.to_owned()))
+        }
+    }
+
+    fn matches(&self, apath: &Apath, kind: Kind) -> bool {
+        match self {
+            Matcher::Glob(exclude) => exclude.matches_kind(apath, kind),
+            Matcher::Substring(s) => apath.contains(s.as_str()),
+        }
+    }
+}
+
+/// Find entries in all bands whose apath matches `pattern`, in band order and then
+/// apath order.
+///
+/// A pattern containing glob characters (`*?[{`) matches like an exclude pattern:
+/// a pattern without a slash matches a name in any directory. Otherwise, any apath
+/// containing the pattern matches.
+///
+/// Each band's own index is searched, so entries that an incomplete band didn't
+/// reach aren't reported for that band. Bands that can't be opened are reported to
+/// the monitor as errors and skipped.
+pub fn find(
+    archive: &Archive,
+    pattern: &str,
+    monitor: Arc<dyn Monitor>,
+) -> Result<impl Iterator<Item = FindMatch>> {
+    let matcher = Arc::new(Matcher::new(pattern)?);
+    let archive = archive.clone();
+    Ok(archive
+        .list_band_ids()?
+        .into_iter()
+        .filter_map(move |band_id| match Band::open(&archive, band_id) {
+            Ok(band) => Some((band_id, band)),
+            Err(err) => {
+                monitor.error(err);
+                None
+            }
+        })
+        .flat_map(move |(band_id, band)| {
+            let matcher = matcher.clone();
+            band.index()
+                .iter_entries()
+                .filter(move |entry| matcher.matches(&entry.apath, entry.kind()))
+                .map(move |entry| FindMatch {
+                    band_id,
+                    kind: entry.kind(),
+                    apath: entry.apath,
+                })
+        }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn glob_and_substring_patterns() {
+        let matches = |matcher: &Matcher, apath: &str| matcher.matches(&apath.into(), Kind::File);
+        let glob = Matcher::new("*.txt").unwrap();
+        assert!(matches(&glob, "/notes.txt"));
+        assert!(matches(&glob, "/a/b/notes.txt"));
+        assert!(!matches(&glob, "/notes.txt.bak"));
+        let substring = Matcher::new("otes").unwrap();
+        assert!(matches(&substring, "/a/notes.txt"));
+        assert!(!matches(&substring, "/a/nodes.txt"));
+    }
+}
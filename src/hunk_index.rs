@@ -128,7 +128,12 @@ impl IndexHunkIndex {
                 break;
             }
 
-            if !recursive && hunk.start_path[path.len() + 1..].contains('/') {
+            if !recursive
+                && hunk
+                    .start_path
+                    .strip_prefix(path)
+                    .is_some_and(|rest| rest.contains('/'))
+            {
                 /* hunk does already contain directory content */
                 break;
             }
@@ -140,3 +145,36 @@ impl IndexHunkIndex {
         result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hunk(index: u32, start_path: &str, end_path: &str) -> HunkIndexMeta {
+        HunkIndexMeta {
+            index,
+            start_path: start_path.into(),
+            end_path: end_path.into(),
+        }
+    }
+
+    #[test]
+    fn hunks_for_root_directory() {
+        let hunk_index = IndexHunkIndex {
+            hunks: vec![
+                hunk(0, "/", "/b"),
+                hunk(1, "/c", "/a/x"),
+                hunk(2, "/a/y", "/a/z"),
+                hunk(3, "/a/z/deep", "/b/q"),
+            ],
+        };
+        assert_eq!(
+            hunk_index.find_hunks_for_subdir(&Apath::root(), false),
+            [0, 1]
+        );
+        assert_eq!(
+            hunk_index.find_hunks_for_subdir(&Apath::root(), true),
+            [0, 1, 2, 3]
+        );
+    }
+}
@@ -14,18 +14,24 @@
 //! Index lists the files in a band in the archive.
 
 use std::cmp::Ordering;
+use std::collections::VecDeque;
 use std::iter::Peekable;
+use std::num::NonZeroUsize;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::vec;
 
+use bytes::Bytes;
+
 use crate::transport::Transport;
 use itertools::Itertools;
+use lru::LruCache;
 use time::OffsetDateTime;
 use tracing::{debug, debug_span, error};
 use transport::WriteMode;
 
 use crate::compress::snappy::{Compressor, Decompressor};
+use crate::compress::zstd;
 use crate::counters::Counter;
 use crate::entry::KindMeta;
 use crate::monitor::Monitor;
@@ -82,6 +88,25 @@ pub struct IndexEntry {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub target: Option<String>,
+
+    /// For stored files, the hash of the whole content of the file, computed as it
+    /// was backed up.
+    ///
+    /// This is absent in indexes written by older versions, for empty files, and for
+    /// files whose storage was resumed from an interrupted backup, in which case the
+    /// content can only be hashed by reading its blocks.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<BlockHash>,
+
+    /// For directories, whether the directory had no entries at all in the source.
+    ///
+    /// This is only recorded if [crate::BackupOptions::record_empty_dirs] was set. A
+    /// stored directory with no children that was not empty in the source had all
+    /// its contents excluded or skipped.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub empty_in_source: Option<bool>,
 }
 // GRCOV_EXCLUDE_STOP
 
@@ -98,7 +123,9 @@ impl From<IndexEntry> for EntryValue {
                     .expect("symlink entry should have a target"),
             },
             Kind::Dir => KindMeta::Dir,
-            Kind::Unknown => KindMeta::Unknown,
+            Kind::Unknown => KindMeta::Unknown {
+                special: SpecialKind::Other,
+            },
         };
         EntryValue {
             apath: index_entry.apath,
@@ -163,6 +190,8 @@ impl IndexEntry {
             apath: source.apath().clone(),
             kind: source.kind(),
             addrs: Vec::new(),
+            content_hash: None,
+            empty_in_source: None,
             target: source.symlink_target().map(|t| t.to_owned()),
             mtime: mtime.unix_timestamp(),
             mtime_nanos: mtime.nanosecond(),
@@ -172,6 +201,20 @@ impl IndexEntry {
     }
 }
 
+/// How to compress newly written index hunks.
+///
+/// Each hunk is read back with whichever algorithm it was written with, so bands
+/// and hunks using different algorithms can be mixed in one archive.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum IndexCompression {
+    /// Snappy, which is fast, and can be read by all versions of Conserve.
+    #[default]
+    Snappy,
+    /// Zstandard, which makes indexes several times smaller than Snappy, but can't
+    /// be read by older versions of Conserve.
+    Zstd,
+}
+
 /// Write out index hunks.
 ///
 /// This class is responsible for: remembering the hunk number, and checking that the
@@ -195,6 +238,8 @@ pub struct IndexWriter {
     check_order: apath::DebugCheckOrder,
 
     compressor: Compressor,
+
+    compression: IndexCompression,
 }
 
 /// Accumulate and write out index entries into files in an index directory.
@@ -208,6 +253,15 @@ impl IndexWriter {
             hunks_written: 0,
             check_order: apath::DebugCheckOrder::new(),
             compressor: Compressor::new(),
+            compression: IndexCompression::Snappy,
+        }
+    }
+
+    /// Compress hunks written from now on with this algorithm.
+    pub fn with_compression(self, compression: IndexCompression) -> IndexWriter {
+        IndexWriter {
+            compression,
+            ..self
         }
     }
 
@@ -253,7 +307,10 @@ impl IndexWriter {
         if (self.sequence % HUNKS_PER_SUBDIR) == 0 {
             self.transport.create_dir(&subdir_relpath(self.sequence))?;
         }
-        let compressed_bytes = self.compressor.compress(&json)?;
+        let compressed_bytes = match self.compression {
+            IndexCompression::Snappy => self.compressor.compress(&json)?,
+            IndexCompression::Zstd => zstd::compress(&json)?,
+        };
         self.transport
             .write_file(&relpath, &compressed_bytes, WriteMode::CreateNew)?;
         self.hunks_written += 1;
@@ -277,6 +334,52 @@ fn hunk_relpath(hunk_number: u32) -> String {
     format!("{:05}/{:09}", hunk_number / HUNKS_PER_SUBDIR, hunk_number)
 }
 
+/// An in-memory cache of parsed index hunks, shared by all the bands of an archive.
+///
+/// Hunks are never rewritten once they've been written, so cached content stays
+/// valid until the band is deleted.
+#[derive(Debug)]
+pub(crate) struct IndexHunkCache {
+    hunks: Mutex<LruCache<(BandId, u32), Vec<IndexEntry>>>,
+}
+
+impl IndexHunkCache {
+    /// Make a new cache holding up to `capacity` hunks.
+    pub(crate) fn new(capacity: NonZeroUsize) -> IndexHunkCache {
+        IndexHunkCache {
+            hunks: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    fn get(&self, band_id: BandId, hunk_number: u32) -> Option<Vec<IndexEntry>> {
+        self.hunks
+            .lock()
+            .unwrap()
+            .get(&(band_id, hunk_number))
+            .cloned()
+    }
+
+    fn put(&self, band_id: BandId, hunk_number: u32, entries: Vec<IndexEntry>) {
+        self.hunks
+            .lock()
+            .unwrap()
+            .put((band_id, hunk_number), entries);
+    }
+
+    /// Forget all the hunks from a band, for example because it was deleted.
+    pub(crate) fn remove_band(&self, band_id: BandId) {
+        let mut hunks = self.hunks.lock().unwrap();
+        let keys = hunks
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|(b, _)| *b == band_id)
+            .collect_vec();
+        for key in keys {
+            hunks.pop(&key);
+        }
+    }
+}
+
 /// Utility to read the stored index
 pub struct IndexRead {
     /// Transport pointing to this index directory.
@@ -287,6 +390,45 @@ pub struct IndexRead {
 
     /// Current read statistics of this index
     pub stats: IndexReadStats,
+
+    /// If set, a cache of parsed hunks shared with other readers, and the band
+    /// that this index belongs to.
+    cache: Option<(BandId, Arc<IndexHunkCache>)>,
+
+    /// If set, start reading hunks ahead of the one being returned, when
+    /// iterating through the index.
+    read_ahead: Option<IndexReadAhead>,
+}
+
+/// A hunk being read in the background by an [IndexHunkIter], or None if it was
+/// found in the cache.
+type PendingHunk = Option<mpsc::Receiver<transport::Result<Bytes>>>;
+
+/// How far to read ahead through an index, and the threads that do the reading.
+///
+/// The pool is shared by all the indexes read from one archive.
+#[derive(Clone, Debug)]
+pub(crate) struct IndexReadAhead {
+    hunks: usize,
+    pool: Arc<rayon::ThreadPool>,
+}
+
+impl IndexReadAhead {
+    /// Read up to `hunks` hunks ahead, or None if `hunks` is zero.
+    pub(crate) fn new(hunks: usize) -> Option<IndexReadAhead> {
+        if hunks == 0 {
+            return None;
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(hunks)
+            .thread_name(|i| format!("index-read-{i}"))
+            .build()
+            .expect("Failed to build thread pool");
+        Some(IndexReadAhead {
+            hunks,
+            pool: Arc::new(pool),
+        })
+    }
 }
 
 impl IndexRead {
@@ -301,22 +443,89 @@ impl IndexRead {
             transport,
             decompressor: Decompressor::new(),
             stats: IndexReadStats::default(),
+            cache: None,
+            read_ahead: None,
+        }
+    }
+
+    /// Look up and remember hunks from this band in a shared cache.
+    pub(crate) fn with_cache(self, band_id: BandId, cache: Arc<IndexHunkCache>) -> IndexRead {
+        IndexRead {
+            cache: Some((band_id, cache)),
+            ..self
         }
     }
 
+    /// While iterating, read up to `hunks` hunks ahead of the one being returned,
+    /// in the background, to hide the latency of a remote archive.
+    pub(crate) fn with_read_ahead(self, read_ahead: Option<IndexReadAhead>) -> IndexRead {
+        IndexRead { read_ahead, ..self }
+    }
+
     /// Clone the read index.
     /// Note:
     /// This has several side effects:
     /// - Depending on the implementation of the decompressor, duplicate might not be a cheap option.
     /// - Every read index has its own unique read stats, therefore the clone does not inherit the read stats.
     pub(crate) fn duplicate(&self) -> Self {
-        Self::open(self.transport.clone())
+        IndexRead {
+            cache: self.cache.clone(),
+            read_ahead: self.read_ahead.clone(),
+            ..Self::open(self.transport.clone())
+        }
     }
 
     /// Read and parse a specific hunk
     pub fn read_hunk(&mut self, hunk_number: u32) -> Result<Option<Vec<IndexEntry>>> {
+        if let Some((band_id, cache)) = &self.cache {
+            if let Some(entries) = cache.get(*band_id, hunk_number) {
+                self.stats.cached_index_hunks += 1;
+                return Ok(Some(entries));
+            }
+        }
+        let read = self.transport.read_file(&hunk_relpath(hunk_number));
+        self.parse_hunk(hunk_number, read)
+    }
+
+    /// Start reading a hunk in the background, unless it's in the cache.
+    fn start_read_hunk(&self, read_ahead: &IndexReadAhead, hunk_number: u32) -> PendingHunk {
+        if let Some((band_id, cache)) = &self.cache {
+            if cache.get(*band_id, hunk_number).is_some() {
+                return None;
+            }
+        }
+        let transport = self.transport.clone();
+        let (sender, receiver) = mpsc::sync_channel(1);
+        read_ahead.pool.spawn(move || {
+            // The iterator may have been dropped before the read finished.
+            let _ = sender.send(transport.read_file(&hunk_relpath(hunk_number)));
+        });
+        Some(receiver)
+    }
+
+    /// Wait for a hunk read by [IndexRead::start_read_hunk], and parse it.
+    fn finish_read_hunk(
+        &mut self,
+        hunk_number: u32,
+        pending: PendingHunk,
+    ) -> Result<Option<Vec<IndexEntry>>> {
+        match pending {
+            Some(receiver) => {
+                let read = receiver.recv().expect("index read task panicked");
+                self.parse_hunk(hunk_number, read)
+            }
+            None => self.read_hunk(hunk_number),
+        }
+    }
+
+    /// Decompress and parse the content of a hunk file, and remember it in the cache.
+    fn parse_hunk(
+        &mut self,
+        hunk_number: u32,
+        read: transport::Result<Bytes>,
+    ) -> Result<Option<Vec<IndexEntry>>> {
         let path = hunk_relpath(hunk_number);
-        let compressed_bytes = match self.transport.read_file(&path) {
+        let compressed_bytes = match read {
             Ok(b) => b,
             Err(err) if err.is_not_found() => {
                 // TODO: Cope with one hunk being missing, while there are still
@@ -328,7 +537,11 @@ impl IndexRead {
         };
         self.stats.index_hunks += 1;
         self.stats.compressed_index_bytes += compressed_bytes.len() as u64;
-        let index_bytes = self.decompressor.decompress(&compressed_bytes)?;
+        let index_bytes = if zstd::is_zstd(&compressed_bytes) {
+            zstd::decompress(&compressed_bytes)?
+        } else {
+            self.decompressor.decompress(&compressed_bytes)?
+        };
         self.stats.uncompressed_index_bytes += index_bytes.len() as u64;
         let entries: Vec<IndexEntry> =
             serde_json::from_slice(&index_bytes).map_err(|source| Error::DeserializeJson {
@@ -338,6 +551,9 @@ impl IndexRead {
         if entries.is_empty() {
             // It's legal, it's just weird - and it can be produced by some old Conserve versions.
         }
+        if let Some((band_id, cache)) = &self.cache {
+            cache.put(*band_id, hunk_number, entries.clone());
+        }
         Ok(Some(entries))
     }
 
@@ -374,6 +590,7 @@ impl IndexRead {
             hunks: hunks.into_iter(),
             index: self,
             after: None,
+            pending: VecDeque::new(),
         }
     }
 
@@ -384,6 +601,7 @@ impl IndexRead {
             hunks,
             index: self,
             after: None,
+            pending: VecDeque::new(),
         }
     }
 }
@@ -396,6 +614,8 @@ pub struct IndexHunkIter {
     pub index: IndexRead,
     /// If set, yield only entries ordered after this apath.
     after: Option<Apath>,
+    /// Hunks being read ahead, in order.
+    pending: VecDeque<(u32, PendingHunk)>,
 }
 
 impl Iterator for IndexHunkIter {
@@ -403,8 +623,8 @@ impl Iterator for IndexHunkIter {
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            let hunk_number = self.hunks.next()?;
-            let entries = match self.index.read_hunk(hunk_number) {
+            let (hunk_number, read) = self.read_next_hunk()?;
+            let entries = match read {
                 Ok(None) => return None,
                 Ok(Some(entries)) => entries,
                 Err(err) => {
@@ -439,6 +659,29 @@ impl Iterator for IndexHunkIter {
 }
 
 impl IndexHunkIter {
+    /// Read the next hunk, after starting to read any hunks after it that should be
+    /// read ahead.
+    fn read_next_hunk(&mut self) -> Option<(u32, Result<Option<Vec<IndexEntry>>>)> {
+        let Some(read_ahead) = self.index.read_ahead.clone() else {
+            let hunk_number = self.hunks.next()?;
+            return Some((hunk_number, self.index.read_hunk(hunk_number)));
+        };
+        while self.pending.len() <= read_ahead.hunks {
+            let Some(hunk_number) = self.hunks.next() else {
+                break;
+            };
+            self.pending.push_back((
+                hunk_number,
+                self.index.start_read_hunk(&read_ahead, hunk_number),
+            ));
+        }
+        let (hunk_number, pending) = self.pending.pop_front()?;
+        Some((
+            hunk_number,
+            self.index.finish_read_hunk(hunk_number, pending),
+        ))
+    }
+
     /// Advance self so that it returns only entries with apaths ordered after `apath`.
     #[must_use]
     pub fn advance_to_after(self, apath: &Apath) -> Self {
@@ -483,7 +726,7 @@ impl<HI: Iterator<Item = Vec<IndexEntry>>> Iterator for IndexEntryIter<HI> {
                 if !self.subtree.is_prefix_of(&entry.apath) {
                     continue;
                 }
-                if self.exclude.matches(&entry.apath) {
+                if self.exclude.matches_kind(&entry.apath, entry.kind) {
                     continue;
                 }
                 return Some(entry);
@@ -564,6 +807,8 @@ mod tests {
             mtime_nanos: 0,
             kind: Kind::File,
             addrs: vec![],
+            content_hash: None,
+            empty_in_source: None,
             target: None,
             unix_mode: Default::default(),
             owner: Default::default(),
@@ -578,6 +823,8 @@ mod tests {
             mtime_nanos: 0,
             kind: Kind::File,
             addrs: vec![],
+            content_hash: None,
+            empty_in_source: None,
             target: None,
             unix_mode: Default::default(),
             owner: Default::default(),
@@ -666,6 +913,60 @@ mod tests {
         assert!(it.next().is_none(), "Expected no more entries");
     }
 
+    /// Write these entries in one hunk with the given compression, and return the
+    /// compressed size.
+    fn write_compressed(
+        testdir: &TempDir,
+        compression: IndexCompression,
+        entries: &[IndexEntry],
+    ) -> usize {
+        let monitor = TestMonitor::arc();
+        let mut ib =
+            IndexWriter::new(Transport::local(testdir.path())).with_compression(compression);
+        ib.append_entries(&mut entries.to_vec());
+        ib.finish(monitor.clone()).unwrap();
+        monitor.get_counter(Counter::IndexWriteCompressedBytes)
+    }
+
+    #[test]
+    fn zstd_hunks_are_smaller_and_read_back_identically() {
+        let entries = (0..5000)
+            .map(|i| sample_entry(&format!("/src/module{:02}/file{i:05}.rs", i / 100)))
+            .collect_vec();
+        let snappy_dir = TempDir::new().unwrap();
+        let snappy_len = write_compressed(&snappy_dir, IndexCompression::Snappy, &entries);
+        let zstd_dir = TempDir::new().unwrap();
+        let zstd_len = write_compressed(&zstd_dir, IndexCompression::Zstd, &entries);
+        assert!(
+            zstd_len * 2 < snappy_len,
+            "zstd index is {zstd_len} bytes and snappy {snappy_len}"
+        );
+        let hunk = std::fs::read(zstd_dir.path().join(hunk_relpath(0))).unwrap();
+        assert!(zstd::is_zstd(&hunk));
+        for dir in [&snappy_dir, &zstd_dir] {
+            let read = IndexRead::open_path(dir.path())
+                .iter_entries()
+                .collect_vec();
+            assert_eq!(read, entries);
+        }
+    }
+
+    #[test]
+    fn hunks_with_different_compression_read_back() {
+        let (testdir, mut ib) = setup();
+        ib.append_entries(&mut vec![sample_entry("/1.1"), sample_entry("/1.2")]);
+        ib.finish_hunk(TestMonitor::arc()).unwrap();
+        let mut ib = ib.with_compression(IndexCompression::Zstd);
+        ib.append_entries(&mut vec![sample_entry("/2.1"), sample_entry("/2.2")]);
+        ib.finish(TestMonitor::arc()).unwrap();
+
+        let names: Vec<String> = IndexRead::open_path(testdir.path())
+            .iter_entries()
+            .map(|entry| entry.apath.into())
+            .collect();
+        assert_eq!(names, ["/1.1", "/1.2", "/2.1", "/2.2"]);
+    }
+
     #[test]
     fn multiple_hunks() {
         let (testdir, mut ib) = setup();
@@ -20,12 +20,15 @@ use std::fs::FileType;
 use serde::{Deserialize, Serialize};
 
 /// Kind of file that can be stored in the archive.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Ord, PartialOrd)]
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Ord, PartialOrd, clap::ValueEnum,
+)]
 pub enum Kind {
     File,
     Dir,
     Symlink,
     /// Unknown file observed in local tree. Shouldn't be stored.
+    #[value(skip)]
     Unknown,
 }
 
@@ -42,3 +45,38 @@ impl From<FileType> for Kind {
         }
     }
 }
+
+/// A kind of special file, such as a device or FIFO, that can't be stored in the
+/// archive, and so is counted and skipped when it's found in a source tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum SpecialKind {
+    BlockDevice,
+    CharDevice,
+    Fifo,
+    Socket,
+    /// Some other kind of file not supported on this platform.
+    Other,
+}
+
+impl From<FileType> for SpecialKind {
+    #[cfg(unix)]
+    fn from(ft: FileType) -> SpecialKind {
+        use std::os::unix::fs::FileTypeExt;
+        if ft.is_block_device() {
+            SpecialKind::BlockDevice
+        } else if ft.is_char_device() {
+            SpecialKind::CharDevice
+        } else if ft.is_fifo() {
+            SpecialKind::Fifo
+        } else if ft.is_socket() {
+            SpecialKind::Socket
+        } else {
+            SpecialKind::Other
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from(_ft: FileType) -> SpecialKind {
+        SpecialKind::Other
+    }
+}
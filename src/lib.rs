@@ -16,16 +16,25 @@ pub mod apath;
 pub mod archive;
 pub mod backup;
 mod band;
+mod band_manifest;
 pub mod bandid;
+mod block_dictionary;
 pub mod blockdir;
 pub mod blockhash;
+mod cas_export;
 pub mod change;
+pub mod chunk;
+pub mod clock;
+mod compact;
 pub mod compress;
+mod content_manifest;
 pub mod counters;
 mod diff;
+pub mod doctor;
 pub mod entry;
 pub mod errors;
 pub mod excludes;
+mod find;
 mod gc_lock;
 mod hunk_index;
 pub mod index;
@@ -50,35 +59,47 @@ mod tree;
 pub mod unix_mode;
 pub mod unix_time;
 pub mod validate;
+mod write_lock;
 
 pub use crate::apath::Apath;
 pub use crate::archive::Archive;
 pub use crate::archive::DeleteOptions;
-pub use crate::backup::{backup, BackupOptions, BackupStats};
-pub use crate::band::{Band, BandSelectionPolicy};
+pub use crate::backup::{
+    backup, BackupOptions, BackupStats, BlockSizeRule, DEFAULT_MAX_ENTRIES_PER_HUNK,
+    LOW_SPACE_WARNING_BYTES,
+};
+pub use crate::band::{Band, BandSelectionPolicy, BandsSelection};
 pub use crate::bandid::BandId;
-pub use crate::blockdir::BlockDir;
+pub use crate::block_dictionary::train_block_dictionary;
+pub use crate::blockdir::{BlockDir, BlockStore, TransportBlockStore, DEFAULT_BLOCK_CACHE_SIZE};
 pub use crate::blockhash::BlockHash;
+pub use crate::cas_export::{cas_export, CasExportFile, CasExportStats, CAS_EXPORT_MANIFEST};
 pub use crate::change::{ChangeCallback, EntryChange};
-pub use crate::diff::{diff, DiffOptions};
+pub use crate::chunk::Chunking;
+pub use crate::compact::{compact, CompactOptions, CompactStats};
+pub use crate::content_manifest::{content_manifest, ManifestEntry};
+pub use crate::diff::{diff, diff_stored_trees, Diff, DiffOptions};
+pub use crate::doctor::doctor;
 pub use crate::entry::{EntryTrait, EntryValue};
 pub use crate::errors::Error;
 pub use crate::excludes::Exclude;
+pub use crate::find::{find, FindMatch};
 pub use crate::gc_lock::GarbageCollectionLock;
-pub use crate::index::{IndexEntry, IndexRead, IndexWriter};
-pub use crate::kind::Kind;
+pub use crate::index::{IndexCompression, IndexEntry, IndexRead, IndexWriter};
+pub use crate::kind::{Kind, SpecialKind};
 pub use crate::live_tree::LiveTree;
 pub use crate::merge::MergeTrees;
 pub use crate::misc::bytes_to_human_mb;
 pub use crate::mount::{mount, MountOptions};
-pub use crate::owner::Owner;
-pub use crate::restore::{restore, RestoreOptions};
-pub use crate::show::{show_versions, ShowVersionsOptions};
-pub use crate::stats::DeleteStats;
+pub use crate::owner::{Owner, OwnerMap};
+pub use crate::restore::{restore, RestoreOptions, RestoreStats};
+pub use crate::show::{show_versions, JsonFormat, ShowVersionsOptions};
+pub use crate::stats::{DeleteStats, IndexReadStats};
 pub use crate::stored_tree::StoredTree;
 pub use crate::tree::{ReadTree, TreeSize};
 pub use crate::unix_mode::UnixMode;
-pub use crate::validate::ValidateOptions;
+pub use crate::validate::{ValidateOptions, ValidationProblem, ValidationReport};
+pub use crate::write_lock::{LockHolder, WriteLock};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -101,6 +122,12 @@ static BAND_HEAD_FILENAME: &str = "BANDHEAD";
 /// Metadata file in the band directory, for closed bands.
 static BAND_TAIL_FILENAME: &str = "BANDTAIL";
 
+/// Progress through storing a large file, in the band directory while it's being written.
+static BAND_PARTIAL_FILENAME: &str = "PARTIAL";
+
+/// Marker in the band directory of a backup that was stopped on request.
+static BAND_INTERRUPTED_FILENAME: &str = "INTERRUPTED";
+
 /// Length of the binary content hash.
 pub(crate) const BLAKE_HASH_SIZE_BYTES: usize = 64;
 
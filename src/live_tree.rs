@@ -14,14 +14,17 @@
 //! Access a "live" on-disk tree as a source for backups, destination for restores, etc.
 
 use std::collections::vec_deque::VecDeque;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io::ErrorKind;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
 use tracing::{error, warn};
 
+use crate::counters::Counter;
 use crate::entry::KindMeta;
 use crate::monitor::Monitor;
 use crate::stats::LiveTreeIterStats;
@@ -31,18 +34,43 @@ use crate::*;
 #[derive(Clone)]
 pub struct LiveTree {
     path: PathBuf,
+    max_files_per_dir: Option<usize>,
 }
 
 impl LiveTree {
     /// Open the live tree rooted at `path`.
+    ///
+    /// If `path` is itself a symlink, it's resolved, so that the tree is the
+    /// directory it points to. Symlinks inside the tree are not followed.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<LiveTree> {
         // TODO: Maybe fail here if the root doesn't exist or isn't a directory?
+        let mut path = path.as_ref().to_path_buf();
+        if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            path = path
+                .canonicalize()
+                .map_err(|source| Error::BrokenSourceSymlink {
+                    path: path.clone(),
+                    source,
+                })?;
+        }
         Ok(LiveTree {
-            path: path.as_ref().to_path_buf(),
+            path,
+            max_files_per_dir: None,
         })
     }
 
-    fn relative_path(&self, apath: &Apath) -> PathBuf {
+    /// Iterate at most this many entries from each directory, the first in order of
+    /// their names, skipping the rest with a warning.
+    ///
+    /// Excluded entries aren't counted. Skipped directories aren't descended into.
+    pub fn with_max_files_per_dir(self, max_files_per_dir: Option<usize>) -> LiveTree {
+        LiveTree {
+            max_files_per_dir,
+            ..self
+        }
+    }
+
+    pub(crate) fn relative_path(&self, apath: &Apath) -> PathBuf {
         apath.below(&self.path)
     }
 
@@ -57,6 +85,113 @@ impl LiveTree {
         let path = self.relative_path(&entry.apath);
         fs::File::open(&path).map_err(|source| Error::ReadSourceFile { path, source })
     }
+
+    /// Return entries for only the listed paths, and the directories containing them,
+    /// in apath order.
+    ///
+    /// Paths may be relative to the root of the tree, or absolute paths inside it.
+    /// Listing a directory includes only the directory itself, not its contents.
+    ///
+    /// Paths that don't exist, that are outside the tree, or that are excluded, are
+    /// skipped with a warning.
+    pub fn listed_entries<I, P>(&self, paths: I, exclude: &Exclude) -> Vec<EntryValue>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        // Metadata of listed paths, which is read to check they exist, and None for
+        // the directories containing them, which are read later.
+        let mut listed: BTreeMap<Apath, Option<fs::Metadata>> = BTreeMap::new();
+        for path in paths {
+            let path = path.as_ref();
+            let apath = match self.apath_for(path) {
+                Ok(apath) => apath,
+                Err(UnlistablePath::OutsideTree) => {
+                    warn!(
+                        "Listed path {path:?} is not inside the source tree {:?}",
+                        self.path
+                    );
+                    continue;
+                }
+                Err(UnlistablePath::NotUtf8) => {
+                    warn!("Listed path {path:?} is not valid UTF-8");
+                    continue;
+                }
+            };
+            let metadata = match fs::symlink_metadata(self.relative_path(&apath)) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    warn!("Listed path {path:?} can't be read: {err}");
+                    continue;
+                }
+            };
+            if exclude.matches_kind(&apath, metadata.file_type().into())
+                || std::iter::successors(apath.parent(), Apath::parent)
+                    .any(|a| exclude.matches_kind(&a, Kind::Dir))
+            {
+                warn!("Listed path {apath} is excluded");
+                continue;
+            }
+            let mut parent = apath.parent();
+            listed.insert(apath, Some(metadata));
+            while let Some(p) = parent {
+                parent = p.parent();
+                listed.entry(p).or_insert(None);
+            }
+        }
+        listed
+            .into_iter()
+            .filter_map(|(apath, metadata)| {
+                let path = self.relative_path(&apath);
+                let metadata = match metadata.map_or_else(|| fs::symlink_metadata(&path), Ok) {
+                    Ok(metadata) => metadata,
+                    Err(err) => {
+                        error!("Failed to read source metadata from {path:?}: {err}");
+                        return None;
+                    }
+                };
+                match entry_from_fs_metadata(apath, &path, &metadata) {
+                    Ok(entry) => Some(entry),
+                    Err(err) => {
+                        warn!("Can't back up listed path {path:?}: {err}");
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Return the apath for a path relative to the tree, or absolute within it.
+    fn apath_for(&self, path: &Path) -> std::result::Result<Apath, UnlistablePath> {
+        let path = if path.is_absolute() {
+            path.strip_prefix(&self.path)
+                .map_err(|_| UnlistablePath::OutsideTree)?
+        } else {
+            path
+        };
+        let mut apath = Apath::root();
+        for component in path.components() {
+            match component {
+                Component::CurDir => (),
+                Component::Normal(name) => {
+                    apath = apath.append(name.to_str().ok_or(UnlistablePath::NotUtf8)?)
+                }
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                    return Err(UnlistablePath::OutsideTree)
+                }
+            }
+        }
+        Ok(apath)
+    }
+}
+
+/// Why a path listed for backup has no apath in the tree.
+#[derive(Debug, PartialEq, Eq)]
+enum UnlistablePath {
+    /// The path is outside the source tree, or climbs out of it.
+    OutsideTree,
+    /// A component of the path isn't valid UTF-8, so can't be stored.
+    NotUtf8,
 }
 
 impl tree::ReadTree for LiveTree {
@@ -67,13 +202,19 @@ impl tree::ReadTree for LiveTree {
         &self,
         subtree: Apath,
         exclude: Exclude,
-        _monitor: Arc<dyn Monitor>,
+        monitor: Arc<dyn Monitor>,
     ) -> Result<Self::IT> {
-        Iter::new(&self.path, subtree, exclude)
+        Iter::new(
+            &self.path,
+            subtree,
+            exclude,
+            self.max_files_per_dir,
+            monitor,
+        )
     }
 }
 
-fn entry_from_fs_metadata(
+pub(crate) fn entry_from_fs_metadata(
     apath: Apath,
     source_path: &Path,
     metadata: &fs::Metadata,
@@ -107,9 +248,9 @@ fn entry_from_fs_metadata(
         };
         KindMeta::Symlink { target }
     } else {
-        return Err(Error::UnsupportedSourceKind {
-            path: source_path.to_owned(),
-        });
+        KindMeta::Unknown {
+            special: metadata.file_type().into(),
+        }
     };
     let owner = Owner::from(metadata);
     let unix_mode = UnixMode::from(metadata.permissions());
@@ -130,7 +271,6 @@ fn entry_from_fs_metadata(
 /// is the defined order for files stored in an archive.  Within those files and
 /// child directories, visit them according to a sorted comparison by their UTF-8
 /// name.
-#[derive(Debug)]
 pub struct Iter {
     /// Root of the source tree.
     root_path: PathBuf,
@@ -148,13 +288,39 @@ pub struct Iter {
     /// Patterns to exclude from iteration.
     exclude: Exclude,
 
+    /// Return at most this many children of each directory.
+    max_files_per_dir: Option<usize>,
+
     stats: LiveTreeIterStats,
+
+    monitor: Arc<dyn Monitor>,
+}
+
+impl fmt::Debug for Iter {
+    #[mutants::skip] // unimportant to test
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Iter")
+            .field("root_path", &self.root_path)
+            .field("dir_deque", &self.dir_deque)
+            .field("entry_deque", &self.entry_deque)
+            .field("check_order", &self.check_order)
+            .field("exclude", &self.exclude)
+            .field("max_files_per_dir", &self.max_files_per_dir)
+            .field("stats", &self.stats)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Iter {
     /// Construct a new iter that will visit everything below this root path,
     /// subject to some exclusions
-    fn new(root_path: &Path, subtree: Apath, exclude: Exclude) -> Result<Iter> {
+    fn new(
+        root_path: &Path,
+        subtree: Apath,
+        exclude: Exclude,
+        max_files_per_dir: Option<usize>,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<Iter> {
         let start_path = subtree.below(root_path);
         let start_metadata = fs::symlink_metadata(&start_path)?;
         // Preload iter to return the root and then recurse into it.
@@ -173,7 +339,9 @@ impl Iter {
             dir_deque,
             check_order: apath::DebugCheckOrder::new(),
             exclude,
+            max_files_per_dir,
             stats: LiveTreeIterStats::default(),
+            monitor,
         })
     }
 
@@ -193,7 +361,6 @@ impl Iter {
                 return;
             }
         };
-        let mut subdir_apaths: Vec<Apath> = Vec::new();
         for dir_entry in dir_iter {
             let dir_entry = match dir_entry {
                 Ok(dir_entry) => dir_entry,
@@ -212,11 +379,6 @@ impl Iter {
             };
             let child_apath = parent_apath.append(child_name);
 
-            if self.exclude.matches(&child_apath) {
-                self.stats.exclusions += 1;
-                continue;
-            }
-
             let ft = match dir_entry.file_type() {
                 Ok(ft) => ft,
                 Err(e) => {
@@ -224,6 +386,10 @@ impl Iter {
                     continue;
                 }
             };
+            if self.exclude.matches_kind(&child_apath, Kind::from(ft)) {
+                self.stats.exclusions += 1;
+                continue;
+            }
             if ft.is_dir() {
                 // TODO: Count them?
                 // TODO: Perhaps an option to back them up anyhow?
@@ -254,17 +420,9 @@ impl Iter {
                 }
             };
 
-            if ft.is_dir() {
-                subdir_apaths.push(child_apath.clone());
-            }
             let child_path = dir_path.join(dir_entry.file_name());
             let entry = match entry_from_fs_metadata(child_apath, &child_path, &metadata) {
                 Ok(entry) => entry,
-                Err(Error::UnsupportedSourceKind { .. }) => {
-                    // It's not too surprising that there would be fifos or sockets or files
-                    // we don't support; don't log them.
-                    continue;
-                }
                 Err(err) => {
                     error!("Failed to build entry for {child_path:?}: {err:?}");
                     continue;
@@ -272,18 +430,30 @@ impl Iter {
             };
             children.push((child_name.to_string(), entry));
         }
+        children.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        if let Some(limit) = self.max_files_per_dir {
+            if children.len() > limit {
+                let skipped = children.len() - limit;
+                warn!(
+                    "Directory {parent_apath} has {} entries, more than the limit of {limit}: \
+                    skipping the last {skipped} by name",
+                    children.len()
+                );
+                children.truncate(limit);
+                self.stats.overflow_entries += skipped;
+                self.monitor.count(Counter::OverflowEntries, skipped);
+            }
+        }
         // To get the right overall tree ordering, any new subdirectories
         // discovered here should be visited together in apath order, but before
         // any previously pending directories. In other words, in reverse order
-        // push them onto the front of the dir deque.
-        if !subdir_apaths.is_empty() {
-            subdir_apaths.sort_unstable();
-            self.dir_deque.reserve(subdir_apaths.len());
-            for a in subdir_apaths.into_iter().rev() {
-                self.dir_deque.push_front(a);
+        // push them onto the front of the dir deque. Children are already sorted
+        // by name, which for siblings is apath order.
+        for (_, entry) in children.iter().rev() {
+            if entry.kind() == Kind::Dir {
+                self.dir_deque.push_front(entry.apath.clone());
             }
         }
-        children.sort_unstable_by(|a, b| a.0.cmp(&b.0));
         self.entry_deque.extend(children.into_iter().map(|x| x.1));
     }
 }
@@ -447,4 +617,30 @@ mod test {
         );
         assert_eq!(names, ["/", "/a"]);
     }
+
+    #[test]
+    fn listed_entries_include_parents_in_order() {
+        let tf = TreeFixture::new();
+        tf.create_dir("a");
+        tf.create_dir("a/b");
+        tf.create_file("a/b/c");
+        tf.create_file("a/z");
+        tf.create_file("a/unlisted");
+        tf.create_file("top");
+        let lt = LiveTree::open(tf.path()).unwrap();
+        let entries = lt.listed_entries(
+            [
+                PathBuf::from("top"),
+                tf.path().join("a/b/c"),
+                PathBuf::from("./a/z"),
+                PathBuf::from("missing"),
+                PathBuf::from("../outside"),
+            ],
+            &Exclude::nothing(),
+        );
+        assert_eq!(
+            entry_iter_to_apath_strings(&entries),
+            ["/", "/a", "/top", "/a/b", "/a/z", "/a/b/c"]
+        );
+    }
 }
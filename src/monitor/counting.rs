@@ -0,0 +1,82 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A monitor that totals counters, for programs that use Conserve as a library
+//! and want to report what an operation did without a terminal UI.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tracing::warn;
+
+use super::task::{Task, TaskList};
+use super::Monitor;
+use crate::counters::{Counter, Counters};
+use crate::Error;
+
+/// A monitor that accumulates the values of all counters, so that they can be
+/// read after the operation.
+///
+/// Non-fatal errors are logged through `tracing` and counted. Tasks are tracked
+/// but not shown.
+#[derive(Default)]
+pub struct CountingMonitor {
+    counters: Counters,
+    error_count: AtomicUsize,
+    task_list: Mutex<TaskList>,
+}
+
+impl CountingMonitor {
+    pub fn new() -> Self {
+        CountingMonitor::default()
+    }
+
+    /// Construct a new CountingMonitor and wrap it in an Arc.
+    pub fn arc() -> Arc<CountingMonitor> {
+        Arc::new(CountingMonitor::new())
+    }
+
+    /// Return a copy of the current values of all counters.
+    pub fn snapshot(&self) -> Counters {
+        self.counters.clone()
+    }
+
+    /// Return the current value of one counter.
+    pub fn get_counter(&self, counter: Counter) -> usize {
+        self.counters.get(counter)
+    }
+
+    /// Return the number of non-fatal errors reported so far.
+    pub fn error_count(&self) -> usize {
+        self.error_count.load(Ordering::Relaxed)
+    }
+}
+
+impl Monitor for CountingMonitor {
+    fn count(&self, counter: Counter, increment: usize) {
+        self.counters.count(counter, increment)
+    }
+
+    fn set_counter(&self, counter: Counter, value: usize) {
+        self.counters.set(counter, value)
+    }
+
+    fn error(&self, error: Error) {
+        warn!("{error}");
+        self.error_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn start_task(&self, name: String) -> Task {
+        self.task_list.lock().unwrap().start_task(name)
+    }
+}
@@ -0,0 +1,365 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A monitor that writes structured events as json, for a GUI or other program
+//! driving Conserve.
+//!
+//! Events are written one json object per line, each with an `event` field giving
+//! its type:
+//!
+//! * `{"event":"task_start","task":1,"name":"Backup"}`: a task started; `task` is an
+//!   id unique within this monitor.
+//! * `{"event":"task_progress","task":1,"name":"Backup","done":10,"total":100}`: the
+//!   name or progress of a running task changed. `total` is 0 if it's not known.
+//! * `{"event":"task_finish","task":1}`: a task finished.
+//! * `{"event":"counter","counter":"Files","value":12}`: the new value of a
+//!   [Counter] that changed.
+//! * `{"event":"error","code":"BLOCK_MISSING","message":"..."}`: a non-fatal error,
+//!   with the stable code from [Error::code].
+//!
+//! Errors and task starts are written as they happen. Task progress, task finishes,
+//! and counters are written at regular intervals, and once more when the monitor is
+//! dropped, so that the last values are always seen.
+//!
+//! New event types and fields may be added in future, so readers should ignore any
+//! they don't understand.
+
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::Duration;
+
+use serde::Serialize;
+use tracing::warn;
+
+use super::task::{Task, TaskList, TaskState};
+use super::Monitor;
+use crate::counters::{Counter, Counters};
+use crate::{Error, Result};
+
+/// How often to write task progress and counter events.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// One event written by an [EventMonitor].
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    TaskStart {
+        task: u64,
+        name: &'a str,
+    },
+    TaskProgress {
+        task: u64,
+        name: &'a str,
+        done: usize,
+        total: usize,
+    },
+    TaskFinish {
+        task: u64,
+    },
+    Counter {
+        counter: &'static str,
+        value: usize,
+    },
+    Error {
+        code: &'static str,
+        message: &'a str,
+    },
+}
+
+/// A monitor that writes json events to a socket, pipe, or file.
+///
+/// It can be used on its own, or attached to another monitor so that it sees
+/// the same events.
+pub struct EventMonitor {
+    state: Arc<State>,
+    /// A thread that periodically writes events for changed tasks and counters.
+    ///
+    /// This is None during drop, when the thread has been joined.
+    poller: Option<JoinHandle<()>>,
+    /// True to ask the poller thread to stop, during drop.
+    stop_poller: Arc<AtomicBool>,
+}
+
+struct State {
+    /// The destination for events, or None if writing to it failed.
+    out: Mutex<Option<BufWriter<Box<dyn Write + Send>>>>,
+    counters: Counters,
+    /// The last value written for each counter, in the order of [Counters::iter].
+    written_counters: Mutex<Vec<usize>>,
+    tasks: Mutex<Vec<WatchedTask>>,
+    next_task_id: AtomicU64,
+}
+
+struct WatchedTask {
+    id: u64,
+    state: Weak<TaskState>,
+    name: String,
+    done: usize,
+    total: usize,
+}
+
+impl EventMonitor {
+    /// Make a monitor that writes events to `out`.
+    pub fn new(out: impl Write + Send + 'static) -> EventMonitor {
+        let state = Arc::new(State {
+            out: Mutex::new(Some(BufWriter::new(Box::new(out)))),
+            counters: Counters::default(),
+            written_counters: Mutex::new(Counters::default().iter().map(|(_, v)| v).collect()),
+            tasks: Mutex::default(),
+            next_task_id: AtomicU64::new(1),
+        });
+        let stop_poller = Arc::new(AtomicBool::new(false));
+        let poller = {
+            let state = state.clone();
+            let stop_poller = stop_poller.clone();
+            spawn(move || {
+                while !stop_poller.load(Relaxed) {
+                    state.poll();
+                    sleep(POLL_INTERVAL);
+                }
+            })
+        };
+        EventMonitor {
+            state,
+            poller: Some(poller),
+            stop_poller,
+        }
+    }
+
+    /// Make a monitor that writes events to the Unix socket, named pipe, or file at `path`.
+    ///
+    /// Unix sockets are connected to; anything else is opened for writing, and
+    /// regular files are appended to.
+    pub fn open(path: &Path) -> Result<EventMonitor> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            use std::os::unix::net::UnixStream;
+            if path
+                .metadata()
+                .is_ok_and(|metadata| metadata.file_type().is_socket())
+            {
+                return Ok(EventMonitor::new(UnixStream::connect(path)?));
+            }
+        }
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(EventMonitor::new(file))
+    }
+
+    /// Write events about a task created by another monitor.
+    pub fn watch_task(&self, task: &Task) {
+        self.state.watch_task(task)
+    }
+}
+
+impl Drop for EventMonitor {
+    fn drop(&mut self) {
+        self.stop_poller.store(true, Relaxed);
+        if let Some(poller) = self.poller.take() {
+            poller.join().expect("Wait for event poller thread to stop");
+        }
+        self.state.poll();
+    }
+}
+
+impl State {
+    fn write_events<'a>(&self, events: impl IntoIterator<Item = Event<'a>>) {
+        let mut out = self.out.lock().unwrap();
+        let Some(writer) = out.as_mut() else {
+            return;
+        };
+        let result = events
+            .into_iter()
+            .try_for_each(|event| {
+                serde_json::to_writer(&mut *writer, &event).map_err(io::Error::from)?;
+                writer.write_all(b"\n")
+            })
+            .and_then(|()| writer.flush());
+        if let Err(err) = result {
+            warn!("Failed to write monitor events, so no more will be written: {err}");
+            *out = None;
+        }
+    }
+
+    fn watch_task(&self, task: &Task) {
+        let id = self.next_task_id.fetch_add(1, Relaxed);
+        let task_state: &TaskState = task.as_ref();
+        let name = task_state.name();
+        self.write_events([Event::TaskStart {
+            task: id,
+            name: &name,
+        }]);
+        self.tasks.lock().unwrap().push(WatchedTask {
+            id,
+            state: task.downgrade(),
+            name,
+            done: 0,
+            total: 0,
+        });
+    }
+
+    /// Write events for tasks and counters that changed since the last poll.
+    fn poll(&self) {
+        let mut tasks = self.tasks.lock().unwrap();
+        let mut finished = Vec::new();
+        let mut changed = Vec::new();
+        tasks.retain_mut(|task| {
+            let Some(state) = task.state.upgrade() else {
+                finished.push(task.id);
+                return false;
+            };
+            let (name, done, total) = (state.name(), state.done(), state.total());
+            if (&name, done, total) != (&task.name, task.done, task.total) {
+                (task.name, task.done, task.total) = (name, done, total);
+                changed.push(task.id);
+            }
+            true
+        });
+        let mut written_counters = self.written_counters.lock().unwrap();
+        let mut counter_events = Vec::new();
+        for ((counter, value), written) in self.counters.iter().zip(written_counters.iter_mut()) {
+            if value != *written {
+                *written = value;
+                counter_events.push(Event::Counter {
+                    counter: counter.into(),
+                    value,
+                });
+            }
+        }
+        let events = tasks
+            .iter()
+            .filter(|task| changed.contains(&task.id))
+            .map(|task| Event::TaskProgress {
+                task: task.id,
+                name: &task.name,
+                done: task.done,
+                total: task.total,
+            })
+            .chain(finished.into_iter().map(|task| Event::TaskFinish { task }))
+            .chain(counter_events);
+        self.write_events(events);
+    }
+}
+
+impl Monitor for EventMonitor {
+    fn count(&self, counter: Counter, increment: usize) {
+        self.state.counters.count(counter, increment)
+    }
+
+    fn set_counter(&self, counter: Counter, value: usize) {
+        self.state.counters.set(counter, value)
+    }
+
+    fn error(&self, error: Error) {
+        self.state.write_events([Event::Error {
+            code: error.code(),
+            message: &error.to_string(),
+        }]);
+    }
+
+    fn start_task(&self, name: String) -> Task {
+        // The task is only referenced by the caller, and by the weak reference used
+        // to watch it.
+        let task = TaskList::default().start_task(name);
+        self.watch_task(&task);
+        task
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::read_to_string;
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::monitor::test::TestMonitor;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+    use crate::{backup, BackupOptions};
+
+    #[test]
+    fn backup_writes_json_events() {
+        let temp = tempfile::tempdir().unwrap();
+        let events_path = temp.path().join("events");
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file_with_contents("hello", b"hello world\n");
+        srcdir.create_dir("subdir");
+        let monitor = Arc::new(EventMonitor::open(&events_path).unwrap());
+        backup(
+            &af,
+            srcdir.path(),
+            &BackupOptions::default(),
+            monitor.clone(),
+        )
+        .unwrap();
+        monitor.error(Error::NotImplemented);
+        drop(monitor);
+
+        let events: Vec<Value> = read_to_string(&events_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("event is valid json"))
+            .collect();
+        dbg!(&events);
+        let task_start = events
+            .iter()
+            .find(|event| event["event"] == "task_start")
+            .expect("task start event");
+        let task_id = &task_start["task"];
+        assert!(task_id.is_u64());
+        assert!(task_start["name"].is_string());
+        assert!(events
+            .iter()
+            .any(|event| event["event"] == "task_finish" && event["task"] == *task_id));
+        assert!(events.iter().any(|event| event["event"] == "counter"
+            && event["counter"] == "Files"
+            && event["value"] == 1));
+        assert!(events.iter().any(|event| event["event"] == "error"
+            && event["code"] == "NOT_IMPLEMENTED"
+            && event["message"] == "This feature is not implemented"));
+    }
+
+    #[test]
+    fn watch_task_from_another_monitor() {
+        let temp = tempfile::tempdir().unwrap();
+        let events_path = temp.path().join("events");
+        let monitor = EventMonitor::open(&events_path).unwrap();
+        let other = TestMonitor::arc();
+        let task = other.start_task("Measure".to_owned());
+        task.set_total(10);
+        task.set_done(5);
+        monitor.watch_task(&task);
+        monitor.state.poll();
+        drop(task);
+        drop(monitor);
+        let lines: Vec<String> = read_to_string(&events_path)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"event":"task_start","task":1,"name":"Measure"}"#,
+                r#"{"event":"task_progress","task":1,"name":"Measure","done":5,"total":10}"#,
+                r#"{"event":"task_finish","task":1}"#,
+            ]
+        );
+    }
+}
@@ -2,6 +2,8 @@
 
 //! Communication from the library to a monitor: a test, a UI, etc.
 
+pub mod counting;
+pub mod events;
 pub mod task;
 pub mod test;
 pub mod void;
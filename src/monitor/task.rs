@@ -2,11 +2,16 @@
 
 //! Tasks are an abstraction to report progress on a long-running operation
 //! from the core library to a UI, such as a progress bar.
+//!
+//! Tasks can have subtasks, for the part of the work currently being done: for
+//! example validating all bands is one task, with a subtask for each band being
+//! validated. Each unit of the parent's `done` count typically corresponds to one
+//! subtask finishing.
 
 use std::fmt::Display;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
-use std::sync::{Arc, RwLock, Weak};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 
 #[derive(Default)]
 pub struct TaskList {
@@ -15,29 +20,33 @@ pub struct TaskList {
 
 impl TaskList {
     pub fn start_task(&mut self, name: String) -> Task {
-        let inner = Arc::new(TaskState {
-            name: name.into(),
-            total: 0.into(),
-            done: 0.into(),
-        });
-        self.tasks.push(Arc::downgrade(&inner));
-        Task(inner)
+        let task = Task::new(name);
+        self.tasks.push(task.downgrade());
+        task
     }
 
+    /// Return the top-level tasks that are still alive.
+    ///
+    /// Their subtasks are available from [TaskState::active_subtasks].
     pub fn active_tasks(&mut self) -> impl Iterator<Item = Arc<TaskState>> {
-        let mut v = Vec::new();
-        self.tasks.retain(|task| {
-            if let Some(inner) = task.upgrade() {
-                v.push(inner);
-                true
-            } else {
-                false
-            }
-        });
-        v.into_iter()
+        upgrade_live(&mut self.tasks).into_iter()
     }
 }
 
+/// Return the tasks that are still alive, and forget the others.
+fn upgrade_live(tasks: &mut Vec<Weak<TaskState>>) -> Vec<Arc<TaskState>> {
+    let mut v = Vec::new();
+    tasks.retain(|task| {
+        if let Some(inner) = task.upgrade() {
+            v.push(inner);
+            true
+        } else {
+            false
+        }
+    });
+    v
+}
+
 #[derive(Debug, Clone)]
 /// A Task is constructed from a monitor. It can
 /// be updated while it's alive. When it's dropped, the progress
@@ -45,6 +54,24 @@ impl TaskList {
 pub struct Task(Arc<TaskState>);
 
 impl Task {
+    fn new(name: String) -> Task {
+        Task(Arc::new(TaskState {
+            name: name.into(),
+            total: 0.into(),
+            done: 0.into(),
+            subtasks: Mutex::default(),
+        }))
+    }
+
+    /// Start a task for part of the work of this task.
+    ///
+    /// The subtask is shown under this task while it's alive.
+    pub fn start_subtask(&self, name: String) -> Task {
+        let subtask = Task::new(name);
+        self.0.subtasks.lock().unwrap().push(subtask.downgrade());
+        subtask
+    }
+
     pub fn set_total(&self, total: usize) {
         self.0.total.store(total, Relaxed)
     }
@@ -60,6 +87,11 @@ impl Task {
     pub fn set_name(&self, name: String) {
         *self.0.name.write().unwrap() = name;
     }
+
+    /// Return a reference to the task's state that doesn't keep the task alive.
+    pub(crate) fn downgrade(&self) -> Weak<TaskState> {
+        Arc::downgrade(&self.0)
+    }
 }
 
 impl AsRef<TaskState> for Task {
@@ -73,6 +105,7 @@ pub struct TaskState {
     name: RwLock<String>,
     total: AtomicUsize,
     done: AtomicUsize,
+    subtasks: Mutex<Vec<Weak<TaskState>>>,
 }
 
 impl TaskState {
@@ -88,12 +121,44 @@ impl TaskState {
         self.done.load(Relaxed)
     }
 
-    pub fn percent(&self) -> usize {
-        let total = self.total.load(Relaxed);
+    /// Return the subtasks that are still alive.
+    pub fn active_subtasks(&self) -> Vec<Arc<TaskState>> {
+        upgrade_live(&mut self.subtasks.lock().unwrap())
+    }
+
+    /// The fraction of the task that's complete, between 0 and 1.
+    ///
+    /// Each active subtask counts as its own fraction of one unit of this task, so
+    /// progress moves smoothly while subtasks are running.
+    pub fn fraction(&self) -> f64 {
+        let total = self.total();
         if total == 0 {
-            0
-        } else {
-            self.done.load(Relaxed) * 100 / total
+            return 0.0;
+        }
+        let subtasks: f64 = self
+            .active_subtasks()
+            .iter()
+            .map(|subtask| subtask.fraction())
+            .sum();
+        ((self.done() as f64 + subtasks) / total as f64).min(1.0)
+    }
+
+    pub fn percent(&self) -> usize {
+        (self.fraction() * 100.0) as usize
+    }
+
+    /// Format this task and its active subtasks, one per line, with subtasks
+    /// indented under their parents.
+    pub fn format_tree(&self) -> String {
+        let mut s = String::new();
+        self.write_tree(&mut s, 0);
+        s
+    }
+
+    fn write_tree(&self, s: &mut String, depth: usize) {
+        *s += &format!("{:indent$}{self}\n", "", indent = depth * 2);
+        for subtask in self.active_subtasks() {
+            subtask.write_tree(s, depth + 1);
         }
     }
 }
@@ -114,8 +179,66 @@ impl Display for TaskState {
                 name,
                 done,
                 total,
-                done as f64 * 100.0 / total as f64
+                self.fraction() * 100.0
             )
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subtasks_are_listed_under_their_parent() {
+        let mut list = TaskList::default();
+        let bands = list.start_task("Validate bands".to_owned());
+        bands.set_total(12);
+        bands.set_done(2);
+        let band = bands.start_subtask("Band b0002".to_owned());
+        band.set_total(2000);
+        band.set_done(450);
+        let tasks: Vec<_> = list.active_tasks().collect();
+        assert_eq!(tasks.len(), 1, "subtasks aren't top-level tasks");
+        assert_eq!(
+            tasks[0].format_tree(),
+            "Validate bands: 2/12, 18.5%\n  Band b0002: 450/2000, 22.5%\n"
+        );
+    }
+
+    #[test]
+    fn parent_progress_includes_active_subtasks() {
+        let parent = Task::new("parent".to_owned());
+        parent.set_total(4);
+        parent.set_done(1);
+        let child = parent.start_subtask("child".to_owned());
+        child.set_total(10);
+        child.set_done(5);
+        let grandchild = child.start_subtask("grandchild".to_owned());
+        grandchild.set_total(2);
+        grandchild.set_done(1);
+        // The child is (5 + 0.5) / 10 done, so the parent is (1 + 0.55) / 4.
+        assert_eq!(child.as_ref().fraction(), 0.55);
+        assert_eq!(parent.as_ref().fraction(), 0.3875);
+        assert_eq!(parent.as_ref().percent(), 38);
+
+        // When the child finishes, the parent counts it as done and forgets it.
+        drop(grandchild);
+        drop(child);
+        parent.increment(1);
+        assert_eq!(parent.as_ref().fraction(), 0.5);
+        assert!(parent.as_ref().active_subtasks().is_empty());
+        assert_eq!(parent.as_ref().format_tree(), "parent: 2/4, 50.0%\n");
+    }
+
+    #[test]
+    fn progress_is_capped_at_complete() {
+        let parent = Task::new("parent".to_owned());
+        parent.set_total(1);
+        parent.set_done(1);
+        let child = parent.start_subtask("child".to_owned());
+        child.set_total(1);
+        child.set_done(1);
+        assert_eq!(parent.as_ref().fraction(), 1.0);
+    }
+}
@@ -120,7 +120,7 @@ const DIRECTORY_ATTRIBUTES: u32 =
     FILE_ATTRIBUTE_DIRECTORY | FILE_ATTRIBUTE_NOT_CONTENT_INDEXED | FILE_ATTRIBUTE_RECALL_ON_OPEN;
 
 fn index_entry_to_directory_entry(entry: &IndexEntry) -> Option<DirectoryEntry> {
-    let file_name = entry.apath.split('/').last()?;
+    let file_name = entry.apath.file_name();
     if entry.kind == Kind::Dir {
         Some(
             DirectoryInfo {
@@ -191,7 +191,7 @@ impl ArchiveProjectionSource {
     }
 
     pub fn get_or_open_tree(&self, policy: BandSelectionPolicy) -> Result<Arc<StoredTree>> {
-        let band_id = self.archive.resolve_band_id(policy)?;
+        let band_id = self.archive.resolve_band(policy)?;
         self.stored_tree_cache
             .lock()
             .unwrap()
@@ -317,29 +317,8 @@ impl ArchiveProjectionSource {
 
         let iterator = hunks.iter().flat_map(|e| &**e);
 
-        let path_prefix = target_path.to_string();
         let entries = iterator
-            .filter(|entry| {
-                if !entry.apath.starts_with(&path_prefix) {
-                    /* Not the directory we're interested in */
-                    return false;
-                }
-
-                if entry.apath.len() <= path_prefix.len() {
-                    /*
-                     * Skipping the containing directory entry which is eqal to path_prefix.
-                     */
-                    return false;
-                }
-
-                let file_name = &entry.apath[path_prefix.len()..].trim_start_matches('/');
-                if file_name.contains('/') {
-                    /* entry is a file which is within a sub-directory */
-                    return false;
-                }
-
-                true
-            })
+            .filter(|entry| entry.apath.parent().as_ref() == Some(&target_path))
             .filter_map(index_entry_to_directory_entry)
             .collect_vec();
 
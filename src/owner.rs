@@ -18,6 +18,7 @@
 // better than just saving the uid and gid, so that backups may potentially
 // be restored on a different system.
 
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::io;
 use std::path::Path;
@@ -56,6 +57,40 @@ impl Owner {
     }
 }
 
+/// Changes to the stored owners of entries, applied when restoring.
+///
+/// For example, this can make a tree backed up as root usable when restored by an
+/// ordinary user.
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub struct OwnerMap {
+    /// Give entries stored as owned by the key user to the value user instead.
+    pub users: HashMap<String, String>,
+    /// Give entries stored as owned by the key group to the value group instead.
+    pub groups: HashMap<String, String>,
+    /// Don't set owners at all, leaving restored entries owned by the user running
+    /// the restore. This overrides `users` and `groups`.
+    pub current_user: bool,
+}
+
+impl OwnerMap {
+    /// Return the owner to set on a restored entry stored with `owner`.
+    ///
+    /// An owner with no user or group leaves the entry's ownership unchanged.
+    pub fn map(&self, owner: &Owner) -> Owner {
+        if self.current_user {
+            return Owner::default();
+        }
+        let lookup = |map: &HashMap<String, String>, name: &Option<String>| {
+            name.as_ref()
+                .map(|name| map.get(name).unwrap_or(name).clone())
+        };
+        Owner {
+            user: lookup(&self.users, &owner.user),
+            group: lookup(&self.groups, &owner.group),
+        }
+    }
+}
+
 impl Display for Owner {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let none = "none".to_string();
@@ -75,3 +110,35 @@ impl Display for Owner {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn map_owner() {
+        let stored = Owner {
+            user: Some("root".to_owned()),
+            group: Some("wheel".to_owned()),
+        };
+        assert_eq!(OwnerMap::default().map(&stored), stored);
+
+        let map = OwnerMap {
+            users: HashMap::from([("root".to_owned(), "mbp".to_owned())]),
+            ..Default::default()
+        };
+        assert_eq!(
+            map.map(&stored),
+            Owner {
+                user: Some("mbp".to_owned()),
+                group: Some("wheel".to_owned()),
+            }
+        );
+
+        let map = OwnerMap {
+            current_user: true,
+            ..map
+        };
+        assert!(map.map(&stored).is_none());
+    }
+}
@@ -12,11 +12,16 @@
 
 //! Restore from the archive to the filesystem.
 
-use std::fs::{create_dir_all, File};
-use std::io::{self, Write};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{create_dir_all, remove_dir_all, remove_file, File};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use derive_more::{Add, AddAssign};
 use fail::fail_point;
 use filetime::set_file_handle_times;
 #[cfg(unix)]
@@ -26,7 +31,10 @@ use tracing::{instrument, trace, warn};
 
 use crate::counters::Counter;
 use crate::io::{directory_is_empty, ensure_dir_exists};
+use crate::live_tree::entry_from_fs_metadata;
+use crate::merge::MatchedEntries;
 use crate::monitor::Monitor;
+use crate::stats::{write_count, write_duration, write_size, write_throughput};
 use crate::unix_time::ToFileTime;
 use crate::*;
 
@@ -42,6 +50,102 @@ pub struct RestoreOptions<'cb> {
 
     // Call this callback as each entry is successfully restored.
     pub change_callback: Option<ChangeCallback<'cb>>,
+
+    /// Give the owner of each restored file permission to read and write it, and
+    /// to list restored directories, regardless of the stored mode.
+    pub readable: bool,
+
+    /// Restore only entries of these kinds, or everything if None.
+    ///
+    /// Directories containing selected entries are still created, but if directories
+    /// aren't selected their stored permissions and mtimes aren't restored.
+    pub kinds: Option<Vec<Kind>>,
+
+    /// Restore only files, symlinks, and other entries that aren't directories if
+    /// their stored mtime is later than this.
+    ///
+    /// Directories are still restored, so that the selected entries can be
+    /// restored inside them.
+    pub newer_than: Option<OffsetDateTime>,
+
+    /// Restore only entries that aren't directories if their stored mtime is
+    /// earlier than this.
+    pub older_than: Option<OffsetDateTime>,
+
+    /// Stop after restoring this many files, for example to spot-check a large backup.
+    ///
+    /// Directories and symlinks reached before then are restored but not counted.
+    /// A warning is logged if the restore stops early.
+    pub limit_files: Option<usize>,
+
+    /// Stop before restoring a file that would take the total size of the restored
+    /// files over this many bytes.
+    pub limit_bytes: Option<u64>,
+
+    /// Refuse to restore paths, including the destination directory, longer than this
+    /// many bytes, in addition to any limit of the destination filesystem.
+    pub max_path_len: Option<usize>,
+
+    /// Change the owners of restored entries from those that were stored.
+    pub owner_map: OwnerMap,
+
+    /// Permissions for restored entries with no stored mode, such as those from
+    /// archives written by Conserve before 0.6.17. Directories also get search
+    /// permission wherever this gives read permission.
+    ///
+    /// If this is None, those entries get the default permissions for new files, as
+    /// limited by the process umask.
+    pub default_mode: Option<u32>,
+
+    /// Don't set the stored permissions at all, leaving every restored entry with the
+    /// default permissions for new files. This overrides `readable` and
+    /// `default_mode`.
+    ///
+    /// To leave the owners unchanged too, set [OwnerMap::current_user].
+    pub skip_permissions: bool,
+
+    /// Don't write anything to the destination, but check that every block needed
+    /// to restore the selected files is present and, unless `quick` is set, can be
+    /// read and has the right content.
+    ///
+    /// Missing or damaged blocks are reported as errors.
+    pub check_only: bool,
+
+    /// With `check_only`, only check that the blocks are present, without reading them.
+    pub quick: bool,
+
+    /// Restore the tree's structure and metadata but not the content of files: each
+    /// file is created empty, with its stored permissions, owner, and mtime.
+    ///
+    /// No blocks are read, so this is a fast way to check the directory structure or
+    /// the effect of `owner_map`. It shouldn't be combined with `update`, which would
+    /// see the empty files as changed, and restore fails with
+    /// [Error::MetadataOnlyOverwrite] if it's combined with `overwrite`, which would
+    /// truncate existing files.
+    pub metadata_only: bool,
+
+    /// Set the mtime of restored entries, including directories, to the time the
+    /// restore started if their stored mtime is later than that.
+    ///
+    /// This avoids future mtimes, which can confuse build tools, when the clock of
+    /// the machine restoring is behind that of the machine that made the backup.
+    pub clamp_mtime_to_now: bool,
+
+    /// Update a destination that may already hold an earlier copy of the tree,
+    /// rather than requiring it to be empty.
+    ///
+    /// Files and symlinks already present with the stored kind, size, mtime, and
+    /// symlink target are left alone, including their permissions and owner.
+    /// Anything else at a restored path is replaced.
+    pub update: bool,
+
+    /// With `update`, also compare the content of files whose size and mtime match,
+    /// and replace them if it differs.
+    pub verify_content: bool,
+
+    /// With `update`, delete entries in the destination that aren't in the stored
+    /// tree, other than those that are excluded.
+    pub delete: bool,
 }
 
 impl Default for RestoreOptions<'_> {
@@ -52,21 +156,152 @@ impl Default for RestoreOptions<'_> {
             exclude: Exclude::nothing(),
             only_subtree: None,
             change_callback: None,
+            readable: false,
+            kinds: None,
+            newer_than: None,
+            older_than: None,
+            limit_files: None,
+            limit_bytes: None,
+            max_path_len: None,
+            owner_map: OwnerMap::default(),
+            default_mode: None,
+            skip_permissions: false,
+            check_only: false,
+            quick: false,
+            metadata_only: false,
+            clamp_mtime_to_now: false,
+            update: false,
+            verify_content: false,
+            delete: false,
         }
     }
 }
 
+impl RestoreOptions<'_> {
+    /// True if an entry with this stored mtime is inside the `newer_than` and
+    /// `older_than` window.
+    fn mtime_selected(&self, mtime: OffsetDateTime) -> bool {
+        self.newer_than
+            .map_or(true, |newer_than| mtime > newer_than)
+            && self
+                .older_than
+                .map_or(true, |older_than| mtime < older_than)
+    }
+
+    /// True if restoring another file of this size would go past `limit_files` or
+    /// `limit_bytes`.
+    fn limit_reached(&self, stats: &RestoreStats, size: u64) -> bool {
+        self.limit_files.is_some_and(|limit| stats.files >= limit)
+            || self
+                .limit_bytes
+                .is_some_and(|limit| stats.uncompressed_file_bytes + size > limit)
+    }
+}
+
+/// Counts of what was restored, and of the blocks read to do it.
+#[derive(Add, AddAssign, Debug, Default, Eq, PartialEq, Clone)]
+pub struct RestoreStats {
+    pub files: usize,
+    pub symlinks: usize,
+    pub directories: usize,
+    pub unknown_kind: usize,
+
+    /// Entries that were already in the destination of an update, and left alone.
+    pub unchanged: usize,
+    /// Entries that were deleted from the destination of an update because they
+    /// aren't in the stored tree.
+    pub deleted: usize,
+
+    /// Total bytes written into restored files.
+    pub uncompressed_file_bytes: u64,
+
+    /// Entries that could not be restored.
+    pub errors: usize,
+
+    pub read_blocks: usize,
+    pub read_blocks_uncompressed_bytes: usize,
+    pub read_blocks_compressed_bytes: usize,
+    /// Blocks that were found in memory rather than being read again.
+    pub block_cache_hits: usize,
+
+    pub elapsed: Duration,
+}
+
+impl RestoreStats {
+    /// Bytes of file content restored per second, or zero if no time has passed.
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.uncompressed_file_bytes as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for RestoreStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_count(w, "files", self.files);
+        write_size(w, "  file bytes", self.uncompressed_file_bytes);
+        write_count(w, "symlinks", self.symlinks);
+        write_count(w, "directories", self.directories);
+        write_count(w, "unsupported file kind", self.unknown_kind);
+        if self.unchanged > 0 || self.deleted > 0 {
+            write_count(w, "unchanged", self.unchanged);
+            write_count(w, "deleted", self.deleted);
+        }
+        writeln!(w)?;
+
+        write_count(w, "blocks read", self.read_blocks);
+        write_size(
+            w,
+            "  uncompressed",
+            self.read_blocks_uncompressed_bytes as u64,
+        );
+        write_size(w, "  compressed", self.read_blocks_compressed_bytes as u64);
+        write_count(w, "block cache hits", self.block_cache_hits);
+        writeln!(w)?;
+
+        write_count(w, "errors", self.errors);
+        write_duration(w, "elapsed", self.elapsed)?;
+        write_throughput(w, "restored", self.throughput())?;
+
+        Ok(())
+    }
+}
+
 /// Restore a selected version, or by default the latest, to a destination directory.
+///
+/// Returns statistics about what was restored.
 pub fn restore(
     archive: &Archive,
     destination: &Path,
     options: &RestoreOptions,
     monitor: Arc<dyn Monitor>,
-) -> Result<()> {
+) -> Result<RestoreStats> {
+    if options.metadata_only && options.overwrite {
+        return Err(Error::MetadataOnlyOverwrite);
+    }
+    let start = Instant::now();
+    let mtime_limit = options.clamp_mtime_to_now.then(OffsetDateTime::now_utc);
+    let mut stats = RestoreStats::default();
+    let block_stats = &archive.block_dir.stats;
+    let start_read_blocks = block_stats.read_blocks.load(Relaxed);
+    let start_read_compressed = block_stats.read_block_compressed_bytes.load(Relaxed);
+    let start_read_uncompressed = block_stats.read_block_uncompressed_bytes.load(Relaxed);
+    let start_cache_hits = block_stats.cache_hit.load(Relaxed);
     let st = archive.open_stored_tree(options.band_selection.clone())?;
-    ensure_dir_exists(destination)?;
-    if !options.overwrite && !directory_is_empty(destination)? {
-        return Err(Error::DestinationNotEmpty);
+    if !st.is_closed()? {
+        warn!(
+            band_id = %st.band().id(),
+            "Restoring from an incomplete backup: the tree may be partial, with entries it didn't reach taken from earlier backups"
+        );
+    }
+    if !options.check_only {
+        ensure_dir_exists(destination)?;
+        if !options.overwrite && !options.update && !directory_is_empty(destination)? {
+            return Err(Error::DestinationNotEmpty);
+        }
     }
     let task = monitor.start_task("Restore".to_string());
     let block_dir = archive.block_dir();
@@ -86,13 +321,145 @@ pub fn restore(
         options.exclude.clone(),
         monitor.clone(),
     )?;
+    let path_limits = PathLimits::new(destination, options.max_path_len);
+    let mut destination_check = if options.check_only {
+        None
+    } else {
+        Some(DestinationCheck::new(destination)?)
+    };
     let mut deferrals = Vec::new();
+    let mut case_collisions = (!options.check_only && destination_is_case_insensitive(destination))
+        .then(CaseCollisions::default);
     for entry in entry_iter {
         task.set_name(format!("Restore {}", entry.apath));
-        let path = destination.join(&entry.apath[1..]);
+        if entry.kind() != Kind::Dir && !options.mtime_selected(entry.mtime()) {
+            continue;
+        }
+        if options.check_only {
+            if options
+                .kinds
+                .as_ref()
+                .is_some_and(|kinds| !kinds.contains(&entry.kind()))
+            {
+                continue;
+            }
+            check_entry(
+                &entry,
+                options.quick,
+                block_dir,
+                &mut stats,
+                monitor.clone(),
+            );
+            continue;
+        }
+        if let Some(case_collisions) = &mut case_collisions {
+            if case_collisions.is_inside_skipped_dir(&entry.apath) {
+                continue;
+            }
+            if let Some(existing) = case_collisions.check(&entry.apath, entry.kind()) {
+                monitor.error(Error::RestoreCaseCollision {
+                    apath: entry.apath.clone(),
+                    existing,
+                });
+                stats.errors += 1;
+                continue;
+            }
+        }
+        let Some(path) = entry.apath.below_checked(destination) else {
+            monitor.error(Error::RestoreUnsafeApath {
+                apath: entry.apath.clone(),
+            });
+            stats.errors += 1;
+            continue;
+        };
+        if let Some(destination_check) = &mut destination_check {
+            if let Err(err) = destination_check.check(&entry.apath, &path) {
+                monitor.error(err);
+                stats.errors += 1;
+                continue;
+            }
+        }
+        if let Some(kinds) = &options.kinds {
+            if !kinds.contains(&entry.kind()) {
+                continue;
+            }
+            if entry.kind() != Kind::Dir {
+                let parent = path.parent().expect("restored path has a parent");
+                if let Err(source) = create_dir_all(parent) {
+                    monitor.error(Error::RestoreDirectory {
+                        path: parent.to_owned(),
+                        source,
+                    });
+                    stats.errors += 1;
+                    continue;
+                }
+            }
+        }
+        if let Err(err) = path_limits.check(&path) {
+            monitor.error(err);
+            stats.errors += 1;
+            continue;
+        }
+        if entry.kind() == Kind::File
+            && options.limit_reached(&stats, entry.size().unwrap_or_default())
+        {
+            warn!(
+                "Restore stopped at the limit, after {} files of {}; later entries weren't restored",
+                stats.files,
+                bytes_to_human_mb(stats.uncompressed_file_bytes),
+            );
+            break;
+        }
+        let mut unix_mode = entry.unix_mode();
+        if let Some(default_mode) = options.default_mode {
+            unix_mode = unix_mode.or_default(default_mode, entry.kind());
+        }
+        if options.readable {
+            unix_mode = unix_mode.with_owner_access(entry.kind());
+        }
+        if options.skip_permissions {
+            unix_mode = UnixMode::default();
+        }
+        let owner = options.owner_map.map(entry.owner());
+        let mtime = mtime_limit.map_or(entry.mtime(), |limit| entry.mtime().min(limit));
+        let mut replaced = None;
+        if options.update {
+            match existing_entry(
+                &path,
+                &entry,
+                mtime,
+                options.verify_content,
+                block_dir,
+                &monitor,
+            ) {
+                Ok(Existing::Absent) => {}
+                Ok(Existing::Same) if entry.kind() == Kind::Dir => {
+                    // Still set its permissions and mtime, and restore its contents.
+                    replaced = Some(None);
+                }
+                Ok(Existing::Same) => {
+                    stats.unchanged += 1;
+                    continue;
+                }
+                Ok(Existing::Different(existing)) => {
+                    if let Err(source) = remove_existing(&path, existing.kind()) {
+                        monitor.error(Error::RestoreRemove { path, source });
+                        stats.errors += 1;
+                        continue;
+                    }
+                    replaced = Some(Some(existing));
+                }
+                Err(err) => {
+                    monitor.error(err);
+                    stats.errors += 1;
+                    continue;
+                }
+            }
+        }
         match entry.kind() {
             Kind::Dir => {
                 monitor.count(Counter::Dirs, 1);
+                stats.directories += 1;
                 if *entry.apath() != Apath::root() {
                     if let Err(err) = create_dir(&path) {
                         if err.kind() != io::ErrorKind::AlreadyExists {
@@ -100,46 +467,466 @@ pub fn restore(
                                 path: path.clone(),
                                 source: err,
                             });
+                            stats.errors += 1;
                             continue;
                         }
                     }
                 }
                 deferrals.push(DirDeferral {
                     path,
-                    unix_mode: entry.unix_mode(),
-                    mtime: entry.mtime(),
-                    owner: entry.owner().clone(),
+                    unix_mode,
+                    mtime,
+                    owner,
                 })
             }
             Kind::File => {
                 monitor.count(Counter::Files, 1);
-                if let Err(err) = restore_file(path.clone(), &entry, block_dir, monitor.clone()) {
-                    monitor.error(err);
-                    continue;
+                stats.files += 1;
+                match restore_file(
+                    path.clone(),
+                    &entry,
+                    !options.metadata_only,
+                    unix_mode,
+                    mtime,
+                    &owner,
+                    block_dir,
+                    monitor.clone(),
+                ) {
+                    Ok(bytes) => stats.uncompressed_file_bytes += bytes,
+                    Err(err) => {
+                        monitor.error(err);
+                        stats.errors += 1;
+                        continue;
+                    }
                 }
             }
             Kind::Symlink => {
                 monitor.count(Counter::Symlinks, 1);
-                if let Err(err) = restore_symlink(&path, &entry) {
+                stats.symlinks += 1;
+                if let Err(err) = restore_symlink(&path, &entry, mtime, &owner) {
                     monitor.error(err);
+                    stats.errors += 1;
                     continue;
                 }
             }
             Kind::Unknown => {
+                stats.unknown_kind += 1;
                 monitor.error(Error::InvalidMetadata {
                     details: format!("Unknown file kind {:?}", entry.apath()),
                 });
             }
         };
         if let Some(cb) = options.change_callback.as_ref() {
-            // Since we only restore to empty directories they're all added.
-            cb(&EntryChange::added(&entry))?;
+            // Unless updating, we only restore to empty directories, so they're all added.
+            match &replaced {
+                None => cb(&EntryChange::added(&entry))?,
+                Some(Some(existing)) => cb(&EntryChange::changed(existing, &entry))?,
+                Some(None) => {}
+            }
         }
     }
+    if options.update && options.delete {
+        delete_extra_entries(&st, destination, options, &mut stats, monitor.clone())?;
+    }
     apply_deferrals(&deferrals, monitor.clone())?;
+    stats.read_blocks = block_stats.read_blocks.load(Relaxed) - start_read_blocks;
+    stats.read_blocks_compressed_bytes =
+        block_stats.read_block_compressed_bytes.load(Relaxed) - start_read_compressed;
+    stats.read_blocks_uncompressed_bytes =
+        block_stats.read_block_uncompressed_bytes.load(Relaxed) - start_read_uncompressed;
+    stats.block_cache_hits = block_stats.cache_hit.load(Relaxed) - start_cache_hits;
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+/// Check that an entry could be restored, by reading or checking the presence of
+/// the blocks holding a file's content, and count it in the stats.
+fn check_entry(
+    entry: &IndexEntry,
+    quick: bool,
+    block_dir: &BlockDir,
+    stats: &mut RestoreStats,
+    monitor: Arc<dyn Monitor>,
+) {
+    match entry.kind() {
+        Kind::Dir => stats.directories += 1,
+        Kind::Symlink => stats.symlinks += 1,
+        Kind::Unknown => {
+            stats.unknown_kind += 1;
+            monitor.error(Error::InvalidMetadata {
+                details: format!("Unknown file kind {:?}", entry.apath()),
+            });
+        }
+        Kind::File => {
+            monitor.count(Counter::Files, 1);
+            stats.files += 1;
+            for addr in &entry.addrs {
+                let result = if quick {
+                    match block_dir.contains(&addr.hash, monitor.clone()) {
+                        Ok(true) => Ok(()),
+                        Ok(false) => Err(Error::BlockMissing {
+                            hash: addr.hash.clone(),
+                        }),
+                        Err(err) => Err(err),
+                    }
+                } else {
+                    block_dir
+                        .read_address(addr, monitor.clone())
+                        .map(|bytes| monitor.count(Counter::FileBytes, bytes.len()))
+                };
+                if let Err(source) = result {
+                    monitor.error(Error::RestoreFileBlock {
+                        apath: entry.apath.clone(),
+                        hash: addr.hash.clone(),
+                        source: Box::new(source),
+                    });
+                    stats.errors += 1;
+                    return;
+                }
+                stats.uncompressed_file_bytes += addr.len;
+            }
+        }
+    }
+}
+
+/// What an update finds at the path of an entry to be restored.
+enum Existing {
+    /// Nothing is there.
+    Absent,
+    /// An entry that matches the stored entry, or a directory where one is stored.
+    Same,
+    /// Something else, which should be replaced.
+    Different(EntryValue),
+}
+
+/// Look at what's already at `path` in the destination of an update.
+fn existing_entry(
+    path: &Path,
+    entry: &IndexEntry,
+    mtime: OffsetDateTime,
+    verify_content: bool,
+    block_dir: &BlockDir,
+    monitor: &Arc<dyn Monitor>,
+) -> Result<Existing> {
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Existing::Absent),
+        Err(source) => {
+            return Err(Error::RestoreFile {
+                path: path.to_owned(),
+                source,
+            })
+        }
+    };
+    let existing = entry_from_fs_metadata(entry.apath.clone(), path, &metadata)?;
+    let same = existing.kind() == entry.kind()
+        && match entry.kind() {
+            Kind::Dir => true,
+            Kind::File => {
+                existing.size() == entry.size()
+                    && existing.mtime() == mtime
+                    && (!verify_content
+                        || file_content_matches(path, entry, block_dir, monitor.clone())?)
+            }
+            Kind::Symlink => existing.symlink_target() == entry.symlink_target(),
+            Kind::Unknown => false,
+        };
+    Ok(if same {
+        Existing::Same
+    } else {
+        Existing::Different(existing)
+    })
+}
+
+/// True if the file at `path` has the content of a stored file entry.
+fn file_content_matches(
+    path: &Path,
+    entry: &IndexEntry,
+    block_dir: &BlockDir,
+    monitor: Arc<dyn Monitor>,
+) -> Result<bool> {
+    let file_error = |source| Error::RestoreFile {
+        path: path.to_owned(),
+        source,
+    };
+    let mut file = File::open(path).map_err(file_error)?;
+    let mut buf = Vec::new();
+    for addr in &entry.addrs {
+        let bytes = block_dir
+            .read_address(addr, monitor.clone())
+            .map_err(|source| Error::RestoreFileBlock {
+                apath: entry.apath.clone(),
+                hash: addr.hash.clone(),
+                source: Box::new(source),
+            })?;
+        buf.resize(bytes.len(), 0);
+        match file.read_exact(&mut buf) {
+            Ok(()) if buf == bytes.as_ref() => {}
+            Ok(()) => return Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(file_error(err)),
+        }
+    }
+    Ok(file.read(&mut [0]).map_err(file_error)? == 0)
+}
+
+/// Remove something in the destination of an update, so that it can be replaced.
+fn remove_existing(path: &Path, kind: Kind) -> io::Result<()> {
+    if kind == Kind::Dir {
+        remove_dir_all(path)
+    } else {
+        remove_file(path)
+    }
+}
+
+/// Delete entries from the destination of an update that aren't in the stored tree.
+///
+/// This runs after everything stored has been restored, so that the destination
+/// can be compared to the stored tree entry by entry.
+fn delete_extra_entries(
+    st: &StoredTree,
+    destination: &Path,
+    options: &RestoreOptions,
+    stats: &mut RestoreStats,
+    monitor: Arc<dyn Monitor>,
+) -> Result<()> {
+    let subtree = options.only_subtree.clone().unwrap_or_else(Apath::root);
+    let stored = st.iter_entries(subtree.clone(), options.exclude.clone(), monitor.clone())?;
+    let live = LiveTree::open(destination)?.iter_entries(
+        subtree,
+        options.exclude.clone(),
+        monitor.clone(),
+    )?;
+    let extra: Vec<EntryValue> = MergeTrees::new(stored, live)
+        .filter_map(|matched| match matched {
+            MatchedEntries::Right(live_entry) => Some(live_entry),
+            _ => None,
+        })
+        .collect();
+    // Delete the contents of directories before the directories themselves.
+    for live_entry in extra.iter().rev() {
+        let path = live_entry.apath.below(destination);
+        let result = if live_entry.kind() == Kind::Dir {
+            std::fs::remove_dir(&path)
+        } else {
+            remove_file(&path)
+        };
+        if let Err(source) = result {
+            monitor.error(Error::RestoreRemove { path, source });
+            stats.errors += 1;
+            continue;
+        }
+        stats.deleted += 1;
+        if let Some(cb) = options.change_callback.as_ref() {
+            cb(&EntryChange::deleted(live_entry))?;
+        }
+    }
     Ok(())
 }
 
+/// Checks that entries are restored inside the destination.
+///
+/// A symlink restored earlier, or already in the destination of an update, could
+/// otherwise lead a later entry to be written outside it.
+#[derive(Debug)]
+struct DestinationCheck {
+    destination: PathBuf,
+    canonical_destination: PathBuf,
+    /// Directories below the destination already found to be real directories, not
+    /// symlinks, so that their children needn't look at them again.
+    real_dirs: HashSet<PathBuf>,
+}
+
+impl DestinationCheck {
+    fn new(destination: &Path) -> Result<DestinationCheck> {
+        let canonical_destination =
+            destination
+                .canonicalize()
+                .map_err(|source| Error::RestoreDirectory {
+                    path: destination.to_owned(),
+                    source,
+                })?;
+        Ok(DestinationCheck {
+            destination: destination.to_owned(),
+            canonical_destination,
+            real_dirs: HashSet::new(),
+        })
+    }
+
+    /// Check that the directory that will contain `path` is inside the destination.
+    ///
+    /// Parents that don't exist yet will be created as directories, so only
+    /// existing symlinks between `path` and the destination need to be resolved.
+    fn check(&mut self, apath: &Apath, path: &Path) -> Result<()> {
+        // Restoring this entry may replace a directory that was there.
+        self.real_dirs.remove(path);
+        if *apath == Apath::root() {
+            return Ok(());
+        }
+        let outside = || Error::RestoreOutsideDestination {
+            apath: apath.clone(),
+            path: path.to_owned(),
+        };
+        let mut new_real_dirs = Vec::new();
+        let mut dir = path.parent();
+        while let Some(parent) = dir {
+            if parent == self.destination || self.real_dirs.contains(parent) {
+                break;
+            }
+            match parent.symlink_metadata() {
+                Ok(metadata) if metadata.file_type().is_symlink() => match parent.canonicalize() {
+                    Ok(canonical) if canonical.starts_with(&self.canonical_destination) => break,
+                    Ok(_) => return Err(outside()),
+                    Err(source) => {
+                        return Err(Error::RestoreDirectory {
+                            path: parent.to_owned(),
+                            source,
+                        })
+                    }
+                },
+                Ok(_) => new_real_dirs.push(parent.to_owned()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(source) => {
+                    return Err(Error::RestoreDirectory {
+                        path: parent.to_owned(),
+                        source,
+                    })
+                }
+            }
+            dir = parent.parent();
+        }
+        if dir.is_none() {
+            return Err(outside());
+        }
+        self.real_dirs.extend(new_real_dirs);
+        Ok(())
+    }
+}
+
+/// Limits on the paths that can be created in the destination.
+///
+/// These are checked before restoring each entry, so that an overlong path gets a
+/// clear error rather than an obscure one from the OS.
+#[derive(Debug)]
+struct PathLimits {
+    /// Maximum length in bytes of a whole path.
+    max_path_len: Option<usize>,
+    /// Maximum length in bytes of one component of a path.
+    max_name_len: Option<usize>,
+}
+
+impl PathLimits {
+    /// Find the limits of the filesystem containing `destination`, combined with a
+    /// limit set by the user.
+    fn new(destination: &Path, max_path_len: Option<usize>) -> PathLimits {
+        #[cfg(unix)]
+        let (fs_path_len, fs_name_len) = {
+            use nix::unistd::{pathconf, PathconfVar};
+            let limit = |var| {
+                pathconf(destination, var)
+                    .ok()
+                    .flatten()
+                    .and_then(|limit| usize::try_from(limit).ok())
+            };
+            // PATH_MAX includes the terminating nul.
+            (
+                limit(PathconfVar::PATH_MAX).map(|limit| limit.saturating_sub(1)),
+                limit(PathconfVar::NAME_MAX),
+            )
+        };
+        #[cfg(not(unix))]
+        let (fs_path_len, fs_name_len) = {
+            // Rust's standard library transparently handles long paths on Windows.
+            let _ = destination;
+            (None, None)
+        };
+        PathLimits {
+            max_path_len: match (max_path_len, fs_path_len) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            max_name_len: fs_name_len,
+        }
+    }
+
+    fn check(&self, path: &Path) -> Result<()> {
+        let len = path.as_os_str().len();
+        if let Some(limit) = self.max_path_len {
+            if len > limit {
+                return Err(Error::RestorePathTooLong {
+                    path: path.to_owned(),
+                    len,
+                    limit,
+                });
+            }
+        }
+        if let (Some(limit), Some(name)) = (self.max_name_len, path.file_name()) {
+            if name.len() > limit {
+                return Err(Error::RestoreNameTooLong {
+                    path: path.to_owned(),
+                    len: name.len(),
+                    limit,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// True if the destination directory seems to be on a filesystem that doesn't
+/// distinguish names differing only by case, such as the defaults on macOS and Windows.
+fn destination_is_case_insensitive(destination: &Path) -> bool {
+    match tempfile::Builder::new()
+        .prefix(".conserve-case-probe-")
+        .tempfile_in(destination)
+    {
+        Ok(probe) => {
+            let name = probe.path().file_name().unwrap().to_string_lossy();
+            destination.join(name.to_uppercase()).exists()
+        }
+        Err(err) => {
+            warn!(
+                ?err,
+                "Failed to check whether the destination is case-sensitive"
+            );
+            false
+        }
+    }
+}
+
+/// Detects stored entries whose names differ only by case, which can't both be
+/// restored onto a case-insensitive filesystem.
+///
+/// This remembers every apath restored, so is only used when the destination
+/// is case-insensitive.
+#[derive(Default)]
+struct CaseCollisions {
+    /// Lowercased apaths already restored, mapped to the original apath.
+    seen: HashMap<String, Apath>,
+    /// Directories that were skipped because they collided: their contents
+    /// would otherwise be merged into the other directory.
+    skipped_dirs: Vec<Apath>,
+}
+
+impl CaseCollisions {
+    /// Remember an apath about to be restored, returning the apath it collides with, if any.
+    fn check(&mut self, apath: &Apath, kind: Kind) -> Option<Apath> {
+        let folded = apath.to_lowercase();
+        if let Some(existing) = self.seen.get(&folded) {
+            if kind == Kind::Dir {
+                self.skipped_dirs.push(apath.clone());
+            }
+            return Some(existing.clone());
+        }
+        self.seen.insert(folded, apath.clone());
+        None
+    }
+
+    fn is_inside_skipped_dir(&self, apath: &Apath) -> bool {
+        self.skipped_dirs.iter().any(|dir| dir.is_prefix_of(apath))
+    }
+}
+
 fn create_dir(path: &Path) -> io::Result<()> {
     fail_point!("restore::create-dir", |_| {
         Err(io::Error::new(
@@ -164,12 +951,16 @@ struct DirDeferral {
 }
 
 fn apply_deferrals(deferrals: &[DirDeferral], monitor: Arc<dyn Monitor>) -> Result<()> {
+    // Deferrals are in apath order, so every directory comes after its parent. Apply them
+    // deepest first, so that a directory isn't made unsearchable until everything
+    // inside it has been finished. Mtimes are set last in each directory, so that
+    // nothing else touches it afterwards.
     for DirDeferral {
         path,
         unix_mode,
         mtime,
         owner,
-    } in deferrals
+    } in deferrals.iter().rev()
     {
         if let Err(source) = owner.set_owner(path) {
             monitor.error(Error::RestoreOwnership {
@@ -177,11 +968,12 @@ fn apply_deferrals(deferrals: &[DirDeferral], monitor: Arc<dyn Monitor>) -> Resu
                 source,
             });
         }
-        if let Err(source) = unix_mode.set_permissions(path) {
-            monitor.error(Error::RestorePermissions {
+        match unix_mode.set_permissions(path) {
+            Ok(()) => warn_if_inaccessible(path, *unix_mode, Kind::Dir),
+            Err(source) => monitor.error(Error::RestorePermissions {
                 path: path.clone(),
                 source,
-            });
+            }),
         }
         if let Err(source) = filetime::set_file_mtime(path, (*mtime).to_file_time()) {
             monitor.error(Error::RestoreModificationTime {
@@ -193,19 +985,48 @@ fn apply_deferrals(deferrals: &[DirDeferral], monitor: Arc<dyn Monitor>) -> Resu
     Ok(())
 }
 
-/// Copy in the contents of a file from another tree.
+/// Warn if a restored file or directory has a mode that stops its owner using it,
+/// which can be surprising after a restore.
+fn warn_if_inaccessible(path: &Path, unix_mode: UnixMode, kind: Kind) {
+    if unix_mode.denies_owner_access(kind) {
+        warn!(
+            ?path,
+            %unix_mode,
+            "Restored {} is not accessible to its owner; restore with --readable to add owner permissions",
+            if kind == Kind::Dir { "directory" } else { "file" },
+        );
+    }
+}
+
+/// Copy in the contents of a file from another tree, and set its mode to `unix_mode`
+/// and its modification time to `mtime`.
+///
+/// If `content` is false, the file is left empty and no blocks are read.
+///
+/// Returns the number of bytes written.
+#[allow(clippy::too_many_arguments)]
 #[instrument(skip(source_entry, block_dir, monitor))]
 fn restore_file(
     path: PathBuf,
     source_entry: &IndexEntry,
+    content: bool,
+    unix_mode: UnixMode,
+    mtime: OffsetDateTime,
+    owner: &Owner,
     block_dir: &BlockDir,
     monitor: Arc<dyn Monitor>,
-) -> Result<()> {
+) -> Result<u64> {
+    let mut bytes_written = 0;
     let mut out = File::create(&path).map_err(|err| Error::RestoreFile {
         path: path.clone(),
         source: err,
     })?;
-    for addr in &source_entry.addrs {
+    let addrs = if content {
+        source_entry.addrs.as_slice()
+    } else {
+        &[]
+    };
+    for addr in addrs {
         // TODO: We could combine small parts
         // in memory, and then write them in a single system call. However
         // for the probably common cases of files with one part, or
@@ -223,42 +1044,48 @@ fn restore_file(
             source: err,
         })?;
         monitor.count(Counter::FileBytes, bytes.len());
+        bytes_written += bytes.len() as u64;
     }
     out.flush().map_err(|source| Error::RestoreFile {
         path: path.clone(),
         source,
     })?;
 
-    let mtime = Some(source_entry.mtime().to_file_time());
+    let mtime = Some(mtime.to_file_time());
     set_file_handle_times(&out, mtime, mtime).map_err(|source| Error::RestoreModificationTime {
         path: path.clone(),
         source,
     })?;
 
     // Restore permissions only if there are mode bits stored in the archive
-    if let Err(source) = source_entry.unix_mode().set_permissions(&path) {
-        monitor.error(Error::RestorePermissions {
+    match unix_mode.set_permissions(&path) {
+        Ok(()) => warn_if_inaccessible(&path, unix_mode, Kind::File),
+        Err(source) => monitor.error(Error::RestorePermissions {
             path: path.clone(),
             source,
-        });
+        }),
     }
 
     // Restore ownership if possible.
     // TODO: Stats and warnings if a user or group is specified in the index but
     // does not exist on the local system.
-    if let Err(source) = source_entry.owner().set_owner(&path) {
+    if let Err(source) = owner.set_owner(&path) {
         monitor.error(Error::RestoreOwnership {
             path: path.clone(),
             source,
         });
     }
-    // TODO: Accumulate more stats.
     trace!("Restored file");
-    Ok(())
+    Ok(bytes_written)
 }
 
 #[cfg(unix)]
-fn restore_symlink(path: &Path, entry: &IndexEntry) -> Result<()> {
+fn restore_symlink(
+    path: &Path,
+    entry: &IndexEntry,
+    mtime: OffsetDateTime,
+    owner: &Owner,
+) -> Result<()> {
     use std::os::unix::fs as unix_fs;
     if let Some(ref target) = entry.symlink_target() {
         if let Err(source) = unix_fs::symlink(target, path) {
@@ -267,13 +1094,13 @@ fn restore_symlink(path: &Path, entry: &IndexEntry) -> Result<()> {
                 source,
             });
         }
-        if let Err(source) = entry.owner().set_owner(path) {
+        if let Err(source) = owner.set_owner(path) {
             return Err(Error::RestoreOwnership {
                 path: path.to_owned(),
                 source,
             });
         }
-        let mtime = entry.mtime().to_file_time();
+        let mtime = mtime.to_file_time();
         if let Err(source) = set_symlink_file_times(path, mtime, mtime) {
             return Err(Error::RestoreModificationTime {
                 path: path.to_owned(),
@@ -290,9 +1117,50 @@ fn restore_symlink(path: &Path, entry: &IndexEntry) -> Result<()> {
 
 #[cfg(not(unix))]
 #[mutants::skip]
-fn restore_symlink(_restore_path: &Path, entry: &IndexEntry) -> Result<()> {
+fn restore_symlink(
+    _restore_path: &Path,
+    entry: &IndexEntry,
+    _mtime: OffsetDateTime,
+    _owner: &Owner,
+) -> Result<()> {
     // TODO: Add a test with a canned index containing a symlink, and expect
     // it cannot be restored on Windows and can be on Unix.
     warn!("Can't restore symlinks on non-Unix: {}", entry.apath());
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names_differing_by_case_collide() {
+        let mut collisions = CaseCollisions::default();
+        assert_eq!(collisions.check(&"/".into(), Kind::Dir), None);
+        assert_eq!(collisions.check(&"/Foo".into(), Kind::File), None);
+        assert_eq!(collisions.check(&"/bar".into(), Kind::File), None);
+        assert_eq!(
+            collisions.check(&"/foo".into(), Kind::File),
+            Some(Apath::from("/Foo"))
+        );
+        assert_eq!(
+            collisions.check(&"/BAR".into(), Kind::Symlink),
+            Some(Apath::from("/bar"))
+        );
+        assert!(collisions.skipped_dirs.is_empty());
+    }
+
+    #[test]
+    fn contents_of_colliding_directory_are_skipped() {
+        let mut collisions = CaseCollisions::default();
+        assert_eq!(collisions.check(&"/Dir".into(), Kind::Dir), None);
+        assert_eq!(collisions.check(&"/Dir/a".into(), Kind::File), None);
+        assert_eq!(
+            collisions.check(&"/dir".into(), Kind::Dir),
+            Some(Apath::from("/Dir"))
+        );
+        assert!(collisions.is_inside_skipped_dir(&"/dir/b".into()));
+        assert!(!collisions.is_inside_skipped_dir(&"/Dir/b".into()));
+        assert!(!collisions.is_inside_skipped_dir(&"/dirt".into()));
+    }
+}
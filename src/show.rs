@@ -20,6 +20,7 @@ use std::borrow::Cow;
 use std::io::{BufWriter, Write};
 use std::sync::Arc;
 
+use serde::{Serialize, Serializer};
 use time::format_description::well_known::Rfc3339;
 use time::UtcOffset;
 use tracing::error;
@@ -40,8 +41,16 @@ pub struct ShowVersionsOptions {
     pub start_time: bool,
     /// Show how much time the backup took, or "incomplete" if it never finished.
     pub backup_duration: bool,
+    /// Show the source directory that was backed up, if it was recorded, after the
+    /// other columns.
+    pub source_path: bool,
     /// Show times in this zone.
     pub timezone: Option<UtcOffset>,
+    /// Write a json object for each version, including which Conserve version and host
+    /// wrote it, instead of text.
+    ///
+    /// Times are in UTC, and the other options except `newest_first` are ignored.
+    pub json: Option<JsonFormat>,
 }
 
 /// Print a list of versions, one per line, on stdout.
@@ -50,31 +59,55 @@ pub fn show_versions(
     options: &ShowVersionsOptions,
     monitor: Arc<TermUiMonitor>,
 ) -> Result<()> {
-    let mut band_ids = archive.list_band_ids()?;
+    let manifest = band_manifest::read(archive);
+    let mut band_ids = match &manifest {
+        Some(infos) => infos.iter().map(|info| info.id).collect(),
+        None => archive.list_band_ids()?,
+    };
     if options.newest_first {
         band_ids.reverse();
     }
+    let mut json_infos = Vec::new();
     for band_id in band_ids {
-        if !(options.tree_size || options.start_time || options.backup_duration) {
+        if options.json.is_none()
+            && !(options.tree_size
+                || options.start_time
+                || options.backup_duration
+                || options.source_path)
+        {
             println!("{}", band_id);
             continue;
         }
         let mut l: Vec<String> = Vec::new();
         l.push(format!("{band_id:<20}"));
-        let band = match Band::open(archive, band_id) {
-            Ok(band) => band,
-            Err(err) => {
-                error!("Failed to open band {band_id:?}: {err}");
-                continue;
-            }
-        };
-        let info = match band.get_info() {
-            Ok(info) => info,
-            Err(err) => {
-                error!("Failed to read band tail {band_id:?}: {err}");
-                continue;
+        // Closed bands never change, so their summary in the manifest is current.
+        let info = match manifest
+            .iter()
+            .flatten()
+            .find(|info| info.id == band_id && info.is_closed)
+        {
+            Some(info) => info.clone(),
+            None => {
+                let band = match Band::open(archive, band_id) {
+                    Ok(band) => band,
+                    Err(err) => {
+                        error!("Failed to open band {band_id:?}: {err}");
+                        continue;
+                    }
+                };
+                match band.get_info() {
+                    Ok(info) => info,
+                    Err(err) => {
+                        error!("Failed to read band tail {band_id:?}: {err}");
+                        continue;
+                    }
+                }
             }
         };
+        if options.json.is_some() {
+            json_infos.push(info);
+            continue;
+        }
 
         if options.start_time {
             let mut start_time = info.start_time;
@@ -114,18 +147,82 @@ pub fn show_versions(
             );
             l.push(format!("{tree_mb_str:>14}",));
         }
+
+        if options.source_path {
+            if let Some(source_path) = &info.source_path {
+                l.push(source_path.clone());
+            }
+        }
         monitor.clear_progress_bars(); // to avoid fighting with stdout
         println!("{}", l.join(" "));
     }
+    if let Some(json_format) = options.json {
+        monitor.clear_progress_bars();
+        write_json_seq(json_infos, json_format, &mut std::io::stdout())?;
+    }
+    Ok(())
+}
+
+/// How to write json output.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum JsonFormat {
+    /// One compact json value per line.
+    #[default]
+    Ndjson,
+    /// A single indented json array.
+    Pretty,
+    /// A single compact json array on one line.
+    Array,
+}
+
+/// Write a sequence of values as json.
+///
+/// In every format, values are written as they're produced rather than collected first,
+/// so an error part way through leaves an unterminated array.
+pub fn write_json_seq<I>(values: I, format: JsonFormat, w: &mut dyn Write) -> Result<()>
+where
+    I: IntoIterator,
+    I::Item: Serialize,
+{
+    let mut bw = BufWriter::new(w);
+    match format {
+        JsonFormat::Ndjson => {
+            for value in values {
+                serde_json::to_writer(&mut bw, &value)?;
+                writeln!(bw)?;
+            }
+        }
+        JsonFormat::Pretty => {
+            serde_json::Serializer::pretty(&mut bw).collect_seq(values)?;
+            writeln!(bw)?;
+        }
+        JsonFormat::Array => {
+            serde_json::Serializer::new(&mut bw).collect_seq(values)?;
+            writeln!(bw)?;
+        }
+    }
+    bw.flush()?;
+    Ok(())
+}
+
+/// Write a single value as json: indented if the format is pretty, and otherwise on one line.
+pub fn write_json_value<T: Serialize>(
+    value: &T,
+    format: JsonFormat,
+    w: &mut dyn Write,
+) -> Result<()> {
+    let mut bw = BufWriter::new(w);
+    match format {
+        JsonFormat::Pretty => serde_json::to_writer_pretty(&mut bw, value)?,
+        JsonFormat::Ndjson | JsonFormat::Array => serde_json::to_writer(&mut bw, value)?,
+    }
+    writeln!(bw)?;
+    bw.flush()?;
     Ok(())
 }
 
 pub fn show_index_json(band: &Band, w: &mut dyn Write) -> Result<()> {
-    // TODO: Maybe use https://docs.serde.rs/serde/ser/trait.Serializer.html#method.collect_seq.
-    let bw = BufWriter::new(w);
-    let index_entries: Vec<IndexEntry> = band.index().iter_entries().collect();
-    serde_json::ser::to_writer_pretty(bw, &index_entries)
-        .map_err(|source| Error::SerializeJson { source })
+    write_json_seq(band.index().iter_entries(), JsonFormat::Pretty, w)
 }
 
 pub fn show_entry_names<E: EntryTrait, I: Iterator<Item = E>>(
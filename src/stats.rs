@@ -15,9 +15,12 @@ use std::fmt;
 use std::time::Duration;
 
 use derive_more::{Add, AddAssign};
+use serde::Serialize;
 use thousands::Separable;
 
+use crate::counters::{Counter, Counters};
 use crate::misc::duration_to_hms;
+use crate::monitor::Monitor;
 
 pub fn mb_string(s: u64) -> String {
     (s / 1_000_000).separate_with_commas()
@@ -67,6 +70,15 @@ pub(crate) fn write_duration(
     writeln!(w, "{:>12}      {}", duration_to_hms(duration), label)
 }
 
+/// Write a rate of megabytes per second.
+pub(crate) fn write_throughput(
+    w: &mut fmt::Formatter<'_>,
+    label: &str,
+    bytes_per_second: f64,
+) -> fmt::Result {
+    writeln!(w, "{:>12.1} MB/s {}", bytes_per_second / 1e6, label)
+}
+
 /// Describes sizes of data read or written, with both the
 /// compressed and uncompressed size.
 #[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq)]
@@ -75,20 +87,67 @@ pub struct Sizes {
     pub uncompressed: u64,
 }
 
-#[derive(Default, Debug, Clone, Eq, PartialEq)]
+#[derive(Default, Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct IndexReadStats {
     pub index_hunks: usize,
     pub uncompressed_index_bytes: u64,
     pub compressed_index_bytes: u64,
+    /// Hunks found in the archive's index cache rather than read from the transport.
+    pub cached_index_hunks: usize,
     pub errors: usize,
 }
 
+impl IndexReadStats {
+    /// Collect the index reads counted by a monitor, for example over a whole
+    /// `ls` or `restore`.
+    pub fn from_counters(counters: &Counters) -> IndexReadStats {
+        IndexReadStats {
+            index_hunks: counters.get(Counter::IndexReads),
+            uncompressed_index_bytes: counters.get(Counter::IndexReadUncompressedBytes) as u64,
+            compressed_index_bytes: counters.get(Counter::IndexReadCompressedBytes) as u64,
+            cached_index_hunks: counters.get(Counter::IndexReadCacheHits),
+            errors: counters.get(Counter::IndexReadErrors),
+        }
+    }
+
+    /// Add these reads to a monitor's counters.
+    pub(crate) fn count(&self, monitor: &dyn Monitor) {
+        monitor.count(Counter::IndexReads, self.index_hunks);
+        monitor.count(
+            Counter::IndexReadUncompressedBytes,
+            self.uncompressed_index_bytes as usize,
+        );
+        monitor.count(
+            Counter::IndexReadCompressedBytes,
+            self.compressed_index_bytes as usize,
+        );
+        monitor.count(Counter::IndexReadCacheHits, self.cached_index_hunks);
+        monitor.count(Counter::IndexReadErrors, self.errors);
+    }
+}
+
+impl fmt::Display for IndexReadStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(w, "index read stats")?;
+        write_count(w, "index hunks read", self.index_hunks);
+        write_compressed_size(
+            w,
+            self.compressed_index_bytes,
+            self.uncompressed_index_bytes,
+        );
+        write_count(w, "index hunks found in cache", self.cached_index_hunks);
+        write_count(w, "index read errors", self.errors);
+        Ok(())
+    }
+}
+
 #[derive(Debug, Default, Clone, Eq, PartialEq)]
 pub struct LiveTreeIterStats {
     pub directories_visited: usize,
     pub exclusions: usize,
     pub metadata_error: usize,
     pub entries_returned: usize,
+    pub overflow_entries: usize,
 }
 
 #[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq)]
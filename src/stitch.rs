@@ -28,6 +28,7 @@
 //!   seen.
 //! * Bands might be deleted, so their numbers are not contiguous.
 
+use std::mem::take;
 use std::sync::Arc;
 
 use tracing::trace;
@@ -117,7 +118,9 @@ impl Iterator for IterStitchedIndexHunks {
                     band_id,
                     index_hunks,
                 } => {
-                    if let Some(hunk) = index_hunks.next() {
+                    let hunk = index_hunks.next();
+                    take(&mut index_hunks.index.stats).count(self.monitor.as_ref());
+                    if let Some(hunk) = hunk {
                         if let Some(last_apath) = hunk.last().map(|entry| entry.apath.clone()) {
                             trace!(%last_apath, "return hunk");
                             self.last_apath = Some(last_apath);
@@ -200,6 +203,8 @@ mod test {
             mtime: 0,
             mtime_nanos: 0,
             addrs: Vec::new(),
+            content_hash: None,
+            empty_in_source: None,
             unix_mode: Default::default(),
             owner: Default::default(),
         }
@@ -29,7 +29,7 @@ use crate::*;
 pub struct StoredTree {
     band: Band,
     archive: Archive,
-    block_dir: Arc<BlockDir>,
+    pub(crate) block_dir: Arc<BlockDir>,
 }
 
 impl StoredTree {
@@ -45,6 +45,11 @@ impl StoredTree {
         &self.band
     }
 
+    /// The id of the band holding this tree.
+    pub fn band_id(&self) -> BandId {
+        self.band.id()
+    }
+
     pub fn is_closed(&self) -> Result<bool> {
         self.band.is_closed()
     }
@@ -15,5 +15,25 @@
 mod monitor;
 mod trace;
 
-pub use monitor::TermUiMonitor;
+pub use clap::ColorChoice;
+
+pub use monitor::{TermUiMonitor, DEFAULT_PROGRESS_INTERVAL};
 pub use trace::{enable_tracing, TraceTimeStyle};
+
+/// Decide whether messages on the terminal are colored.
+///
+/// With [ColorChoice::Auto], they're colored only on a color terminal, and not
+/// if the `NO_COLOR` environment variable is set to anything but an empty string.
+///
+/// This must be called before [enable_tracing] to affect trace messages.
+pub fn set_color_choice(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            std::env::var_os("NO_COLOR").map_or(true, |v| v.is_empty())
+                && clicolors_control::colors_enabled()
+        }
+    };
+    clicolors_control::set_colors_enabled(enabled);
+}
@@ -2,18 +2,22 @@
 
 //! Monitor on a terminal UI.
 
+use std::collections::BTreeMap;
+use std::io::{self, Write};
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::{Arc, Mutex};
 use std::thread::{sleep, spawn, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use nutmeg::{Destination, View};
+use serde::Serialize;
 use thousands::Separable;
 use tracing::error;
 
 use crate::counters::{Counter, Counters};
-use crate::monitor::task::{Task, TaskList};
+use crate::monitor::events::EventMonitor;
+use crate::monitor::task::{Task, TaskList, TaskState};
 use crate::monitor::Monitor;
 use crate::Error;
 
@@ -30,10 +34,21 @@ pub struct TermUiMonitor {
     poller: Option<JoinHandle<()>>,
     /// True to ask the poller thread to stop, during drop.
     stop_poller: Arc<AtomicBool>,
+    /// If progress is written as json snapshots, the last snapshot written.
+    json_progress: Option<Arc<JsonProgress>>,
     /// Number of errors reported.
     error_count: AtomicUsize,
+    /// Also send everything reported to this monitor as json events.
+    events: Option<EventMonitor>,
 }
 
+/// Default minimum time between redraws of the progress bars.
+pub const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The longest the poller thread sleeps at a time, so that it stops promptly
+/// even if the progress interval is long.
+const MAX_POLL_SLEEP: Duration = Duration::from_millis(100);
+
 /// The nutmeg model.
 pub(super) struct Model {
     counters: Arc<Counters>,
@@ -43,6 +58,31 @@ pub(super) struct Model {
 impl TermUiMonitor {
     /// Make a new terminal UI monitor.
     pub fn new(show_progress: bool) -> Self {
+        TermUiMonitor::new_with_interval(show_progress, DEFAULT_PROGRESS_INTERVAL)
+    }
+
+    /// Make a new terminal UI monitor that redraws progress bars at most once
+    /// per `progress_interval`.
+    ///
+    /// Counters and tasks can be updated much more often than this: the
+    /// changes are shown together on the next redraw.
+    pub fn new_with_interval(show_progress: bool, progress_interval: Duration) -> Self {
+        TermUiMonitor::new_inner(show_progress, false, progress_interval)
+    }
+
+    /// Make a new terminal UI monitor that, rather than drawing progress bars,
+    /// writes a snapshot of progress as one line of json to stderr at most once per
+    /// `progress_interval`, for a wrapper that draws its own progress UI.
+    ///
+    /// Each snapshot is like
+    /// `{"event":"progress","tasks":[{"name":"Backup","done":0,"total":0,"subtasks":[]}],"counters":{"Files":12}}`,
+    /// listing the active tasks and the counters that are not zero. A snapshot is
+    /// written only when something changed, and once more when the monitor is dropped.
+    pub fn new_with_json_progress(progress_interval: Duration) -> Self {
+        TermUiMonitor::new_inner(false, true, progress_interval)
+    }
+
+    fn new_inner(show_progress: bool, json_progress: bool, progress_interval: Duration) -> Self {
         let counters = Arc::new(Counters::default());
         let tasks = Arc::new(Mutex::new(TaskList::default()));
         // We'll update from a polling thread at regular intervals, so we don't need Nutmeg to rate limit updates.
@@ -58,13 +98,37 @@ impl TermUiMonitor {
             options,
         ));
         let stop_poller = Arc::new(AtomicBool::new(false));
-        let poller = if show_progress {
+        let json_progress = json_progress.then(|| {
+            Arc::new(JsonProgress {
+                counters: counters.clone(),
+                tasks: tasks.clone(),
+                last: Mutex::default(),
+            })
+        });
+        let poller = if let Some(json_progress) = &json_progress {
+            let json_progress = json_progress.clone();
+            let stop_poller2 = stop_poller.clone();
+            let mut throttle = RedrawThrottle::new(progress_interval);
+            let poll_sleep = progress_interval.clamp(Duration::from_millis(1), MAX_POLL_SLEEP);
+            Some(spawn(move || {
+                while !stop_poller2.load(Relaxed) {
+                    if throttle.ready(Instant::now()) {
+                        json_progress.write_if_changed();
+                    }
+                    sleep(poll_sleep);
+                }
+            }))
+        } else if show_progress {
             let view2 = view.clone();
             let stop_poller2 = stop_poller.clone();
+            let mut throttle = RedrawThrottle::new(progress_interval);
+            let poll_sleep = progress_interval.clamp(Duration::from_millis(1), MAX_POLL_SLEEP);
             Some(spawn(move || {
                 while !stop_poller2.load(Relaxed) {
-                    view2.update(|_| {});
-                    sleep(Duration::from_millis(100));
+                    if throttle.ready(Instant::now()) {
+                        view2.update(|_| {});
+                    }
+                    sleep(poll_sleep);
                 }
             }))
         } else {
@@ -76,10 +140,18 @@ impl TermUiMonitor {
             view,
             poller,
             stop_poller,
+            json_progress,
             error_count: AtomicUsize::new(0),
+            events: None,
         }
     }
 
+    /// Also write everything reported to this monitor as json events.
+    pub fn with_events(mut self, events: EventMonitor) -> Self {
+        self.events = Some(events);
+        self
+    }
+
     pub(super) fn view(&self) -> Arc<View<Model>> {
         Arc::clone(&self.view)
     }
@@ -107,25 +179,138 @@ impl Drop for TermUiMonitor {
                 .join()
                 .expect("Wait for nutmeg poller thread to stop");
         }
+        if let Some(json_progress) = &self.json_progress {
+            json_progress.write_if_changed();
+        }
+    }
+}
+
+/// Writes snapshots of progress as json lines on stderr.
+struct JsonProgress {
+    counters: Arc<Counters>,
+    tasks: Arc<Mutex<TaskList>>,
+    /// The last snapshot written.
+    last: Mutex<String>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename = "progress")]
+struct ProgressSnapshot {
+    tasks: Vec<TaskSnapshot>,
+    counters: BTreeMap<&'static str, usize>,
+}
+
+#[derive(Serialize)]
+struct TaskSnapshot {
+    name: String,
+    done: usize,
+    /// Zero if not known.
+    total: usize,
+    subtasks: Vec<TaskSnapshot>,
+}
+
+impl TaskSnapshot {
+    fn new(task: &TaskState) -> TaskSnapshot {
+        TaskSnapshot {
+            name: task.name(),
+            done: task.done(),
+            total: task.total(),
+            subtasks: task
+                .active_subtasks()
+                .iter()
+                .map(|subtask| TaskSnapshot::new(subtask))
+                .collect(),
+        }
+    }
+}
+
+impl JsonProgress {
+    fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            tasks: self
+                .tasks
+                .lock()
+                .unwrap()
+                .active_tasks()
+                .map(|task| TaskSnapshot::new(&task))
+                .collect(),
+            counters: self
+                .counters
+                .iter()
+                .filter(|(_, value)| *value > 0)
+                .map(|(counter, value)| (counter.into(), value))
+                .collect(),
+        }
+    }
+
+    fn write_if_changed(&self) {
+        let json = serde_json::to_string(&self.snapshot()).expect("Serialize progress");
+        let mut last = self.last.lock().unwrap();
+        if *last != json {
+            // Progress is only advisory, so if stderr can't be written there's nothing better to do.
+            let _ = writeln!(io::stderr().lock(), "{json}");
+            *last = json;
+        }
     }
 }
 
 impl Monitor for TermUiMonitor {
     fn count(&self, counter: Counter, increment: usize) {
-        self.counters.count(counter, increment)
+        self.counters.count(counter, increment);
+        if let Some(events) = &self.events {
+            events.count(counter, increment);
+        }
     }
 
     fn set_counter(&self, counter: Counter, value: usize) {
-        self.counters.set(counter, value)
+        self.counters.set(counter, value);
+        if let Some(events) = &self.events {
+            events.set_counter(counter, value);
+        }
     }
 
     fn error(&self, error: Error) {
         error!(target: "conserve", "{error}");
         self.error_count.fetch_add(1, Relaxed);
+        if let Some(events) = &self.events {
+            events.error(error);
+        }
     }
 
     fn start_task(&self, name: String) -> Task {
-        self.tasks.lock().unwrap().start_task(name)
+        let task = self.tasks.lock().unwrap().start_task(name);
+        if let Some(events) = &self.events {
+            events.watch_task(&task);
+        }
+        task
+    }
+}
+
+/// Decides when progress bars should be redrawn, so that they're redrawn at most
+/// once per interval however often they're polled.
+#[derive(Debug)]
+struct RedrawThrottle {
+    interval: Duration,
+    last_redraw: Option<Instant>,
+}
+
+impl RedrawThrottle {
+    fn new(interval: Duration) -> Self {
+        RedrawThrottle {
+            interval,
+            last_redraw: None,
+        }
+    }
+
+    /// Return true if it's time to redraw, and if so remember that a redraw happened now.
+    fn ready(&mut self, now: Instant) -> bool {
+        match self.last_redraw {
+            Some(last) if now.saturating_duration_since(last) < self.interval => false,
+            _ => {
+                self.last_redraw = Some(now);
+                true
+            }
+        }
     }
 }
 
@@ -138,8 +323,45 @@ impl nutmeg::Model for Model {
             }
         }
         for task in self.tasks.lock().unwrap().active_tasks() {
-            s += &format!("{}\n", task);
+            s += &task.format_tree();
         }
         s
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn updates_faster_than_the_interval_are_coalesced() {
+        let mut throttle = RedrawThrottle::new(Duration::from_millis(100));
+        let start = Instant::now();
+        let redraws = (0..100)
+            .map(|i| start + Duration::from_millis(i * 10))
+            .filter(|&now| throttle.ready(now))
+            .count();
+        assert_eq!(redraws, 10);
+    }
+
+    #[test]
+    fn json_progress_snapshot_includes_subtasks_and_nonzero_counters() {
+        let monitor = TermUiMonitor::new_with_json_progress(Duration::from_secs(3600));
+        let task = monitor.start_task("Validate".to_owned());
+        task.set_total(3);
+        let _band = task.start_subtask("Band b0001".to_owned());
+        monitor.count(Counter::Files, 2);
+        let snapshot = monitor.json_progress.as_ref().unwrap().snapshot();
+        assert_eq!(
+            serde_json::to_string(&snapshot).unwrap(),
+            r#"{"event":"progress","tasks":[{"name":"Validate","done":0,"total":3,"subtasks":[{"name":"Band b0001","done":0,"total":0,"subtasks":[]}]}],"counters":{"Files":2}}"#
+        );
+    }
+
+    #[test]
+    fn zero_interval_redraws_every_time() {
+        let mut throttle = RedrawThrottle::new(Duration::ZERO);
+        let now = Instant::now();
+        assert!((0..5).all(|_| throttle.ready(now)));
+    }
+}
@@ -16,16 +16,23 @@
 
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
 use std::{error, fmt, io, result};
 
 use bytes::Bytes;
 use derive_more::Display;
 use time::OffsetDateTime;
+use tracing::warn;
 use url::Url;
 
 use crate::*;
 
+pub mod filter;
 pub mod local;
+pub mod memory;
+pub mod mirror;
+pub mod record;
 #[cfg(feature = "sftp")]
 pub mod sftp;
 
@@ -58,6 +65,22 @@ impl Transport {
         }
     }
 
+    /// Make a new, empty transport that keeps everything in memory.
+    ///
+    /// Clones of this transport, and transports made from it by [Transport::chdir],
+    /// share the same contents, which are lost when the last of them is dropped.
+    pub fn memory() -> Self {
+        Transport {
+            protocol: Arc::new(memory::Protocol::new()),
+        }
+    }
+
+    /// Make a transport from a [Protocol] implemented outside this crate, for
+    /// example to store archives in some other kind of storage.
+    pub fn from_protocol(protocol: Arc<dyn Protocol>) -> Self {
+        Transport { protocol }
+    }
+
     /// Open a new transport from a string that might be a URL or local path.
     pub fn new(s: &str) -> Result<Self> {
         if let Ok(url) = Url::parse(s) {
@@ -102,6 +125,23 @@ impl Transport {
         self.protocol.read_file(path)
     }
 
+    /// Read a file, checking its content with a callback.
+    ///
+    /// The last call of the check is on the content that's returned. Most transports
+    /// return the content whatever the check says, but a [Transport::mirror] reads
+    /// from the other copy if the check fails.
+    ///
+    /// If the read fails partway through with an error that might be transient, it's
+    /// retried a few times, continuing from where it stopped on transports that
+    /// can read part of a file.
+    pub fn read_file_verified(
+        &self,
+        path: &str,
+        check: &mut dyn FnMut(&Bytes) -> bool,
+    ) -> Result<Bytes> {
+        self.protocol.read_file_verified(path, check)
+    }
+
     /// List a directory, separating out file and subdirectory names.
     ///
     /// Names are in the arbitrary order that they're returned from the transport.
@@ -153,10 +193,96 @@ impl Transport {
         self.protocol.url()
     }
 
-    #[allow(unused)]
-    fn local_path(&self) -> Option<PathBuf> {
+    /// Return a transport that flushes each file, and the directory containing it, to
+    /// stable storage before returning from a write.
+    ///
+    /// This is slower but means that a completed backup survives a crash or power loss
+    /// immediately afterwards. It only has an effect on local filesystems; other
+    /// transports are returned unchanged.
+    pub fn durable(&self) -> Transport {
+        Transport {
+            protocol: self
+                .protocol
+                .durable()
+                .unwrap_or_else(|| self.protocol.clone()),
+        }
+    }
+
+    /// Make a transport that keeps a copy of everything in both `primary` and
+    /// `secondary`, so that files missing or damaged in the primary can be read from,
+    /// and repaired from, the secondary.
+    ///
+    /// Writes and deletions must succeed in both to succeed. See [mirror] for details.
+    pub fn mirror(primary: &Transport, secondary: &Transport) -> Transport {
+        Transport {
+            protocol: Arc::new(mirror::Protocol::new(
+                primary.protocol.clone(),
+                secondary.protocol.clone(),
+            )),
+        }
+    }
+
+    /// Make a transport that passes blocks and index hunks through external commands
+    /// as they're written and read, for example to encrypt them.
+    ///
+    /// Each command is run by the shell, and filters stdin to stdout. See [filter]
+    /// for details.
+    pub fn external_filter(&self, encrypt_command: &str, decrypt_command: &str) -> Transport {
+        Transport {
+            protocol: Arc::new(filter::Protocol::new(
+                self.protocol.clone(),
+                encrypt_command,
+                decrypt_command,
+            )),
+        }
+    }
+
+    /// True if content written through this transport is passed through external
+    /// commands, from [Transport::external_filter].
+    pub fn is_filtered(&self) -> bool {
+        self.protocol.is_filtered()
+    }
+
+    /// Wrap this transport so that all calls through it, and through any transports
+    /// derived from it by [Transport::chdir], are recorded.
+    ///
+    /// This is intended for tests that check how much IO an operation does.
+    pub fn record_calls(&self) -> Transport {
+        Transport {
+            protocol: Arc::new(record::Protocol::new(self.protocol.clone(), Duration::ZERO)),
+        }
+    }
+
+    /// Like [Transport::record_calls], but also wait for `read_latency` in each read
+    /// of a file or listing of a directory, to test the behavior of operations on a
+    /// slow remote archive.
+    pub fn record_calls_with_read_latency(&self, read_latency: Duration) -> Transport {
+        Transport {
+            protocol: Arc::new(record::Protocol::new(self.protocol.clone(), read_latency)),
+        }
+    }
+
+    /// Return the calls recorded so far, if this is a recording transport from
+    /// [Transport::record_calls], or otherwise an empty list.
+    pub fn recorded_calls(&self) -> Vec<record::Call> {
+        self.protocol.recorded_calls().unwrap_or_default()
+    }
+
+    /// Return the largest number of calls that were in progress at once through a
+    /// recording transport from [Transport::record_calls], or otherwise 0.
+    pub fn max_concurrent_calls(&self) -> usize {
+        self.protocol.max_concurrent_calls().unwrap_or_default()
+    }
+
+    /// Return the local directory addressed by this transport, if it's on the local filesystem.
+    pub(crate) fn local_path(&self) -> Option<PathBuf> {
         self.protocol.local_path()
     }
+
+    /// Return the number of bytes that can still be written to the storage, if known.
+    pub(crate) fn available_space(&self) -> Option<u64> {
+        self.protocol.available_space()
+    }
 }
 
 impl fmt::Debug for Transport {
@@ -174,9 +300,48 @@ pub enum WriteMode {
     CreateNew,
 }
 
-trait Protocol: Send + Sync {
+/// Storage for an archive, wrapped by a [Transport].
+///
+/// This is implemented for each kind of storage, and can be implemented by other
+/// crates for their own storage, to be passed to [Transport::from_protocol].
+///
+/// Paths are relative to the directory addressed by the protocol, with components
+/// separated by `/`, and the empty string for the directory itself. Archives only
+/// use names made of ASCII letters, digits, and punctuation.
+///
+/// Errors should have an [ErrorKind] of [ErrorKind::NotFound] when a file or
+/// directory doesn't exist, because Conserve relies on that to see what's in the
+/// archive.
+pub trait Protocol: Send + Sync {
+    /// Read the whole content of a file.
     fn read_file(&self, path: &str) -> Result<Bytes>;
 
+    /// Read a file and check its content, giving the protocol a chance to find
+    /// another copy if the check fails.
+    ///
+    /// The last call of the check is always on the content that's returned, so the
+    /// check can keep whatever it computed from it.
+    fn read_file_verified(
+        &self,
+        path: &str,
+        check: &mut dyn FnMut(&Bytes) -> bool,
+    ) -> Result<Bytes> {
+        let content = read_file_resuming(self, path)?;
+        check(&content);
+        Ok(content)
+    }
+
+    /// Read a file from `offset` to the end, appending its content to `buf`.
+    ///
+    /// If the read fails partway through, `buf` keeps what was read before the
+    /// failure, so that the read can be resumed from there. This default reads the
+    /// whole file every time.
+    fn read_file_from(&self, path: &str, offset: u64, buf: &mut Vec<u8>) -> Result<()> {
+        let content = self.read_file(path)?;
+        buf.extend_from_slice(content.get(offset as usize..).unwrap_or_default());
+        Ok(())
+    }
+
     /// Write a complete file.
     ///
     /// Depending on the [WriteMode] this may either overwrite existing files, or error.
@@ -184,7 +349,13 @@ trait Protocol: Send + Sync {
     /// As much as possible, the file should be written atomically so that it is only visible with
     /// the complete content.
     fn write_file(&self, relpath: &str, content: &[u8], mode: WriteMode) -> Result<()>;
+
+    /// List the names of the files and subdirectories in a directory.
     fn list_dir(&self, relpath: &str) -> Result<ListDir>;
+
+    /// Create a directory, succeeding if it already exists.
+    ///
+    /// Its parent directory is always created first.
     fn create_dir(&self, relpath: &str) -> Result<()>;
 
     /// Get metadata about a file.
@@ -199,11 +370,75 @@ trait Protocol: Send + Sync {
     /// Make a new transport addressing a subdirectory.
     fn chdir(&self, relpath: &str) -> Arc<dyn Protocol>;
 
+    /// The URL of the directory addressed by this protocol, used in messages.
     fn url(&self) -> &Url;
 
+    /// The local directory addressed by this protocol, if it's on the local
+    /// filesystem.
     fn local_path(&self) -> Option<PathBuf> {
         None
     }
+
+    /// The number of bytes available to be written to the storage, if it can be
+    /// found out.
+    fn available_space(&self) -> Option<u64> {
+        None
+    }
+
+    /// Return a version of this protocol that flushes writes to stable storage,
+    /// or None if that's not needed or not supported.
+    fn durable(&self) -> Option<Arc<dyn Protocol>> {
+        None
+    }
+
+    /// True if this protocol passes content through external commands.
+    fn is_filtered(&self) -> bool {
+        false
+    }
+
+    /// Return the calls recorded by this protocol, if it records calls.
+    fn recorded_calls(&self) -> Option<Vec<record::Call>> {
+        None
+    }
+
+    /// Return the largest number of calls in progress at once, if this protocol
+    /// records calls.
+    fn max_concurrent_calls(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Prefix of the names of temporary files written while replacing a file, which may
+/// be left behind if the write is interrupted.
+///
+/// Earlier versions wrote temporary block files with the same prefix.
+pub(crate) const TEMP_FILE_PREFIX: &str = "tmp";
+
+/// Number of times a read that fails with a possibly transient error is retried.
+const READ_RETRIES: u32 = 4;
+
+/// Read a whole file, resuming after transient failures from the content already
+/// read.
+fn read_file_resuming<P: Protocol + ?Sized>(protocol: &P, path: &str) -> Result<Bytes> {
+    let mut buf = Vec::new();
+    let mut retries = 0;
+    loop {
+        match protocol.read_file_from(path, buf.len() as u64, &mut buf) {
+            Ok(()) => return Ok(buf.into()),
+            Err(err) if err.is_transient() && retries < READ_RETRIES => {
+                retries += 1;
+                warn!(
+                    ?err,
+                    path,
+                    bytes_read = buf.len(),
+                    retries,
+                    "Read failed; resuming"
+                );
+                sleep(Duration::from_millis(100) * retries);
+            }
+            Err(err) => return Err(err),
+        }
+    }
 }
 
 /// A directory entry read from a transport.
@@ -306,6 +541,28 @@ impl Error {
         self.kind == ErrorKind::NotFound
     }
 
+    /// True if the operation might succeed if it's tried again, such as after a
+    /// network failure.
+    ///
+    /// This is true for connection errors, and IO errors that interrupted a
+    /// transfer, but not for other errors such as a corrupt response.
+    pub fn is_transient(&self) -> bool {
+        self.kind == ErrorKind::Connect
+            || self
+                .source
+                .as_ref()
+                .and_then(|source| source.downcast_ref::<io::Error>())
+                .is_some_and(|source| {
+                    matches!(
+                        source.kind(),
+                        io::ErrorKind::Interrupted
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::ConnectionReset
+                            | io::ErrorKind::UnexpectedEof
+                    )
+                })
+    }
+
     /// The URL where this error occurred, if known.
     pub fn url(&self) -> Option<&Url> {
         self.url.as_ref()
@@ -332,13 +589,157 @@ impl error::Error for Error {
     }
 }
 
-type Result<T> = result::Result<T, Error>;
+/// The result of a transport operation.
+pub type Result<T> = result::Result<T, Error>;
 
 #[cfg(test)]
 mod test {
+    use std::io;
     use std::path::Path;
+    use std::sync::{Arc, Mutex};
 
-    use super::Transport;
+    use bytes::Bytes;
+    use url::Url;
+
+    use super::{Error, ErrorKind, ListDir, Metadata, Protocol, Result, Transport, WriteMode};
+    use crate::monitor::test::TestMonitor;
+    use crate::{BackupStats, BlockDir, BlockHash};
+
+    /// A protocol whose reads fail partway through a given number of times before
+    /// they succeed, remembering the offsets they were asked to start from.
+    struct Flaky {
+        inner: Arc<dyn Protocol>,
+        failures: Arc<Mutex<usize>>,
+        offsets: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl Protocol for Flaky {
+        fn read_file(&self, path: &str) -> Result<Bytes> {
+            self.inner.read_file(path)
+        }
+
+        fn read_file_from(&self, path: &str, offset: u64, buf: &mut Vec<u8>) -> Result<()> {
+            self.offsets.lock().unwrap().push(offset);
+            let content = self.inner.read_file(path)?;
+            let rest = &content[offset as usize..];
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                buf.extend_from_slice(&rest[..rest.len() / 2]);
+                return Err(Error {
+                    kind: ErrorKind::Other,
+                    source: Some(Box::new(io::Error::from(io::ErrorKind::ConnectionReset))),
+                    url: None,
+                });
+            }
+            buf.extend_from_slice(rest);
+            Ok(())
+        }
+
+        fn write_file(&self, relpath: &str, content: &[u8], mode: WriteMode) -> Result<()> {
+            self.inner.write_file(relpath, content, mode)
+        }
+
+        fn list_dir(&self, relpath: &str) -> Result<ListDir> {
+            self.inner.list_dir(relpath)
+        }
+
+        fn create_dir(&self, relpath: &str) -> Result<()> {
+            self.inner.create_dir(relpath)
+        }
+
+        fn metadata(&self, relpath: &str) -> Result<Metadata> {
+            self.inner.metadata(relpath)
+        }
+
+        fn remove_file(&self, relpath: &str) -> Result<()> {
+            self.inner.remove_file(relpath)
+        }
+
+        fn remove_dir_all(&self, relpath: &str) -> Result<()> {
+            self.inner.remove_dir_all(relpath)
+        }
+
+        fn chdir(&self, relpath: &str) -> Arc<dyn Protocol> {
+            Arc::new(Flaky {
+                inner: self.inner.chdir(relpath),
+                failures: self.failures.clone(),
+                offsets: self.offsets.clone(),
+            })
+        }
+
+        fn url(&self) -> &Url {
+            self.inner.url()
+        }
+    }
+
+    #[test]
+    fn block_read_resumes_after_partial_failures() {
+        let memory = Transport::memory();
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 253) as u8).collect();
+        let hash = BlockHash::hash_bytes(&content);
+        BlockDir::create(memory.clone())
+            .unwrap()
+            .store_or_deduplicate(
+                Bytes::from(content.clone()),
+                false,
+                &mut BackupStats::default(),
+                TestMonitor::arc(),
+            )
+            .unwrap();
+
+        let offsets = Arc::new(Mutex::new(Vec::new()));
+        let flaky = Transport {
+            protocol: Arc::new(Flaky {
+                inner: memory.protocol.clone(),
+                failures: Arc::new(Mutex::new(2)),
+                offsets: offsets.clone(),
+            }),
+        };
+        let read = BlockDir::open(flaky)
+            .get_block_content(&hash, TestMonitor::arc())
+            .unwrap();
+        assert_eq!(read.as_ref(), content);
+        assert_eq!(BlockHash::hash_bytes(&read), hash);
+
+        // Each retry continued from the end of what was already read.
+        let offsets = offsets.lock().unwrap();
+        assert_eq!(offsets.len(), 3);
+        assert_eq!(offsets[0], 0);
+        assert!(
+            offsets[1] > offsets[0] && offsets[2] > offsets[1],
+            "{offsets:?}"
+        );
+    }
+
+    #[test]
+    fn only_interrupted_transfers_are_transient() {
+        let io_error = |kind| Error {
+            kind: ErrorKind::Other,
+            source: Some(Box::new(io::Error::from(kind))),
+            url: None,
+        };
+        assert!(io_error(io::ErrorKind::TimedOut).is_transient());
+        assert!(io_error(io::ErrorKind::UnexpectedEof).is_transient());
+        assert!(!io_error(io::ErrorKind::InvalidData).is_transient());
+        assert!(!Error {
+            kind: ErrorKind::Other,
+            source: None,
+            url: None
+        }
+        .is_transient());
+        assert!(Error {
+            kind: ErrorKind::Connect,
+            source: None,
+            url: None
+        }
+        .is_transient());
+        assert!(!Error::io_error(
+            Path::new("/nothing"),
+            io::Error::from(io::ErrorKind::NotFound)
+        )
+        .is_transient());
+    }
 
     #[test]
     fn get_path_from_local_transport() {
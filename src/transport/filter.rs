@@ -0,0 +1,307 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A transport that passes the content of blocks and index hunks through external
+//! commands, such as `gpg` or `age`, to encrypt them when they're written and
+//! decrypt them when they're read.
+//!
+//! Each command is run through the shell, reading the content on stdin and writing
+//! the result to stdout. It's an error if the command can't be run or exits with a
+//! failure status, and the message includes what the command wrote to stderr.
+//!
+//! Only the payload files are filtered: blocks under `d/` and index hunks under
+//! `b*/i/`. Archive and band headers, locks, and other small metadata files are
+//! stored as they are, so that the archive can still be recognized and its bands
+//! listed without the commands.
+//!
+//! Blocks are still named by the hash of their uncompressed plaintext, so that
+//! identical content is deduplicated, but this means anyone who can list the
+//! archive can tell whether it holds a given block of content.
+//!
+//! A new process is started for every file, so this is slow for archives with many
+//! small blocks.
+
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+
+use bytes::Bytes;
+use url::Url;
+
+use super::record::join_relpath;
+use super::{Error, ErrorKind, ListDir, Metadata, Result, WriteMode};
+
+pub(super) struct Protocol {
+    inner: Arc<dyn super::Protocol>,
+    /// Path of this protocol relative to the top of the archive.
+    prefix: String,
+    encrypt_command: Arc<str>,
+    decrypt_command: Arc<str>,
+}
+
+impl Protocol {
+    pub(super) fn new(
+        inner: Arc<dyn super::Protocol>,
+        encrypt_command: &str,
+        decrypt_command: &str,
+    ) -> Self {
+        Protocol {
+            inner,
+            prefix: String::new(),
+            encrypt_command: encrypt_command.into(),
+            decrypt_command: decrypt_command.into(),
+        }
+    }
+
+    /// True if a file holds blocks or index content, which is passed through the
+    /// commands.
+    fn is_payload(&self, relpath: &str) -> bool {
+        let path = join_relpath(&self.prefix, relpath);
+        let mut parts = path.split('/');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("d"), Some(_), _) => true,
+            (Some(band), Some("i"), Some(_)) => band.starts_with('b'),
+            _ => false,
+        }
+    }
+
+    fn decrypt(&self, relpath: &str, content: &[u8]) -> Result<Bytes> {
+        run_filter(&self.decrypt_command, content)
+            .map(Bytes::from)
+            .map_err(|err| self.filter_error(&self.decrypt_command, relpath, err))
+    }
+
+    fn filter_error(&self, command: &str, relpath: &str, source: io::Error) -> Error {
+        Error {
+            kind: ErrorKind::Other,
+            source: Some(Box::new(io::Error::new(
+                source.kind(),
+                format!("Filter command {command:?} failed: {source}"),
+            ))),
+            url: self.inner.url().join(relpath).ok(),
+        }
+    }
+}
+
+/// Run a shell command with `input` on its stdin, and return what it writes to stdout.
+fn run_filter(command: &str, input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("Child has stdin");
+    let mut stdout = child.stdout.take().expect("Child has stdout");
+    let mut stderr = child.stderr.take().expect("Child has stderr");
+    // Write on another thread, so that the command doesn't block writing output
+    // that we're not yet reading.
+    let (output, errors) = thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(input));
+        let error_reader = scope.spawn(move || {
+            let mut errors = String::new();
+            stderr.read_to_string(&mut errors).map(|_| errors)
+        });
+        let mut output = Vec::new();
+        let read_result = stdout.read_to_end(&mut output);
+        let write_result = writer.join().expect("Join filter input thread");
+        let errors = error_reader
+            .join()
+            .expect("Join filter error thread")
+            .unwrap_or_default();
+        // A command that fails might not read all its input, so report its exit
+        // status in preference to a broken pipe.
+        (read_result.map(|_| (output, write_result)), errors)
+    });
+    let status = child.wait()?;
+    if !status.success() {
+        let errors = errors.trim();
+        return Err(io::Error::other(if errors.is_empty() {
+            format!("{status}")
+        } else {
+            format!("{status}: {errors}")
+        }));
+    }
+    let (output, write_result) = output?;
+    write_result?;
+    Ok(output)
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+impl super::Protocol for Protocol {
+    fn read_file(&self, relpath: &str) -> Result<Bytes> {
+        let content = self.inner.read_file(relpath)?;
+        if self.is_payload(relpath) {
+            self.decrypt(relpath, &content)
+        } else {
+            Ok(content)
+        }
+    }
+
+    /// Decrypt each copy the inner transport reads before checking it, so that a
+    /// mirror can fall back to another copy if one can't be decrypted.
+    fn read_file_verified(
+        &self,
+        relpath: &str,
+        check: &mut dyn FnMut(&Bytes) -> bool,
+    ) -> Result<Bytes> {
+        if !self.is_payload(relpath) {
+            return self.inner.read_file_verified(relpath, check);
+        }
+        let mut decrypted = None;
+        self.inner.read_file_verified(relpath, &mut |content| {
+            let result = self.decrypt(relpath, content);
+            let ok = match &result {
+                Ok(plain) => check(plain),
+                Err(_) => false,
+            };
+            decrypted = Some(result);
+            ok
+        })?;
+        decrypted.expect("Inner transport checked the content")
+    }
+
+    fn write_file(&self, relpath: &str, content: &[u8], mode: WriteMode) -> Result<()> {
+        if self.is_payload(relpath) {
+            let encrypted = run_filter(&self.encrypt_command, content)
+                .map_err(|err| self.filter_error(&self.encrypt_command, relpath, err))?;
+            self.inner.write_file(relpath, &encrypted, mode)
+        } else {
+            self.inner.write_file(relpath, content, mode)
+        }
+    }
+
+    fn list_dir(&self, relpath: &str) -> Result<ListDir> {
+        self.inner.list_dir(relpath)
+    }
+
+    fn create_dir(&self, relpath: &str) -> Result<()> {
+        self.inner.create_dir(relpath)
+    }
+
+    fn metadata(&self, relpath: &str) -> Result<Metadata> {
+        self.inner.metadata(relpath)
+    }
+
+    fn remove_file(&self, relpath: &str) -> Result<()> {
+        self.inner.remove_file(relpath)
+    }
+
+    fn remove_dir_all(&self, relpath: &str) -> Result<()> {
+        self.inner.remove_dir_all(relpath)
+    }
+
+    fn chdir(&self, relpath: &str) -> Arc<dyn super::Protocol> {
+        Arc::new(Protocol {
+            inner: self.inner.chdir(relpath),
+            prefix: join_relpath(&self.prefix, relpath),
+            encrypt_command: Arc::clone(&self.encrypt_command),
+            decrypt_command: Arc::clone(&self.decrypt_command),
+        })
+    }
+
+    fn url(&self) -> &Url {
+        self.inner.url()
+    }
+
+    fn local_path(&self) -> Option<PathBuf> {
+        self.inner.local_path()
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        self.inner.available_space()
+    }
+
+    fn durable(&self) -> Option<Arc<dyn super::Protocol>> {
+        Some(Arc::new(Protocol {
+            inner: self.inner.durable()?,
+            prefix: self.prefix.clone(),
+            encrypt_command: Arc::clone(&self.encrypt_command),
+            decrypt_command: Arc::clone(&self.decrypt_command),
+        }))
+    }
+
+    fn is_filtered(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use crate::transport::{Transport, WriteMode};
+
+    #[test]
+    fn payload_files_are_filtered_and_metadata_is_not() {
+        let inner = Transport::memory();
+        let filtered = inner.external_filter("tr a-z A-Z", "tr A-Z a-z");
+        for dir in ["d", "d/abc", "b0000", "b0000/i", "b0000/i/00000"] {
+            filtered.create_dir(dir).unwrap();
+        }
+        filtered
+            .write_file("CONSERVE", b"header", WriteMode::CreateNew)
+            .unwrap();
+        let blocks = filtered.chdir("d");
+        blocks
+            .write_file("abc/abcdef", b"block", WriteMode::CreateNew)
+            .unwrap();
+        filtered
+            .chdir("b0000")
+            .write_file("i/00000/000000000", b"index", WriteMode::CreateNew)
+            .unwrap();
+        filtered
+            .write_file("b0000/BANDHEAD", b"head", WriteMode::CreateNew)
+            .unwrap();
+
+        assert_eq!(inner.read_file("CONSERVE").unwrap().as_ref(), b"header");
+        assert_eq!(inner.read_file("d/abc/abcdef").unwrap().as_ref(), b"BLOCK");
+        assert_eq!(
+            inner.read_file("b0000/i/00000/000000000").unwrap().as_ref(),
+            b"INDEX"
+        );
+        assert_eq!(inner.read_file("b0000/BANDHEAD").unwrap().as_ref(), b"head");
+        assert_eq!(blocks.read_file("abc/abcdef").unwrap().as_ref(), b"block");
+        assert_eq!(
+            filtered
+                .read_file_verified("d/abc/abcdef", &mut |content| content.as_ref() == b"block")
+                .unwrap()
+                .as_ref(),
+            b"block"
+        );
+    }
+
+    #[test]
+    fn failing_command_is_an_error() {
+        let filtered = Transport::memory().external_filter("conserve-no-such-command", "cat");
+        filtered.create_dir("d").unwrap();
+        filtered.create_dir("d/abc").unwrap();
+        let err = filtered
+            .write_file("d/abc/abcdef", b"block", WriteMode::CreateNew)
+            .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("conserve-no-such-command"), "{message}");
+        assert!(!filtered.is_file("d/abc/abcdef").unwrap());
+    }
+}
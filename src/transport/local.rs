@@ -15,6 +15,7 @@
 use std::fs::{create_dir, remove_dir_all, remove_file, File};
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::{io, path};
 
@@ -22,11 +23,27 @@ use bytes::Bytes;
 use tracing::{error, instrument, trace, warn};
 use url::Url;
 
-use super::{Error, ListDir, Metadata, Result, WriteMode};
+use super::{Error, ListDir, Metadata, Result, WriteMode, TEMP_FILE_PREFIX};
+
+/// Make a temporary file in `dir`, to be written and then renamed over the file it
+/// replaces.
+///
+/// If the process stops before the rename, the file is left behind.
+pub(crate) fn new_temp_file(dir: &Path) -> io::Result<tempfile::NamedTempFile> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(TEMP_FILE_PREFIX);
+    #[cfg(unix)]
+    builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+    builder.tempfile_in(dir)
+}
 
 pub(super) struct Protocol {
     path: PathBuf,
     url: Url,
+    /// If true, flush written files and the directories containing them to disk.
+    durable: bool,
+    /// Number of files and directories flushed, shared with protocols made by chdir.
+    sync_count: Arc<AtomicUsize>,
 }
 
 impl Protocol {
@@ -35,6 +52,8 @@ impl Protocol {
             path: path.to_owned(),
             url: Url::from_directory_path(path::absolute(path).expect("make path absolute"))
                 .expect("convert path to URL"),
+            durable: false,
+            sync_count: Arc::default(),
         }
     }
 
@@ -42,6 +61,41 @@ impl Protocol {
         debug_assert!(!relpath.contains("/../"), "path must not contain /../");
         self.path.join(relpath)
     }
+
+    fn sync_file(&self, file: &File) -> io::Result<()> {
+        self.sync_count.fetch_add(1, Ordering::Relaxed);
+        file.sync_all()
+    }
+
+    /// Atomically replace the file at `path`, by writing a temporary file alongside it
+    /// and renaming it into place, so that readers and concurrent writers never see a
+    /// partly written file.
+    fn replace_file(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let mut temp = new_temp_file(path.parent().unwrap_or(&self.path))?;
+        temp.write_all(content)?;
+        if self.durable {
+            self.sync_file(temp.as_file())?;
+        }
+        temp.persist(path).map_err(|err| err.error)?;
+        trace!("Replaced {path:?} with {} bytes", content.len());
+        if self.durable {
+            self.sync_parent_dir(path)?;
+        }
+        Ok(())
+    }
+
+    /// Flush the directory containing `path`, so that a newly created entry is durable.
+    ///
+    /// Directories can't be opened as files on Windows, and NTFS journals directory
+    /// changes anyhow, so this only does anything on Unix.
+    fn sync_parent_dir(&self, path: &Path) -> io::Result<()> {
+        if cfg!(unix) {
+            let parent = path.parent().unwrap_or(&self.path);
+            self.sync_file(&File::open(parent)?)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl super::Protocol for Protocol {
@@ -67,22 +121,29 @@ impl super::Protocol for Protocol {
         try_block(path).map_err(|err| Error::io_error(path, err))
     }
 
+    fn read_file_from(&self, relpath: &str, offset: u64, buf: &mut Vec<u8>) -> Result<()> {
+        let path = &self.full_path(relpath);
+        File::open(path)
+            .and_then(|mut file| {
+                file.seek(io::SeekFrom::Start(offset))?;
+                file.read_to_end(buf)
+            })
+            .map(|_| ())
+            .map_err(|err| Error::io_error(path, err))
+    }
+
     #[instrument(skip(self, content))]
     fn write_file(&self, relpath: &str, content: &[u8], write_mode: WriteMode) -> Result<()> {
-        // TODO: Just write directly; remove if the write fails.
         let full_path = self.full_path(relpath);
         let oops = |err| super::Error::io_error(&full_path, err);
-        let mut options = File::options();
-        options.write(true);
-        match write_mode {
-            WriteMode::CreateNew => {
-                options.create_new(true);
-            }
-            WriteMode::Overwrite => {
-                options.create(true).truncate(true);
-            }
+        if write_mode == WriteMode::Overwrite {
+            return self.replace_file(&full_path, content).map_err(oops);
         }
-        let mut file = options.open(&full_path).map_err(oops)?;
+        let mut file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&full_path)
+            .map_err(oops)?;
         if let Err(err) = file.write_all(content) {
             error!("Failed to write {full_path:?}: {err:?}");
             drop(file);
@@ -92,6 +153,10 @@ impl super::Protocol for Protocol {
             return Err(oops(err));
         }
         trace!("Wrote {} bytes", content.len());
+        if self.durable {
+            self.sync_file(&file).map_err(oops)?;
+            self.sync_parent_dir(&full_path).map_err(oops)?;
+        }
         Ok(())
     }
 
@@ -121,13 +186,14 @@ impl super::Protocol for Protocol {
 
     fn create_dir(&self, relpath: &str) -> Result<()> {
         let path = self.full_path(relpath);
-        create_dir(&path).or_else(|err| {
-            if err.kind() == io::ErrorKind::AlreadyExists {
-                Ok(())
-            } else {
-                Err(super::Error::io_error(&path, err))
-            }
-        })
+        match create_dir(&path) {
+            Ok(()) if self.durable => self
+                .sync_parent_dir(&path)
+                .map_err(|err| super::Error::io_error(&path, err)),
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            Err(err) => Err(super::Error::io_error(&path, err)),
+        }
     }
 
     fn metadata(&self, relpath: &str) -> Result<Metadata> {
@@ -158,12 +224,35 @@ impl super::Protocol for Protocol {
         Arc::new(Protocol {
             path: self.path.join(relpath),
             url: self.url.join(relpath).expect("join URL"),
+            durable: self.durable,
+            sync_count: Arc::clone(&self.sync_count),
         })
     }
 
+    fn durable(&self) -> Option<Arc<dyn super::Protocol>> {
+        Some(Arc::new(Protocol {
+            path: self.path.clone(),
+            url: self.url.clone(),
+            durable: true,
+            sync_count: Arc::clone(&self.sync_count),
+        }))
+    }
+
     fn local_path(&self) -> Option<PathBuf> {
         Some(self.path.clone())
     }
+
+    #[cfg(unix)]
+    #[allow(clippy::unnecessary_cast)] // The types are narrower on some Unixes.
+    fn available_space(&self) -> Option<u64> {
+        match nix::sys::statvfs::statvfs(&self.path) {
+            Ok(stat) => Some(stat.blocks_available() as u64 * stat.fragment_size() as u64),
+            Err(err) => {
+                warn!(?err, path = ?self.path, "Failed to get available space");
+                None
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -177,7 +266,7 @@ mod test {
 
     use super::*;
     use crate::kind::Kind;
-    use crate::transport::{self, Transport};
+    use crate::transport::{self, Protocol as _, Transport};
 
     #[test]
     fn read_file() {
@@ -194,6 +283,18 @@ mod test {
         temp.close().unwrap();
     }
 
+    #[test]
+    fn read_file_from_offset_appends_the_rest() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        temp.child("poem.txt")
+            .write_str("the ribs of the disaster")
+            .unwrap();
+        let protocol = Protocol::new(temp.path());
+        let mut buf = b"the ribs".to_vec();
+        protocol.read_file_from("poem.txt", 8, &mut buf).unwrap();
+        assert_eq!(buf, b"the ribs of the disaster");
+    }
+
     #[test]
     fn read_file_not_found() {
         let temp = assert_fs::TempDir::new().unwrap();
@@ -221,6 +322,14 @@ mod test {
         assert_eq!(io_source.kind(), io::ErrorKind::NotFound);
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn available_space_is_known() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let transport = Transport::local(temp.path());
+        assert!(transport.available_space().unwrap() > 0);
+    }
+
     #[test]
     fn read_metadata() {
         let temp = assert_fs::TempDir::new().unwrap();
@@ -320,6 +429,51 @@ mod test {
         );
     }
 
+    #[test]
+    fn overwrite_replaces_file_without_leaving_temporary_files() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let transport = Transport::local(temp.path());
+        temp.child("file").write_str("original content").unwrap();
+        let original = File::open(temp.child("file").path()).unwrap();
+        transport
+            .write_file("file", b"new content", WriteMode::Overwrite)
+            .unwrap();
+        // The file was replaced rather than truncated, so a reader that already had
+        // it open still sees the old content.
+        assert_eq!(io::read_to_string(original).unwrap(), "original content");
+        temp.child("file").assert("new content");
+        assert_eq!(transport.list_dir("").unwrap().files, ["file"]);
+    }
+
+    #[test]
+    fn durable_writes_sync_files_and_directories() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let protocol = Protocol::new(temp.path());
+        let durable = super::super::Protocol::durable(&protocol).unwrap();
+        let sub = durable.chdir("sub");
+
+        durable.create_dir("sub").unwrap();
+        sub.write_file("f", b"durable", WriteMode::CreateNew)
+            .unwrap();
+        temp.child("sub").child("f").assert("durable");
+        let expected = if cfg!(unix) { 3 } else { 1 };
+        assert_eq!(protocol.sync_count.load(Ordering::Relaxed), expected);
+
+        // Existing directories don't need to be synced again.
+        durable.create_dir("sub").unwrap();
+        assert_eq!(protocol.sync_count.load(Ordering::Relaxed), expected);
+    }
+
+    #[test]
+    fn non_durable_writes_dont_sync() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let protocol = Protocol::new(temp.path());
+        super::super::Protocol::create_dir(&protocol, "sub").unwrap();
+        super::super::Protocol::write_file(&protocol, "sub/f", b"fast", WriteMode::CreateNew)
+            .unwrap();
+        assert_eq!(protocol.sync_count.load(Ordering::Relaxed), 0);
+    }
+
     #[test]
     fn create_existing_dir() {
         let temp = assert_fs::TempDir::new().unwrap();
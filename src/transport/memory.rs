@@ -0,0 +1,342 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! An archive held entirely in memory, for tests and for short-lived archives.
+//!
+//! Directories behave like those on a local filesystem: files can only be written
+//! into directories that already exist, and listing or removing a missing directory
+//! fails.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use time::OffsetDateTime;
+use url::Url;
+
+use super::{Error, ErrorKind, ListDir, Metadata, Result, WriteMode};
+use crate::Kind;
+
+/// The contents of the whole memory filesystem, keyed by slash-separated paths
+/// relative to the root, with the root itself as "".
+#[derive(Debug)]
+struct Fs {
+    files: BTreeMap<String, File>,
+    /// Modification times of directories.
+    dirs: BTreeMap<String, OffsetDateTime>,
+}
+
+#[derive(Debug)]
+struct File {
+    content: Bytes,
+    modified: OffsetDateTime,
+}
+
+impl Fs {
+    fn new() -> Fs {
+        Fs {
+            files: BTreeMap::new(),
+            dirs: BTreeMap::from([(String::new(), OffsetDateTime::now_utc())]),
+        }
+    }
+
+    fn parent_exists(&self, path: &str) -> bool {
+        self.dirs.contains_key(parent(path))
+    }
+}
+
+pub(super) struct Protocol {
+    fs: Arc<Mutex<Fs>>,
+    /// Path of this protocol's directory within the filesystem.
+    prefix: String,
+    url: Url,
+}
+
+impl Protocol {
+    pub(super) fn new() -> Self {
+        Protocol {
+            fs: Arc::new(Mutex::new(Fs::new())),
+            prefix: String::new(),
+            url: Url::parse("memory:///").expect("parse memory URL"),
+        }
+    }
+
+    /// Return the path within the filesystem for a path relative to this protocol.
+    fn full_path(&self, relpath: &str) -> String {
+        self.prefix
+            .split('/')
+            .chain(relpath.split('/'))
+            .filter(|part| !part.is_empty() && *part != ".")
+            .inspect(|part| debug_assert!(*part != "..", "path must not contain .."))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    fn error(&self, kind: ErrorKind, full_path: &str) -> Error {
+        Error {
+            kind,
+            source: None,
+            url: self.url.join(&format!("/{full_path}")).ok(),
+        }
+    }
+}
+
+/// Return the parent of a full path, with "" for the root.
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/// Iterate the entries of a map that are strictly below the directory `dir`.
+fn children<'a, V>(
+    map: &'a BTreeMap<String, V>,
+    dir: &str,
+) -> impl Iterator<Item = (&'a String, &'a V)> + 'a {
+    let start = if dir.is_empty() {
+        String::new()
+    } else {
+        format!("{dir}/")
+    };
+    map.range::<String, _>((Bound::Excluded(&start), Bound::Unbounded))
+        .take_while(move |(path, _)| path.starts_with(&start))
+}
+
+impl super::Protocol for Protocol {
+    fn read_file(&self, relpath: &str) -> Result<Bytes> {
+        let path = self.full_path(relpath);
+        let fs = self.fs.lock().unwrap();
+        fs.files
+            .get(&path)
+            .map(|file| file.content.clone())
+            .ok_or_else(|| self.error(ErrorKind::NotFound, &path))
+    }
+
+    fn write_file(&self, relpath: &str, content: &[u8], mode: WriteMode) -> Result<()> {
+        let path = self.full_path(relpath);
+        let mut fs = self.fs.lock().unwrap();
+        if !fs.parent_exists(&path) {
+            return Err(self.error(ErrorKind::NotFound, &path));
+        }
+        if fs.dirs.contains_key(&path)
+            || (mode == WriteMode::CreateNew && fs.files.contains_key(&path))
+        {
+            return Err(self.error(ErrorKind::AlreadyExists, &path));
+        }
+        // The whole content is inserted at once, so the write is atomic.
+        fs.files.insert(
+            path,
+            File {
+                content: Bytes::copy_from_slice(content),
+                modified: OffsetDateTime::now_utc(),
+            },
+        );
+        Ok(())
+    }
+
+    fn list_dir(&self, relpath: &str) -> Result<ListDir> {
+        let path = self.full_path(relpath);
+        let fs = self.fs.lock().unwrap();
+        if !fs.dirs.contains_key(&path) {
+            return Err(self.error(ErrorKind::NotFound, &path));
+        }
+        let name_if_child = |child: &String| {
+            let name = child[path.len()..].trim_start_matches('/');
+            (!name.contains('/')).then(|| name.to_owned())
+        };
+        Ok(ListDir {
+            files: children(&fs.files, &path)
+                .filter_map(|(child, _)| name_if_child(child))
+                .collect(),
+            dirs: children(&fs.dirs, &path)
+                .filter_map(|(child, _)| name_if_child(child))
+                .collect(),
+        })
+    }
+
+    fn create_dir(&self, relpath: &str) -> Result<()> {
+        let path = self.full_path(relpath);
+        let mut fs = self.fs.lock().unwrap();
+        if fs.dirs.contains_key(&path) {
+            Ok(())
+        } else if fs.files.contains_key(&path) {
+            Err(self.error(ErrorKind::AlreadyExists, &path))
+        } else if !fs.parent_exists(&path) {
+            Err(self.error(ErrorKind::NotFound, &path))
+        } else {
+            fs.dirs.insert(path, OffsetDateTime::now_utc());
+            Ok(())
+        }
+    }
+
+    fn metadata(&self, relpath: &str) -> Result<Metadata> {
+        let path = self.full_path(relpath);
+        let fs = self.fs.lock().unwrap();
+        if let Some(file) = fs.files.get(&path) {
+            Ok(Metadata {
+                len: file.content.len() as u64,
+                kind: Kind::File,
+                modified: file.modified,
+            })
+        } else if let Some(modified) = fs.dirs.get(&path) {
+            Ok(Metadata {
+                len: 0,
+                kind: Kind::Dir,
+                modified: *modified,
+            })
+        } else {
+            Err(self.error(ErrorKind::NotFound, &path))
+        }
+    }
+
+    fn remove_file(&self, relpath: &str) -> Result<()> {
+        let path = self.full_path(relpath);
+        match self.fs.lock().unwrap().files.remove(&path) {
+            Some(_) => Ok(()),
+            None => Err(self.error(ErrorKind::NotFound, &path)),
+        }
+    }
+
+    fn remove_dir_all(&self, relpath: &str) -> Result<()> {
+        let path = self.full_path(relpath);
+        let mut fs = self.fs.lock().unwrap();
+        if fs.dirs.remove(&path).is_none() {
+            return Err(self.error(ErrorKind::NotFound, &path));
+        }
+        let below = |child: &String| {
+            child.len() > path.len() && child.starts_with(&path) && {
+                path.is_empty() || child.as_bytes()[path.len()] == b'/'
+            }
+        };
+        fs.files.retain(|child, _| !below(child));
+        fs.dirs.retain(|child, _| !below(child));
+        if path.is_empty() {
+            // The root always exists.
+            fs.dirs.insert(String::new(), OffsetDateTime::now_utc());
+        }
+        Ok(())
+    }
+
+    fn chdir(&self, relpath: &str) -> Arc<dyn super::Protocol> {
+        let prefix = self.full_path(relpath);
+        Arc::new(Protocol {
+            fs: Arc::clone(&self.fs),
+            url: self
+                .url
+                .join(&format!("/{prefix}/"))
+                .expect("join memory URL"),
+            prefix,
+        })
+    }
+
+    fn url(&self) -> &Url {
+        &self.url
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::transport::{self, Transport, WriteMode};
+    use crate::Kind;
+
+    #[test]
+    fn write_read_and_list() {
+        let transport = Transport::memory();
+        transport.create_dir("subdir").unwrap();
+        transport
+            .write_file("subdir/subfile", b"Morning coffee", WriteMode::CreateNew)
+            .unwrap();
+        transport
+            .write_file("root file", b"", WriteMode::CreateNew)
+            .unwrap();
+        assert_eq!(
+            transport.read_file("subdir/subfile").unwrap().as_ref(),
+            b"Morning coffee"
+        );
+
+        let root_list = transport.list_dir(".").unwrap();
+        assert_eq!(root_list.files, ["root file"]);
+        assert_eq!(root_list.dirs, ["subdir"]);
+        let subdir_list = transport.chdir("subdir").list_dir("").unwrap();
+        assert_eq!(subdir_list.files, ["subfile"]);
+        assert!(subdir_list.dirs.is_empty());
+
+        let metadata = transport.metadata("subdir/subfile").unwrap();
+        assert_eq!(metadata.len, 14);
+        assert_eq!(metadata.kind, Kind::File);
+        assert_eq!(transport.metadata("subdir").unwrap().kind, Kind::Dir);
+        assert!(transport.is_file("root file").unwrap());
+        assert!(!transport.is_file("subdir").unwrap());
+        assert_eq!(transport.url().as_str(), "memory:///");
+        assert_eq!(
+            transport.chdir("subdir").url().as_str(),
+            "memory:///subdir/"
+        );
+    }
+
+    #[test]
+    fn write_modes() {
+        let transport = Transport::memory();
+        transport
+            .write_file("f", b"original", WriteMode::CreateNew)
+            .unwrap();
+        let err = transport
+            .write_file("f", b"again", WriteMode::CreateNew)
+            .unwrap_err();
+        assert_eq!(err.kind(), transport::ErrorKind::AlreadyExists);
+        transport
+            .write_file("f", b"new content", WriteMode::Overwrite)
+            .unwrap();
+        assert_eq!(transport.read_file("f").unwrap().as_ref(), b"new content");
+    }
+
+    #[test]
+    fn missing_paths_are_not_found() {
+        let transport = Transport::memory();
+        assert!(transport.read_file("nothing").unwrap_err().is_not_found());
+        assert!(transport.metadata("nothing").unwrap_err().is_not_found());
+        assert!(transport.list_dir("nothing").unwrap_err().is_not_found());
+        assert!(transport.remove_file("nothing").unwrap_err().is_not_found());
+        assert!(transport
+            .remove_dir_all("nothing")
+            .unwrap_err()
+            .is_not_found());
+        assert!(transport
+            .create_dir("no/parent")
+            .unwrap_err()
+            .is_not_found());
+        let err = transport
+            .write_file("no/parent", b"", WriteMode::CreateNew)
+            .unwrap_err();
+        assert!(err.is_not_found());
+        assert_eq!(err.url().unwrap().as_str(), "memory:///no/parent");
+    }
+
+    #[test]
+    fn remove_file_and_dir() {
+        let transport = Transport::memory();
+        transport.create_dir("aaa").unwrap();
+        transport.create_dir("aaa/bbb").unwrap();
+        transport.create_dir("aaab").unwrap();
+        transport
+            .write_file("aaa/bbb/f", b"", WriteMode::CreateNew)
+            .unwrap();
+        transport
+            .write_file("aaa/g", b"", WriteMode::CreateNew)
+            .unwrap();
+        transport.remove_file("aaa/g").unwrap();
+        assert!(transport.list_dir("aaa").unwrap().files.is_empty());
+
+        transport.remove_dir_all("aaa").unwrap();
+        assert_eq!(transport.list_dir("").unwrap().dirs, ["aaab"]);
+        assert!(transport.read_file("aaa/bbb/f").unwrap_err().is_not_found());
+    }
+}
@@ -0,0 +1,281 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A transport that keeps two copies of an archive, so that a file that's missing
+//! or damaged in one can be read from, and repaired from, the other.
+//!
+//! Reads come from the primary, falling back to the secondary if the file isn't
+//! found there, or if the caller's check of its content fails. A good copy read
+//! from the secondary is written back to the primary, to heal it.
+//!
+//! Writes, directory creation, and deletions go to both copies, primary first, and
+//! only succeed if they succeed on both. If the primary fails, the secondary isn't
+//! changed.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tracing::{debug, warn};
+use url::Url;
+
+use super::{read_file_resuming, ListDir, Metadata, Result, WriteMode};
+
+pub(super) struct Protocol {
+    primary: Arc<dyn super::Protocol>,
+    secondary: Arc<dyn super::Protocol>,
+}
+
+impl Protocol {
+    pub(super) fn new(
+        primary: Arc<dyn super::Protocol>,
+        secondary: Arc<dyn super::Protocol>,
+    ) -> Self {
+        Protocol { primary, secondary }
+    }
+
+    /// Read a file from the secondary after it couldn't be read from the primary,
+    /// and if it passes the check, copy it back to the primary.
+    fn read_secondary_and_heal(
+        &self,
+        relpath: &str,
+        check: &mut dyn FnMut(&Bytes) -> bool,
+    ) -> Option<Bytes> {
+        let content = match read_file_resuming(&*self.secondary, relpath) {
+            Ok(content) => content,
+            Err(err) => {
+                debug!(?err, relpath, "Failed to read from mirror secondary");
+                return None;
+            }
+        };
+        if !check(&content) {
+            warn!(relpath, "File is damaged in both copies of the mirror");
+            return None;
+        }
+        warn!(
+            relpath,
+            "Read file from mirror secondary, and copying it to the primary"
+        );
+        if let Err(err) = self
+            .primary
+            .write_file(relpath, &content, WriteMode::Overwrite)
+        {
+            warn!(relpath, ?err, "Failed to repair file in mirror primary");
+        }
+        Some(content)
+    }
+}
+
+/// Return whether the operation succeeded, or false if the file was not found.
+fn ignore_not_found(result: Result<()>) -> Result<bool> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(err) if err.is_not_found() => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+impl super::Protocol for Protocol {
+    fn read_file(&self, relpath: &str) -> Result<Bytes> {
+        self.read_file_verified(relpath, &mut |_| true)
+    }
+
+    fn read_file_verified(
+        &self,
+        relpath: &str,
+        check: &mut dyn FnMut(&Bytes) -> bool,
+    ) -> Result<Bytes> {
+        match read_file_resuming(&*self.primary, relpath) {
+            Ok(content) if check(&content) => Ok(content),
+            Ok(content) => {
+                warn!(relpath, "File is damaged in mirror primary");
+                match self.read_secondary_and_heal(relpath, check) {
+                    Some(good) => Ok(good),
+                    None => {
+                        // Leave the caller's check seeing the content that's returned.
+                        check(&content);
+                        Ok(content)
+                    }
+                }
+            }
+            Err(err) if err.is_not_found() => {
+                self.read_secondary_and_heal(relpath, check).ok_or(err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write_file(&self, relpath: &str, content: &[u8], mode: WriteMode) -> Result<()> {
+        self.primary.write_file(relpath, content, mode)?;
+        self.secondary.write_file(relpath, content, mode)
+    }
+
+    /// List files and directories present in either copy.
+    fn list_dir(&self, relpath: &str) -> Result<ListDir> {
+        let mut list = self.primary.list_dir(relpath)?;
+        match self.secondary.list_dir(relpath) {
+            Ok(secondary) => {
+                for (names, more) in [
+                    (&mut list.files, secondary.files),
+                    (&mut list.dirs, secondary.dirs),
+                ] {
+                    for name in more {
+                        if !names.contains(&name) {
+                            names.push(name);
+                        }
+                    }
+                }
+            }
+            Err(err) if err.is_not_found() => (),
+            Err(err) => return Err(err),
+        }
+        Ok(list)
+    }
+
+    fn create_dir(&self, relpath: &str) -> Result<()> {
+        self.primary.create_dir(relpath)?;
+        self.secondary.create_dir(relpath)
+    }
+
+    fn metadata(&self, relpath: &str) -> Result<Metadata> {
+        match self.primary.metadata(relpath) {
+            Err(err) if err.is_not_found() => self.secondary.metadata(relpath),
+            result => result,
+        }
+    }
+
+    /// Remove the file from both copies; it's only an error if it's in neither.
+    fn remove_file(&self, relpath: &str) -> Result<()> {
+        let in_primary = ignore_not_found(self.primary.remove_file(relpath))?;
+        match self.secondary.remove_file(relpath) {
+            Err(err) if err.is_not_found() && in_primary => Ok(()),
+            result => result,
+        }
+    }
+
+    fn remove_dir_all(&self, relpath: &str) -> Result<()> {
+        let in_primary = ignore_not_found(self.primary.remove_dir_all(relpath))?;
+        match self.secondary.remove_dir_all(relpath) {
+            Err(err) if err.is_not_found() && in_primary => Ok(()),
+            result => result,
+        }
+    }
+
+    fn chdir(&self, relpath: &str) -> Arc<dyn super::Protocol> {
+        Arc::new(Protocol {
+            primary: self.primary.chdir(relpath),
+            secondary: self.secondary.chdir(relpath),
+        })
+    }
+
+    fn url(&self) -> &Url {
+        self.primary.url()
+    }
+
+    fn local_path(&self) -> Option<PathBuf> {
+        self.primary.local_path()
+    }
+
+    /// The space available on whichever of the two has less.
+    fn available_space(&self) -> Option<u64> {
+        match (
+            self.primary.available_space(),
+            self.secondary.available_space(),
+        ) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn is_filtered(&self) -> bool {
+        self.primary.is_filtered()
+    }
+
+    fn durable(&self) -> Option<Arc<dyn super::Protocol>> {
+        let (primary, secondary) = (self.primary.durable(), self.secondary.durable());
+        if primary.is_none() && secondary.is_none() {
+            return None;
+        }
+        Some(Arc::new(Protocol {
+            primary: primary.unwrap_or_else(|| self.primary.clone()),
+            secondary: secondary.unwrap_or_else(|| self.secondary.clone()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::transport::{Transport, WriteMode};
+
+    #[test]
+    fn writes_go_to_both_and_reads_fall_back_to_secondary() {
+        let (primary, secondary) = (Transport::memory(), Transport::memory());
+        let mirror = Transport::mirror(&primary, &secondary);
+        mirror.create_dir("d").unwrap();
+        mirror
+            .write_file("d/f", b"content", WriteMode::CreateNew)
+            .unwrap();
+        assert_eq!(secondary.read_file("d/f").unwrap().as_ref(), b"content");
+
+        primary.remove_file("d/f").unwrap();
+        assert_eq!(mirror.list_dir("d").unwrap().files, ["f"]);
+        assert_eq!(mirror.read_file("d/f").unwrap().as_ref(), b"content");
+        // The primary was healed.
+        assert_eq!(primary.read_file("d/f").unwrap().as_ref(), b"content");
+
+        mirror.remove_file("d/f").unwrap();
+        assert!(!secondary.is_file("d/f").unwrap());
+        assert!(mirror.remove_file("d/f").unwrap_err().is_not_found());
+        assert!(mirror.read_file("d/f").unwrap_err().is_not_found());
+    }
+
+    #[test]
+    fn failed_check_reads_from_secondary() {
+        let (primary, secondary) = (Transport::memory(), Transport::memory());
+        let mirror = Transport::mirror(&primary, &secondary);
+        mirror
+            .write_file("f", b"good", WriteMode::CreateNew)
+            .unwrap();
+        primary
+            .write_file("f", b"bad", WriteMode::Overwrite)
+            .unwrap();
+        let content = mirror
+            .read_file_verified("f", &mut |content| content.as_ref() == b"good")
+            .unwrap();
+        assert_eq!(content.as_ref(), b"good");
+        assert_eq!(primary.read_file("f").unwrap().as_ref(), b"good");
+
+        // If both copies are bad, the primary's content is returned for the caller to
+        // report.
+        primary
+            .write_file("f", b"bad", WriteMode::Overwrite)
+            .unwrap();
+        secondary
+            .write_file("f", b"worse", WriteMode::Overwrite)
+            .unwrap();
+        let content = mirror
+            .read_file_verified("f", &mut |content| content.as_ref() == b"good")
+            .unwrap();
+        assert_eq!(content.as_ref(), b"bad");
+    }
+
+    #[test]
+    fn write_fails_if_secondary_fails() {
+        let (primary, secondary) = (Transport::memory(), Transport::memory());
+        let mirror = Transport::mirror(&primary, &secondary);
+        primary.create_dir("d").unwrap();
+        let err = mirror
+            .write_file("d/f", b"content", WriteMode::CreateNew)
+            .unwrap_err();
+        assert!(err.is_not_found());
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A transport wrapper that records all the calls made through it, so that tests
+//! can make assertions about how much IO an operation does.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+
+use bytes::Bytes;
+use url::Url;
+
+use super::{ListDir, Metadata, Result, WriteMode};
+
+/// The kind of operation performed on a transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Verb {
+    ReadFile,
+    WriteFile,
+    ListDir,
+    CreateDir,
+    Metadata,
+    RemoveFile,
+    RemoveDirAll,
+}
+
+/// One recorded call: the operation and the path relative to the
+/// root of the recording transport.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Call(pub Verb, pub String);
+
+pub(super) struct Protocol {
+    inner: Arc<dyn super::Protocol>,
+    /// Path of this protocol relative to where the recording started.
+    prefix: String,
+    calls: Arc<Mutex<Vec<Call>>>,
+    concurrency: Arc<Concurrency>,
+    /// Time to wait in each file read or directory listing, to simulate a remote
+    /// archive.
+    read_latency: Duration,
+}
+
+/// Counts of calls in progress, shared by all the protocols in one recording.
+#[derive(Default)]
+struct Concurrency {
+    current: AtomicUsize,
+    max: AtomicUsize,
+}
+
+/// Marks a call as in progress until it's dropped.
+struct InProgress<'a>(&'a Concurrency);
+
+impl Drop for InProgress<'_> {
+    fn drop(&mut self) {
+        self.0.current.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Protocol {
+    pub(super) fn new(inner: Arc<dyn super::Protocol>, read_latency: Duration) -> Self {
+        Protocol {
+            inner,
+            prefix: String::new(),
+            calls: Arc::default(),
+            concurrency: Arc::default(),
+            read_latency,
+        }
+    }
+
+    /// Record a call, returning a guard that counts it as in progress until the
+    /// call returns.
+    #[must_use]
+    fn record(&self, verb: Verb, relpath: &str) -> InProgress<'_> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(Call(verb, join_relpath(&self.prefix, relpath)));
+        let current = self.concurrency.current.fetch_add(1, Ordering::SeqCst) + 1;
+        self.concurrency.max.fetch_max(current, Ordering::SeqCst);
+        InProgress(&self.concurrency)
+    }
+}
+
+pub(super) fn join_relpath(prefix: &str, relpath: &str) -> String {
+    if prefix.is_empty() {
+        relpath.to_owned()
+    } else if relpath.is_empty() {
+        prefix.to_owned()
+    } else {
+        format!("{prefix}/{relpath}")
+    }
+}
+
+impl super::Protocol for Protocol {
+    fn read_file(&self, relpath: &str) -> Result<Bytes> {
+        let _in_progress = self.record(Verb::ReadFile, relpath);
+        sleep(self.read_latency);
+        self.inner.read_file(relpath)
+    }
+
+    fn read_file_verified(
+        &self,
+        relpath: &str,
+        check: &mut dyn FnMut(&Bytes) -> bool,
+    ) -> Result<Bytes> {
+        let _in_progress = self.record(Verb::ReadFile, relpath);
+        sleep(self.read_latency);
+        self.inner.read_file_verified(relpath, check)
+    }
+
+    fn write_file(&self, relpath: &str, content: &[u8], mode: WriteMode) -> Result<()> {
+        let _in_progress = self.record(Verb::WriteFile, relpath);
+        self.inner.write_file(relpath, content, mode)
+    }
+
+    fn list_dir(&self, relpath: &str) -> Result<ListDir> {
+        let _in_progress = self.record(Verb::ListDir, relpath);
+        sleep(self.read_latency);
+        self.inner.list_dir(relpath)
+    }
+
+    fn create_dir(&self, relpath: &str) -> Result<()> {
+        let _in_progress = self.record(Verb::CreateDir, relpath);
+        self.inner.create_dir(relpath)
+    }
+
+    fn metadata(&self, relpath: &str) -> Result<Metadata> {
+        let _in_progress = self.record(Verb::Metadata, relpath);
+        self.inner.metadata(relpath)
+    }
+
+    fn remove_file(&self, relpath: &str) -> Result<()> {
+        let _in_progress = self.record(Verb::RemoveFile, relpath);
+        self.inner.remove_file(relpath)
+    }
+
+    fn remove_dir_all(&self, relpath: &str) -> Result<()> {
+        let _in_progress = self.record(Verb::RemoveDirAll, relpath);
+        self.inner.remove_dir_all(relpath)
+    }
+
+    fn chdir(&self, relpath: &str) -> Arc<dyn super::Protocol> {
+        Arc::new(Protocol {
+            inner: self.inner.chdir(relpath),
+            prefix: join_relpath(&self.prefix, relpath),
+            calls: Arc::clone(&self.calls),
+            concurrency: Arc::clone(&self.concurrency),
+            read_latency: self.read_latency,
+        })
+    }
+
+    fn durable(&self) -> Option<Arc<dyn super::Protocol>> {
+        Some(Arc::new(Protocol {
+            inner: self.inner.durable()?,
+            prefix: self.prefix.clone(),
+            calls: Arc::clone(&self.calls),
+            concurrency: Arc::clone(&self.concurrency),
+            read_latency: self.read_latency,
+        }))
+    }
+
+    fn url(&self) -> &Url {
+        self.inner.url()
+    }
+
+    fn local_path(&self) -> Option<PathBuf> {
+        self.inner.local_path()
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        self.inner.available_space()
+    }
+
+    fn is_filtered(&self) -> bool {
+        self.inner.is_filtered()
+    }
+
+    fn recorded_calls(&self) -> Option<Vec<Call>> {
+        Some(self.calls.lock().unwrap().clone())
+    }
+
+    fn max_concurrent_calls(&self) -> Option<usize> {
+        Some(self.concurrency.max.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+    use crate::transport::Transport;
+
+    #[test]
+    fn record_calls_through_chdir() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let transport = Transport::local(temp.path()).record_calls();
+        transport.create_dir("sub").unwrap();
+        let sub = transport.chdir("sub");
+        sub.write_file("f", b"hello", WriteMode::CreateNew).unwrap();
+        assert_eq!(sub.read_file("f").unwrap().as_ref(), b"hello");
+        assert_eq!(
+            transport.recorded_calls(),
+            [
+                Call(Verb::CreateDir, "sub".to_owned()),
+                Call(Verb::WriteFile, "sub/f".to_owned()),
+                Call(Verb::ReadFile, "sub/f".to_owned()),
+            ]
+        );
+        assert_eq!(
+            sub.local_path().as_deref(),
+            Some(temp.path().join("sub").as_path())
+        );
+        assert!(Transport::local(Path::new("/tmp"))
+            .recorded_calls()
+            .is_empty());
+    }
+}
@@ -276,6 +276,34 @@ impl super::Protocol for Protocol {
         Ok(body_bytes)
     }
 
+    /// Read the rest of the object with a range request, appending each part of the
+    /// body as it arrives, so that an interrupted read can be resumed.
+    fn read_file_from(&self, relpath: &str, offset: u64, buf: &mut Vec<u8>) -> Result<()> {
+        let _span = trace_span!("S3Transport::read_file_from", %relpath, offset).entered();
+        let key = self.join_path(relpath);
+        let mut request = self.client.get_object().bucket(&self.bucket).key(&key);
+        if offset > 0 {
+            request = request.range(format!("bytes={offset}-"));
+        }
+        let mut body = self
+            .runtime
+            .block_on(request.send())
+            .map_err(|source| self.s3_error(&key, source))?
+            .body;
+        while let Some(chunk) = self
+            .runtime
+            .block_on(body.try_next())
+            .map_err(|source| Error {
+                kind: ErrorKind::Other,
+                url: self.url.join(relpath).ok(),
+                source: Some(Box::new(source)),
+            })?
+        {
+            buf.extend_from_slice(&chunk);
+        }
+        Ok(())
+    }
+
     #[mutants::skip] // does nothing so hard to observe!
     fn create_dir(&self, relpath: &str) -> Result<()> {
         // There are no directory objects, so there's nothing to create.
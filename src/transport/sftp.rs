@@ -3,7 +3,7 @@
 //! Read/write archive over SFTP.
 
 use std::fmt;
-use std::io::{self, Read, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::net::TcpStream;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -145,6 +145,20 @@ impl super::Protocol for Protocol {
         Ok(buf.into())
     }
 
+    fn read_file_from(&self, path: &str, offset: u64, buf: &mut Vec<u8>) -> Result<()> {
+        let full_path = self.base_path.join(path);
+        let url = &self.url.join(path).expect("join URL");
+        trace!("read {url} from {offset}");
+        let mut file = self
+            .sftp
+            .open(&full_path)
+            .map_err(|err| self.ssh_error(err, path))?;
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_to_end(buf))
+            .map_err(|err| io_error(err, url))?;
+        Ok(())
+    }
+
     fn create_dir(&self, relpath: &str) -> Result<()> {
         let full_path = self.base_path.join(relpath);
         trace!("create_dir {:?}", full_path);
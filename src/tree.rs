@@ -14,6 +14,7 @@
 //! Abstract Tree trait.
 
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::counters::Counter;
 use crate::monitor::Monitor;
@@ -40,22 +41,125 @@ pub trait ReadTree {
     ///
     /// This typically requires walking all entries, which may take a while.
     fn size(&self, exclude: Exclude, monitor: Arc<dyn Monitor>) -> Result<TreeSize> {
-        let mut file_bytes = 0u64;
+        self.size_with_subtotals(Apath::root(), exclude, monitor, Duration::MAX, &mut |_| ())
+    }
+
+    /// Measure the size of the subtree below `subtree`, passing the size measured so
+    /// far to `subtotal` whenever `interval` has passed since the previous subtotal.
+    ///
+    /// The monitor is also updated after every file, so that slow measurements,
+    /// such as of a large stored tree on a remote archive, show progress.
+    fn size_with_subtotals(
+        &self,
+        subtree: Apath,
+        exclude: Exclude,
+        monitor: Arc<dyn Monitor>,
+        interval: Duration,
+        subtotal: &mut dyn FnMut(&TreeSize),
+    ) -> Result<TreeSize> {
+        let mut size = TreeSize::default();
         let task = monitor.start_task("Measure tree".to_string());
-        for e in self.iter_entries(Apath::root(), exclude, monitor.clone())? {
+        let mut last_subtotal = Instant::now();
+        for e in self.iter_entries(subtree, exclude, monitor.clone())? {
             // While just measuring size, ignore directories/files we can't stat.
             if let Some(bytes) = e.size() {
                 monitor.count(Counter::Files, 1);
                 monitor.count(Counter::FileBytes, bytes as usize);
-                file_bytes += bytes;
+                if e.kind() == Kind::File {
+                    size.files += 1;
+                }
+                size.file_bytes += bytes;
                 task.increment(bytes as usize);
+                if last_subtotal.elapsed() >= interval {
+                    subtotal(&size);
+                    last_subtotal = Instant::now();
+                }
             }
         }
-        Ok(TreeSize { file_bytes })
+        Ok(size)
     }
 }
 
 /// The measured size of a tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct TreeSize {
     pub file_bytes: u64,
+    /// The number of files counted.
+    pub files: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::monitor::task::{Task, TaskList};
+    use crate::monitor::test::TestMonitor;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    /// Records every increment to the file byte counter.
+    #[derive(Default)]
+    struct RecordFileBytes {
+        increments: Mutex<Vec<usize>>,
+        tasks: Mutex<TaskList>,
+    }
+
+    impl Monitor for RecordFileBytes {
+        fn count(&self, counter: Counter, increment: usize) {
+            if counter == Counter::FileBytes {
+                self.increments.lock().unwrap().push(increment);
+            }
+        }
+
+        fn set_counter(&self, _counter: Counter, _value: usize) {}
+
+        fn error(&self, error: Error) {
+            panic!("unexpected error {error:?}");
+        }
+
+        fn start_task(&self, name: String) -> Task {
+            self.tasks.lock().unwrap().start_task(name)
+        }
+    }
+
+    #[test]
+    fn stored_tree_size_reports_incremental_progress_and_subtotals() {
+        let af = ScratchArchive::new();
+        let src = TreeFixture::new();
+        src.create_file_with_contents("a", b"1");
+        src.create_file_with_contents("b", b"22");
+        src.create_dir("c");
+        src.create_file_with_contents("c/d", b"4444");
+        backup(
+            &af,
+            src.path(),
+            &BackupOptions::default(),
+            TestMonitor::arc(),
+        )
+        .unwrap();
+        let tree = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+
+        let monitor = Arc::new(RecordFileBytes::default());
+        let mut subtotals = Vec::new();
+        let size = tree
+            .size_with_subtotals(
+                Apath::root(),
+                Exclude::nothing(),
+                monitor.clone(),
+                Duration::ZERO,
+                &mut |size| subtotals.push(size.file_bytes),
+            )
+            .unwrap();
+
+        assert_eq!(
+            size,
+            TreeSize {
+                file_bytes: 7,
+                files: 3
+            }
+        );
+        // Directories in stored trees have a size of zero, so they're counted too.
+        assert_eq!(*monitor.increments.lock().unwrap(), [0, 1, 2, 0, 4]);
+        assert_eq!(subtotals, [0, 1, 3, 3, 7]);
+    }
 }
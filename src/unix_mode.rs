@@ -39,6 +39,8 @@ use std::path::Path;
 
 use serde::{Deserialize, Serialize};
 
+use crate::Kind;
+
 #[derive(Debug, Default, Clone, Copy, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct UnixMode(Option<u32>);
 
@@ -79,6 +81,31 @@ impl PartialEq for UnixMode {
 impl Eq for UnixMode {}
 
 impl UnixMode {
+    /// Return this mode with permissions added so that the owner can read and write
+    /// the file, or for a directory also list it. Other bits are unchanged.
+    pub fn with_owner_access(self, kind: Kind) -> UnixMode {
+        UnixMode(self.0.map(|mode| mode | owner_access_bits(kind)))
+    }
+
+    /// Return this mode, or if it's not known, `default`.
+    ///
+    /// For directories, search permission is added to the default wherever it gives
+    /// read permission, so that a default such as `0o644` gives `0o755`.
+    pub fn or_default(self, default: u32, kind: Kind) -> UnixMode {
+        match (self.0, kind) {
+            (Some(_), _) => self,
+            (None, Kind::Dir) => UnixMode::from(default | (default & 0o444) >> 2),
+            (None, _) => UnixMode::from(default),
+        }
+    }
+
+    /// True if the mode is known and doesn't let the owner read the file, or for
+    /// a directory list it.
+    pub fn denies_owner_access(self, kind: Kind) -> bool {
+        let needed = owner_access_bits(kind) & !0o200;
+        self.0.is_some_and(|mode| mode & needed != needed)
+    }
+
     pub fn readonly(self) -> bool {
         // determine if a file is readonly based on whether the owning user can write to it
         // if the mode is None, then we assume it is not readonly
@@ -89,6 +116,15 @@ impl UnixMode {
     }
 }
 
+/// Owner permission bits needed to use a file of this kind: read and write, and
+/// for directories, search.
+fn owner_access_bits(kind: Kind) -> u32 {
+    match kind {
+        Kind::Dir => 0o700,
+        _ => 0o600,
+    }
+}
+
 impl fmt::Display for UnixMode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Convert to string. Since the file type bits are stripped, there will
@@ -137,6 +173,46 @@ impl From<Permissions> for UnixMode {
 #[cfg(test)]
 mod tests {
     use crate::unix_mode::UnixMode;
+    use crate::Kind;
+
+    #[test]
+    fn or_default_applies_only_to_unknown_modes() {
+        assert_eq!(
+            UnixMode::default().or_default(0o640, Kind::File),
+            UnixMode::from(0o640)
+        );
+        assert_eq!(
+            UnixMode::default().or_default(0o640, Kind::Dir),
+            UnixMode::from(0o750)
+        );
+        assert_eq!(
+            UnixMode::from(0o600).or_default(0o644, Kind::File),
+            UnixMode::from(0o600)
+        );
+    }
+
+    #[test]
+    fn owner_access() {
+        assert!(UnixMode::from(0o000).denies_owner_access(Kind::File));
+        assert!(UnixMode::from(0o077).denies_owner_access(Kind::File));
+        assert!(!UnixMode::from(0o400).denies_owner_access(Kind::File));
+        assert!(UnixMode::from(0o600).denies_owner_access(Kind::Dir));
+        assert!(!UnixMode::from(0o500).denies_owner_access(Kind::Dir));
+        assert!(!UnixMode::default().denies_owner_access(Kind::File));
+
+        assert_eq!(
+            UnixMode::from(0o4040).with_owner_access(Kind::File),
+            UnixMode::from(0o4640)
+        );
+        assert_eq!(
+            UnixMode::from(0o055).with_owner_access(Kind::Dir),
+            UnixMode::from(0o755)
+        );
+        assert_eq!(
+            UnixMode::default().with_owner_access(Kind::File),
+            UnixMode::default()
+        );
+    }
     #[test]
     fn display_unix_modes() {
         assert_eq!("rwxrwxr--", format!("{}", UnixMode::from(0o774)));
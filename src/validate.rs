@@ -11,12 +11,16 @@
 // GNU General Public License for more details.
 
 use std::cmp::max;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
+use rayon::prelude::*;
+use serde::Serialize;
 use tracing::debug;
 
+use crate::counters::Counter;
+use crate::monitor::task::Task;
 use crate::monitor::Monitor;
 use crate::*;
 
@@ -25,52 +29,266 @@ use crate::*;
 pub struct ValidateOptions {
     /// Assume blocks that are present have the right content: don't read and hash them.
     pub skip_block_hashes: bool,
+
+    /// Validate the indexes of up to this many bands at once.
+    ///
+    /// If zero, use as many as there are threads in the current rayon pool: by default,
+    /// one per CPU. Errors are reported in the same order regardless.
+    pub band_concurrency: usize,
+
+    /// Before checking blocks, delete block files of length zero, which can be left
+    /// by an interrupted write. Blocks they should have held are then reported as
+    /// missing, and are stored again by the next backup of the same files.
+    pub remove_empty_blocks: bool,
+
+    /// Check only these bands, and only the blocks they reference, rather than the
+    /// whole archive.
+    ///
+    /// Blocks referenced by no band are then not read, so damage to them isn't found.
+    pub bands: BandsSelection,
+}
+
+/// The problems found by [Archive::validate].
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationReport {
+    /// True if no problems were found.
+    pub ok: bool,
+    /// Problems in the order they were found.
+    pub problems: Vec<ValidationProblem>,
+    /// The number of problems of each kind.
+    pub counts: BTreeMap<&'static str, usize>,
+}
+
+/// One problem found by validation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ValidationProblem {
+    /// The kind of problem: the name of the [Error] variant, such as `BlockMissing`.
+    pub kind: &'static str,
+    pub message: String,
+    /// The band in which the problem was found, if it's specific to one band.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub band_id: Option<BandId>,
+    /// The block with the problem, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<BlockHash>,
+    /// The file with the problem, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub apath: Option<Apath>,
+}
+
+impl ValidationProblem {
+    fn new(error: &Error, band_id: Option<BandId>) -> ValidationProblem {
+        let hash = match error {
+            Error::BlockCorrupt { hash }
+            | Error::BlockMissing { hash }
+            | Error::BlockTooShort { hash, .. }
+            | Error::RestoreFileBlock { hash, .. } => Some(hash.clone()),
+            _ => None,
+        };
+        let apath = match error {
+            Error::RestoreFileBlock { apath, .. }
+            | Error::PathTooLong { apath, .. }
+            | Error::PathTooDeep { apath, .. } => Some(apath.clone()),
+            _ => None,
+        };
+        let band_id = match error {
+            Error::UnsupportedBandVersion { band_id, .. }
+            | Error::UnsupportedBandFormatFlags { band_id, .. }
+            | Error::BandHeadMissing { band_id }
+            | Error::BandNotFound { band_id }
+            | Error::IndexHunkNotFound { band_id, .. } => Some(*band_id),
+            _ => band_id,
+        };
+        ValidationProblem {
+            kind: error.into(),
+            message: error.to_string(),
+            band_id,
+            hash,
+            apath,
+        }
+    }
+}
+
+/// Records the problems found by validation into a [ValidationReport], while passing
+/// everything through to another monitor.
+pub(crate) struct ReportProblems {
+    inner: Arc<dyn Monitor>,
+    problems: Mutex<Vec<ValidationProblem>>,
+}
+
+impl ReportProblems {
+    pub(crate) fn new(inner: Arc<dyn Monitor>) -> ReportProblems {
+        ReportProblems {
+            inner,
+            problems: Mutex::default(),
+        }
+    }
+
+    /// Report a problem found in one band.
+    fn band_error(&self, band_id: BandId, error: Error) {
+        self.problems
+            .lock()
+            .unwrap()
+            .push(ValidationProblem::new(&error, Some(band_id)));
+        self.inner.error(error)
+    }
+
+    pub(crate) fn report(&self) -> ValidationReport {
+        let problems = self.problems.lock().unwrap().clone();
+        let mut counts = BTreeMap::new();
+        for problem in &problems {
+            *counts.entry(problem.kind).or_default() += 1;
+        }
+        ValidationReport {
+            ok: problems.is_empty(),
+            problems,
+            counts,
+        }
+    }
+}
+
+impl Monitor for ReportProblems {
+    fn count(&self, counter: Counter, increment: usize) {
+        self.inner.count(counter, increment)
+    }
+
+    fn set_counter(&self, counter: Counter, value: usize) {
+        self.inner.set_counter(counter, value)
+    }
+
+    fn error(&self, error: Error) {
+        self.problems
+            .lock()
+            .unwrap()
+            .push(ValidationProblem::new(&error, None));
+        self.inner.error(error)
+    }
+
+    fn start_task(&self, name: String) -> Task {
+        self.inner.start_task(name)
+    }
 }
 
 /// Validate the indexes of all bands.
 ///
+/// Bands are checked concurrently, but errors are reported to the monitor in band order,
+/// after all bands are checked.
+///
 /// Returns the lengths of all blocks that were referenced, so that the caller can check
 /// that all blocks are present and long enough.
 pub(crate) fn validate_bands(
     archive: &Archive,
     band_ids: &[BandId],
-    monitor: Arc<dyn Monitor>,
+    options: &ValidateOptions,
+    monitor: Arc<ReportProblems>,
 ) -> Result<HashMap<BlockHash, u64>> {
-    let mut block_lens = HashMap::new();
     let task = monitor.start_task("Validate indexes".to_string());
     task.set_total(band_ids.len());
-    'band: for band_id in band_ids.iter() {
-        task.increment(1);
-        let band = match Band::open(archive, *band_id) {
-            Ok(band) => band,
-            Err(err) => {
-                monitor.error(err);
-                continue 'band;
-            }
-        };
-        if let Err(err) = band.validate(monitor.clone()) {
-            monitor.error(err);
-            continue 'band;
-        };
-        let st = match archive.open_stored_tree(BandSelectionPolicy::Specified(*band_id)) {
-            Err(err) => {
-                monitor.error(err);
-                continue 'band;
-            }
-            Ok(st) => st,
-        };
-        let band_block_lens = match validate_stored_tree(&st, monitor.clone()) {
-            Err(err) => {
-                monitor.error(err);
-                continue 'band;
-            }
-            Ok(block_lens) => block_lens,
-        };
-        merge_block_lens(&mut block_lens, &band_block_lens);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(match options.band_concurrency {
+            0 => rayon::current_num_threads(),
+            n => n,
+        })
+        .build()
+        .expect("Failed to build thread pool");
+    let results: Vec<_> = pool.install(|| {
+        band_ids
+            .par_iter()
+            .map(|band_id| {
+                let band_monitor = Arc::new(CollectErrors::new(monitor.clone()));
+                let band_task = task.start_subtask(format!("Band {band_id}"));
+                let block_lens = validate_band(archive, *band_id, &band_task, band_monitor.clone());
+                drop(band_task);
+                task.increment(1);
+                (*band_id, band_monitor.take_errors(), block_lens)
+            })
+            .collect()
+    });
+    let mut block_lens = HashMap::new();
+    for (band_id, errors, band_block_lens) in results {
+        errors
+            .into_iter()
+            .for_each(|err| monitor.band_error(band_id, err));
+        if let Some(band_block_lens) = band_block_lens {
+            merge_block_lens(&mut block_lens, &band_block_lens);
+        }
     }
     Ok(block_lens)
 }
 
+/// Validate one band, returning the lengths of the blocks it references, or None if
+/// the band couldn't be read.
+fn validate_band(
+    archive: &Archive,
+    band_id: BandId,
+    task: &Task,
+    monitor: Arc<dyn Monitor>,
+) -> Option<HashMap<BlockHash, u64>> {
+    let band = match Band::open(archive, band_id) {
+        Ok(band) => band,
+        Err(err) => {
+            monitor.error(err);
+            return None;
+        }
+    };
+    if let Err(err) = band.validate(monitor.clone()) {
+        monitor.error(err);
+        return None;
+    };
+    let st = match archive.open_stored_tree(BandSelectionPolicy::Specified(band_id)) {
+        Err(err) => {
+            monitor.error(err);
+            return None;
+        }
+        Ok(st) => st,
+    };
+    match validate_stored_tree(&st, task, monitor.clone()) {
+        Err(err) => {
+            monitor.error(err);
+            None
+        }
+        Ok(block_lens) => Some(block_lens),
+    }
+}
+
+/// Holds back errors from one band so that they can be reported in a stable order,
+/// while passing everything else through to the real monitor.
+struct CollectErrors {
+    inner: Arc<dyn Monitor>,
+    errors: Mutex<Vec<Error>>,
+}
+
+impl CollectErrors {
+    fn new(inner: Arc<dyn Monitor>) -> CollectErrors {
+        CollectErrors {
+            inner,
+            errors: Mutex::default(),
+        }
+    }
+
+    fn take_errors(&self) -> Vec<Error> {
+        std::mem::take(&mut self.errors.lock().unwrap())
+    }
+}
+
+impl Monitor for CollectErrors {
+    fn count(&self, counter: Counter, increment: usize) {
+        self.inner.count(counter, increment)
+    }
+
+    fn set_counter(&self, counter: Counter, value: usize) {
+        self.inner.set_counter(counter, value)
+    }
+
+    fn error(&self, error: Error) {
+        self.errors.lock().unwrap().push(error)
+    }
+
+    fn start_task(&self, name: String) -> Task {
+        self.inner.start_task(name)
+    }
+}
+
 fn merge_block_lens(into: &mut HashMap<BlockHash, u64>, from: &HashMap<BlockHash, u64>) {
     for (bh, bl) in from {
         into.entry(bh.clone())
@@ -79,19 +297,20 @@ fn merge_block_lens(into: &mut HashMap<BlockHash, u64>, from: &HashMap<BlockHash
     }
 }
 
+/// Check the entries of a stored tree, counting files into `task`.
 fn validate_stored_tree(
     st: &StoredTree,
+    task: &Task,
     monitor: Arc<dyn Monitor>,
 ) -> Result<HashMap<BlockHash, u64>> {
     // TODO: Check other entry properties are correct.
     // TODO: Check they're in apath order.
-    // TODO: Count progress for index blocks within one tree?
-    let _task = monitor.start_task(format!("Validate stored tree {}", st.band().id()));
     let mut block_lens = HashMap::new();
     for entry in st
         .iter_entries(Apath::root(), Exclude::nothing(), monitor.clone())?
         .filter(|entry| entry.kind() == Kind::File)
     {
+        task.increment(1);
         // TODO: Read index hunks, count into the task per hunk. Then, we can
         // read hunks in parallel.
         for addr in entry.addrs {
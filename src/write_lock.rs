@@ -0,0 +1,193 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A lock held on the whole archive by any operation that writes to it: backup,
+//! delete, and gc.
+//!
+//! The lock is a file, `LOCK`, at the top of the archive, created only if it
+//! doesn't already exist. It records which process holds it, so that a second
+//! writer can fail straight away with an error saying who to wait for, rather
+//! than racing with the first.
+//!
+//! This is complementary to the [GarbageCollectionLock]: older versions of
+//! Conserve don't know about this lock, but do respect the gc lock.
+//!
+//! On transports that can't create a file only if it's absent, such as some
+//! cloud stores, two writers starting at almost the same moment might both get
+//! the lock.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{debug, warn};
+
+use crate::jsonio::{self, read_json};
+use crate::transport::{self, Transport, WriteMode};
+use crate::*;
+
+/// Name of the lock file in the archive directory.
+pub(crate) static WRITE_LOCK_FILENAME: &str = "LOCK";
+
+/// Description of the process holding a [WriteLock], as stored in the lock file.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LockHolder {
+    /// Process id of the holder.
+    #[serde(default)]
+    pub pid: Option<u32>,
+    /// Name of the host where the holder is running.
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Unix time when the lock was taken.
+    #[serde(default)]
+    pub start_time: Option<i64>,
+}
+
+impl LockHolder {
+    fn current(archive: &Archive) -> LockHolder {
+        LockHolder {
+            pid: Some(std::process::id()),
+            hostname: whoami::fallible::hostname().ok(),
+            start_time: Some(archive.clock.now().unix_timestamp()),
+        }
+    }
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "process {pid}")?,
+            None => write!(f, "an unknown process")?,
+        }
+        if let Some(hostname) = &self.hostname {
+            write!(f, " on {hostname}")?;
+        }
+        if let Some(start_time) = self
+            .start_time
+            .and_then(|t| OffsetDateTime::from_unix_timestamp(t).ok())
+            .and_then(|t| t.format(&Rfc3339).ok())
+        {
+            write!(f, " since {start_time}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Lock on an archive held while writing to it, excluding other writers.
+///
+/// The lock is released when the object is dropped.
+#[derive(Debug)]
+pub struct WriteLock {
+    transport: Transport,
+}
+
+impl WriteLock {
+    /// Lock the archive for writing.
+    ///
+    /// Returns [Error::ArchiveLocked] if another process holds the lock.
+    pub fn acquire(archive: &Archive) -> Result<WriteLock> {
+        let transport = archive.transport().clone();
+        let content = serde_json::to_vec(&LockHolder::current(archive))?;
+        match transport.write_file(WRITE_LOCK_FILENAME, &content, WriteMode::CreateNew) {
+            Ok(()) => {
+                debug!("Took archive write lock");
+                Ok(WriteLock { transport })
+            }
+            Err(err) if err.kind() == transport::ErrorKind::AlreadyExists => {
+                let holder = WriteLock::holder(archive)?.unwrap_or_default();
+                Err(Error::ArchiveLocked { holder })
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Take the lock, first removing any existing lock.
+    ///
+    /// Use this only if you're confident that the process holding the lock has
+    /// terminated and the lock is stale.
+    pub fn break_lock(archive: &Archive) -> Result<WriteLock> {
+        if let Some(holder) = WriteLock::holder(archive)? {
+            warn!(%holder, "Breaking archive write lock");
+            archive.transport().remove_file(WRITE_LOCK_FILENAME)?;
+        }
+        WriteLock::acquire(archive)
+    }
+
+    /// Describe the process holding the lock, or None if the archive isn't locked.
+    ///
+    /// If the lock file exists but can't be parsed, the holder has no details.
+    pub fn holder(archive: &Archive) -> Result<Option<LockHolder>> {
+        match read_json::<LockHolder>(archive.transport(), WRITE_LOCK_FILENAME) {
+            Ok(holder) => Ok(holder),
+            Err(err @ jsonio::Error::Json { .. }) => {
+                warn!(?err, "Failed to parse archive lock file");
+                Ok(Some(LockHolder::default()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Drop for WriteLock {
+    fn drop(&mut self) {
+        if let Err(err) = self.transport.remove_file(WRITE_LOCK_FILENAME) {
+            // Print directly to stderr, in case the UI structure is in a
+            // bad state during unwind.
+            eprintln!("Failed to delete {WRITE_LOCK_FILENAME}: {err:?}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_fixtures::ScratchArchive;
+
+    #[test]
+    fn second_lock_fails_with_holder() {
+        let archive = ScratchArchive::new();
+        let lock = WriteLock::acquire(&archive).unwrap();
+        let holder = WriteLock::holder(&archive).unwrap().unwrap();
+        assert_eq!(holder.pid, Some(std::process::id()));
+
+        let err = WriteLock::acquire(&archive).unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.starts_with(&format!(
+                "Archive is locked by process {}",
+                std::process::id()
+            )),
+            "{message}"
+        );
+        assert!(message.contains(" since "), "{message}");
+
+        drop(lock);
+        assert_eq!(WriteLock::holder(&archive).unwrap(), None);
+        WriteLock::acquire(&archive).unwrap();
+    }
+
+    #[test]
+    fn unreadable_lock_is_still_held() {
+        let archive = ScratchArchive::new();
+        archive
+            .transport()
+            .write_file(WRITE_LOCK_FILENAME, b"garbage", WriteMode::CreateNew)
+            .unwrap();
+        assert_eq!(
+            WriteLock::acquire(&archive).unwrap_err().to_string(),
+            "Archive is locked by an unknown process"
+        );
+        let _lock = WriteLock::break_lock(&archive).unwrap();
+    }
+}
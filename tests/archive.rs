@@ -15,15 +15,25 @@
 
 use std::fs;
 use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
 
 use assert_fs::prelude::*;
 use assert_fs::TempDir;
 
 use conserve::archive::Archive;
+use conserve::counters::Counter;
 use conserve::monitor::test::TestMonitor;
-use conserve::test_fixtures::ScratchArchive;
+use conserve::termui::TermUiMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::transport::record::{Call, Verb};
+use conserve::transport::Transport;
 use conserve::Band;
 use conserve::BandId;
+use conserve::{
+    backup, show_versions, Apath, BackupOptions, BandSelectionPolicy, BandsSelection, BlockHash,
+    Exclude, Kind, ReadTree, ShowVersionsOptions, StoredTree, ValidateOptions,
+};
 use rayon::prelude::ParallelIterator;
 
 #[test]
@@ -55,6 +65,25 @@ fn fails_on_non_empty_directory() {
     temp.close().unwrap();
 }
 
+#[test]
+fn refuse_to_create_archive_inside_archive() {
+    let temp = TempDir::new().unwrap();
+    let outer = temp.child("outer");
+    Archive::create_path(outer.path()).unwrap();
+
+    for inner in ["inner", "d/inner", "d/abc/inner"] {
+        let err = Archive::create_path(&outer.path().join(inner)).unwrap_err();
+        assert!(
+            matches!(&err, conserve::Error::NewArchiveInsideArchive { archive } if archive == outer.path()),
+            "{err:?}"
+        );
+        assert!(err
+            .to_string()
+            .starts_with("Can't create an archive inside the existing archive"));
+    }
+    outer.child("inner").assert(predicates::path::missing());
+}
+
 /// A new archive contains just one header file.
 /// The header is readable json containing only a version number.
 #[test]
@@ -91,6 +120,41 @@ fn empty_archive() {
     );
 }
 
+#[test]
+fn archive_from_newer_format_is_refused() {
+    for version in ["0.7", "1.0", "0.60"] {
+        let temp = TempDir::new().unwrap();
+        temp.child("CONSERVE")
+            .write_str(&format!(
+                "{{\"conserve_archive_version\":\"{version}\",\"something_new\":true}}\n"
+            ))
+            .unwrap();
+        let err = Archive::open_path(temp.path()).unwrap_err();
+        match &err {
+            conserve::Error::ArchiveFormatTooNew { found, supported } => {
+                assert_eq!(found, version);
+                assert_eq!(supported, conserve::ARCHIVE_VERSION);
+            }
+            other => panic!("unexpected error {other:?}"),
+        }
+        assert!(err.to_string().contains("upgrade Conserve"), "{err}");
+    }
+}
+
+#[test]
+fn archive_from_older_or_unparseable_format_is_unsupported() {
+    for version in ["0.5", "0", "banana"] {
+        let temp = TempDir::new().unwrap();
+        temp.child("CONSERVE")
+            .write_str(&format!("{{\"conserve_archive_version\":\"{version}\"}}\n"))
+            .unwrap();
+        assert!(matches!(
+            Archive::open_path(temp.path()),
+            Err(conserve::Error::UnsupportedArchiveVersion { .. })
+        ));
+    }
+}
+
 #[test]
 fn create_bands() {
     let af = ScratchArchive::new();
@@ -125,3 +189,604 @@ fn create_bands() {
         0
     );
 }
+
+/// Count how many index hunks were read from the transport.
+fn count_index_hunk_reads(calls: &[Call]) -> usize {
+    calls
+        .iter()
+        .filter(|Call(verb, path)| *verb == Verb::ReadFile && path.contains("/i/"))
+        .count()
+}
+
+#[test]
+fn index_cache_reads_each_hunk_once() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let list_latest = |archive: &Archive| {
+        archive
+            .iter_entries(
+                BandSelectionPolicy::Latest,
+                "/".into(),
+                Exclude::nothing(),
+                TestMonitor::arc(),
+            )
+            .unwrap()
+            .count()
+    };
+
+    // Without the cache, every iteration reads the hunks again.
+    let transport = Transport::local(af.path()).record_calls();
+    let archive = Archive::open(transport.clone()).unwrap();
+    let n_entries = list_latest(&archive);
+    let first_reads = count_index_hunk_reads(&transport.recorded_calls());
+    assert!(first_reads > 0);
+    assert_eq!(list_latest(&archive), n_entries);
+    assert_eq!(
+        count_index_hunk_reads(&transport.recorded_calls()),
+        2 * first_reads
+    );
+
+    // With the cache, the second iteration is served from memory.
+    let transport = Transport::local(af.path()).record_calls();
+    let archive = Archive::open(transport.clone())
+        .unwrap()
+        .with_index_cache(100);
+    assert_eq!(list_latest(&archive), n_entries);
+    assert_eq!(list_latest(&archive), n_entries);
+    assert_eq!(
+        count_index_hunk_reads(&transport.recorded_calls()),
+        first_reads
+    );
+}
+
+#[test]
+fn index_read_ahead_overlaps_hunk_reads() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    for i in 0..20 {
+        tf.create_file(&format!("file{i:02}"));
+    }
+    backup(
+        &af,
+        tf.path(),
+        &BackupOptions {
+            max_entries_per_hunk: 1,
+            ..Default::default()
+        },
+        TestMonitor::arc(),
+    )
+    .unwrap();
+
+    let list = |read_ahead: usize| {
+        let transport =
+            Transport::local(af.path()).record_calls_with_read_latency(Duration::from_millis(10));
+        let archive = Archive::open(transport.clone())
+            .unwrap()
+            .with_index_read_ahead(read_ahead);
+        let apaths: Vec<Apath> = archive
+            .iter_entries(
+                BandSelectionPolicy::LatestClosed,
+                "/".into(),
+                Exclude::nothing(),
+                TestMonitor::arc(),
+            )
+            .unwrap()
+            .map(|entry| entry.apath)
+            .collect();
+        assert!(count_index_hunk_reads(&transport.recorded_calls()) >= 21);
+        (apaths, transport.max_concurrent_calls())
+    };
+
+    let (sequential, sequential_concurrency) = list(0);
+    let (read_ahead, read_ahead_concurrency) = list(8);
+    assert_eq!(sequential.len(), 21);
+    assert_eq!(read_ahead, sequential);
+    assert_eq!(sequential_concurrency, 1);
+    assert!(
+        read_ahead_concurrency > 1 && read_ahead_concurrency <= 8,
+        "read ahead made {read_ahead_concurrency} concurrent reads"
+    );
+}
+
+#[test]
+fn block_list_concurrency_is_bounded() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    for i in 0..16 {
+        tf.create_file_with_contents(&format!("file{i:02}"), format!("content {i}").as_bytes());
+    }
+    let options = BackupOptions {
+        small_file_cap: 0,
+        ..Default::default()
+    };
+    backup(&af, tf.path(), &options, TestMonitor::arc()).unwrap();
+    let subdirs = fs::read_dir(af.path().join("d")).unwrap().count();
+    assert!(subdirs > 8, "{subdirs} block subdirectories");
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(8)
+        .build()
+        .unwrap();
+    let list_blocks = |tasks: usize| {
+        let transport =
+            Transport::local(af.path()).record_calls_with_read_latency(Duration::from_millis(20));
+        let archive = Archive::open(transport.clone())
+            .unwrap()
+            .with_block_list_concurrency(tasks)
+            .with_block_cache(0);
+        let monitor = TestMonitor::arc();
+        let blocks = pool.install(|| {
+            archive
+                .block_dir()
+                .blocks(monitor.clone())
+                .unwrap()
+                .collect::<Vec<BlockHash>>()
+        });
+        assert_eq!(blocks.len(), 16);
+        monitor.assert_counter(Counter::BlockSubdirsListed, subdirs);
+        transport.max_concurrent_calls()
+    };
+
+    assert!(list_blocks(0) > 2);
+    assert_eq!(list_blocks(2), 2);
+}
+
+fn show_all_versions(archive: &Archive) {
+    let options = ShowVersionsOptions {
+        start_time: true,
+        backup_duration: true,
+        ..Default::default()
+    };
+    show_versions(archive, &options, Arc::new(TermUiMonitor::new(false))).unwrap();
+}
+
+#[test]
+fn versions_served_from_band_manifest() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    assert!(af.path().join("BANDS").is_file());
+
+    let transport = Transport::local(af.path()).record_calls();
+    let archive = Archive::open(transport.clone()).unwrap();
+    let n_open_calls = transport.recorded_calls().len();
+    show_all_versions(&archive);
+    let calls = transport.recorded_calls().split_off(n_open_calls);
+    assert_eq!(
+        calls,
+        [
+            Call(Verb::ReadFile, "BANDS".to_owned()),
+            // Check that the manifest lists exactly the bands in the archive.
+            Call(Verb::ListDir, String::new()),
+        ]
+    );
+}
+
+#[test]
+fn backup_writes_band_manifest_while_holding_lock() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file("hello");
+    let transport = Transport::local(af.path()).record_calls();
+    let archive = Archive::open(transport.clone()).unwrap();
+    backup(&archive, tf.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let calls = transport.recorded_calls();
+    let position = |call: Call| {
+        calls
+            .iter()
+            .position(|c| *c == call)
+            .unwrap_or_else(|| panic!("{call:?} not in {calls:#?}"))
+    };
+    assert!(
+        position(Call(Verb::WriteFile, "BANDS".to_owned()))
+            < position(Call(Verb::RemoveFile, "LOCK".to_owned()))
+    );
+}
+
+#[test]
+fn stale_band_manifest_is_ignored() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let manifest = fs::read(af.path().join("BANDS")).unwrap();
+    // As if an older version of Conserve deleted a band without updating the manifest.
+    fs::remove_dir_all(af.path().join("b0000")).unwrap();
+
+    let transport = Transport::local(af.path()).record_calls();
+    let archive = Archive::open(transport.clone()).unwrap();
+    show_all_versions(&archive);
+    assert!(transport
+        .recorded_calls()
+        .contains(&Call(Verb::ReadFile, "b0001/BANDHEAD".to_owned())));
+    assert!(!transport
+        .recorded_calls()
+        .iter()
+        .any(|Call(_, path)| path.starts_with("b0000")));
+    // Reading versions doesn't rewrite the manifest.
+    assert_eq!(fs::read(af.path().join("BANDS")).unwrap(), manifest);
+}
+
+#[test]
+fn corrupt_band_manifest_falls_back_to_listing() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    fs::write(af.path().join("BANDS"), "not json {").unwrap();
+
+    let transport = Transport::local(af.path()).record_calls();
+    let archive = Archive::open(transport.clone()).unwrap();
+    show_all_versions(&archive);
+    assert!(transport
+        .recorded_calls()
+        .contains(&Call(Verb::ListDir, String::new())));
+
+    // Reading versions doesn't rewrite the manifest; the next backup will.
+    assert_eq!(
+        fs::read_to_string(af.path().join("BANDS")).unwrap(),
+        "not json {"
+    );
+    assert!(!transport
+        .recorded_calls()
+        .iter()
+        .any(|Call(verb, _)| *verb == Verb::WriteFile));
+}
+
+#[test]
+fn validate_in_one_thread_makes_one_call_at_a_time() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..3 {
+        for j in 0..10 {
+            srcdir.create_file_with_contents(&format!("file{j}"), format!("{i} {j}").as_bytes());
+        }
+        backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    }
+
+    let transport = Transport::local(af.path()).record_calls();
+    let archive = Archive::open(transport.clone()).unwrap();
+    let monitor = TestMonitor::arc();
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap()
+        .install(|| archive.validate(&ValidateOptions::default(), monitor.clone()))
+        .unwrap();
+    monitor.assert_no_errors();
+    assert!(transport
+        .recorded_calls()
+        .iter()
+        .any(|Call(verb, path)| *verb == Verb::ReadFile && path.starts_with("d/")));
+    assert_eq!(transport.max_concurrent_calls(), 1);
+}
+
+/// Make an archive with three backups, each of one different file, so that each
+/// band references a different block.
+fn archive_with_three_distinct_bands() -> ScratchArchive {
+    let af = ScratchArchive::new();
+    for i in 0..3 {
+        let srcdir = TreeFixture::new();
+        srcdir.create_file_with_contents(&format!("file{i}"), format!("content {i}").as_bytes());
+        backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    }
+    af
+}
+
+#[test]
+fn select_bands_by_range_and_latest() {
+    let af = archive_with_three_distinct_bands();
+    let b = |i| BandId::new(&[i]);
+    assert_eq!(
+        af.select_bands(BandsSelection::All).unwrap(),
+        [b(0), b(1), b(2)]
+    );
+    assert_eq!(
+        af.select_bands(BandsSelection::Range(b(1), b(5))).unwrap(),
+        [b(1), b(2)]
+    );
+    assert_eq!(
+        af.select_bands(BandsSelection::Latest(2)).unwrap(),
+        [b(1), b(2)]
+    );
+    assert_eq!(
+        af.select_bands(BandsSelection::Latest(10)).unwrap(),
+        [b(0), b(1), b(2)]
+    );
+}
+
+#[test]
+fn validate_selected_bands_checks_only_their_blocks() {
+    let af = archive_with_three_distinct_bands();
+    let latest_blocks = af
+        .referenced_blocks(&[BandId::new(&[2])], TestMonitor::arc())
+        .unwrap();
+    assert_eq!(latest_blocks.len(), 1);
+
+    let validate_counting_reads = |bands| {
+        let transport = Transport::local(af.path()).record_calls();
+        let archive = Archive::open(transport.clone()).unwrap();
+        let options = ValidateOptions {
+            bands,
+            ..Default::default()
+        };
+        let report = archive.validate(&options, TestMonitor::arc()).unwrap();
+        let calls = transport.recorded_calls();
+        let block_reads = calls
+            .iter()
+            .filter(|Call(verb, path)| *verb == Verb::ReadFile && path.starts_with("d/"))
+            .count();
+        (report, block_reads, count_index_hunk_reads(&calls))
+    };
+
+    let (report, block_reads, index_reads) = validate_counting_reads(BandsSelection::All);
+    assert!(report.ok);
+    assert_eq!(block_reads, 3);
+    assert_eq!(index_reads, 3);
+
+    let (report, block_reads, index_reads) = validate_counting_reads(BandsSelection::Latest(1));
+    assert!(report.ok);
+    assert_eq!(block_reads, 1);
+    assert_eq!(index_reads, 1);
+
+    // Damage to blocks used only by other bands is found by full validation, but not
+    // by validating the latest band.
+    let all_blocks: Vec<BlockHash> = af.block_dir().blocks(TestMonitor::arc()).unwrap().collect();
+    for hash in all_blocks {
+        if !latest_blocks.contains(&hash) {
+            let name = hash.to_string();
+            fs::write(af.path().join("d").join(&name[..3]).join(&name), b"garbage").unwrap();
+        }
+    }
+    let (report, _, _) = validate_counting_reads(BandsSelection::All);
+    assert!(!report.ok);
+    let (report, _, _) = validate_counting_reads(BandsSelection::Latest(1));
+    assert!(report.ok, "{report:#?}");
+}
+
+#[test]
+fn disk_usage_counts_duplicated_content_once() {
+    use rand::{RngCore, SeedableRng};
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let mut content = vec![0; 100_000];
+    rand::rngs::StdRng::seed_from_u64(1).fill_bytes(&mut content);
+    srcdir.create_file_with_contents("a", &content);
+    srcdir.create_file_with_contents("b", &content);
+    let options = BackupOptions {
+        small_file_cap: 0,
+        ..Default::default()
+    };
+    for _ in 0..2 {
+        backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    }
+
+    let monitor = TestMonitor::arc();
+    let usage = af.disk_usage(monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(usage.bands, 2);
+    assert_eq!(usage.logical_bytes, 4 * content.len() as u64);
+    assert_eq!(usage.blocks, 1);
+    let block = af
+        .block_dir()
+        .blocks(TestMonitor::arc())
+        .unwrap()
+        .collect::<Vec<_>>()
+        .remove(0);
+    assert_eq!(
+        usage.physical_bytes,
+        af.block_dir().compressed_size(&block).unwrap()
+    );
+    assert!(usage.physical_bytes < usage.logical_bytes);
+    let factor = usage.factor.unwrap();
+    assert_eq!(
+        factor,
+        usage.logical_bytes as f64 / usage.physical_bytes as f64
+    );
+    // Random content doesn't compress, so the saving is all from deduplication.
+    assert!(factor > 3.9 && factor <= 4.0, "{factor}");
+}
+
+#[test]
+fn disk_usage_of_empty_archive_has_no_factor() {
+    let af = ScratchArchive::new();
+    let usage = af.disk_usage(TestMonitor::arc()).unwrap();
+    assert_eq!(usage.logical_bytes, 0);
+    assert_eq!(usage.physical_bytes, 0);
+    assert_eq!(usage.factor, None);
+}
+
+#[test]
+fn all_referenced_addresses_match_band_indexes() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let refs = af
+        .all_referenced_addresses()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+    let mut expected = Vec::new();
+    for band_id in af.list_band_ids().unwrap() {
+        for entry in Band::open(&af, band_id).unwrap().index().iter_entries() {
+            for address in entry.addrs {
+                expected.push((band_id, entry.apath.to_string(), address));
+            }
+        }
+    }
+    assert_eq!(
+        refs.into_iter()
+            .map(|r| (r.band_id, r.apath.to_string(), r.address))
+            .collect::<Vec<_>>(),
+        expected
+    );
+    // Each band has some files, and the second has one more.
+    let count_in = |band_id: BandId| expected.iter().filter(|e| e.0 == band_id).count();
+    assert!(count_in(BandId::zero()) > 0);
+    assert_eq!(count_in(BandId::zero()) + 1, count_in(BandId::new(&[1])));
+}
+
+#[test]
+fn stored_trees_yields_each_complete_band_in_order() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let _incomplete = Band::create(&af).unwrap();
+
+    let trees = af
+        .stored_trees()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        trees
+            .iter()
+            .map(|(band_id, _)| *band_id)
+            .collect::<Vec<_>>(),
+        [BandId::zero(), BandId::new(&[1])]
+    );
+    let apaths = |tree: &StoredTree| {
+        tree.iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+            .unwrap()
+            .filter(|entry| entry.kind == Kind::File)
+            .map(|entry| entry.apath.to_string())
+            .collect::<Vec<_>>()
+    };
+    for (band_id, tree) in &trees {
+        assert_eq!(tree.band_id(), *band_id);
+    }
+    assert_eq!(apaths(&trees[0].1), ["/hello", "/subdir/subfile"]);
+    assert_eq!(
+        apaths(&trees[1].1),
+        ["/hello", "/hello2", "/subdir/subfile"]
+    );
+}
+
+#[test]
+fn bands_referencing_shared_and_unshared_blocks() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file_with_contents("shared", b"in both backups");
+    tf.create_file_with_contents("changed", b"first version");
+    // Store each file in its own block, named by the hash of its content.
+    let options = BackupOptions {
+        small_file_cap: 0,
+        ..Default::default()
+    };
+    backup(&af, tf.path(), &options, TestMonitor::arc()).unwrap();
+    tf.create_file_with_contents("changed", b"second version");
+    backup(&af, tf.path(), &options, TestMonitor::arc()).unwrap();
+
+    let monitor = TestMonitor::arc();
+    let block_of = |content: &[u8]| BlockHash::hash_bytes(content);
+    assert_eq!(
+        af.bands_referencing(&block_of(b"in both backups"), monitor.clone())
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>(),
+        [BandId::new(&[0]), BandId::new(&[1])]
+    );
+    assert_eq!(
+        af.bands_referencing(&block_of(b"second version"), monitor.clone())
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>(),
+        [BandId::new(&[1])]
+    );
+    assert!(af
+        .bands_referencing(&block_of(b"never stored"), monitor.clone())
+        .unwrap()
+        .is_empty());
+
+    let info = af
+        .block_info(&block_of(b"first version"), monitor.clone())
+        .unwrap();
+    assert_eq!(info.bands.into_iter().collect::<Vec<_>>(), [BandId::zero()]);
+    monitor.assert_no_errors();
+}
+
+#[test]
+fn resolve_band_in_empty_archive() {
+    let af = ScratchArchive::new();
+    for policy in [
+        BandSelectionPolicy::LatestClosed,
+        BandSelectionPolicy::Latest,
+        BandSelectionPolicy::Specified(BandId::zero()),
+    ] {
+        let err = af.resolve_band(policy).unwrap_err();
+        assert_eq!(err.to_string(), "Archive is empty");
+    }
+}
+
+#[test]
+fn resolve_band_with_only_incomplete_bands() {
+    let af = ScratchArchive::new();
+    Band::create(&af).unwrap();
+
+    let err = af
+        .resolve_band(BandSelectionPolicy::LatestClosed)
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Archive has no complete bands");
+    assert_eq!(
+        af.resolve_band(BandSelectionPolicy::Latest).unwrap(),
+        BandId::zero()
+    );
+    assert_eq!(
+        af.resolve_band(BandSelectionPolicy::Specified(BandId::zero()))
+            .unwrap(),
+        BandId::zero()
+    );
+}
+
+#[test]
+fn resolve_band_with_complete_and_incomplete_bands() {
+    let af = ScratchArchive::new();
+    Band::create(&af).unwrap().close(0).unwrap();
+    Band::create(&af).unwrap();
+
+    assert_eq!(
+        af.resolve_band(BandSelectionPolicy::LatestClosed).unwrap(),
+        BandId::new(&[0])
+    );
+    assert_eq!(
+        af.resolve_band(BandSelectionPolicy::Latest).unwrap(),
+        BandId::new(&[1])
+    );
+    assert_eq!(
+        af.resolve_band(BandSelectionPolicy::Specified(BandId::new(&[1])))
+            .unwrap(),
+        BandId::new(&[1])
+    );
+    let err = af
+        .resolve_band(BandSelectionPolicy::Specified(BandId::new(&[7])))
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Band not found: b0007");
+}
+
+#[test]
+fn metadata_set_and_get_persists_across_reopen() {
+    let af = ScratchArchive::new();
+    assert!(af.metadata().unwrap().is_empty());
+    af.set_metadata("managed-by", "ops").unwrap();
+    af.set_metadata("policy", "keep 30 days").unwrap();
+    af.set_metadata("managed-by", "infra").unwrap();
+    assert_eq!(af.get_metadata("managed-by").unwrap(), "infra");
+
+    let reopened = Archive::open_path(af.path()).unwrap();
+    assert_eq!(reopened.get_metadata("managed-by").unwrap(), "infra");
+    assert_eq!(reopened.get_metadata("policy").unwrap(), "keep 30 days");
+    assert_eq!(reopened.metadata().unwrap().len(), 2);
+}
+
+#[test]
+fn metadata_get_absent_key_is_not_found() {
+    let af = ScratchArchive::new();
+    af.set_metadata("owner-team", "storage").unwrap();
+    let err = af.get_metadata("policy").unwrap_err();
+    assert!(
+        matches!(&err, conserve::Error::MetadataKeyNotFound { key } if key == "policy"),
+        "{err:?}"
+    );
+    assert_eq!(
+        err.to_string(),
+        "No value is set for \"policy\" in the archive metadata"
+    );
+}
@@ -21,6 +21,7 @@ use rayon::prelude::ParallelIterator;
 use tracing_test::traced_test;
 
 use conserve::counters::Counter;
+use conserve::monitor::counting::CountingMonitor;
 use conserve::monitor::test::TestMonitor;
 use conserve::test_fixtures::{ScratchArchive, TreeFixture};
 use conserve::*;
@@ -155,6 +156,56 @@ pub fn backup_more_excludes() {
     assert_eq!(0, stats.unknown_kind);
 }
 
+#[test]
+fn backup_applies_archive_excludes_with_option_excludes() {
+    let af = ScratchArchive::new();
+    af.set_exclude_patterns(["*.tmp", "# comment", "", "/node_modules"])
+        .unwrap();
+    assert_eq!(af.exclude_patterns().unwrap(), ["*.tmp", "/node_modules"]);
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("a.tmp");
+    srcdir.create_file("b.o");
+    srcdir.create_file("keep");
+    srcdir.create_dir("node_modules");
+    srcdir.create_file("node_modules/x.js");
+
+    let options = BackupOptions {
+        exclude: Exclude::from_strings(["*.o"]).unwrap(),
+        ..Default::default()
+    };
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).expect("backup");
+    let apaths = |af: &ScratchArchive| {
+        af.open_stored_tree(BandSelectionPolicy::LatestClosed)
+            .unwrap()
+            .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+            .unwrap()
+            .map(|entry| entry.apath().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(apaths(&af), ["/", "/keep"]);
+
+    let options = BackupOptions {
+        ignore_archive_excludes: true,
+        ..Default::default()
+    };
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).expect("backup");
+    assert_eq!(
+        apaths(&af),
+        [
+            "/",
+            "/a.tmp",
+            "/b.o",
+            "/keep",
+            "/node_modules",
+            "/node_modules/x.js"
+        ]
+    );
+
+    af.set_exclude_patterns(Vec::<String>::new()).unwrap();
+    assert!(af.exclude_patterns().unwrap().is_empty());
+    assert!(!af.path().join("EXCLUDES").exists());
+}
+
 fn check_backup(af: &ScratchArchive) {
     let band_ids = af.list_band_ids().unwrap();
     assert_eq!(1, band_ids.len());
@@ -250,6 +301,240 @@ fn large_file() {
     assert_eq!(large_content, content);
 }
 
+#[test]
+fn index_records_content_hash_of_stored_files() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    let large_content: Vec<u8> = (0..(3 << 20)).map(|i| (i % 251) as u8).collect();
+    tf.create_file_with_contents("large", &large_content);
+    tf.create_file_with_contents("small", b"small content");
+    tf.create_file_with_contents("empty", b"");
+    let options = BackupOptions {
+        max_block_size: 1 << 20,
+        ..Default::default()
+    };
+    backup(&af, tf.path(), &options, TestMonitor::arc()).expect("backup");
+
+    let content_hashes = |af: &ScratchArchive| {
+        af.open_stored_tree(BandSelectionPolicy::LatestClosed)
+            .unwrap()
+            .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+            .unwrap()
+            .filter(|entry| entry.kind() == Kind::File)
+            .map(|entry| (entry.apath.to_string(), entry.content_hash))
+            .collect::<Vec<_>>()
+    };
+    let expected = vec![
+        ("/empty".to_owned(), None),
+        (
+            "/large".to_owned(),
+            Some(BlockHash::hash_bytes(&large_content)),
+        ),
+        (
+            "/small".to_owned(),
+            Some(BlockHash::hash_bytes(b"small content")),
+        ),
+    ];
+    assert_eq!(content_hashes(&af), expected);
+
+    // Unchanged files keep the hash from the basis index.
+    backup(&af, tf.path(), &options, TestMonitor::arc()).expect("backup");
+    assert_eq!(content_hashes(&af), expected);
+}
+
+#[test]
+fn record_empty_dirs_distinguishes_excluded_contents() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_dir("empty");
+    tf.create_dir("full");
+    tf.create_file("full/file");
+    tf.create_dir("logs");
+    tf.create_file("logs/a.log");
+    let exclude = Exclude::from_strings(["*.log"]).unwrap();
+    let empty_in_source = |af: &ScratchArchive| {
+        af.open_stored_tree(BandSelectionPolicy::LatestClosed)
+            .unwrap()
+            .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+            .unwrap()
+            .map(|entry| (entry.apath.to_string(), entry.empty_in_source))
+            .collect::<Vec<_>>()
+    };
+
+    let options = BackupOptions {
+        exclude: exclude.clone(),
+        ..Default::default()
+    };
+    backup(&af, tf.path(), &options, TestMonitor::arc()).expect("backup");
+    assert!(empty_in_source(&af)
+        .iter()
+        .all(|(_, empty)| empty.is_none()));
+
+    let options = BackupOptions {
+        exclude: exclude.clone(),
+        record_empty_dirs: true,
+        ..Default::default()
+    };
+    backup(&af, tf.path(), &options, TestMonitor::arc()).expect("backup");
+    assert_eq!(
+        empty_in_source(&af),
+        [
+            ("/".to_owned(), Some(false)),
+            ("/empty".to_owned(), Some(true)),
+            ("/full".to_owned(), Some(false)),
+            ("/logs".to_owned(), Some(false)),
+            ("/full/file".to_owned(), None),
+        ]
+    );
+
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &af,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .expect("restore");
+    assert_eq!(
+        std::fs::read_dir(restore_dir.path().join("empty"))
+            .unwrap()
+            .count(),
+        0
+    );
+
+    let st = af
+        .open_stored_tree(BandSelectionPolicy::LatestClosed)
+        .unwrap();
+    let diff_options = DiffOptions {
+        exclude,
+        include_unchanged: true,
+        ..Default::default()
+    };
+    let changes = diff(&st, &tf.live_tree(), &diff_options, TestMonitor::arc())
+        .unwrap()
+        .collect::<Vec<_>>();
+    assert_eq!(changes.len(), 5);
+    assert!(
+        changes.iter().all(|change| change.change.is_unchanged()),
+        "{changes:#?}"
+    );
+}
+
+/// Back up a large file, then the same file with a few bytes inserted at the start,
+/// and return the stats from the second backup.
+fn back_up_shifted_file(chunking: Chunking) -> BackupStats {
+    use rand::{RngCore, SeedableRng};
+
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    let mut content = vec![0; 4 << 20];
+    rand::rngs::StdRng::seed_from_u64(1).fill_bytes(&mut content);
+    let options = BackupOptions {
+        max_block_size: 256 << 10,
+        chunking,
+        ..Default::default()
+    };
+    tf.create_file_with_contents("large", &content);
+    backup(&af, tf.path(), &options, TestMonitor::arc()).expect("first backup");
+
+    content.splice(0..0, *b"a few new bytes");
+    tf.create_file_with_contents("large", &content);
+    let stats = backup(&af, tf.path(), &options, TestMonitor::arc()).expect("second backup");
+    assert_eq!(stats.modified_files, 1);
+
+    let rd = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(&af, rd.path(), &RestoreOptions::default(), monitor.clone()).expect("restore");
+    monitor.assert_no_errors();
+    assert_eq!(std::fs::read(rd.path().join("large")).unwrap(), content);
+    stats
+}
+
+#[test]
+fn content_defined_chunking_deduplicates_shifted_content() {
+    let fixed = back_up_shifted_file(Chunking::Fixed);
+    let content_defined = back_up_shifted_file(Chunking::ContentDefined);
+    assert_eq!(fixed.deduplicated_blocks, 0);
+    assert!(
+        content_defined.written_blocks * 4 < fixed.written_blocks,
+        "content-defined chunking wrote {} blocks, and fixed chunking {}",
+        content_defined.written_blocks,
+        fixed.written_blocks
+    );
+    assert!(content_defined.deduplicated_blocks > 0);
+}
+
+#[test]
+fn dedup_prefixes_stores_truncated_file_without_new_blocks() {
+    use rand::{RngCore, SeedableRng};
+
+    const BLOCK_SIZE: usize = 10_000;
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    let mut content = vec![0; 3 * BLOCK_SIZE];
+    rand::rngs::StdRng::seed_from_u64(1).fill_bytes(&mut content);
+    let options = BackupOptions {
+        max_block_size: BLOCK_SIZE,
+        small_file_cap: 100,
+        dedup_prefixes: true,
+        ..Default::default()
+    };
+    tf.create_file_with_contents("big", &content);
+    backup(&af, tf.path(), &options, TestMonitor::arc()).expect("first backup");
+    let first_addrs = Band::open(&af, BandId::zero())
+        .unwrap()
+        .index()
+        .iter_entries()
+        .find(|entry| entry.apath == "/big")
+        .unwrap()
+        .addrs;
+    assert_eq!(first_addrs.len(), 3);
+
+    // The last block of the truncated file is a prefix of the last block stored before.
+    content.truncate(2 * BLOCK_SIZE + 5000);
+    tf.create_file_with_contents("big", &content);
+    let stats = backup(&af, tf.path(), &options, TestMonitor::arc()).expect("second backup");
+    assert_eq!(stats.modified_files, 1);
+    assert_eq!(stats.written_blocks, 0);
+    assert_eq!(stats.deduplicated_blocks, 3);
+    assert_eq!(stats.prefix_deduplicated_blocks, 1);
+
+    let addrs = Band::open(&af, BandId::new(&[1]))
+        .unwrap()
+        .index()
+        .iter_entries()
+        .find(|entry| entry.apath == "/big")
+        .unwrap()
+        .addrs;
+    assert_eq!(addrs[..2], first_addrs[..2]);
+    assert_eq!(addrs[2].hash, first_addrs[2].hash);
+    assert_eq!(addrs[2].start, 0);
+    assert_eq!(addrs[2].len, 5000);
+
+    let rd = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(&af, rd.path(), &RestoreOptions::default(), monitor.clone()).expect("restore");
+    monitor.assert_no_errors();
+    assert_eq!(std::fs::read(rd.path().join("big")).unwrap(), content);
+}
+
+#[test]
+fn truncated_file_is_stored_as_new_block_without_dedup_prefixes() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    let options = BackupOptions {
+        max_block_size: 10_000,
+        small_file_cap: 100,
+        ..Default::default()
+    };
+    tf.create_file_with_contents("big", &[b'x'; 10_000]);
+    backup(&af, tf.path(), &options, TestMonitor::arc()).unwrap();
+    tf.create_file_with_contents("big", &[b'x'; 8000]);
+    let stats = backup(&af, tf.path(), &options, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.written_blocks, 1);
+    assert_eq!(stats.prefix_deduplicated_blocks, 0);
+}
+
 /// If some files are unreadable, others are stored and the backup completes with warnings.
 #[cfg(unix)]
 #[test]
@@ -344,6 +629,48 @@ pub fn symlink() {
     assert_eq!(e2.target.as_ref().unwrap(), "/a/broken/destination");
 }
 
+#[cfg(unix)]
+#[test]
+#[traced_test]
+fn warn_dangling_symlinks_still_stores_them() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("file");
+    srcdir.create_dir("subdir");
+    srcdir.create_symlink("subdir/good", "../file");
+    srcdir.create_symlink("subdir/dangling", "../missing");
+    let options = BackupOptions {
+        warn_dangling_symlinks: true,
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    let stats = backup(&af, srcdir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    monitor.assert_counter(Counter::DanglingSymlinks, 1);
+    assert_eq!(stats.symlinks, 2);
+    assert_eq!(stats.dangling_symlinks, 1);
+    assert!(stats.to_string().contains("dangling symlinks"));
+    assert!(!BackupStats::default().to_string().contains("dangling"));
+    assert!(logs_contain(
+        "Symlink /subdir/dangling points to \"../missing\", which doesn't exist"
+    ));
+
+    let targets = af
+        .open_stored_tree(BandSelectionPolicy::LatestClosed)
+        .unwrap()
+        .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+        .unwrap()
+        .filter_map(|entry| Some((entry.apath.to_string(), entry.target?)))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        targets,
+        [
+            ("/subdir/dangling".to_owned(), "../missing".to_owned()),
+            ("/subdir/good".to_owned(), "../file".to_owned())
+        ]
+    );
+}
+
 #[test]
 pub fn empty_file_uses_zero_blocks() {
     let af = ScratchArchive::new();
@@ -659,3 +986,640 @@ fn detect_unchanged_from_stitched_index() {
     assert_eq!(stats.unmodified_files, 2, "both files are unmodified");
     assert_eq!(monitor.get_counter(Counter::IndexWrites), 3);
 }
+
+#[test]
+fn refuse_to_back_up_source_inside_archive() {
+    let af = ScratchArchive::new();
+    let err = backup(
+        &af,
+        &af.path().join("d"),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap_err();
+    assert!(
+        matches!(err, Error::BackupSourceInsideArchive { .. }),
+        "{err:?}"
+    );
+    assert!(af.list_band_ids().unwrap().is_empty());
+}
+
+#[test]
+fn refuse_to_back_up_source_containing_archive_unless_excluded() {
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("backups");
+    let archive = Archive::create_path(&srcdir.path().join("backups/archive")).unwrap();
+
+    let err = backup(
+        &archive,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Backup source contains the archive at /backups/archive; exclude it to back up this source"
+    );
+    assert!(archive.list_band_ids().unwrap().is_empty());
+
+    // Excluding the archive or any directory containing it is enough.
+    for pattern in ["/backups/archive", "/backups"] {
+        let options = BackupOptions {
+            exclude: Exclude::from_strings([pattern]).unwrap(),
+            ..Default::default()
+        };
+        backup(&archive, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    }
+    assert_eq!(archive.list_band_ids().unwrap().len(), 2);
+}
+
+#[test]
+fn verify_dedup_replaces_corrupt_existing_block() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+
+    // Replace the stored block with different, validly compressed, content.
+    let hash: BlockHash = HELLO_HASH.parse().unwrap();
+    let block_path = af.path().join("d").join(blockdir::block_relpath(&hash));
+    let corrupt = snap::raw::Encoder::new()
+        .compress_vec(b"not what you stored\n")
+        .unwrap();
+    std::fs::write(&block_path, &corrupt).unwrap();
+
+    // Back up a changed file with the same content, so that it has the same hash.
+    let srcdir2 = TreeFixture::new();
+    let path = srcdir2.create_file("hello");
+    // Make sure it's not skipped as unchanged.
+    set_file_mtime(path, FileTime::from_unix_time(1_000_000_000, 0)).unwrap();
+    let archive = Archive::open_path(af.path()).unwrap();
+    let options = BackupOptions {
+        verify_dedup: true,
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    let stats = backup(&archive, srcdir2.path(), &options, monitor.clone()).unwrap();
+    let errors = monitor.take_errors();
+    assert_eq!(errors.len(), 1);
+    assert!(
+        matches!(&errors[0], Error::DeduplicatedBlockCorrupt { hash: h } if *h == hash),
+        "{errors:?}"
+    );
+    assert_eq!(stats.replaced_corrupt_blocks, 1);
+    assert_eq!(stats.deduplicated_blocks, 0);
+    assert_eq!(stats.written_blocks, 1);
+
+    // The block has been healed.
+    let archive = Archive::open_path(af.path()).unwrap();
+    let content = archive
+        .block_dir()
+        .get_block_content(&hash, TestMonitor::arc())
+        .unwrap();
+    assert_eq!(content.as_ref(), b"contents");
+}
+
+#[test]
+fn verify_dedup_accepts_matching_existing_block() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+
+    let srcdir2 = TreeFixture::new();
+    let path = srcdir2.create_file("hello");
+    // Make sure it's not skipped as unchanged.
+    set_file_mtime(path, FileTime::from_unix_time(1_000_000_000, 0)).unwrap();
+    let archive = Archive::open_path(af.path()).unwrap();
+    let options = BackupOptions {
+        verify_dedup: true,
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    let stats = backup(&archive, srcdir2.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.deduplicated_blocks, 1);
+    assert_eq!(stats.replaced_corrupt_blocks, 0);
+    assert_eq!(stats.written_blocks, 0);
+}
+
+#[test]
+#[traced_test]
+fn warn_about_long_paths() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let long_name = "x".repeat(60);
+    srcdir.create_dir(&long_name);
+    srcdir.create_file(&format!("{long_name}/{long_name}"));
+    srcdir.create_file("short");
+    let options = BackupOptions {
+        max_path_len: Some(100),
+        max_path_depth: Some(5),
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    let stats = backup(&af, srcdir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert!(logs_contain(
+        "is 122 bytes long, more than the limit of 100"
+    ));
+    assert_eq!(stats.files, 2, "long path is still stored");
+}
+
+#[test]
+fn strict_paths_skips_long_and_deep_paths() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("a");
+    srcdir.create_dir("a/b");
+    srcdir.create_file("a/b/deep");
+    srcdir.create_file(&"x".repeat(60));
+    srcdir.create_file("short");
+    let options = BackupOptions {
+        max_path_len: Some(50),
+        max_path_depth: Some(2),
+        strict_paths: true,
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    let stats = backup(&af, srcdir.path(), &options, monitor.clone()).unwrap();
+    let errors = monitor.take_errors();
+    assert_eq!(errors.len(), 2, "{errors:?}");
+    assert!(matches!(
+        &errors[1],
+        Error::PathTooDeep {
+            depth: 3,
+            limit: 2,
+            ..
+        }
+    ));
+    assert!(matches!(
+        &errors[0],
+        Error::PathTooLong {
+            len: 61,
+            limit: 50,
+            ..
+        }
+    ));
+    assert_eq!(stats.errors, 2);
+
+    let names = af
+        .open_stored_tree(BandSelectionPolicy::LatestClosed)
+        .unwrap()
+        .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+        .unwrap()
+        .map(|entry| entry.apath.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["/", "/a", "/short", "/a/b"]);
+}
+
+#[test]
+#[traced_test]
+fn max_files_per_dir_keeps_the_first_entries_by_name() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("big");
+    srcdir.create_dir("small");
+    for name in ["e", "b", "d", "a"] {
+        srcdir.create_file(&format!("big/{name}"));
+    }
+    srcdir.create_dir("big/c");
+    srcdir.create_file("big/c/inside");
+    srcdir.create_file("small/f");
+    let options = BackupOptions {
+        max_files_per_dir: Some(3),
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    backup(&af, srcdir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    monitor.assert_counter(Counter::OverflowEntries, 2);
+    assert!(logs_contain(
+        "Directory /big has 5 entries, more than the limit of 3: skipping the last 2 by name"
+    ));
+
+    let names = af
+        .open_stored_tree(BandSelectionPolicy::LatestClosed)
+        .unwrap()
+        .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+        .unwrap()
+        .map(|entry| entry.apath.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [
+            "/",
+            "/big",
+            "/small",
+            "/big/a",
+            "/big/b",
+            "/big/c",
+            "/big/c/inside",
+            "/small/f"
+        ]
+    );
+}
+
+#[test]
+fn concurrent_backup_is_refused_while_first_holds_write_lock() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file("a");
+    let second_result = std::cell::RefCell::new(None);
+    let options = BackupOptions {
+        change_callback: Some(Box::new(|_change| {
+            // Try a second backup into the same archive while the first is running.
+            *second_result.borrow_mut() = Some(backup(
+                &af,
+                tf.path(),
+                &BackupOptions::default(),
+                TestMonitor::arc(),
+            ));
+            Ok(())
+        })),
+        ..Default::default()
+    };
+    backup(&af, tf.path(), &options, TestMonitor::arc()).expect("first backup");
+    drop(options);
+    let err = second_result
+        .into_inner()
+        .expect("callback was called")
+        .expect_err("second backup fails");
+    assert!(
+        matches!(err, Error::ArchiveLocked { ref holder } if holder.pid == Some(std::process::id())),
+        "{err:?}"
+    );
+    assert_eq!(af.list_band_ids().unwrap().len(), 1);
+
+    // Once the first backup finishes, the lock is released.
+    assert_eq!(WriteLock::holder(&af).unwrap(), None);
+    backup(
+        &af,
+        tf.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .expect("third backup");
+}
+
+#[test]
+fn break_lock_allows_backup_after_stale_write_lock() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file("a");
+    // Simulate a process that died holding the lock.
+    std::mem::forget(WriteLock::acquire(&af).unwrap());
+
+    let err = backup(
+        &af,
+        tf.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .expect_err("backup is refused");
+    assert!(err.to_string().starts_with("Archive is locked by process"));
+
+    let options = BackupOptions {
+        break_lock: true,
+        ..Default::default()
+    };
+    backup(&af, tf.path(), &options, TestMonitor::arc()).expect("backup breaking lock");
+    assert_eq!(WriteLock::holder(&af).unwrap(), None);
+}
+
+#[test]
+#[traced_test]
+fn backup_only_listed_source_paths_and_their_parents() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("a");
+    srcdir.create_dir("a/b");
+    srcdir.create_file("a/b/wanted");
+    srcdir.create_file("a/b/unwanted");
+    srcdir.create_dir("c");
+    srcdir.create_file("c/unwanted");
+    srcdir.create_file("top");
+    let options = BackupOptions {
+        source_paths: Some(vec!["a/b/wanted".into(), "top".into(), "missing".into()]),
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    let stats = backup(&af, srcdir.path(), &options, monitor.clone()).unwrap();
+    assert_eq!(stats.errors, 0);
+    assert!(monitor.take_errors().is_empty());
+    assert!(logs_contain("Listed path \"missing\" can't be read"));
+
+    let names = af
+        .open_stored_tree(BandSelectionPolicy::LatestClosed)
+        .unwrap()
+        .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+        .unwrap()
+        .map(|entry| entry.apath.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["/", "/a", "/top", "/a/b", "/a/b/wanted"]);
+}
+
+#[test]
+fn block_size_rules_apply_to_matching_files() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("media");
+    srcdir.create_file_with_contents("media/movie.mp4", &[b'm'; 10_000]);
+    srcdir.create_file_with_contents("main.rs", &[b'r'; 10_000]);
+    let options = BackupOptions {
+        max_block_size: 1000,
+        small_file_cap: 100,
+        block_size_rules: vec![BlockSizeRule::new(["*.mp4", "*.mkv"], 4000).unwrap()],
+        ..Default::default()
+    };
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    let block_lens = |apath: &str| {
+        band.index()
+            .iter_entries()
+            .find(|entry| entry.apath == apath)
+            .unwrap()
+            .addrs
+            .iter()
+            .map(|addr| addr.len)
+            .collect::<Vec<u64>>()
+    };
+    assert_eq!(block_lens("/media/movie.mp4"), [4000, 4000, 2000]);
+    assert_eq!(block_lens("/main.rs"), [1000; 10]);
+}
+
+#[cfg(unix)]
+#[test]
+#[traced_test]
+fn fifo_is_counted_and_skipped() {
+    use nix::sys::stat::Mode;
+    use nix::unistd::mkfifo;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("a");
+    mkfifo(&srcdir.path().join("fifo"), Mode::S_IRWXU).unwrap();
+
+    let stats = backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(stats.fifos, 1);
+    assert_eq!(stats.unknown_kind, 1);
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.errors, 0);
+    assert!(logs_contain(
+        "Skipped 1 special files that can't be backed up"
+    ));
+
+    let names = af
+        .open_stored_tree(BandSelectionPolicy::LatestClosed)
+        .unwrap()
+        .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+        .unwrap()
+        .map(|entry| entry.apath.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["/", "/a"]);
+}
+
+#[test]
+fn directory_only_exclude_pattern_keeps_files_of_same_name() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("logs");
+    srcdir.create_file("logs/today");
+    srcdir.create_dir("src");
+    srcdir.create_file("src/logs");
+    let options = BackupOptions {
+        exclude: Exclude::from_strings(["logs/"]).unwrap(),
+        ..Default::default()
+    };
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+
+    let stored_names = |af: &ScratchArchive, exclude| {
+        af.open_stored_tree(BandSelectionPolicy::LatestClosed)
+            .unwrap()
+            .iter_entries(Apath::root(), exclude, TestMonitor::arc())
+            .unwrap()
+            .map(|entry| entry.apath.to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        stored_names(&af, Exclude::nothing()),
+        ["/", "/src", "/src/logs"]
+    );
+
+    // The same pattern applies to directories in the index.
+    let af = ScratchArchive::new();
+    backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(
+        stored_names(&af, Exclude::from_strings(["logs/"]).unwrap()),
+        ["/", "/src", "/src/logs"]
+    );
+}
+
+#[test]
+fn stop_request_writes_pending_index_entries_and_marks_band_interrupted() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    for name in ["a", "b", "c"] {
+        tf.create_file(name);
+    }
+    let stop = AtomicBool::new(false);
+    let options = BackupOptions {
+        change_callback: Some(Box::new(|change| {
+            if change.apath == "/b" {
+                stop.store(true, Ordering::Relaxed);
+            }
+            Ok(())
+        })),
+        stop_requested: Some(&stop),
+        ..Default::default()
+    };
+    let err = backup(&af, tf.path(), &options, TestMonitor::arc()).unwrap_err();
+    assert!(
+        matches!(err, Error::BackupInterrupted { band_id } if band_id == BandId::zero()),
+        "{err:?}"
+    );
+
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert!(band.is_interrupted().unwrap());
+    assert!(!band.is_closed().unwrap());
+    // Everything stored before the stop, which was all in one hunk, was written.
+    let names: Vec<String> = band
+        .index()
+        .iter_entries()
+        .map(|entry| entry.apath.to_string())
+        .collect();
+    assert_eq!(names, ["/", "/a", "/b"]);
+    let monitor = TestMonitor::arc();
+    af.validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(WriteLock::holder(&af).unwrap(), None);
+
+    // The next backup completes, using the interrupted band as its basis.
+    let stats = backup(
+        &af,
+        tf.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(stats.unmodified_files, 2);
+    assert_eq!(stats.new_files, 1);
+}
+
+#[test]
+fn counting_monitor_totals_match_backup_stats() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file("hello");
+    tf.create_file_with_contents("big", &[7u8; 300_000]);
+    tf.create_dir("subdir");
+    tf.create_file("subdir/world");
+    let monitor = CountingMonitor::arc();
+    let stats = backup(
+        &af,
+        tf.path(),
+        &BackupOptions {
+            max_block_size: 100_000,
+            ..Default::default()
+        },
+        monitor.clone(),
+    )
+    .unwrap();
+
+    let snapshot = monitor.snapshot();
+    assert_eq!(snapshot.get(Counter::Files), stats.files);
+    assert_eq!(snapshot.get(Counter::Files), 3);
+    assert_eq!(snapshot.get(Counter::BlockWrites), stats.written_blocks);
+    assert!(stats.written_blocks > 0);
+    assert_eq!(monitor.error_count(), 0);
+}
+
+#[test]
+fn bands_with_snappy_and_zstd_indexes_both_read() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    srcdir.create_file("world");
+    let options = BackupOptions {
+        index_compression: IndexCompression::Zstd,
+        ..Default::default()
+    };
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+
+    let band = Band::open(&af, BandId::new(&[0])).unwrap();
+    assert!(band.format_flags().is_empty());
+    assert_eq!(band.index_compression(), IndexCompression::Snappy);
+    let band = Band::open(&af, BandId::new(&[1])).unwrap();
+    assert_eq!(band.format_flags(), ["index_zstd"]);
+    assert_eq!(band.index_compression(), IndexCompression::Zstd);
+
+    for (band_id, expected) in [(0, vec!["/", "/hello"]), (1, vec!["/", "/hello", "/world"])] {
+        let apaths: Vec<String> = af
+            .open_stored_tree(BandSelectionPolicy::Specified(BandId::new(&[band_id])))
+            .unwrap()
+            .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+            .unwrap()
+            .map(|entry| entry.apath().to_string())
+            .collect();
+        assert_eq!(apaths, expected);
+    }
+    let report = af
+        .validate(&ValidateOptions::default(), TestMonitor::arc())
+        .unwrap();
+    assert!(report.ok, "{report:?}");
+}
+
+#[cfg(unix)]
+#[test]
+fn source_symlink_to_directory_backs_up_the_directory() {
+    use std::os::unix::fs::symlink;
+
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file_with_contents("hello", b"hello");
+    tf.create_symlink("inner_link", "hello");
+    let link_dir = TempDir::new().unwrap();
+    let link_path = link_dir.path().join("source");
+    symlink(tf.path(), &link_path).unwrap();
+
+    let monitor = TestMonitor::arc();
+    let stats = backup(&af, &link_path, &BackupOptions::default(), monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.symlinks, 1);
+
+    let entries = af
+        .open_stored_tree(BandSelectionPolicy::LatestClosed)
+        .unwrap()
+        .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+        .unwrap()
+        .map(|entry| (entry.apath.to_string(), entry.kind()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        entries,
+        [
+            ("/".to_owned(), Kind::Dir),
+            ("/hello".to_owned(), Kind::File),
+            ("/inner_link".to_owned(), Kind::Symlink),
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn broken_source_symlink_fails_without_creating_a_band() {
+    let af = ScratchArchive::new();
+    let link_dir = TempDir::new().unwrap();
+    let link_path = link_dir.path().join("source");
+    std::os::unix::fs::symlink(link_dir.path().join("missing"), &link_path).unwrap();
+
+    let err = backup(
+        &af,
+        &link_path,
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap_err();
+    assert!(
+        matches!(err, Error::BrokenSourceSymlink { ref path, .. } if *path == link_path),
+        "{err:?}"
+    );
+    assert_eq!(af.list_band_ids().unwrap(), []);
+}
@@ -0,0 +1,125 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test archives whose blocks are compressed with a trained zstd dictionary.
+
+use std::fs::read;
+
+use assert_fs::TempDir;
+
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::transport::Transport;
+use conserve::*;
+
+const FILES: usize = 500;
+
+/// Make a tree of many small, similar, files, like logs.
+fn similar_files() -> TreeFixture {
+    let tf = TreeFixture::new();
+    for i in 0..FILES {
+        let content = (0..20)
+            .map(|j| {
+                format!(
+                    "2024-03-{:02} 10:{:02}:{:02} INFO worker{} handled request id={} path=/api/items/{} status=200\n",
+                    i % 28 + 1,
+                    j,
+                    i % 60,
+                    i % 5,
+                    i * 20 + j,
+                    (i * 7 + j) % 1000,
+                )
+            })
+            .collect::<String>();
+        tf.create_file_with_contents(&format!("log{i:04}.txt"), content.as_bytes());
+    }
+    tf
+}
+
+/// Store each file in its own block, so that each is compressed separately.
+fn separate_blocks() -> BackupOptions<'static> {
+    BackupOptions {
+        small_file_cap: 0,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn dictionary_archive_is_smaller_and_restores_identically() {
+    let tf = similar_files();
+
+    let plain = ScratchArchive::new();
+    let plain_stats = backup(&plain, tf.path(), &separate_blocks(), TestMonitor::arc()).unwrap();
+
+    let dictionary =
+        train_block_dictionary(tf.path(), Exclude::nothing(), TestMonitor::arc()).unwrap();
+    let archive_dir = TempDir::new().unwrap();
+    let archive =
+        Archive::create_with_block_dictionary(Transport::local(archive_dir.path()), &dictionary)
+            .unwrap();
+    let stats = backup(&archive, tf.path(), &separate_blocks(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.written_blocks, FILES);
+    assert_eq!(stats.written_blocks, plain_stats.written_blocks);
+    assert!(
+        stats.compressed_bytes * 2 < plain_stats.compressed_bytes,
+        "blocks compressed with the dictionary take {} bytes, and without it {}",
+        stats.compressed_bytes,
+        plain_stats.compressed_bytes
+    );
+    let band = Band::open(&archive, BandId::zero()).unwrap();
+    assert_eq!(band.format_flags(), ["block_dictionary"]);
+
+    // The dictionary is read back when the archive is reopened.
+    let archive = Archive::open_path(archive_dir.path()).unwrap();
+    let restore_dir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(
+        &archive,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    for i in 0..FILES {
+        let name = format!("log{i:04}.txt");
+        assert_eq!(
+            read(restore_dir.path().join(&name)).unwrap(),
+            read(tf.path().join(&name)).unwrap()
+        );
+    }
+    let monitor = TestMonitor::arc();
+    archive
+        .validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    monitor.assert_no_errors();
+}
+
+#[test]
+fn archive_without_dictionary_file_fails_to_open() {
+    let tf = similar_files();
+    let dictionary =
+        train_block_dictionary(tf.path(), Exclude::nothing(), TestMonitor::arc()).unwrap();
+    let archive_dir = TempDir::new().unwrap();
+    Archive::create_with_block_dictionary(Transport::local(archive_dir.path()), &dictionary)
+        .unwrap();
+    std::fs::remove_file(archive_dir.path().join("DICTIONARY")).unwrap();
+    assert!(Archive::open_path(archive_dir.path()).is_err());
+}
+
+#[test]
+fn training_needs_enough_samples() {
+    let tf = TreeFixture::new();
+    tf.create_file("hello");
+    assert!(train_block_dictionary(tf.path(), Exclude::nothing(), TestMonitor::arc()).is_err());
+}
@@ -0,0 +1,141 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test keeping blocks in a custom [BlockStore].
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use rayon::prelude::ParallelIterator;
+use tempfile::TempDir;
+
+use conserve::monitor::test::TestMonitor;
+use conserve::monitor::Monitor;
+use conserve::test_fixtures::TreeFixture;
+use conserve::transport::{self, ErrorKind, WriteMode};
+use conserve::*;
+
+/// Blocks held in memory.
+#[derive(Default)]
+struct MapBlockStore {
+    blocks: Mutex<HashMap<String, Bytes>>,
+}
+
+fn not_found() -> transport::Error {
+    transport::Error {
+        kind: ErrorKind::NotFound,
+        source: Some(Box::new(io::Error::from(io::ErrorKind::NotFound))),
+        url: None,
+    }
+}
+
+impl BlockStore for MapBlockStore {
+    fn get(&self, hash: &BlockHash) -> transport::Result<Bytes> {
+        let blocks = self.blocks.lock().unwrap();
+        blocks.get(&hash.to_string()).cloned().ok_or_else(not_found)
+    }
+
+    fn put(&self, hash: &BlockHash, compressed: &[u8], _mode: WriteMode) -> transport::Result<()> {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.insert(hash.to_string(), Bytes::copy_from_slice(compressed));
+        Ok(())
+    }
+
+    fn compressed_size(&self, hash: &BlockHash) -> transport::Result<u64> {
+        Ok(self.get(hash)?.len() as u64)
+    }
+
+    fn list(&self, prefix: &str, _monitor: Arc<dyn Monitor>) -> transport::Result<Vec<BlockHash>> {
+        let blocks = self.blocks.lock().unwrap();
+        Ok(blocks
+            .keys()
+            .map(|name| name.parse::<BlockHash>().unwrap())
+            .filter(|hash| hash.starts_with(prefix))
+            .collect())
+    }
+
+    fn delete(&self, hash: &BlockHash) -> transport::Result<()> {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks
+            .remove(&hash.to_string())
+            .map(|_| ())
+            .ok_or_else(not_found)
+    }
+}
+
+#[test]
+fn backup_and_restore_with_custom_block_store() {
+    let archive_dir = TempDir::new().unwrap();
+    let store = Arc::new(MapBlockStore::default());
+    let archive = Archive::create_path(archive_dir.path())
+        .unwrap()
+        .with_block_store(store.clone());
+    let src = TreeFixture::new();
+    src.create_file_with_contents("hello", b"hello world");
+    src.create_dir("subdir");
+    src.create_file_with_contents("subdir/big", &vec![7u8; 3 << 20]);
+    let options = BackupOptions {
+        max_block_size: 1 << 20,
+        ..Default::default()
+    };
+    let stats = backup(&archive, src.path(), &options, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.files, 2);
+
+    // All the blocks are in the store, and none in the archive directory.
+    let block_count = store.blocks.lock().unwrap().len();
+    assert!(block_count >= 2, "{block_count} blocks stored");
+    assert_eq!(
+        std::fs::read_dir(archive_dir.path().join("d"))
+            .unwrap()
+            .count(),
+        0
+    );
+
+    let archive = Archive::open_path(archive_dir.path())
+        .unwrap()
+        .with_block_store(store.clone());
+    let monitor = TestMonitor::arc();
+    let referenced = archive
+        .referenced_blocks(&archive.list_band_ids().unwrap(), monitor.clone())
+        .unwrap();
+    let present: HashSet<BlockHash> = archive.block_dir().blocks(monitor).unwrap().collect();
+    assert_eq!(referenced, present);
+    assert_eq!(present.len(), block_count);
+
+    let dest = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(
+        &archive,
+        dest.path(),
+        &RestoreOptions::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(
+        std::fs::read(dest.path().join("hello")).unwrap(),
+        b"hello world"
+    );
+    assert_eq!(
+        std::fs::read(dest.path().join("subdir/big")).unwrap(),
+        vec![7u8; 3 << 20]
+    );
+
+    let validate_monitor = TestMonitor::arc();
+    archive
+        .validate(&ValidateOptions::default(), validate_monitor.clone())
+        .unwrap();
+    validate_monitor.assert_no_errors();
+}
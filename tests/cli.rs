@@ -382,6 +382,32 @@ fn restore_only_subtree() {
     dest.close().unwrap();
 }
 
+#[test]
+fn restore_prints_stats_unless_no_stats() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let restore_dir = TempDir::new().unwrap();
+    run_conserve()
+        .arg("restore")
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Restore complete."))
+        .stderr(predicate::str::contains(" MB/s restored"));
+
+    let restore_dir = TempDir::new().unwrap();
+    run_conserve()
+        .args(["restore", "--no-stats"])
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Restore complete.").not())
+        .stderr(predicate::str::contains("MB/s").not());
+}
+
 #[test]
 fn size_exclude() {
     let source = TreeFixture::new();
@@ -396,3 +422,60 @@ fn size_exclude() {
         .success()
         .stdout("10\n");
 }
+
+#[test]
+fn ls_and_size_only_subtree_of_archive() {
+    let archive = "testdata/archive/minimal/v0.6.3/";
+    run_conserve()
+        .args(["ls", "--only", "/subdir", archive])
+        .assert()
+        .success()
+        .stdout("/subdir\n/subdir/subfile\n");
+    run_conserve()
+        .args(["size", "--bytes", "--only", "/subdir", archive])
+        .assert()
+        .success()
+        .stdout("12\n");
+    run_conserve()
+        .args(["size", "--bytes", archive])
+        .assert()
+        .success()
+        .stdout("24\n");
+}
+
+#[test]
+fn size_with_subtotals() {
+    let source = TreeFixture::new();
+    source.create_file_with_contents("a", b"0123456789");
+    source.create_file_with_contents("b", b"01234567890123456789");
+
+    run_conserve()
+        .args(["size", "--bytes", "--subtotal-interval=0", "--source"])
+        .arg(source.path())
+        .assert()
+        .success()
+        .stdout(indoc! { "
+            10 so far, in 1 files
+            30 so far, in 2 files
+            30
+        "});
+}
+
+#[test]
+fn init_inside_archive_fails() {
+    let temp = TempDir::new().unwrap();
+    let outer = temp.child("outer");
+    run_conserve()
+        .arg("init")
+        .arg(outer.path())
+        .assert()
+        .success();
+    run_conserve()
+        .arg("init")
+        .arg(outer.child("d").child("inner").path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Can't create an archive inside the existing archive",
+        ));
+}
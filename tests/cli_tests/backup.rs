@@ -14,11 +14,14 @@
 use std::fs::read_to_string;
 
 use assert_cmd::prelude::*;
-use assert_fs::NamedTempFile;
+use assert_cmd::Command;
+use assert_fs::prelude::*;
+use assert_fs::{NamedTempFile, TempDir};
 use indoc::indoc;
 use serde_json::Deserializer;
 
 use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::{Band, BandId};
 
 use crate::run_conserve;
 
@@ -99,3 +102,429 @@ fn verbose_backup_does_not_print_unchanged_files() {
             * /b
         "});
 }
+
+#[test]
+fn durable_backup_can_be_restored() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file_with_contents("hello", b"durable content");
+
+    run_conserve()
+        .args(["backup", "--no-stats", "--durable"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    let dest = TempDir::new().unwrap();
+    run_conserve()
+        .args(["restore", "--no-stats"])
+        .arg(af.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    dest.child("hello").assert("durable content");
+}
+
+#[test]
+fn backup_entries_per_hunk_sets_index_hunk_size() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    for name in ["a", "b", "c", "d"] {
+        src.create_file(name);
+    }
+
+    run_conserve()
+        .args(["backup", "--no-stats", "--entries-per-hunk", "2"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    // The root directory and four files make five entries.
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert_eq!(band.index().hunks_available().unwrap(), [0, 1, 2]);
+    assert_eq!(band.get_info().unwrap().index_hunk_count, Some(3));
+
+    run_conserve()
+        .args(["backup", "--entries-per-hunk", "0"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("--entries-per-hunk"));
+}
+
+#[test]
+fn backup_init_if_missing_creates_archive() {
+    let temp = TempDir::new().unwrap();
+    let archive_path = temp.path().join("archive");
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    run_conserve()
+        .args(["backup", "--no-stats", "--init-if-missing"])
+        .arg(&archive_path)
+        .arg(src.path())
+        .assert()
+        .success();
+    run_conserve()
+        .arg("ls")
+        .arg(&archive_path)
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+
+    // A second backup goes into the existing archive.
+    run_conserve()
+        .args(["backup", "--no-stats", "--init-if-missing"])
+        .arg(&archive_path)
+        .arg(src.path())
+        .assert()
+        .success();
+    run_conserve()
+        .arg("versions")
+        .arg("--short")
+        .arg(&archive_path)
+        .assert()
+        .success()
+        .stdout("b0000\nb0001\n");
+}
+
+#[test]
+fn backup_init_if_missing_refuses_non_empty_directory() {
+    let temp = TempDir::new().unwrap();
+    temp.child("precious").write_str("not an archive").unwrap();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    run_conserve()
+        .args(["backup", "--no-stats", "--init-if-missing"])
+        .arg(temp.path())
+        .arg(src.path())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("not empty"));
+    temp.child("precious").assert("not an archive");
+    assert!(!temp.child("CONSERVE").exists());
+}
+
+#[test]
+fn backup_init_if_missing_checks_options_before_creating_archive() {
+    let temp = TempDir::new().unwrap();
+    let archive_path = temp.child("archive");
+    let src = TreeFixture::new();
+
+    run_conserve()
+        .args(["backup", "--no-stats", "--init-if-missing"])
+        .args(["--exclude-from", "/nonexistent/excludes"])
+        .arg(archive_path.path())
+        .arg(src.path())
+        .assert()
+        .failure();
+    assert!(!archive_path.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn backup_through_external_filter_commands() {
+    // Flip the top bit of every byte, which is its own inverse.
+    let xor = r"LC_ALL=C tr '\000-\377' '\200-\377\000-\177'";
+    let filter_args = ["--encrypt-command", xor, "--decrypt-command", xor];
+    let temp = TempDir::new().unwrap();
+    let archive = temp.child("archive");
+    let src = TreeFixture::new();
+    src.create_file_with_contents("hello", b"secret content");
+
+    run_conserve()
+        .arg("init")
+        .args(filter_args)
+        .arg(archive.path())
+        .assert()
+        .success();
+    run_conserve()
+        .args(["backup", "--no-stats"])
+        .args(filter_args)
+        .arg(archive.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .arg("ls")
+        .arg(archive.path())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "Archive content is filtered through external commands",
+        ));
+
+    let dest = temp.child("dest");
+    run_conserve()
+        .args(["restore", "--no-stats"])
+        .args(filter_args)
+        .arg(archive.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    dest.child("hello").assert("secret content");
+}
+
+#[test]
+fn backup_into_archive_with_trained_dictionary() {
+    let temp = TempDir::new().unwrap();
+    let archive = temp.child("archive");
+    let src = TreeFixture::new();
+    for i in 0..200 {
+        src.create_file_with_contents(
+            &format!("file{i}.rs"),
+            format!("// Source file {i}\nfn function{i}() -> usize {{\n    {i} * 2\n}}\n")
+                .as_bytes(),
+        );
+    }
+
+    run_conserve()
+        .args(["init", "--train-dictionary"])
+        .arg(src.path())
+        .arg(archive.path())
+        .assert()
+        .success();
+    archive
+        .child("DICTIONARY")
+        .assert(predicates::path::is_file());
+    run_conserve()
+        .args(["backup", "--no-stats"])
+        .arg(archive.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    let dest = temp.child("dest");
+    run_conserve()
+        .args(["restore", "--no-stats"])
+        .arg(archive.path())
+        .arg(dest.path())
+        .assert()
+        .success();
+    dest.child("file17.rs")
+        .assert("// Source file 17\nfn function17() -> usize {\n    17 * 2\n}\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn backup_writes_events_to_unix_socket() {
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    let temp = TempDir::new().unwrap();
+    let socket_path = temp.path().join("events.sock");
+    let listener = UnixListener::bind(&socket_path).unwrap();
+    let reader = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut events = String::new();
+        stream.read_to_string(&mut events).unwrap();
+        events
+    });
+
+    run_conserve()
+        .args(["backup", "--no-stats", "--events-socket"])
+        .arg(&socket_path)
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    let events: Vec<serde_json::Value> = reader
+        .join()
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    println!("{events:#?}");
+    assert!(events
+        .iter()
+        .any(|event| event["event"] == "task_start" && event["name"] == "Backup"));
+    assert!(events.iter().any(|event| event["event"] == "task_finish"));
+    assert!(events.iter().any(|event| event["event"] == "counter"
+        && event["counter"] == "Files"
+        && event["value"] == 1));
+}
+
+#[test]
+fn backup_refuses_locked_archive_until_lock_is_broken() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("a");
+    std::fs::write(
+        af.path().join("LOCK"),
+        br#"{"pid":12345,"hostname":"elsewhere","start_time":1700000000}"#,
+    )
+    .unwrap();
+
+    run_conserve()
+        .args(["backup", "--no-stats"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "Archive is locked by process 12345 on elsewhere since 2023-11-14T22:13:20Z",
+        ));
+    assert!(!af.path().join("b0000").exists());
+
+    run_conserve()
+        .args(["backup", "--no-stats", "--break-lock"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    assert!(af.path().join("b0000").exists());
+    assert!(!af.path().join("LOCK").exists());
+}
+
+#[test]
+fn backup_stdin_paths() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_dir("subdir");
+    src.create_file("subdir/a");
+    src.create_file("subdir/b");
+    src.create_file("c");
+
+    let mut cmd = run_conserve();
+    cmd.args(["backup", "--no-stats", "--stdin-paths"])
+        .arg(af.path())
+        .arg(src.path());
+    Command::from_std(cmd)
+        .write_stdin("subdir/b\0c\0")
+        .assert()
+        .success();
+
+    run_conserve()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/c\n/subdir\n/subdir/b\n");
+}
+
+#[test]
+fn backup_stdin_paths_conflicts_with_excludes_from_stdin() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    run_conserve()
+        .args(["backup", "--stdin-paths", "--exclude-from", "-"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .failure()
+        .code(2)
+        .stderr(predicates::str::contains(
+            "'--stdin-paths' cannot be used with '--exclude-from -'",
+        ));
+    assert_eq!(af.list_band_ids().unwrap(), []);
+}
+
+#[cfg(unix)]
+#[test]
+fn backup_stdin_paths_skips_non_utf8_path() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("c");
+
+    let mut cmd = run_conserve();
+    cmd.args(["backup", "--no-stats", "--stdin-paths"])
+        .arg(af.path())
+        .arg(src.path());
+    Command::from_std(cmd)
+        .write_stdin(&b"caf\xe9\nc\n"[..])
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("caf\\xE9\" is not valid UTF-8"));
+
+    run_conserve()
+        .arg("ls")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/c\n");
+}
+
+#[test]
+fn backup_paths_from_backup() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_dir("subdir");
+    src.create_file_with_contents("subdir/a", b"old a");
+    src.create_file("subdir/gone");
+    src.create_file("unwanted");
+    let mut cmd = run_conserve();
+    cmd.args(["backup", "--no-stats", "--stdin-paths"])
+        .arg(af.path())
+        .arg(src.path());
+    Command::from_std(cmd)
+        .write_stdin("subdir/a\nsubdir/gone\n")
+        .assert()
+        .success();
+
+    src.create_file_with_contents("subdir/a", b"new a");
+    std::fs::remove_file(src.path().join("subdir/gone")).unwrap();
+    src.create_file("subdir/new");
+    run_conserve()
+        .args(["backup", "--no-stats", "--paths-from-backup", "b0"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("\"subdir/gone\" can't be read"));
+
+    run_conserve()
+        .args(["ls", "--backup", "b1"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/subdir\n/subdir/a\n");
+    let restore_dir = TempDir::new().unwrap();
+    run_conserve()
+        .arg("restore")
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .success();
+    restore_dir.child("subdir/a").assert("new a");
+}
+
+#[test]
+fn backup_progress_json_writes_snapshots_without_progress_bars() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("a");
+    src.create_file("b");
+    let output = run_conserve()
+        .args(["backup", "--no-stats", "--progress-json"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+    println!("{stderr}");
+    assert!(
+        !stderr.contains('\x1b'),
+        "no escape sequences for progress bars"
+    );
+    let snapshots: Vec<serde_json::Value> = stderr
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .map(|line| serde_json::from_str(line).expect("snapshot is valid json"))
+        .collect();
+    assert!(!snapshots.is_empty());
+    assert!(snapshots
+        .iter()
+        .all(|snapshot| snapshot["event"] == "progress"));
+    let last = snapshots.last().unwrap();
+    assert_eq!(last["counters"]["Files"], 2);
+}
@@ -0,0 +1,118 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve changed`.
+
+use assert_cmd::prelude::*;
+use filetime::{set_file_mtime, FileTime};
+use indoc::indoc;
+use predicates::prelude::*;
+use serde_json::Value;
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+
+use crate::run_conserve;
+
+fn backup(af: &ScratchArchive, tf: &TreeFixture) {
+    // Keep the root directory's mtime fixed so that it's not reported as changed.
+    set_file_mtime(tf.path(), FileTime::from_unix_time(1_700_000_000, 0)).unwrap();
+    run_conserve()
+        .arg("backup")
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+}
+
+/// Make an archive with two backups, between which one file was added, one changed,
+/// and one deleted.
+fn setup() -> ScratchArchive {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file_with_contents("changed", b"old content");
+    tf.create_file_with_contents("deleted", b"going away");
+    tf.create_file_with_contents("unchanged", b"same");
+    backup(&af, &tf);
+    tf.create_file_with_contents("changed", b"new and longer content");
+    std::fs::remove_file(tf.path().join("deleted")).unwrap();
+    tf.create_file_with_contents("added", b"new file");
+    backup(&af, &tf);
+    af
+}
+
+#[test]
+fn changed_since_first_backup() {
+    let af = setup();
+    run_conserve()
+        .args(["changed", "--since", "b0"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(indoc! {"
+            + /added
+            * /changed
+            - /deleted
+        "})
+        .stderr(predicate::str::is_empty());
+}
+
+#[test]
+fn changed_json_has_kind_of_change() {
+    let af = setup();
+    let output = run_conserve()
+        .args(["changed", "--json", "--since", "b0", "--backup", "b1"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let changes: Vec<(String, String)> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let change: Value = serde_json::from_str(line).unwrap();
+            (
+                change["apath"].as_str().unwrap().to_owned(),
+                change["change"].as_str().unwrap().to_owned(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        changes,
+        [
+            ("/added".to_owned(), "Added".to_owned()),
+            ("/changed".to_owned(), "Changed".to_owned()),
+            ("/deleted".to_owned(), "Deleted".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn changed_since_same_backup_is_empty() {
+    let af = setup();
+    run_conserve()
+        .args(["changed", "--since", "b1"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::is_empty());
+}
+
+#[test]
+fn changed_since_missing_backup_fails() {
+    let af = setup();
+    run_conserve()
+        .args(["changed", "--since", "b7"])
+        .arg(af.path())
+        .assert()
+        .failure();
+}
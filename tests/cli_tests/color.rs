@@ -0,0 +1,72 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for coloring of help and messages.
+
+use assert_cmd::prelude::*;
+use assert_fs::TempDir;
+use predicates::prelude::*;
+
+use crate::run_conserve;
+
+const ESCAPE: &str = "\x1b[";
+
+#[test]
+fn no_color_env_disables_escapes_even_if_color_is_forced() {
+    let temp_dir = TempDir::new().unwrap();
+    run_conserve()
+        .env("NO_COLOR", "1")
+        .env("CLICOLOR_FORCE", "1")
+        .arg("--help")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Usage:"))
+        .stdout(predicate::str::contains(ESCAPE).not());
+    run_conserve()
+        .env("NO_COLOR", "1")
+        .env("CLICOLOR_FORCE", "1")
+        .arg("versions")
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Not a Conserve archive"))
+        .stderr(predicate::str::contains(ESCAPE).not());
+}
+
+#[test]
+fn color_always_forces_escapes_when_not_a_terminal() {
+    let temp_dir = TempDir::new().unwrap();
+    run_conserve()
+        .env_remove("NO_COLOR")
+        .args(["--color", "always", "--help"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(ESCAPE));
+    run_conserve()
+        .env("NO_COLOR", "1")
+        .args(["--color=always", "versions"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(ESCAPE));
+}
+
+#[test]
+fn color_never_disables_escapes() {
+    run_conserve()
+        .env("CLICOLOR_FORCE", "1")
+        .args(["--color", "never", "--help"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(ESCAPE).not());
+}
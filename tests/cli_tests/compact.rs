@@ -0,0 +1,44 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve compact`.
+
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+
+use conserve::test_fixtures::ScratchArchive;
+
+use crate::run_conserve;
+
+#[test]
+fn compact_all_then_validate() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    run_conserve()
+        .args(["compact", "--all"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("bands rewritten"));
+    run_conserve()
+        .arg("validate")
+        .arg(af.path())
+        .assert()
+        .success();
+    run_conserve()
+        .args(["ls"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("/subdir/subfile"));
+}
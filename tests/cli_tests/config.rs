@@ -0,0 +1,55 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve config`.
+
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+
+use conserve::test_fixtures::ScratchArchive;
+
+use crate::run_conserve;
+
+#[test]
+fn config_set_then_get() {
+    let af = ScratchArchive::new();
+    run_conserve()
+        .args(["config", "set"])
+        .arg(af.path())
+        .args(["managed-by", "backup team"])
+        .assert()
+        .success()
+        .stdout("");
+    run_conserve()
+        .args(["config", "get"])
+        .arg(af.path())
+        .arg("managed-by")
+        .assert()
+        .success()
+        .stdout("backup team\n");
+}
+
+#[test]
+fn config_get_absent_key_fails() {
+    let af = ScratchArchive::new();
+    run_conserve()
+        .args(["config", "get"])
+        .arg(af.path())
+        .arg("policy")
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(predicate::str::contains(
+            "No value is set for \"policy\" in the archive metadata",
+        ));
+}
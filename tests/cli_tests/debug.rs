@@ -0,0 +1,340 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for the `conserve debug` CLI.
+
+use std::collections::HashSet;
+
+use assert_cmd::prelude::*;
+use assert_fs::prelude::*;
+use assert_fs::TempDir;
+use predicates::prelude::*;
+use rayon::prelude::ParallelIterator;
+use serde_json::Value;
+
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::{backup, BackupOptions, BlockHash};
+
+use crate::run_conserve;
+
+/// Back up a single small file and return the archive directory and the hash of its block.
+fn archive_with_one_block() -> (TempDir, String) {
+    let src = TempDir::new().unwrap();
+    src.child("hello").write_str("hello world\n").unwrap();
+    let archive = TempDir::new().unwrap();
+    let arch_dir = archive.child("a");
+    run_conserve()
+        .arg("init")
+        .arg(arch_dir.path())
+        .assert()
+        .success();
+    run_conserve()
+        .arg("backup")
+        .arg(arch_dir.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    let output = run_conserve()
+        .args(["debug", "blocks"])
+        .arg(arch_dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let hash = String::from_utf8(output.stdout).unwrap().trim().to_owned();
+    assert_eq!(hash.len(), 128, "expected one block hash: {hash:?}");
+    (archive, hash)
+}
+
+#[test]
+fn block_info_for_valid_block() {
+    let (archive, hash) = archive_with_one_block();
+    let arch_dir = archive.child("a");
+    run_conserve()
+        .args(["debug", "block-info"])
+        .arg(arch_dir.path())
+        .arg(&hash)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!("hash: {hash}\n")))
+        .stdout(predicate::str::contains("uncompressed size: 12 bytes\n"))
+        .stdout(predicate::str::contains("hash matches content: yes\n"))
+        .stdout(predicate::str::contains("referenced by bands: b0000\n"))
+        .stdout(predicate::str::contains("referenced by:\n  b0000 /hello\n"));
+
+    let output = run_conserve()
+        .args(["debug", "block-info", "--json"])
+        .arg(arch_dir.path())
+        .arg(&hash)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let json: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["hash"], hash.as_str());
+    assert_eq!(json["uncompressed_len"], 12);
+    assert_eq!(json["hash_matches"], true);
+    assert_eq!(
+        json["references"],
+        serde_json::json!([{"band_id": "b0000", "apath": "/hello"}])
+    );
+    assert_eq!(json["bands"], serde_json::json!(["b0000"]));
+}
+
+#[test]
+fn block_info_reports_hash_mismatch_for_corrupt_block() {
+    let (archive, hash) = archive_with_one_block();
+    let arch_dir = archive.child("a");
+    let compressed = snap::raw::Encoder::new()
+        .compress_vec(b"something else\n")
+        .unwrap();
+    arch_dir
+        .child("d")
+        .child(&hash[..3])
+        .child(&hash)
+        .write_binary(&compressed)
+        .unwrap();
+    run_conserve()
+        .args(["debug", "block-info"])
+        .arg(arch_dir.path())
+        .arg(&hash)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("uncompressed size: 15 bytes\n"))
+        .stdout(predicate::str::contains("hash matches content: NO\n"));
+
+    let output = run_conserve()
+        .args(["debug", "block-info", "--json"])
+        .arg(arch_dir.path())
+        .arg(&hash)
+        .output()
+        .unwrap();
+    let json: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["hash_matches"], false);
+}
+
+#[test]
+fn block_info_for_missing_block_fails() {
+    let (archive, hash) = archive_with_one_block();
+    let arch_dir = archive.child("a");
+    std::fs::remove_file(arch_dir.child("d").child(&hash[..3]).child(&hash).path()).unwrap();
+    run_conserve()
+        .args(["debug", "block-info"])
+        .arg(arch_dir.path())
+        .arg(&hash)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn block_info_accepts_unique_hash_prefix() {
+    let (archive, hash) = archive_with_one_block();
+    let arch_dir = archive.child("a");
+    run_conserve()
+        .args(["debug", "block-info"])
+        .arg(arch_dir.path())
+        .arg(&hash[..8])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!("hash: {hash}\n")));
+}
+
+#[test]
+fn block_info_rejects_ambiguous_hash_prefix() {
+    let (archive, hash) = archive_with_one_block();
+    let arch_dir = archive.child("a");
+    let other = format!("{}{}", &hash[..8], "0".repeat(120));
+    arch_dir
+        .child("d")
+        .child(&hash[..3])
+        .child(&other)
+        .write_binary(b"")
+        .unwrap();
+    run_conserve()
+        .args(["debug", "block-info"])
+        .arg(arch_dir.path())
+        .arg(&hash[..8])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("is ambiguous"))
+        .stderr(predicate::str::contains(&hash))
+        .stderr(predicate::str::contains(&other));
+}
+
+/// Back up three files into an archive with one entry per index hunk.
+fn archive_with_three_hunks() -> ScratchArchive {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file("a");
+    tf.create_file("b");
+    let options = BackupOptions {
+        max_entries_per_hunk: 1,
+        ..Default::default()
+    };
+    backup(&af, tf.path(), &options, TestMonitor::arc()).unwrap();
+    af
+}
+
+#[test]
+fn debug_index_one_hunk() {
+    let af = archive_with_three_hunks();
+    let output = run_conserve()
+        .args(["debug", "index", "--hunk", "1", "--json-format", "ndjson"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let apaths: Vec<Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["apath"].clone())
+        .collect();
+    assert_eq!(apaths, ["/a"]);
+
+    let output = run_conserve()
+        .args([
+            "debug",
+            "index",
+            "--hunk",
+            "1..3",
+            "--json-format",
+            "ndjson",
+        ])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap().lines().count(), 2);
+}
+
+#[test]
+fn debug_index_out_of_range_hunk_fails() {
+    let af = archive_with_three_hunks();
+    run_conserve()
+        .args(["debug", "index", "--hunk", "2..5"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(predicate::str::contains(
+            "Index hunk 3 not found in b0000, which has 3 hunks",
+        ));
+}
+
+#[test]
+fn block_diff_counts_blocks_unique_to_each_archive() {
+    // Each file is stored in its own block, so identical files share blocks.
+    let options = BackupOptions {
+        small_file_cap: 0,
+        ..Default::default()
+    };
+    let back_up = |contents: &[&str]| {
+        let af = ScratchArchive::new();
+        let src = TreeFixture::new();
+        for (i, content) in contents.iter().enumerate() {
+            src.create_file_with_contents(&format!("file{i}"), content.as_bytes());
+        }
+        backup(&af, src.path(), &options, TestMonitor::arc()).unwrap();
+        af
+    };
+    let first = back_up(&["only first", "shared one", "shared two"]);
+    let second = back_up(&["shared one", "shared two", "only second", "also second"]);
+
+    run_conserve()
+        .args(["debug", "block-diff"])
+        .arg(first.path())
+        .arg(second.path())
+        .assert()
+        .success()
+        .stdout(
+            "only in first archive: 1 blocks\n\
+            only in second archive: 2 blocks\n\
+            in both: 2 blocks\n",
+        );
+
+    let output = run_conserve()
+        .args(["debug", "block-diff", "--json"])
+        .arg(first.path())
+        .arg(second.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let json: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({"only_in_first": 1, "only_in_second": 2, "in_both": 2})
+    );
+    // Together they cover every distinct block.
+    let union: HashSet<BlockHash> = [&first, &second]
+        .into_iter()
+        .flat_map(|af| {
+            af.block_dir()
+                .blocks(TestMonitor::arc())
+                .unwrap()
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    assert_eq!(1 + 2 + 2, union.len());
+}
+
+#[test]
+fn verify_block_reports_ok_for_valid_block() {
+    let (archive, hash) = archive_with_one_block();
+    let arch_dir = archive.child("a");
+    run_conserve()
+        .args(["debug", "verify-block"])
+        .arg(arch_dir.path())
+        .arg(&hash)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!("hash: {hash}\n")))
+        .stdout(predicate::str::contains("uncompressed size: 12 bytes\n"))
+        .stdout(predicate::str::ends_with("OK\n"));
+}
+
+#[test]
+fn verify_block_reports_hash_mismatch_for_corrupt_block() {
+    let (archive, hash) = archive_with_one_block();
+    let arch_dir = archive.child("a");
+    let compressed = snap::raw::Encoder::new()
+        .compress_vec(b"something else\n")
+        .unwrap();
+    arch_dir
+        .child("d")
+        .child(&hash[..3])
+        .child(&hash)
+        .write_binary(&compressed)
+        .unwrap();
+    let actual = BlockHash::hash_bytes(b"something else\n");
+    run_conserve()
+        .args(["debug", "verify-block"])
+        .arg(arch_dir.path())
+        .arg(&hash)
+        .assert()
+        .code(2)
+        .stdout(predicate::str::contains("uncompressed size: 15 bytes\n"))
+        .stdout(predicate::str::contains(format!(
+            "MISMATCH: expected hash {hash}, actual hash {actual}\n"
+        )));
+
+    let output = run_conserve()
+        .args(["debug", "verify-block", "--json"])
+        .arg(arch_dir.path())
+        .arg(&hash)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let json: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["hash"], hash.as_str());
+    assert_eq!(json["actual_hash"], actual.to_string());
+    assert_eq!(json["compressed_len"], compressed.len());
+}
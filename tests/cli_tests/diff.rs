@@ -196,3 +196,49 @@ fn change_file_content() {
             "})
         .stderr(predicate::str::is_empty());
 }
+
+#[test]
+fn swapped_arguments_are_explained() {
+    let (af, tf) = setup();
+    run_conserve()
+        .arg("diff")
+        .arg(tf.path())
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(predicate::str::contains("is not a Conserve archive, but"))
+        .stderr(predicate::str::contains(
+            "give the archive first, then the source directory",
+        ));
+}
+
+#[test]
+fn two_directories_are_explained() {
+    let (_af, tf) = setup();
+    let other = TreeFixture::new();
+    run_conserve()
+        .arg("diff")
+        .arg(other.path())
+        .arg(tf.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "is not a Conserve archive: diff compares an archive to a source directory",
+        ));
+}
+
+#[test]
+fn two_archives_are_explained() {
+    let (af, _tf) = setup();
+    let other = ScratchArchive::new();
+    run_conserve()
+        .arg("diff")
+        .arg(af.path())
+        .arg(other.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "is a Conserve archive, not a source directory",
+        ));
+}
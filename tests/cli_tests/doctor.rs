@@ -0,0 +1,77 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for the `conserve doctor` CLI.
+
+use std::fs::{create_dir, write};
+
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+
+use conserve::test_fixtures::ScratchArchive;
+
+use crate::run_conserve;
+
+#[test]
+fn doctor_finds_no_problems_in_healthy_archive() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    run_conserve()
+        .arg("doctor")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("No problems found.\n");
+}
+
+#[test]
+fn doctor_advises_about_stale_gc_lock_and_incomplete_band() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    af.setup_incomplete_empty_band();
+    write(af.path().join("GC_LOCK"), b"{}\n").unwrap();
+    run_conserve()
+        .arg("doctor")
+        .arg(af.path())
+        .assert()
+        .code(2)
+        .stdout(predicate::str::contains(
+            "Archive is locked for garbage collection.\n",
+        ))
+        .stdout(predicate::str::contains("conserve gc --break-lock"))
+        .stdout(predicate::str::contains(
+            "The last backup, b0002, is incomplete.\n",
+        ))
+        .stdout(predicate::str::contains("conserve restore --backup b0002"));
+}
+
+#[test]
+fn doctor_finds_stray_files_in_block_dir() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let block_dir = af.path().join("d");
+    create_dir(block_dir.join("abc")).unwrap();
+    write(block_dir.join("abc").join("tmp123456"), b"partial").unwrap();
+    create_dir(block_dir.join("junk")).unwrap();
+    run_conserve()
+        .arg("doctor")
+        .arg(af.path())
+        .assert()
+        .code(2)
+        .stdout(predicate::str::contains(
+            "1 temporary files in the block directory: abc/tmp123456.\n",
+        ))
+        .stdout(predicate::str::contains(
+            "1 unexpected files in the block directory: junk.\n",
+        ));
+}
@@ -12,6 +12,7 @@
 // GNU General Public License for more details.
 
 use assert_cmd::prelude::*;
+use assert_cmd::Command;
 use assert_fs::prelude::*;
 use assert_fs::TempDir;
 use indoc::indoc;
@@ -149,6 +150,54 @@ fn exclude_from_file() {
         .success();
 }
 
+#[test]
+fn exclude_from_stdin_matches_exclude_from_file() {
+    let patterns = "#some exclusions\n  *.tmp \n/target\n";
+    let src = TreeFixture::new();
+    src.create_dir("src");
+    src.create_file("src/hello.rs");
+    src.create_dir("junk.tmp");
+    src.create_dir("target");
+    src.create_file("thing~");
+    let exclude_file = TempDir::new().unwrap();
+    exclude_file.child("exclude").write_str(patterns).unwrap();
+
+    let ls_from_file = run_conserve()
+        .args(["ls", "--exclude-from"])
+        .arg(exclude_file.child("exclude").path())
+        .args(["--exclude=*~"])
+        .arg("--source")
+        .arg(src.path())
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let mut from_stdin = run_conserve();
+    from_stdin
+        .args(["ls", "--exclude-from", "-", "--exclude=*~"])
+        .arg("--source")
+        .arg(src.path());
+    Command::from_std(from_stdin)
+        .write_stdin(patterns)
+        .assert()
+        .success()
+        .stdout(String::from_utf8(ls_from_file).unwrap());
+
+    let mut twice = run_conserve();
+    twice
+        .args(["ls", "--exclude-from", "-", "--exclude-from", "-"])
+        .arg("--source")
+        .arg(src.path());
+    Command::from_std(twice)
+        .write_stdin(patterns)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Exclude patterns can be read from stdin only once",
+        ));
+}
+
 /// `--exclude /subtree` should also exclude everything under it.
 ///
 /// <https://github.com/sourcefrog/conserve/issues/160>
@@ -170,6 +219,39 @@ fn ls_exclude_excludes_subtrees() {
         .stderr("");
 }
 
+#[test]
+fn ls_only_subtree_with_relative_excludes() {
+    run_conserve()
+        .args([
+            "ls",
+            "--only",
+            "/subdir",
+            "--relative-excludes",
+            "--exclude",
+            "/subfile",
+            "testdata/archive/simple/v0.6.10",
+        ])
+        .assert()
+        .success()
+        .stdout("/subdir\n")
+        .stderr("");
+}
+
+#[test]
+fn relative_excludes_requires_only() {
+    run_conserve()
+        .args([
+            "ls",
+            "--relative-excludes",
+            "--exclude",
+            "/subfile",
+            "testdata/archive/simple/v0.6.10",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--only"));
+}
+
 /// `--exclude /subtree` should also exclude everything under it.
 ///
 /// <https://github.com/sourcefrog/conserve/issues/160>
@@ -195,3 +277,147 @@ fn restore_exclude_excludes_subtrees() {
         .stderr("");
     dest.child("subdir").assert(predicate::path::missing());
 }
+
+#[test]
+fn archive_excludes_set_at_init_and_by_set_excludes() {
+    let testdir = TempDir::new().unwrap();
+    let arch_dir = testdir.path().join("a");
+    run_conserve()
+        .args(["init", "--exclude", "*.tmp"])
+        .arg(&arch_dir)
+        .assert()
+        .success();
+    run_conserve()
+        .arg("set-excludes")
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stdout("*.tmp\n");
+
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_file("junk.tmp");
+    src.create_file("hello.o");
+    run_conserve()
+        .args(["backup", "-v", "--no-stats", "--exclude", "*.o"])
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout("+ /hello\n");
+
+    run_conserve()
+        .args(["set-excludes", "-e", "/hello"])
+        .arg(&arch_dir)
+        .assert()
+        .success();
+    run_conserve()
+        .arg("set-excludes")
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stdout("/hello\n");
+    run_conserve()
+        .args(["backup", "-v", "--no-stats", "--no-archive-excludes"])
+        .arg(&arch_dir)
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout("+ /hello.o\n+ /junk.tmp\n");
+
+    run_conserve()
+        .args(["set-excludes", "--clear"])
+        .arg(&arch_dir)
+        .assert()
+        .success();
+    run_conserve()
+        .arg("set-excludes")
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stdout("");
+}
+
+#[test]
+fn include_only_rust_files() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("README.md");
+    src.create_dir("src");
+    src.create_file("src/lib.rs");
+    src.create_file("src/lib.o");
+    src.create_dir("src/bin");
+    src.create_file("src/bin/main.rs");
+    src.create_dir("target");
+    src.create_file("target/generated.rs");
+
+    run_conserve()
+        .args(["backup", "--no-stats", "--include", "/**/*.rs"])
+        .args(["--exclude", "/target"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .args(["ls"])
+        .arg(af.path())
+        .assert()
+        .stdout("/\n/src\n/src/bin\n/src/lib.rs\n/src/bin/main.rs\n")
+        .success();
+}
+
+#[test]
+fn diff_applies_archive_excludes() {
+    let af = ScratchArchive::new();
+    run_conserve()
+        .args(["set-excludes", "-e", "*.tmp"])
+        .arg(af.path())
+        .assert()
+        .success();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_file("junk.tmp");
+    run_conserve()
+        .args(["backup", "--no-stats"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .arg("diff")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout("");
+    run_conserve()
+        .args(["diff", "--no-archive-excludes"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stdout("+ /junk.tmp\n");
+}
+
+#[test]
+fn set_excludes_reads_stdin_only_once() {
+    let af = ScratchArchive::new();
+    let mut cmd = run_conserve();
+    cmd.args(["set-excludes", "-E", "-", "-E", "-"])
+        .arg(af.path());
+    Command::from_std(cmd)
+        .write_stdin("*.tmp\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Exclude patterns can be read from stdin only once",
+        ));
+    run_conserve()
+        .arg("set-excludes")
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("");
+}
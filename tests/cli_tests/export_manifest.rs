@@ -0,0 +1,82 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve export-manifest`.
+
+use std::fs;
+
+use assert_cmd::prelude::*;
+use assert_fs::TempDir;
+use blake2_rfc::blake2b::blake2b;
+use serde_json::{Deserializer, Value};
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+
+use crate::run_conserve;
+
+#[test]
+fn manifest_hashes_match_restored_files() {
+    let tf = TreeFixture::new();
+    tf.create_file_with_contents("hello", b"hello world\n");
+    tf.create_file_with_contents("empty", b"");
+    tf.create_dir("subdir");
+    tf.create_file_of_length_with_prefix("subdir/big", 21 << 20, b"big");
+    let af = ScratchArchive::new();
+    run_conserve()
+        .args(["backup", "--no-stats"])
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    let restore_dir = TempDir::new().unwrap();
+    let restored = restore_dir.path().join("restored");
+    run_conserve()
+        .args(["restore", "--no-stats"])
+        .arg(af.path())
+        .arg(&restored)
+        .assert()
+        .success();
+
+    let output = run_conserve()
+        .arg("export-manifest")
+        .arg(af.path())
+        .assert()
+        .success();
+    let manifest = String::from_utf8(output.get_output().stdout.clone()).unwrap();
+    let mut paths = Vec::new();
+    for line in manifest.lines() {
+        let (hash, path) = line.split_once("  ").unwrap();
+        let content = fs::read(restored.join(path)).unwrap();
+        let expected = hex::encode(blake2b(64, &[], &content).as_bytes());
+        assert_eq!(hash, expected, "hash of {path}");
+        paths.push(path);
+    }
+    assert_eq!(paths, ["empty", "hello", "subdir/big"]);
+
+    let output = run_conserve()
+        .args(["export-manifest", "--json", "--only", "/subdir"])
+        .arg(af.path())
+        .assert()
+        .success();
+    let entries: Vec<Value> = Deserializer::from_slice(&output.get_output().stdout)
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["apath"], "/subdir/big");
+    assert_eq!(entries[0]["size"], 21 << 20);
+    assert_eq!(
+        entries[0]["blake2b"].as_str().unwrap(),
+        manifest.lines().last().unwrap().split_once("  ").unwrap().0
+    );
+}
@@ -0,0 +1,85 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve find`.
+
+use std::fs;
+
+use assert_cmd::prelude::*;
+use serde_json::{json, Deserializer, Value};
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+
+use crate::run_conserve;
+
+/// Back up a file, then rename its directory and back it up again.
+fn archive_with_renamed_dir() -> ScratchArchive {
+    let tf = TreeFixture::new();
+    tf.create_dir("old");
+    tf.create_file("old/report.txt");
+    tf.create_file("other");
+    let af = ScratchArchive::new();
+    let backup = || {
+        run_conserve()
+            .args(["backup", "--no-stats"])
+            .arg(af.path())
+            .arg(tf.path())
+            .assert()
+            .success();
+    };
+    backup();
+    fs::rename(tf.path().join("old"), tf.path().join("new")).unwrap();
+    backup();
+    af
+}
+
+#[test]
+fn find_reports_file_in_each_band_after_rename() {
+    let af = archive_with_renamed_dir();
+    run_conserve()
+        .arg("find")
+        .arg(af.path())
+        .arg("report.txt")
+        .assert()
+        .success()
+        .stdout("b0000 /old/report.txt\nb0001 /new/report.txt\n");
+    run_conserve()
+        .arg("find")
+        .arg(af.path())
+        .arg("/new*")
+        .assert()
+        .success()
+        .stdout("b0001 /new\nb0001 /new/report.txt\n");
+}
+
+#[test]
+fn find_json() {
+    let af = archive_with_renamed_dir();
+    let output = run_conserve()
+        .args(["find", "--json"])
+        .arg(af.path())
+        .arg("*.txt")
+        .assert()
+        .success();
+    let matches: Vec<Value> = Deserializer::from_slice(&output.get_output().stdout)
+        .into_iter::<Value>()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(
+        matches,
+        [
+            json!({"band_id": "b0000", "apath": "/old/report.txt", "kind": "File"}),
+            json!({"band_id": "b0001", "apath": "/new/report.txt", "kind": "File"}),
+        ]
+    );
+}
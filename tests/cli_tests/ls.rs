@@ -16,6 +16,7 @@
 use assert_cmd::prelude::*;
 use indoc::indoc;
 use pretty_assertions::assert_eq;
+use serde_json::Value;
 
 use crate::run_conserve;
 
@@ -35,3 +36,112 @@ fn ls_json() {
         "# }
     );
 }
+
+#[test]
+fn ls_json_formats_are_reparseable() {
+    let archive = "./testdata/archive/minimal/v0.6.17";
+    let expected_apaths = ["/", "/hello", "/subdir", "/subdir/subfile"];
+    let ls_stdout = |format: &str| {
+        let cmd = run_conserve()
+            .args(["ls", "--json-format", format, archive])
+            .assert()
+            .success();
+        String::from_utf8(cmd.get_output().stdout.clone()).unwrap()
+    };
+    let apaths = |values: &[Value]| {
+        values
+            .iter()
+            .map(|v| v["apath"].as_str().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    let ndjson = ls_stdout("ndjson");
+    assert_eq!(ndjson.lines().count(), 4);
+    let values = ndjson
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(apaths(&values), expected_apaths);
+
+    let pretty = ls_stdout("pretty");
+    assert!(pretty.starts_with("[\n  {\n"), "{pretty}");
+    let values: Vec<Value> = serde_json::from_str(&pretty).unwrap();
+    assert_eq!(apaths(&values), expected_apaths);
+
+    let array = ls_stdout("array");
+    assert_eq!(array.lines().count(), 1);
+    let values: Vec<Value> = serde_json::from_str(&array).unwrap();
+    assert_eq!(apaths(&values), expected_apaths);
+}
+
+#[cfg(unix)]
+#[test]
+fn ls_kind_symlink_lists_only_symlinks() {
+    use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+
+    let tf = TreeFixture::new();
+    tf.create_file("file");
+    tf.create_dir("subdir");
+    tf.create_file("subdir/subfile");
+    tf.create_symlink("link", "file");
+    tf.create_symlink("subdir/sublink", "subfile");
+
+    let af = ScratchArchive::new();
+    run_conserve()
+        .args(["backup", "--no-stats"])
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .args(["ls", "--kind", "symlink"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/link\n/subdir/sublink\n");
+
+    run_conserve()
+        .args(["ls", "--kind", "symlink", "--kind", "dir", "--source"])
+        .arg(tf.path())
+        .assert()
+        .success()
+        .stdout("/\n/link\n/subdir\n/subdir/sublink\n");
+}
+
+#[test]
+fn ls_stats_json_counts_index_hunks_read() {
+    use assert_fs::TempDir;
+    use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+
+    let tf = TreeFixture::new();
+    for name in ["a", "b", "c", "d", "e"] {
+        tf.create_file(name);
+    }
+    let af = ScratchArchive::new();
+    run_conserve()
+        .args(["backup", "--no-stats", "--entries-per-hunk", "2"])
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    let hunk_count = std::fs::read_dir(af.path().join("b0000/i/00000"))
+        .unwrap()
+        .count();
+    assert_eq!(hunk_count, 3);
+
+    let temp = TempDir::new().unwrap();
+    let stats_path = temp.path().join("stats.json");
+    run_conserve()
+        .args(["ls", "--stats", "--stats-json"])
+        .arg(&stats_path)
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/a\n/b\n/c\n/d\n/e\n")
+        .stderr(predicates::str::contains("index read stats"));
+    let stats: Value = serde_json::from_slice(&std::fs::read(&stats_path).unwrap()).unwrap();
+    assert_eq!(stats["index_hunks"], hunk_count);
+    assert_eq!(stats["errors"], 0);
+    assert!(stats["compressed_index_bytes"].as_u64().unwrap() > 0);
+}
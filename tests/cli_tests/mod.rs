@@ -14,10 +14,20 @@
 //! Run conserve CLI as a subprocess and test it.
 
 mod backup;
+mod changed;
+mod color;
+mod compact;
+mod config;
+mod debug;
 mod delete;
 mod diff;
+mod doctor;
 mod exclude;
+mod export_manifest;
+mod find;
 pub mod ls;
+mod restore;
+mod stats;
 mod trace;
 mod validate;
 mod versions;
@@ -25,5 +35,6 @@ mod versions;
 #[cfg(unix)]
 mod unix {
     mod diff;
+    mod owner;
     mod permissions;
 }
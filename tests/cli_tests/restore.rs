@@ -0,0 +1,207 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve restore` options.
+
+use assert_cmd::prelude::*;
+use assert_fs::TempDir;
+use filetime::{set_file_mtime, FileTime};
+use predicates::prelude::*;
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+
+use crate::run_conserve;
+
+#[test]
+fn restore_newer_than_selects_files_by_stored_mtime() {
+    let tf = TreeFixture::new();
+    tf.create_dir("subdir");
+    tf.create_file("subdir/old");
+    tf.create_file("subdir/new");
+    tf.create_file("new_top");
+    // 2020-01-01 and 2024-01-01.
+    set_file_mtime(
+        tf.path().join("subdir/old"),
+        FileTime::from_unix_time(1_577_836_800, 0),
+    )
+    .unwrap();
+    for name in ["subdir/new", "new_top"] {
+        set_file_mtime(
+            tf.path().join(name),
+            FileTime::from_unix_time(1_704_067_200, 0),
+        )
+        .unwrap();
+    }
+    let af = ScratchArchive::new();
+    run_conserve()
+        .args(["backup", "--no-stats"])
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+
+    let restore_dir = TempDir::new().unwrap();
+    run_conserve()
+        .args(["restore", "--no-stats", "--newer-than", "2022-06-01"])
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .success();
+    assert!(restore_dir.path().join("subdir/new").is_file());
+    assert!(restore_dir.path().join("new_top").is_file());
+    assert!(!restore_dir.path().join("subdir/old").exists());
+
+    let restore_dir = TempDir::new().unwrap();
+    run_conserve()
+        .args([
+            "restore",
+            "--no-stats",
+            "--older-than",
+            "2022-06-01T00:00:00Z",
+        ])
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .success();
+    assert!(restore_dir.path().join("subdir/old").is_file());
+    assert!(!restore_dir.path().join("subdir/new").exists());
+    assert!(!restore_dir.path().join("new_top").exists());
+}
+
+#[test]
+fn restore_newer_than_rejects_unparseable_date() {
+    let af = ScratchArchive::new();
+    let restore_dir = TempDir::new().unwrap();
+    run_conserve()
+        .args(["restore", "--newer-than", "last tuesday"])
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("expected a date"));
+}
+
+#[test]
+fn restore_stats_conflicts_with_no_stats() {
+    let af = ScratchArchive::new();
+    let restore_dir = TempDir::new().unwrap();
+    run_conserve()
+        .args(["restore", "--stats", "--no-stats"])
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .failure()
+        .code(2)
+        .stderr(predicate::str::contains("cannot be used with"));
+}
+
+#[test]
+fn restore_metadata_only_conflicts_with_force_overwrite() {
+    let af = ScratchArchive::new();
+    let restore_dir = TempDir::new().unwrap();
+    run_conserve()
+        .args(["restore", "--metadata-only", "--force-overwrite"])
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .failure()
+        .code(2)
+        .stderr(predicate::str::contains("cannot be used with"));
+}
+
+#[test]
+fn error_json_reports_destination_not_empty_code() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let restore_dir = TempDir::new().unwrap();
+    std::fs::write(restore_dir.path().join("existing"), b"here already").unwrap();
+
+    let output = run_conserve()
+        .args(["restore", "--no-stats", "--error-json"])
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .failure()
+        .get_output()
+        .stderr
+        .clone();
+    let stderr = String::from_utf8(output).unwrap();
+    let error: serde_json::Value = serde_json::from_str(stderr.lines().last().unwrap()).unwrap();
+    assert_eq!(error["code"], "DESTINATION_NOT_EMPTY");
+    assert_eq!(error["message"], "Destination directory is not empty");
+}
+
+#[test]
+fn restore_limit_files_stops_after_that_many_files() {
+    let tf = TreeFixture::new();
+    tf.create_dir("subdir");
+    for name in ["subdir/a", "subdir/b", "subdir/c", "top"] {
+        tf.create_file(name);
+    }
+    let af = ScratchArchive::new();
+    run_conserve()
+        .args(["backup", "--no-stats"])
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+
+    let restore_dir = TempDir::new().unwrap();
+    run_conserve()
+        .args(["restore", "--no-stats", "--limit-files", "2"])
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Restore stopped at the limit, after 2 files",
+        ));
+    // Files in a directory come before its subdirectories.
+    assert!(restore_dir.path().join("top").is_file());
+    assert!(restore_dir.path().join("subdir/a").is_file());
+    assert!(!restore_dir.path().join("subdir/b").exists());
+    assert!(!restore_dir.path().join("subdir/c").exists());
+}
+
+#[test]
+fn restore_cas_export_writes_blocks_and_manifest() {
+    let tf = TreeFixture::new();
+    tf.create_file_with_contents("a", b"same content\n");
+    tf.create_file_with_contents("b", b"same content\n");
+    let af = ScratchArchive::new();
+    run_conserve()
+        .args(["backup", "--no-stats"])
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+
+    let export_dir = TempDir::new().unwrap();
+    run_conserve()
+        .args(["restore", "--no-stats"])
+        .arg(af.path())
+        .arg("--cas-export")
+        .arg(export_dir.path())
+        .assert()
+        .success();
+    assert!(!export_dir.path().join("a").exists());
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(export_dir.path().join("manifest.json")).unwrap())
+            .unwrap();
+    let files = manifest.as_array().unwrap();
+    assert_eq!(files.len(), 2);
+    for file in files {
+        let hash = file["addrs"][0]["hash"].as_str().unwrap();
+        assert!(export_dir.path().join(hash).is_file());
+    }
+}
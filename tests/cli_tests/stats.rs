@@ -0,0 +1,64 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve stats`.
+
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+use serde_json::Value;
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+
+use crate::run_conserve;
+
+#[test]
+fn stats_of_repeated_backups() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file_with_contents("a", &[b'x'; 10_000]);
+    for _ in 0..2 {
+        run_conserve()
+            .args(["backup", "--no-stats"])
+            .arg(af.path())
+            .arg(tf.path())
+            .assert()
+            .success();
+    }
+
+    run_conserve()
+        .args(["stats", "--bytes"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "bands: 2\nlogical size: 20000\nblocks: 1\n",
+        ))
+        .stdout(predicate::str::contains("dedup and compression factor: "));
+
+    let output = run_conserve()
+        .args(["stats", "--json"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let usage: Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(usage["logical_bytes"], 20_000);
+    let physical = usage["physical_bytes"].as_u64().unwrap();
+    assert!(physical < 20_000);
+    assert_eq!(
+        usage["factor"].as_f64().unwrap(),
+        20_000.0 / physical as f64
+    );
+}
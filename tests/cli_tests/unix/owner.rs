@@ -0,0 +1,164 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for restoring file ownership, run only on Unix.
+//!
+//! Only root can give files to other users, so when run as an ordinary user these
+//! only check that the options don't cause errors.
+
+use std::fs::{set_permissions, Permissions};
+use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+
+use assert_cmd::prelude::*;
+use assert_fs::prelude::*;
+use assert_fs::TempDir;
+use predicates::prelude::*;
+
+use crate::run_conserve;
+
+const NOBODY_UID: u32 = 65534;
+
+/// Make an archive containing one file, owned by `nobody` if we're root.
+fn archive_with_file_owned_by_nobody() -> TempDir {
+    let testdir = TempDir::new().unwrap();
+    let src = testdir.child("src");
+    src.create_dir_all().unwrap();
+    src.child("file").write_str("content").unwrap();
+    if is_root() {
+        chown(src.child("file").path(), Some(NOBODY_UID), None).unwrap();
+    }
+    run_conserve()
+        .arg("init")
+        .arg(testdir.child("archive").path())
+        .assert()
+        .success();
+    run_conserve()
+        .arg("backup")
+        .arg(testdir.child("archive").path())
+        .arg(src.path())
+        .assert()
+        .success();
+    testdir
+}
+
+fn is_root() -> bool {
+    nix::unistd::geteuid().is_root()
+}
+
+#[test]
+fn restore_with_owner_current_user() {
+    let testdir = archive_with_file_owned_by_nobody();
+    let dest = testdir.child("dest");
+    run_conserve()
+        .args(["restore", "--no-stats", "--owner-current-user"])
+        .arg(testdir.child("archive").path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty());
+    let metadata = dest.child("file").path().metadata().unwrap();
+    assert_eq!(metadata.uid(), nix::unistd::geteuid().as_raw());
+    assert_eq!(metadata.gid(), nix::unistd::getegid().as_raw());
+}
+
+#[test]
+fn restore_with_no_ownership_and_no_permissions() {
+    let testdir = TempDir::new().unwrap();
+    let src = testdir.child("src");
+    src.create_dir_all().unwrap();
+    src.child("file").write_str("content").unwrap();
+    set_permissions(src.child("file").path(), Permissions::from_mode(0o604)).unwrap();
+    if is_root() {
+        chown(src.child("file").path(), Some(NOBODY_UID), Some(NOBODY_UID)).unwrap();
+    }
+    run_conserve()
+        .arg("init")
+        .arg(testdir.child("archive").path())
+        .assert()
+        .success();
+    run_conserve()
+        .arg("backup")
+        .arg(testdir.child("archive").path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    let dest = testdir.child("dest");
+    run_conserve()
+        .args([
+            "restore",
+            "--no-stats",
+            "--no-ownership",
+            "--no-permissions",
+        ])
+        .arg(testdir.child("archive").path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty());
+    assert_eq!(
+        std::fs::read_to_string(dest.child("file").path()).unwrap(),
+        "content"
+    );
+    // The file gets the same mode as any new file, as limited by the umask.
+    let fresh = testdir.child("fresh");
+    fresh.write_str("").unwrap();
+    let default_mode = fresh.path().metadata().unwrap().mode();
+    let metadata = dest.child("file").path().metadata().unwrap();
+    assert_eq!(metadata.mode(), default_mode);
+    assert_eq!(metadata.uid(), nix::unistd::geteuid().as_raw());
+    assert_eq!(metadata.gid(), nix::unistd::getegid().as_raw());
+}
+
+#[test]
+fn restore_with_mapped_owner() {
+    let testdir = archive_with_file_owned_by_nobody();
+    let current_user = uzers::get_current_username()
+        .unwrap()
+        .into_string()
+        .unwrap();
+
+    if is_root() {
+        // Without a mapping the stored owner is restored.
+        let dest = testdir.child("unmapped");
+        run_conserve()
+            .args(["restore", "--no-stats"])
+            .arg(testdir.child("archive").path())
+            .arg(dest.path())
+            .assert()
+            .success();
+        let metadata = dest.child("file").path().metadata().unwrap();
+        assert_eq!(metadata.uid(), NOBODY_UID);
+    }
+
+    let dest = testdir.child("dest");
+    run_conserve()
+        .args(["restore", "--no-stats", "--map-owner"])
+        .arg(format!("nobody={current_user}"))
+        .arg(testdir.child("archive").path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty());
+    let metadata = dest.child("file").path().metadata().unwrap();
+    assert_eq!(metadata.uid(), nix::unistd::geteuid().as_raw());
+}
+
+#[test]
+fn map_owner_needs_old_and_new_names() {
+    run_conserve()
+        .args(["restore", "--map-owner", "root", "archive", "dest"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("expected OLD=NEW"));
+}
@@ -109,7 +109,7 @@ fn backup_unix_permissions() {
 
     // verify permissions are restored correctly
     run_conserve()
-        .args(["restore", "-v", "-l"])
+        .args(["restore", "-v", "-l", "--no-stats"])
         .arg(&arch_dir)
         .arg(&*restore_dir)
         .assert()
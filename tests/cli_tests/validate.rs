@@ -86,3 +86,94 @@ fn validate_non_fatal_problems_nonzero_result_and_json_log() {
         })
     );
 }
+
+#[test]
+fn validate_with_limited_threads() {
+    let temp = TempDir::new().unwrap();
+    run_conserve()
+        .arg("init")
+        .arg(temp.path())
+        .assert()
+        .success();
+    run_conserve()
+        .args(["validate", "--threads", "1"])
+        .arg(temp.path())
+        .assert()
+        .success();
+    run_conserve()
+        .arg("validate")
+        .arg(temp.path())
+        .env("CONSERVE_THREADS", "2")
+        .assert()
+        .success();
+    run_conserve()
+        .arg("validate")
+        .arg(temp.path())
+        .env("CONSERVE_THREADS", "0")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--threads"));
+}
+
+#[test]
+fn validate_json_reports_problems_in_damaged_archive() {
+    let output = run_conserve()
+        .args(["validate", "--json", "testdata/damaged/missing-block/"])
+        .assert()
+        .code(2);
+    let report: Value = serde_json::from_slice(&output.get_output().stdout).unwrap();
+    dbg!(&report);
+    let hash = "fec91c70284c72d0d4e3684788a90de9338a5b2f47f01fedbe203cafd68708718ae5672d10eca804a8121904047d40d1d6cf11e7a76419357a9469af41f22d01";
+    assert_eq!(
+        report,
+        json!({
+            "ok": false,
+            "problems": [{
+                "kind": "BlockMissing",
+                "message": format!("Referenced block {hash} is missing"),
+                "hash": hash,
+            }],
+            "counts": {"BlockMissing": 1},
+        })
+    );
+}
+
+#[test]
+fn validate_json_reports_ok_for_clean_archive() {
+    let temp = TempDir::new().unwrap();
+    run_conserve()
+        .arg("init")
+        .arg(temp.path())
+        .assert()
+        .success();
+    let output = run_conserve()
+        .args(["validate", "--json"])
+        .arg(temp.path())
+        .assert()
+        .success();
+    let report: Value = serde_json::from_slice(&output.get_output().stdout).unwrap();
+    assert_eq!(report, json!({"ok": true, "problems": [], "counts": {}}));
+}
+
+#[test]
+fn validate_rejects_invalid_bands_selection() {
+    let temp = TempDir::new().unwrap();
+    run_conserve()
+        .args(["init"])
+        .arg(temp.path())
+        .assert()
+        .success();
+    run_conserve()
+        .args(["validate", "--bands", "last3"])
+        .arg(temp.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "expected a range like b5..b8, or latest:N",
+        ));
+    run_conserve()
+        .args(["validate", "--bands", "latest:3"])
+        .arg(temp.path())
+        .assert()
+        .success();
+}
@@ -14,17 +14,30 @@
 //! Tests of the `conserve versions` command.
 
 use assert_cmd::prelude::*;
-use conserve::test_fixtures::ScratchArchive;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
 use indoc::indoc;
 use predicates::function::function;
 use predicates::prelude::*;
+use tempfile::TempDir;
 
 use crate::run_conserve;
 
+/// Copy the old archive used by these tests, so that commands run on it can't change
+/// the checked-in testdata.
+fn copy_simple_archive() -> TempDir {
+    let temp = TempDir::new().unwrap();
+    cp_r::CopyOptions::new()
+        .copy_tree("testdata/archive/simple/v0.6.10", temp.path())
+        .unwrap();
+    temp
+}
+
 #[test]
 fn utc() {
+    let archive = copy_simple_archive();
     run_conserve()
-        .args(["versions", "--utc", "testdata/archive/simple/v0.6.10"])
+        .args(["versions", "--utc"])
+        .arg(archive.path())
         .assert()
         .success()
         .stdout(indoc! { "
@@ -36,13 +49,10 @@ fn utc() {
 
 #[test]
 fn newest_first() {
+    let archive = copy_simple_archive();
     run_conserve()
-        .args([
-            "versions",
-            "--newest",
-            "--utc",
-            "testdata/archive/simple/v0.6.10",
-        ])
+        .args(["versions", "--newest", "--utc"])
+        .arg(archive.path())
         .assert()
         .success()
         .stderr(predicate::str::is_empty())
@@ -57,8 +67,10 @@ fn newest_first() {
 fn local_time() {
     // Without --utc we don't know exactly what times will be produced,
     // and it's hard to control the timezone for tests on Windows.
+    let archive = copy_simple_archive();
     run_conserve()
-        .args(["versions", "testdata/archive/simple/v0.6.10"])
+        .arg("versions")
+        .arg(archive.path())
         .assert()
         .success()
         .stdout(function(|s: &str| s.lines().count() == 3));
@@ -66,8 +78,10 @@ fn local_time() {
 
 #[test]
 fn short() {
+    let archive = copy_simple_archive();
     run_conserve()
-        .args(["versions", "--short", "testdata/archive/simple/v0.6.10"])
+        .args(["versions", "--short"])
+        .arg(archive.path())
         .assert()
         .success()
         .stdout(
@@ -81,13 +95,10 @@ b0002
 
 #[test]
 fn tree_sizes() {
+    let archive = copy_simple_archive();
     run_conserve()
-        .args([
-            "versions",
-            "--sizes",
-            "--utc",
-            "testdata/archive/simple/v0.6.10",
-        ])
+        .args(["versions", "--sizes", "--utc"])
+        .arg(archive.path())
         .assert()
         .success()
         .stdout(indoc! { "
@@ -110,3 +121,85 @@ fn short_newest_first() {
         .stderr(predicate::str::is_empty())
         .stdout("b0001\nb0000\n");
 }
+
+#[test]
+fn json_includes_conserve_version_and_hostname() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let output = run_conserve()
+        .args(["versions", "--json"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let infos: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(infos.len(), 2);
+    assert_eq!(infos[0]["id"], "b0000");
+    assert_eq!(infos[0]["conserve_version"], env!("CARGO_PKG_VERSION"));
+    assert!(infos[0]["hostname"].is_string());
+}
+
+#[test]
+fn json_from_old_archive_has_no_provenance() {
+    let archive = copy_simple_archive();
+    let output = run_conserve()
+        .args(["versions", "--json"])
+        .arg(archive.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 3);
+    for line in stdout.lines() {
+        let info: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert!(info["conserve_version"].is_null());
+        assert!(info["hostname"].is_null());
+    }
+}
+
+#[test]
+fn recorded_source_path_is_shown_by_versions_and_restore() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    run_conserve()
+        .args(["backup", "--no-stats", "--record-source-path"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    let source_path = src.path().canonicalize().unwrap();
+    let source_path = source_path.to_str().unwrap();
+
+    run_conserve()
+        .args(["versions", "--utc", "--source"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("b0000 "))
+        .stdout(predicate::str::ends_with(format!(" {source_path}\n")));
+
+    let output = run_conserve()
+        .args(["versions", "--json"])
+        .arg(af.path())
+        .output()
+        .unwrap();
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(info["source_path"], source_path);
+
+    let dest = TempDir::new().unwrap();
+    run_conserve()
+        .args(["restore", "--no-stats"])
+        .arg(af.path())
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(format!(
+            "Restoring backup b0000 of {source_path:?} taken at "
+        )));
+}
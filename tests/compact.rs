@@ -0,0 +1,154 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test compacting archives.
+
+use std::fs::{self, read_to_string};
+
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+use rayon::prelude::ParallelIterator;
+
+const BACKUPS: usize = 4;
+
+/// Make an archive with a new small block and an index hunk per file in each backup.
+fn fragmented_archive() -> ScratchArchive {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    let options = BackupOptions {
+        max_entries_per_hunk: 1,
+        ..Default::default()
+    };
+    for i in 0..BACKUPS {
+        tf.create_file_with_contents(&format!("file{i}"), format!("content {i}\n").as_bytes());
+        backup(&af, tf.path(), &options, TestMonitor::arc()).unwrap();
+    }
+    af
+}
+
+fn count_hunks(archive: &Archive) -> usize {
+    archive
+        .list_band_ids()
+        .unwrap()
+        .into_iter()
+        .map(|band_id| {
+            let band = Band::open(archive, band_id).unwrap();
+            band.index().hunks_available().unwrap().len()
+        })
+        .sum()
+}
+
+fn count_blocks(archive: &Archive) -> usize {
+    archive
+        .block_dir()
+        .blocks(TestMonitor::arc())
+        .unwrap()
+        .count()
+}
+
+/// Restore each band and check it has the files that were backed up.
+fn check_restores(archive: &Archive) {
+    for i in 0..BACKUPS {
+        let destdir = TreeFixture::new();
+        let options = RestoreOptions {
+            band_selection: BandSelectionPolicy::Specified(BandId::new(&[i as u32])),
+            ..Default::default()
+        };
+        let monitor = TestMonitor::arc();
+        restore(archive, destdir.path(), &options, monitor.clone()).unwrap();
+        monitor.assert_no_errors();
+        for j in 0..=i {
+            assert_eq!(
+                read_to_string(destdir.path().join(format!("file{j}"))).unwrap(),
+                format!("content {j}\n")
+            );
+        }
+        assert!(!destdir.path().join(format!("file{}", i + 1)).exists());
+    }
+}
+
+#[test]
+fn compact_all_bands_reduces_hunks_and_blocks() {
+    let af = fragmented_archive();
+    // Each band has a hunk for the root directory and one for each file.
+    assert_eq!(count_hunks(&af), 2 + 3 + 4 + 5);
+    assert_eq!(count_blocks(&af), BACKUPS);
+
+    let monitor = TestMonitor::arc();
+    let options = CompactOptions {
+        all_bands: true,
+        ..Default::default()
+    };
+    let stats = compact(&af, &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.bands_rewritten, BACKUPS);
+    assert_eq!(stats.index_hunks_before, 14);
+    assert_eq!(stats.index_hunks_after, BACKUPS);
+    assert_eq!(stats.small_blocks_repacked, BACKUPS);
+    assert_eq!(stats.packed_blocks_written, 1);
+
+    assert_eq!(count_hunks(&af), BACKUPS);
+    assert_eq!(count_blocks(&af), 1);
+    for band_id in af.list_band_ids().unwrap() {
+        let info = Band::open(&af, band_id).unwrap().get_info().unwrap();
+        assert!(info.is_closed);
+        assert_eq!(info.index_hunk_count, Some(1));
+    }
+    let report = af
+        .validate(&ValidateOptions::default(), TestMonitor::arc())
+        .unwrap();
+    assert!(report.ok, "{report:?}");
+    check_restores(&af);
+
+    // Compacting again has nothing to do.
+    let stats = compact(&af, &options, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.bands_rewritten, 0);
+    assert_eq!(stats.packed_blocks_written, 0);
+}
+
+#[test]
+fn compact_latest_band_only_rewrites_its_index() {
+    let af = fragmented_archive();
+    let stats = compact(&af, &CompactOptions::default(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.bands_rewritten, 1);
+    assert_eq!(stats.index_hunks_before, 5);
+    assert_eq!(stats.index_hunks_after, 1);
+    assert_eq!(stats.small_blocks_repacked, 0);
+    assert_eq!(count_hunks(&af), 2 + 3 + 4 + 1);
+    assert_eq!(count_blocks(&af), BACKUPS);
+    check_restores(&af);
+}
+
+#[test]
+fn compact_finishes_installing_an_interrupted_index() {
+    let af = fragmented_archive();
+    let options = CompactOptions {
+        all_bands: true,
+        ..Default::default()
+    };
+    compact(&af, &options, TestMonitor::arc()).unwrap();
+    // Simulate being interrupted after the new index of the first band was complete,
+    // but before it replaced the old index.
+    let band_dir = af.path().join("b0000");
+    fs::rename(band_dir.join("i"), band_dir.join("i.compact")).unwrap();
+    fs::write(band_dir.join("i.compact").join("COMPLETE"), b"").unwrap();
+    fs::create_dir(band_dir.join("i")).unwrap();
+
+    let stats = compact(&af, &options, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.bands_rewritten, 1);
+    assert_eq!(stats.index_hunks_after, 1);
+    assert!(!band_dir.join("i.compact").exists());
+    assert_eq!(count_hunks(&af), BACKUPS);
+    check_restores(&af);
+}
@@ -15,6 +15,8 @@
 
 use std::path::Path;
 
+use rayon::prelude::ParallelIterator;
+
 use conserve::monitor::test::TestMonitor;
 use tracing_test::traced_test;
 
@@ -35,6 +37,20 @@ fn missing_block_when_checking_hashes() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn validation_report_lists_missing_block() {
+    let archive = Archive::open_path(Path::new("testdata/damaged/missing-block")).unwrap();
+    let report = archive
+        .validate(&ValidateOptions::default(), TestMonitor::arc())
+        .unwrap();
+    assert!(!report.ok);
+    assert_eq!(report.problems.len(), 1);
+    assert_eq!(report.problems[0].kind, "BlockMissing");
+    assert!(report.problems[0].hash.is_some());
+    assert_eq!(report.problems[0].band_id, None);
+    assert_eq!(report.counts["BlockMissing"], 1);
+}
+
 #[traced_test]
 #[test]
 fn missing_block_skip_block_hashes() -> Result<()> {
@@ -43,6 +59,7 @@ fn missing_block_skip_block_hashes() -> Result<()> {
     archive.validate(
         &ValidateOptions {
             skip_block_hashes: true,
+            ..Default::default()
         },
         monitor.clone(),
     )?;
@@ -52,3 +69,111 @@ fn missing_block_skip_block_hashes() -> Result<()> {
     assert!(matches!(errors[0], Error::BlockMissing { .. }));
     Ok(())
 }
+
+#[test]
+fn concurrent_validation_reports_same_errors_as_sequential() {
+    let af = test_fixtures::ScratchArchive::new();
+    let src = test_fixtures::TreeFixture::new();
+    for i in 0..6 {
+        src.create_file_with_contents(&format!("file{i}"), format!("content {i}").as_bytes());
+        backup(
+            &af,
+            src.path(),
+            &BackupOptions::default(),
+            TestMonitor::arc(),
+        )
+        .unwrap();
+    }
+    // Damage several bands, and remove some blocks.
+    std::fs::remove_file(af.path().join("b0001/BANDHEAD")).unwrap();
+    std::fs::remove_file(af.path().join("b0004/BANDHEAD")).unwrap();
+    let blocks: Vec<BlockHash> = af.block_dir().blocks(TestMonitor::arc()).unwrap().collect();
+    for hash in &blocks[..2] {
+        af.block_dir().delete_block(hash).unwrap();
+    }
+
+    let validate_errors = |band_concurrency| {
+        let monitor = TestMonitor::arc();
+        af.validate(
+            &ValidateOptions {
+                band_concurrency,
+                ..Default::default()
+            },
+            monitor.clone(),
+        )
+        .unwrap();
+        monitor
+            .take_errors()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<String>>()
+    };
+    let sequential = validate_errors(1);
+    dbg!(&sequential);
+    assert_eq!(sequential.len(), 4);
+    for _ in 0..4 {
+        assert_eq!(validate_errors(4), sequential);
+    }
+}
+
+#[test]
+fn validate_removes_empty_blocks_and_next_backup_stores_them_again() {
+    use std::fs::{read_to_string, write};
+
+    use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+    use tempfile::TempDir;
+
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file_with_contents("file", b"some content");
+    backup(
+        &af,
+        tf.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    let hashes: Vec<BlockHash> = af.block_dir().blocks(TestMonitor::arc()).unwrap().collect();
+    assert_eq!(hashes.len(), 1);
+    let block_path = af
+        .path()
+        .join("d")
+        .join(conserve::blockdir::block_relpath(&hashes[0]));
+    write(&block_path, b"").unwrap();
+
+    let monitor = TestMonitor::arc();
+    let options = ValidateOptions {
+        remove_empty_blocks: true,
+        ..Default::default()
+    };
+    af.validate(&options, monitor.clone()).unwrap();
+    assert!(!block_path.exists());
+    let errors = monitor.take_errors();
+    assert_eq!(errors.len(), 1, "{errors:#?}");
+    assert!(matches!(&errors[0], Error::BlockMissing { hash } if *hash == hashes[0]));
+
+    let stats = backup(
+        &af,
+        tf.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(stats.replaced_damaged_blocks, 1);
+    assert_eq!(stats.written_blocks, 1);
+    let monitor = TestMonitor::arc();
+    af.validate(&options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &af,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(
+        read_to_string(restore_dir.path().join("file")).unwrap(),
+        "some content"
+    );
+}
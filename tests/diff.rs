@@ -15,6 +15,7 @@
 use filetime::{set_file_mtime, FileTime};
 use itertools::Itertools;
 
+use conserve::change::Change;
 use conserve::monitor::test::TestMonitor;
 use conserve::test_fixtures::{ScratchArchive, TreeFixture};
 use conserve::*;
@@ -155,3 +156,82 @@ fn symlink_target_change_reported_as_changed() {
     assert!(changes[0].change.is_changed());
     assert!(!changes[0].change.is_unchanged());
 }
+
+#[test]
+fn diff_can_be_filtered_and_collected_by_library_callers() {
+    let (a, tf) = create_tree();
+    tf.create_file_with_contents("new", b"new file");
+    tf.create_dir("newdir");
+    let st = a.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+
+    let changes: Diff = diff(
+        &st,
+        &tf.live_tree(),
+        &DiffOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    let added: Vec<String> = changes
+        .filter(|change| matches!(change.change, Change::Added { .. }))
+        .map(|change| change.apath.to_string())
+        .collect();
+    assert_eq!(added, ["/new", "/newdir"]);
+}
+
+#[test]
+fn verify_content_finds_change_with_same_size_and_mtime() {
+    let (a, tf) = create_tree();
+    let path = tf.path().join("thing");
+    let mtime = FileTime::from_last_modification_time(&std::fs::metadata(&path).unwrap());
+    std::fs::write(&path, b"CONTENTS OF THING").unwrap();
+    set_file_mtime(&path, mtime).unwrap();
+    let st = a.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+
+    let changes = diff(
+        &st,
+        &tf.live_tree(),
+        &DiffOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap()
+    .collect_vec();
+    assert_eq!(changes, []);
+
+    let options = DiffOptions {
+        verify_content: true,
+        ..DiffOptions::default()
+    };
+    let monitor = TestMonitor::arc();
+    let changes = diff(&st, &tf.live_tree(), &options, monitor.clone())
+        .unwrap()
+        .collect_vec();
+    monitor.assert_no_errors();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].apath, "/thing");
+    assert!(changes[0].change.is_changed());
+}
+
+#[test]
+fn verify_content_uses_stored_content_hash_without_reading_blocks() {
+    let (a, tf) = create_tree();
+    // Remove all the blocks: the comparison can only succeed using the hash in the
+    // index.
+    let block_dir_path = a.path().join("d");
+    std::fs::remove_dir_all(&block_dir_path).unwrap();
+    std::fs::create_dir(&block_dir_path).unwrap();
+    let st = a.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+
+    let options = DiffOptions {
+        verify_content: true,
+        include_unchanged: true,
+        ..DiffOptions::default()
+    };
+    let monitor = TestMonitor::arc();
+    let changes = diff(&st, &tf.live_tree(), &options, monitor.clone())
+        .unwrap()
+        .collect_vec();
+    monitor.assert_no_errors();
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[1].apath, "/thing");
+    assert!(changes[1].change.is_unchanged());
+}
@@ -5,8 +5,23 @@
 
 use conserve::test_fixtures::ScratchArchive;
 use conserve::*;
+use serde_json::{json, Value};
+use tracing_test::traced_test;
 use transport::WriteMode;
 
+/// Write a band head by hand, because the library prevents writing unknown flags.
+fn write_band_head(af: &ScratchArchive, head: Value) {
+    af.transport().create_dir("b0000").unwrap();
+    af.transport()
+        .chdir("b0000")
+        .write_file(
+            "BANDHEAD",
+            &serde_json::to_vec(&head).unwrap(),
+            WriteMode::CreateNew,
+        )
+        .unwrap();
+}
+
 #[test]
 // This can be updated if/when Conserve does start writing some flags by default.
 fn default_format_flags_are_empty() {
@@ -57,3 +72,55 @@ fn unknown_format_flag_fails_to_open() {
         "Unsupported band format flags [\"wibble\"] in b0000"
     )
 }
+
+#[test]
+fn unknown_required_flag_fails_to_open_even_with_optional_flags() {
+    let af = ScratchArchive::new();
+    write_band_head(
+        &af,
+        json!({
+            "start_time": 1676651990,
+            "band_format_version": "23.2.0",
+            "format_flags": ["sparse"],
+            "optional_format_flags": ["xattrs"],
+        }),
+    );
+    let err = Band::open(&af, BandId::zero()).unwrap_err();
+    assert!(
+        matches!(&err, Error::UnsupportedBandFormatFlags { band_id, unsupported_flags }
+            if *band_id == BandId::zero() && unsupported_flags == &["sparse"]),
+        "{err:?}"
+    );
+}
+
+#[test]
+#[traced_test]
+fn unknown_optional_flag_warns_and_opens() {
+    let af = ScratchArchive::new();
+    write_band_head(
+        &af,
+        json!({
+            "start_time": 1676651990,
+            "band_format_version": "0.6.3",
+            "optional_format_flags": ["xattrs"],
+        }),
+    );
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert!(band.format_flags().is_empty());
+    assert_eq!(band.optional_format_flags(), ["xattrs"]);
+
+    // Opening the band again doesn't repeat the warning.
+    Band::open(&af, BandId::zero()).unwrap();
+    logs_assert(|lines| {
+        match lines
+            .iter()
+            .filter(|line| {
+                line.contains("Band uses optional format flags [\"xattrs\"] that aren't understood")
+            })
+            .count()
+        {
+            1 => Ok(()),
+            n => Err(format!("warning logged {n} times")),
+        }
+    });
+}
@@ -0,0 +1,186 @@
+// Conserve backup system.
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Check that backing up a canned tree writes exactly the same archive as before.
+//!
+//! The old archives in `testdata/archive` check that we can still read what earlier
+//! versions wrote; this checks that we haven't accidentally changed what we write.
+//! The tree, clock, and options are fixed, and the archive's headers, index hunks,
+//! and list of blocks are compared to golden files in `testdata/golden/format`.
+//!
+//! If the format is changed deliberately, regenerate the golden files with
+//!
+//!     CONSERVE_UPDATE_GOLDEN=1 cargo test --test format_snapshot
+//!
+//! and check the differences before committing them.
+//!
+//! This runs only on Linux, because Unix permissions and symlinks are part of the
+//! stored tree, and the permissions of symlinks vary between Unixes.
+
+#![cfg(target_os = "linux")]
+
+use std::collections::BTreeMap;
+use std::fs::{self, set_permissions, Permissions};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+
+use filetime::{set_file_mtime, set_symlink_file_times, FileTime};
+use pretty_assertions::assert_eq;
+use rayon::prelude::ParallelIterator;
+use tempfile::TempDir;
+use time::OffsetDateTime;
+
+use conserve::clock::FixedClock;
+use conserve::monitor::test::TestMonitor;
+use conserve::*;
+
+const GOLDEN_DIR: &str = "testdata/golden/format";
+
+/// Band head fields that depend on where and with which release the test runs.
+const VOLATILE_HEAD_FIELDS: &[&str] = &["conserve_version", "hostname"];
+
+#[test]
+fn backup_of_canned_tree_matches_golden_files() {
+    let actual = snapshot_canned_backup();
+    let golden_dir = Path::new(GOLDEN_DIR);
+    if std::env::var_os("CONSERVE_UPDATE_GOLDEN").is_some() {
+        if golden_dir.exists() {
+            fs::remove_dir_all(golden_dir).unwrap();
+        }
+        for (name, content) in &actual {
+            let path = golden_dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        return;
+    }
+    let mut expected = BTreeMap::new();
+    read_golden_dir(golden_dir, golden_dir, &mut expected);
+    assert_eq!(
+        expected.keys().collect::<Vec<_>>(),
+        actual.keys().collect::<Vec<_>>(),
+        "Files written differ from {GOLDEN_DIR}; if this is intended, \
+        regenerate with CONSERVE_UPDATE_GOLDEN=1"
+    );
+    for (name, content) in &actual {
+        assert_eq!(
+            expected[name], *content,
+            "{name} differs from {GOLDEN_DIR}; if this is intended, \
+            regenerate with CONSERVE_UPDATE_GOLDEN=1"
+        );
+    }
+}
+
+/// Back up the canned tree into a new archive, and return the content of the
+/// files to compare, by their golden file name.
+fn snapshot_canned_backup() -> BTreeMap<String, String> {
+    let tree = TempDir::new().unwrap();
+    make_canned_tree(tree.path());
+    let archive_dir = TempDir::new().unwrap();
+    let start_time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+    let archive = Archive::create_path(archive_dir.path())
+        .unwrap()
+        .with_clock(Arc::new(FixedClock::new(start_time)));
+    let options = BackupOptions {
+        max_block_size: 1000,
+        small_file_cap: 100,
+        owner: false,
+        ..Default::default()
+    };
+    backup(&archive, tree.path(), &options, TestMonitor::arc()).unwrap();
+
+    let read = |relpath: &str| fs::read_to_string(archive_dir.path().join(relpath)).unwrap();
+    let mut snapshot = BTreeMap::new();
+    snapshot.insert("CONSERVE".to_owned(), read("CONSERVE"));
+
+    let mut head: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(&read("b0000/BANDHEAD")).unwrap();
+    for field in VOLATILE_HEAD_FIELDS {
+        assert!(head.remove(*field).is_some(), "BANDHEAD has no {field}");
+    }
+    snapshot.insert(
+        "b0000/BANDHEAD".to_owned(),
+        serde_json::to_string_pretty(&head).unwrap() + "\n",
+    );
+    snapshot.insert("b0000/BANDTAIL".to_owned(), read("b0000/BANDTAIL"));
+
+    let hunk_dir = archive_dir.path().join("b0000/i/00000");
+    let mut hunk_names: Vec<String> = fs::read_dir(&hunk_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    hunk_names.sort();
+    for name in hunk_names {
+        let compressed = fs::read(hunk_dir.join(&name)).unwrap();
+        let json = snap::raw::Decoder::new()
+            .decompress_vec(&compressed)
+            .unwrap();
+        snapshot.insert(
+            format!("b0000/i/00000/{name}.json"),
+            String::from_utf8(json).unwrap() + "\n",
+        );
+    }
+
+    let mut blocks: Vec<String> = archive
+        .block_dir()
+        .blocks(TestMonitor::arc())
+        .unwrap()
+        .map(|hash| hash.to_string())
+        .collect();
+    blocks.sort();
+    snapshot.insert("blocks".to_owned(), blocks.join("\n") + "\n");
+    snapshot
+}
+
+/// Make a small tree with fixed content, permissions, and mtimes, including some
+/// small files that are combined into one block, a file larger than one block,
+/// and a symlink.
+fn make_canned_tree(root: &Path) {
+    let mtime = FileTime::from_unix_time(1_600_000_000, 0);
+    fs::write(root.join("hello"), "hello world\n").unwrap();
+    fs::write(root.join("world"), "and goodbye\n").unwrap();
+    fs::create_dir(root.join("subdir")).unwrap();
+    let big: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+    fs::write(root.join("subdir/big"), big).unwrap();
+    symlink("../hello", root.join("subdir/link")).unwrap();
+    set_symlink_file_times(root.join("subdir/link"), mtime, mtime).unwrap();
+    for (path, mode) in [
+        ("hello", 0o644),
+        ("world", 0o600),
+        ("subdir/big", 0o640),
+        ("subdir", 0o750),
+        ("", 0o755),
+    ] {
+        let path = root.join(path);
+        set_permissions(&path, Permissions::from_mode(mode)).unwrap();
+        set_file_mtime(&path, mtime).unwrap();
+    }
+}
+
+fn read_golden_dir(base: &Path, dir: &Path, files: &mut BTreeMap<String, String>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            read_golden_dir(base, &path, files);
+        } else {
+            let name = path
+                .strip_prefix(base)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned();
+            files.insert(name, fs::read_to_string(&path).unwrap());
+        }
+    }
+}
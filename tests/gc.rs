@@ -149,3 +149,28 @@ fn backup_prevented_by_gc_lock() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn gc_prevented_by_write_lock() -> Result<()> {
+    let archive = ScratchArchive::new();
+    let monitor = TestMonitor::arc();
+    let lock = WriteLock::acquire(&archive)?;
+
+    let err = archive
+        .delete_bands(&[], &DeleteOptions::default(), monitor.clone())
+        .unwrap_err();
+    assert!(matches!(err, Error::ArchiveLocked { .. }), "{err:?}");
+
+    // Leak the lock, then gc breaking the lock.
+    std::mem::forget(lock);
+    archive.delete_bands(
+        &[],
+        &DeleteOptions {
+            break_lock: true,
+            ..Default::default()
+        },
+        monitor,
+    )?;
+    assert_eq!(WriteLock::holder(&archive)?, None);
+    Ok(())
+}
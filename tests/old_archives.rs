@@ -282,3 +282,25 @@ fn restore_modify_backup() {
         archive_temp.close().expect("Cleanup copied archive");
     }
 }
+
+/// Archives from before 0.6.17 have no stored permissions, so get the default mode.
+#[cfg(unix)]
+#[test]
+fn restore_old_archive_with_default_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dest = TempDir::new().unwrap();
+    let archive = open_old_archive("0.6.10", "minimal");
+    let options = RestoreOptions {
+        default_mode: Some(0o600),
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    restore(&archive, dest.path(), &options, monitor.clone()).expect("restore");
+    monitor.assert_no_errors();
+
+    let mode = |path: &Path| metadata(path).unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode(dest.child("hello").path()), 0o600);
+    assert_eq!(mode(dest.child("subdir/subfile").path()), 0o600);
+    assert_eq!(mode(dest.child("subdir").path()), 0o700);
+}
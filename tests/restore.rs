@@ -14,10 +14,12 @@
 
 use std::cell::RefCell;
 use std::fs::{create_dir, write};
+use std::time::Duration;
 
 use conserve::counters::Counter;
 use conserve::monitor::test::TestMonitor;
 use tempfile::TempDir;
+use tracing_test::traced_test;
 
 use conserve::test_fixtures::ScratchArchive;
 use conserve::test_fixtures::TreeFixture;
@@ -125,6 +127,86 @@ fn restore_only_subdir() {
     restore_monitor.assert_counter(Counter::Files, 1);
 }
 
+/// Opening an archive doesn't load the list of blocks, so restoring one file only
+/// reads the blocks it needs, even from a huge archive.
+#[test]
+fn restore_one_file_does_not_list_blocks() {
+    use conserve::transport::record::{Call, Verb};
+    use conserve::transport::Transport;
+
+    let src = TreeFixture::new();
+    src.create_file_with_contents("wanted", b"wanted content");
+    src.create_file_with_contents("other", b"other content");
+    let af = ScratchArchive::new();
+    backup(
+        &af,
+        src.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+
+    let transport = Transport::local(af.path()).record_calls();
+    let archive = Archive::open(transport.clone()).unwrap();
+    let destdir = TreeFixture::new();
+    let options = RestoreOptions {
+        only_subtree: Some(Apath::from("/wanted")),
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    restore(&archive, destdir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(
+        std::fs::read(destdir.path().join("wanted")).unwrap(),
+        b"wanted content"
+    );
+    let calls = transport.recorded_calls();
+    assert!(
+        !calls
+            .iter()
+            .any(|Call(verb, path)| *verb == Verb::ListDir && path.starts_with('d')),
+        "Block directory was listed: {calls:?}"
+    );
+}
+
+#[test]
+fn restore_only_subdir_with_relative_excludes() {
+    let src = TreeFixture::new();
+    src.create_dir("home");
+    src.create_dir("home/me");
+    src.create_file("home/me/keep");
+    src.create_dir("home/me/junk");
+    src.create_file("home/me/junk/stuff");
+    src.create_dir("home/me/src");
+    src.create_file("home/me/src/junk");
+    let af = ScratchArchive::new();
+    backup(
+        &af,
+        src.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+
+    let destdir = TreeFixture::new();
+    let monitor = TestMonitor::arc();
+    let options = RestoreOptions {
+        only_subtree: Some(Apath::from("/home/me")),
+        // Relative to the top of the tree, this would match nothing.
+        exclude: Exclude::from_strings(["/junk"])
+            .unwrap()
+            .relative_to(Apath::from("/home/me")),
+        ..Default::default()
+    };
+    restore(&af, destdir.path(), &options, monitor.clone()).expect("restore");
+    monitor.assert_no_errors();
+    let dest = destdir.path();
+    assert!(dest.join("home/me/keep").is_file());
+    assert!(!dest.join("home/me/junk").exists());
+    // The anchored pattern only matches at the top of the subtree.
+    assert!(dest.join("home/me/src/junk").is_file());
+}
+
 #[test]
 pub fn decline_to_overwrite() {
     let af = ScratchArchive::new();
@@ -226,3 +308,827 @@ fn restore_symlink() {
         PathBuf::from("target")
     );
 }
+
+#[test]
+#[cfg(unix)]
+fn restored_directory_mtime_equals_stored_value() {
+    use std::fs::metadata;
+
+    use filetime::{set_file_mtime, FileTime};
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("subdir");
+    srcdir.create_file("subdir/file");
+    srcdir.create_symlink("subdir/link", "file");
+    srcdir.create_dir("subdir/nested");
+    srcdir.create_file("subdir/nested/file");
+    let years_ago = FileTime::from_unix_time(189216000, 123456789);
+    set_file_mtime(srcdir.path().join("subdir/nested"), years_ago).unwrap();
+    set_file_mtime(srcdir.path().join("subdir"), years_ago).unwrap();
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let restore_dir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(
+        &af,
+        restore_dir.path(),
+        &Default::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+
+    let stored_tree = af
+        .open_stored_tree(BandSelectionPolicy::LatestClosed)
+        .unwrap();
+    for apath in ["/subdir", "/subdir/nested"] {
+        let stored_mtime = stored_tree
+            .iter_entries(apath.into(), Exclude::nothing(), TestMonitor::arc())
+            .unwrap()
+            .next()
+            .unwrap()
+            .mtime();
+        let restored_mtime = FileTime::from(
+            metadata(restore_dir.path().join(&apath[1..]))
+                .unwrap()
+                .modified()
+                .unwrap(),
+        );
+        assert_eq!(
+            restored_mtime,
+            FileTime::from_unix_time(stored_mtime.unix_timestamp(), stored_mtime.nanosecond()),
+            "mtime of {apath}"
+        );
+        assert_eq!(restored_mtime, years_ago);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn restore_with_clamp_mtime_to_now_avoids_future_mtimes() {
+    use std::fs::symlink_metadata;
+    use std::time::SystemTime;
+
+    use filetime::{set_file_mtime, set_symlink_file_times, FileTime};
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("subdir");
+    srcdir.create_file("subdir/file");
+    srcdir.create_symlink("subdir/link", "file");
+    let future = FileTime::from_system_time(SystemTime::now() + Duration::from_secs(86_400));
+    set_file_mtime(srcdir.path().join("subdir/file"), future).unwrap();
+    set_symlink_file_times(srcdir.path().join("subdir/link"), future, future).unwrap();
+    set_file_mtime(srcdir.path().join("subdir"), future).unwrap();
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    for clamp_mtime_to_now in [false, true] {
+        let restore_dir = TempDir::new().unwrap();
+        let monitor = TestMonitor::arc();
+        let options = RestoreOptions {
+            clamp_mtime_to_now,
+            ..Default::default()
+        };
+        restore(&af, restore_dir.path(), &options, monitor.clone()).unwrap();
+        monitor.assert_no_errors();
+        let after_restore = FileTime::now();
+        for name in ["subdir", "subdir/file", "subdir/link"] {
+            let restored_mtime = FileTime::from_last_modification_time(
+                &symlink_metadata(restore_dir.path().join(name)).unwrap(),
+            );
+            if clamp_mtime_to_now {
+                assert!(restored_mtime <= after_restore, "mtime of {name}");
+            } else {
+                assert_eq!(restored_mtime, future, "mtime of {name}");
+            }
+        }
+    }
+}
+
+#[test]
+fn restore_stats_count_blocks_and_bytes() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", b"hello world\n");
+    srcdir.create_file_with_contents("b", b"goodbye\n");
+    srcdir.create_dir("subdir");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    // Reopen so that nothing is cached from the backup.
+    let archive = Archive::open_path(af.path()).unwrap();
+    let destdir = TreeFixture::new();
+    let monitor = TestMonitor::arc();
+    let stats = restore(
+        &archive,
+        destdir.path(),
+        &Default::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    dbg!(&stats);
+
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.directories, 2);
+    assert_eq!(stats.uncompressed_file_bytes, 20);
+    assert_eq!(stats.errors, 0);
+    // The small files are combined into one block, which is read once.
+    assert_eq!(stats.read_blocks, 1);
+    assert_eq!(stats.read_blocks_uncompressed_bytes, 20);
+    assert!(stats.read_blocks_compressed_bytes > 0);
+    assert_eq!(stats.block_cache_hits, 1);
+
+    monitor.assert_counter(Counter::BlockReads, 1);
+    monitor.assert_counter(Counter::BlockContentCacheHit, 1);
+    monitor.assert_counter(Counter::FileBytes, 20);
+}
+
+#[test]
+fn restore_stats_report_elapsed_time_and_throughput() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", &[b'a'; 100_000]);
+    srcdir.create_file_with_contents("b", &[b'b'; 100_000]);
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let destdir = TreeFixture::new();
+    let stats = restore(&af, destdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.uncompressed_file_bytes, 200_000);
+    assert!(stats.elapsed > Duration::ZERO);
+    assert_eq!(stats.throughput(), 200_000.0 / stats.elapsed.as_secs_f64());
+    let text = stats.to_string();
+    assert!(text.contains(" MB/s restored"), "{text}");
+}
+
+#[test]
+#[cfg(unix)]
+#[traced_test]
+fn restore_warns_about_inaccessible_files() {
+    use std::fs::{metadata, set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    // Empty files can be backed up without being read.
+    srcdir.create_file_with_contents("private", b"");
+    set_permissions(srcdir.path().join("private"), Permissions::from_mode(0o000)).unwrap();
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let restore_dir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(
+        &af,
+        restore_dir.path(),
+        &Default::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    assert!(logs_contain("Restored file is not accessible to its owner"));
+    let mode = metadata(restore_dir.path().join("private"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o7777, 0o000);
+}
+
+#[test]
+#[cfg(unix)]
+fn metadata_only_restore_creates_empty_files_without_reading_blocks() {
+    use std::fs::{metadata, set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    use conserve::transport::record::{Call, Verb};
+    use conserve::transport::Transport;
+
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("subdir");
+    srcdir.create_file_with_contents("subdir/script", b"#!/bin/sh\necho hello\n");
+    srcdir.create_file_with_contents("private", b"secret");
+    set_permissions(
+        srcdir.path().join("subdir/script"),
+        Permissions::from_mode(0o750),
+    )
+    .unwrap();
+    set_permissions(srcdir.path().join("private"), Permissions::from_mode(0o600)).unwrap();
+    set_permissions(srcdir.path().join("subdir"), Permissions::from_mode(0o710)).unwrap();
+    let af = ScratchArchive::new();
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let transport = Transport::local(af.path()).record_calls();
+    let archive = Archive::open(transport.clone()).unwrap();
+    let restore_dir = TempDir::new().unwrap();
+    let options = RestoreOptions {
+        metadata_only: true,
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    let stats = restore(&archive, restore_dir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.directories, 2);
+    assert_eq!(stats.read_blocks, 0);
+    assert_eq!(stats.uncompressed_file_bytes, 0);
+    let calls = transport.recorded_calls();
+    assert!(
+        !calls.iter().any(|Call(_, path)| path.starts_with("d/")),
+        "Blocks were read: {calls:?}"
+    );
+    assert!(calls.iter().any(|Call(verb, _)| *verb == Verb::ReadFile));
+
+    for (path, mode) in [
+        ("subdir/script", 0o750),
+        ("private", 0o600),
+        ("subdir", 0o710),
+    ] {
+        let metadata = metadata(restore_dir.path().join(path)).unwrap();
+        assert_eq!(
+            metadata.permissions().mode() & 0o7777,
+            mode,
+            "mode of {path}"
+        );
+        if metadata.is_file() {
+            assert_eq!(metadata.len(), 0, "length of {path}");
+        }
+    }
+}
+
+#[test]
+fn metadata_only_restore_refuses_to_overwrite() {
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("hello", b"contents");
+    let af = ScratchArchive::new();
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let options = RestoreOptions {
+        metadata_only: true,
+        overwrite: true,
+        ..Default::default()
+    };
+    let err = restore(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap_err();
+    assert!(matches!(err, Error::MetadataOnlyOverwrite), "{err:?}");
+    assert_eq!(
+        std::fs::read(srcdir.path().join("hello")).unwrap(),
+        b"contents"
+    );
+}
+
+#[test]
+#[cfg(unix)]
+#[traced_test]
+fn restore_readable_adds_owner_permissions() {
+    use std::fs::{metadata, set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("group_only", b"");
+    set_permissions(
+        srcdir.path().join("group_only"),
+        Permissions::from_mode(0o2040),
+    )
+    .unwrap();
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let restore_dir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    let options = RestoreOptions {
+        readable: true,
+        ..Default::default()
+    };
+    restore(&af, restore_dir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert!(!logs_contain("not accessible to its owner"));
+    let mode = metadata(restore_dir.path().join("group_only"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o7777, 0o2640);
+}
+
+#[test]
+#[cfg(unix)]
+fn restore_read_only_directory_after_its_contents() {
+    use std::fs::{metadata, read, set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("ro");
+    srcdir.create_file_with_contents("ro/a", b"aaa");
+    srcdir.create_dir("ro/sub");
+    srcdir.create_file_with_contents("ro/sub/b", b"bbb");
+    for (name, mode) in [("ro/sub", 0o500), ("ro", 0o555)] {
+        set_permissions(srcdir.path().join(name), Permissions::from_mode(mode)).unwrap();
+    }
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    // Let the fixture clean up.
+    for name in ["ro", "ro/sub"] {
+        set_permissions(srcdir.path().join(name), Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let restore_dir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    let stats = restore(
+        &af,
+        restore_dir.path(),
+        &Default::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.files, 2);
+    let dest = restore_dir.path();
+    assert_eq!(read(dest.join("ro/a")).unwrap(), b"aaa");
+    assert_eq!(read(dest.join("ro/sub/b")).unwrap(), b"bbb");
+    let mode = |name| metadata(dest.join(name)).unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode("ro"), 0o555);
+    assert_eq!(mode("ro/sub"), 0o500);
+    for name in ["ro", "ro/sub"] {
+        set_permissions(dest.join(name), Permissions::from_mode(0o755)).unwrap();
+    }
+}
+
+#[test]
+#[traced_test]
+fn restore_incomplete_band_fills_in_from_previous_band() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for name in ["a", "b", "c", "d"] {
+        srcdir.create_file_with_contents(name, b"first");
+    }
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    srcdir.create_file_with_contents("a", b"second version");
+    srcdir.create_file_with_contents("d", b"second version");
+    let options = BackupOptions {
+        max_entries_per_hunk: 2,
+        ..Default::default()
+    };
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    // Simulate the second backup being interrupted after writing the hunks
+    // containing "/", "/a", "/b", and "/c", but before the one containing "/d".
+    let band_dir = af.path().join("b0001");
+    std::fs::remove_file(band_dir.join("BANDTAIL")).unwrap();
+    std::fs::remove_file(band_dir.join("i/00000/000000002")).unwrap();
+
+    let archive = Archive::open_path(af.path()).unwrap();
+    let restore_dir = TempDir::new().unwrap();
+    let options = RestoreOptions {
+        band_selection: BandSelectionPolicy::LatestIncludingIncomplete,
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    let stats = restore(&archive, restore_dir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert!(logs_contain("Restoring from an incomplete backup"));
+    assert_eq!(stats.files, 4);
+    let read = |dir: &TempDir, name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+    assert_eq!(read(&restore_dir, "a"), "second version");
+    assert_eq!(read(&restore_dir, "b"), "first");
+    assert_eq!(read(&restore_dir, "c"), "first");
+    // "/d" wasn't reached by the interrupted backup, so comes from the first one.
+    assert_eq!(read(&restore_dir, "d"), "first");
+
+    // By default, only complete backups are restored.
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &archive,
+        restore_dir.path(),
+        &Default::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(read(&restore_dir, "a"), "first");
+    assert_eq!(read(&restore_dir, "d"), "first");
+}
+
+#[test]
+fn restore_only_files_creates_their_parent_directories() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("top");
+    srcdir.create_dir("subdir");
+    srcdir.create_dir("subdir/deeper");
+    srcdir.create_file("subdir/deeper/file");
+    srcdir.create_dir("empty");
+    srcdir.create_symlink("link", "top");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let restore_dir = TempDir::new().unwrap();
+    let options = RestoreOptions {
+        kinds: Some(vec![Kind::File]),
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    let stats = restore(&af, restore_dir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.directories, 0);
+    assert_eq!(stats.symlinks, 0);
+    assert!(restore_dir.path().join("top").is_file());
+    assert!(restore_dir.path().join("subdir/deeper/file").is_file());
+    assert!(!restore_dir.path().join("empty").exists());
+    assert!(restore_dir.path().join("link").symlink_metadata().is_err());
+}
+
+#[test]
+fn restore_older_than_skips_newer_files_but_keeps_directories() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("subdir");
+    srcdir.create_file("subdir/old");
+    srcdir.create_file("subdir/new");
+    let cutoff = time::OffsetDateTime::from_unix_timestamp(1_600_000_000).unwrap();
+    filetime::set_file_mtime(
+        srcdir.path().join("subdir/old"),
+        filetime::FileTime::from_unix_time(1_500_000_000, 0),
+    )
+    .unwrap();
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let restore_dir = TempDir::new().unwrap();
+    let options = RestoreOptions {
+        older_than: Some(cutoff),
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    let stats = restore(&af, restore_dir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.directories, 2);
+    assert!(restore_dir.path().join("subdir/old").is_file());
+    assert!(!restore_dir.path().join("subdir/new").exists());
+}
+
+#[test]
+fn restore_reports_paths_too_long_for_destination() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file(&"x".repeat(60));
+    srcdir.create_file("short");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let restore_dir = TempDir::new().unwrap();
+    let limit = restore_dir.path().as_os_str().len() + 20;
+    let options = RestoreOptions {
+        max_path_len: Some(limit),
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    let stats = restore(&af, restore_dir.path(), &options, monitor.clone()).unwrap();
+    let errors = monitor.take_errors();
+    assert_eq!(errors.len(), 1, "{errors:?}");
+    let message = errors[0].to_string();
+    assert!(
+        message.contains(&format!("is {} bytes long", limit + 41))
+            && message.contains(&format!("more than the destination's limit of {limit}")),
+        "{message}"
+    );
+    assert_eq!(stats.errors, 1);
+    assert_eq!(stats.files, 1);
+    assert!(restore_dir.path().join("short").is_file());
+    assert!(!restore_dir.path().join("x".repeat(60)).exists());
+}
+
+#[test]
+fn check_only_reports_missing_and_corrupt_blocks_without_writing() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", b"content of a\n");
+    srcdir.create_file_with_contents("b", b"content of b\n");
+    srcdir.create_file_with_contents("c", b"content of c\n");
+    let backup_options = BackupOptions {
+        small_file_cap: 0,
+        ..Default::default()
+    };
+    backup(&af, srcdir.path(), &backup_options, TestMonitor::arc()).unwrap();
+    let hash_of = |name: &str| {
+        af.open_stored_tree(BandSelectionPolicy::LatestClosed)
+            .unwrap()
+            .band()
+            .index()
+            .iter_entries()
+            .find(|entry| entry.apath == *name)
+            .unwrap()
+            .addrs[0]
+            .hash
+            .clone()
+    };
+    let missing = hash_of("/b");
+    af.block_dir().delete_block(&missing).unwrap();
+    let corrupt = hash_of("/c");
+    let corrupt_name = corrupt.to_string();
+    write(
+        af.path()
+            .join("d")
+            .join(&corrupt_name[..3])
+            .join(&corrupt_name),
+        b"garbage",
+    )
+    .unwrap();
+
+    let destdir = TempDir::new().unwrap();
+    let destination = destdir.path().join("restore");
+    let check = |quick| {
+        // Reopen so that nothing is cached.
+        let archive = Archive::open_path(af.path()).unwrap();
+        let monitor = TestMonitor::arc();
+        let options = RestoreOptions {
+            check_only: true,
+            quick,
+            ..Default::default()
+        };
+        let stats = restore(&archive, &destination, &options, monitor.clone()).unwrap();
+        assert!(!destination.exists());
+        assert_eq!(stats.files, 3);
+        let errors = monitor
+            .take_errors()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<String>>();
+        assert_eq!(stats.errors, errors.len());
+        errors
+    };
+
+    let errors = check(false);
+    dbg!(&errors);
+    assert_eq!(errors.len(), 2);
+    assert!(
+        errors[0].contains(&format!("{missing} for /b")),
+        "{errors:?}"
+    );
+    assert!(
+        errors[1].contains(&format!("{corrupt} for /c")),
+        "{errors:?}"
+    );
+
+    // A quick check only notices the missing block.
+    let errors = check(true);
+    assert_eq!(
+        errors,
+        [format!(
+            "Failed to read block content {missing} for /b: Referenced block {missing} is missing"
+        )]
+    );
+}
+
+#[test]
+fn update_restore_writes_only_changed_files_and_deletes_extras() {
+    use std::fs::{read_to_string, remove_file, symlink_metadata};
+
+    use filetime::{set_file_mtime, FileTime};
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("changed", b"stored content");
+    srcdir.create_file_with_contents("removed", b"stored content");
+    srcdir.create_dir("subdir");
+    srcdir.create_file_with_contents("subdir/same", b"stored content");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &af,
+        restore_dir.path(),
+        &Default::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+
+    // Make the restored copy out of date.
+    let dest = restore_dir.path();
+    write(dest.join("changed"), b"edited later").unwrap();
+    remove_file(dest.join("removed")).unwrap();
+    write(dest.join("extra"), b"not in the backup").unwrap();
+    create_dir(dest.join("extradir")).unwrap();
+    write(dest.join("extradir/file"), b"not in the backup").unwrap();
+    // Same size and mtime, different content.
+    let same_path = dest.join("subdir/same");
+    let same_mtime = FileTime::from_last_modification_time(&symlink_metadata(&same_path).unwrap());
+    write(&same_path, b"STORED CONTENT").unwrap();
+    set_file_mtime(&same_path, same_mtime).unwrap();
+
+    let changes = RefCell::new(Vec::new());
+    let options = RestoreOptions {
+        update: true,
+        change_callback: Some(Box::new(|change| {
+            changes.borrow_mut().push(change.to_string());
+            Ok(())
+        })),
+        ..Default::default()
+    };
+    let stats = restore(&af, dest, &options, TestMonitor::arc()).unwrap();
+    drop(options);
+    assert_eq!(changes.take(), ["* /changed", "+ /removed"]);
+    assert_eq!(stats.unchanged, 1);
+    assert_eq!(stats.deleted, 0);
+    assert_eq!(
+        read_to_string(dest.join("changed")).unwrap(),
+        "stored content"
+    );
+    assert_eq!(
+        read_to_string(dest.join("removed")).unwrap(),
+        "stored content"
+    );
+    assert_eq!(read_to_string(&same_path).unwrap(), "STORED CONTENT");
+    assert!(dest.join("extra").is_file());
+
+    let monitor = TestMonitor::arc();
+    let options = RestoreOptions {
+        update: true,
+        verify_content: true,
+        delete: true,
+        change_callback: Some(Box::new(|change| {
+            changes.borrow_mut().push(change.to_string());
+            Ok(())
+        })),
+        ..Default::default()
+    };
+    let stats = restore(&af, dest, &options, monitor.clone()).unwrap();
+    drop(options);
+    monitor.assert_no_errors();
+    assert_eq!(
+        changes.take(),
+        [
+            "* /subdir/same",
+            "- /extradir/file",
+            "- /extradir",
+            "- /extra"
+        ]
+    );
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.unchanged, 2);
+    assert_eq!(stats.deleted, 3);
+    assert_eq!(read_to_string(&same_path).unwrap(), "stored content");
+    assert!(!dest.join("extra").exists());
+    assert!(!dest.join("extradir").exists());
+}
+
+/// Make an archive with one closed band whose only index hunk holds `entries_json`,
+/// as a crafted or damaged archive might.
+fn archive_with_index_hunk(entries_json: &str) -> ScratchArchive {
+    let af = ScratchArchive::new();
+    let empty = TempDir::new().unwrap();
+    backup(&af, empty.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    let compressed = snap::raw::Encoder::new()
+        .compress_vec(entries_json.as_bytes())
+        .unwrap();
+    write(af.path().join("b0000/i/00000/000000000"), compressed).unwrap();
+    af
+}
+
+#[test]
+fn index_entry_escaping_the_tree_is_rejected_on_read() {
+    let af = archive_with_index_hunk(
+        r#"[{"apath":"/","kind":"Dir","mtime":0},
+            {"apath":"/../escape","kind":"File","mtime":0}]"#,
+    );
+    let err = Band::open(&af, BandId::zero())
+        .unwrap()
+        .index()
+        .read_hunk(0)
+        .unwrap_err();
+    assert!(err.to_string().contains("invalid apath"), "{err}");
+
+    let parent = TempDir::new().unwrap();
+    let dest = parent.path().join("dest");
+    restore(&af, &dest, &Default::default(), TestMonitor::arc()).unwrap();
+    assert!(!parent.path().join("escape").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn restore_does_not_write_through_restored_symlink() {
+    let outside = TempDir::new().unwrap();
+    let af = archive_with_index_hunk(&format!(
+        r#"[{{"apath":"/","kind":"Dir","mtime":0}},
+            {{"apath":"/link","kind":"Symlink","mtime":0,"target":{target:?}}},
+            {{"apath":"/link/file","kind":"File","mtime":0}}]"#,
+        target = outside.path().to_str().unwrap(),
+    ));
+    let dest = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    let stats = restore(&af, dest.path(), &Default::default(), monitor.clone()).unwrap();
+    assert_eq!(stats.errors, 1);
+    let errors = monitor.take_errors();
+    assert!(
+        matches!(&errors[..], [Error::RestoreOutsideDestination { apath, .. }] if apath == "/link/file"),
+        "{errors:#?}"
+    );
+    assert!(!outside.path().join("file").exists());
+}
+
+#[test]
+#[traced_test]
+fn restore_stops_before_exceeding_limit_bytes() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    for name in ["a", "b", "c"] {
+        tf.create_file_with_contents(name, b"0123456789");
+    }
+    backup(
+        &af,
+        tf.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+
+    let destdir = TempDir::new().unwrap();
+    let options = RestoreOptions {
+        limit_bytes: Some(25),
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    let stats = restore(&af, destdir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.uncompressed_file_bytes, 20);
+    assert!(destdir.path().join("a").is_file());
+    assert!(destdir.path().join("b").is_file());
+    assert!(!destdir.path().join("c").exists());
+    assert!(logs_contain("Restore stopped at the limit"));
+}
+
+#[test]
+fn cas_export_writes_each_block_once() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    let content = vec![b'a'; 2 << 20];
+    tf.create_file_with_contents("one", &content);
+    tf.create_file_with_contents("two", &content);
+    backup(
+        &af,
+        tf.path(),
+        &BackupOptions {
+            max_block_size: 1 << 20,
+            ..Default::default()
+        },
+        TestMonitor::arc(),
+    )
+    .unwrap();
+
+    let destdir = TempDir::new().unwrap();
+    let st = af
+        .open_stored_tree(BandSelectionPolicy::LatestClosed)
+        .unwrap();
+    let monitor = TestMonitor::arc();
+    let stats = cas_export(
+        &st,
+        destdir.path(),
+        Apath::root(),
+        Exclude::nothing(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.blocks, 1);
+    assert_eq!(stats.block_bytes, 1 << 20);
+
+    let hash = BlockHash::hash_bytes(&content[..1 << 20]);
+    let mut names: Vec<String> = std::fs::read_dir(destdir.path())
+        .unwrap()
+        .map(|de| de.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, [hash.to_string(), CAS_EXPORT_MANIFEST.to_owned()]);
+    assert_eq!(
+        std::fs::read(destdir.path().join(hash.to_string())).unwrap(),
+        &content[..1 << 20]
+    );
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(destdir.path().join(CAS_EXPORT_MANIFEST)).unwrap())
+            .unwrap();
+    let files = manifest.as_array().unwrap();
+    assert_eq!(files.len(), 2);
+    for (file, apath) in files.iter().zip(["/one", "/two"]) {
+        assert_eq!(file["apath"], apath);
+        let addrs = file["addrs"].as_array().unwrap();
+        assert_eq!(addrs.len(), 2);
+        for addr in addrs {
+            assert_eq!(addr["hash"], hash.to_string());
+            assert_eq!(addr["len"], 1 << 20);
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn restore_writes_through_symlink_inside_destination() {
+    let af = archive_with_index_hunk(
+        r#"[{"apath":"/","kind":"Dir","mtime":0},
+            {"apath":"/a","kind":"Dir","mtime":0},
+            {"apath":"/link","kind":"Symlink","mtime":0,"target":"a"},
+            {"apath":"/link/file","kind":"File","mtime":0}]"#,
+    );
+    let dest = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    let stats = restore(&af, dest.path(), &Default::default(), monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.errors, 0);
+    assert!(dest.path().join("a/file").is_file());
+}
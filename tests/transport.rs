@@ -10,12 +10,23 @@
 // MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
 // GNU General Public License for more details.
 
+use std::collections::BTreeMap;
+use std::fs;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 use assert_fs::prelude::*;
+use assert_fs::TempDir;
+use bytes::Bytes;
+use rayon::prelude::ParallelIterator;
+use time::OffsetDateTime;
+use tracing_test::traced_test;
 use url::Url;
 
-use conserve::transport::{ListDir, Transport};
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::TreeFixture;
+use conserve::transport::{self, ErrorKind, ListDir, Metadata, Protocol, Transport, WriteMode};
+use conserve::*;
 
 #[test]
 fn open_local() {
@@ -70,3 +81,360 @@ fn unsupported_location_urls() {
         "Unsupported URL scheme: ftp://user@conserve.example/repo"
     );
 }
+
+#[test]
+fn backup_and_restore_in_memory_matches_local() {
+    let src = TreeFixture::new();
+    src.create_file_with_contents("hello", b"hello world\n");
+    src.create_dir("subdir");
+    src.create_file_with_contents("subdir/subfile", b"I like Rust\n");
+    src.create_file_of_length_with_prefix("subdir/big", 10_000, b"big");
+    src.create_symlink("link", "target");
+    let options = BackupOptions {
+        max_block_size: 4096,
+        small_file_cap: 100,
+        ..Default::default()
+    };
+
+    let memory_archive = Archive::create(Transport::memory()).unwrap();
+    let local_dir = TempDir::new().unwrap();
+    let local_archive = Archive::create_path(local_dir.path()).unwrap();
+    for archive in [&memory_archive, &local_archive] {
+        let monitor = TestMonitor::arc();
+        backup(archive, src.path(), &options, monitor.clone()).unwrap();
+        monitor.assert_no_errors();
+        archive
+            .validate(&ValidateOptions::default(), monitor.clone())
+            .unwrap();
+        monitor.assert_no_errors();
+    }
+
+    let index_entries = |archive: &Archive| {
+        Band::open(archive, BandId::zero())
+            .unwrap()
+            .index()
+            .iter_entries()
+            .collect::<Vec<IndexEntry>>()
+    };
+    assert_eq!(
+        index_entries(&memory_archive),
+        index_entries(&local_archive)
+    );
+    let block_hashes = |archive: &Archive| {
+        let mut hashes = archive
+            .block_dir()
+            .blocks(TestMonitor::arc())
+            .unwrap()
+            .collect::<Vec<BlockHash>>();
+        hashes.sort();
+        hashes
+    };
+    assert_eq!(block_hashes(&memory_archive), block_hashes(&local_archive));
+
+    let restore_and_read = |archive: &Archive| {
+        let dest = TempDir::new().unwrap();
+        let monitor = TestMonitor::arc();
+        restore(
+            archive,
+            dest.path(),
+            &RestoreOptions::default(),
+            monitor.clone(),
+        )
+        .unwrap();
+        monitor.assert_no_errors();
+        ["hello", "subdir/subfile", "subdir/big"].map(|name| fs::read(dest.child(name)).unwrap())
+    };
+    let restored = restore_and_read(&memory_archive);
+    assert_eq!(restored, restore_and_read(&local_archive));
+    assert_eq!(restored[0], b"hello world\n");
+    assert_eq!(restored[2].len(), 10_000);
+}
+
+/// Flips the top bit of every byte, which is its own inverse.
+#[cfg(unix)]
+const XOR_COMMAND: &str = r"LC_ALL=C tr '\000-\377' '\200-\377\000-\177'";
+
+#[cfg(unix)]
+#[test]
+fn backup_and_restore_through_external_filter() {
+    let src = TreeFixture::new();
+    src.create_file_with_contents("hello", b"hello world\n");
+    src.create_dir("subdir");
+    src.create_file_of_length_with_prefix("subdir/big", 10_000, b"big");
+    let inner = Transport::memory();
+    let filtered = inner.external_filter(XOR_COMMAND, XOR_COMMAND);
+    let archive = Archive::create(filtered.clone()).unwrap();
+    let monitor = TestMonitor::arc();
+    backup(
+        &archive,
+        src.path(),
+        &BackupOptions::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+
+    // The stored blocks are still named by their content hash, but can't be read
+    // without the filter.
+    let hashes: Vec<BlockHash> = archive
+        .block_dir()
+        .blocks(TestMonitor::arc())
+        .unwrap()
+        .collect();
+    assert!(!hashes.is_empty());
+    for hash in &hashes {
+        let relpath = format!("d/{}/{hash}", &hash.to_string()[..3]);
+        assert_ne!(
+            inner.read_file(&relpath).unwrap(),
+            filtered.read_file(&relpath).unwrap()
+        );
+    }
+    assert!(matches!(
+        Archive::open(inner.clone()),
+        Err(Error::ArchiveNeedsExternalFilter)
+    ));
+    assert!(matches!(
+        Archive::open(Transport::memory().external_filter("cat", "cat")),
+        Err(Error::NotAnArchive)
+    ));
+
+    let archive = Archive::open(filtered).unwrap();
+    archive
+        .validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    monitor.assert_no_errors();
+    let dest = TempDir::new().unwrap();
+    restore(
+        &archive,
+        dest.path(),
+        &RestoreOptions::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(fs::read(dest.child("hello")).unwrap(), b"hello world\n");
+    assert_eq!(fs::read(dest.child("subdir/big")).unwrap().len(), 10_000);
+}
+
+#[test]
+fn plain_archive_refuses_external_filter() {
+    let inner = Transport::memory();
+    Archive::create(inner.clone()).unwrap();
+    assert!(matches!(
+        Archive::open(inner.external_filter("cat", "cat")),
+        Err(Error::ArchiveNotExternallyFiltered)
+    ));
+}
+
+/// Files and directories kept in a map by their full path, with `None` for directories,
+/// as an example of storage implemented outside Conserve.
+type Files = Arc<Mutex<BTreeMap<String, Option<Bytes>>>>;
+
+struct MapProtocol {
+    files: Files,
+    prefix: String,
+    url: Url,
+    available_space: Option<u64>,
+}
+
+impl MapProtocol {
+    fn full_path(&self, relpath: &str) -> String {
+        match (self.prefix.as_str(), relpath) {
+            (prefix, "") => prefix.to_owned(),
+            ("", relpath) => relpath.to_owned(),
+            (prefix, relpath) => format!("{prefix}/{relpath}"),
+        }
+    }
+
+    fn error(&self, kind: ErrorKind, relpath: &str) -> transport::Error {
+        transport::Error {
+            kind,
+            source: None,
+            url: self.url.join(relpath).ok(),
+        }
+    }
+}
+
+impl Protocol for MapProtocol {
+    fn read_file(&self, relpath: &str) -> transport::Result<Bytes> {
+        match self.files.lock().unwrap().get(&self.full_path(relpath)) {
+            Some(Some(content)) => Ok(content.clone()),
+            _ => Err(self.error(ErrorKind::NotFound, relpath)),
+        }
+    }
+
+    fn write_file(&self, relpath: &str, content: &[u8], mode: WriteMode) -> transport::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let path = self.full_path(relpath);
+        if mode == WriteMode::CreateNew && files.contains_key(&path) {
+            return Err(self.error(ErrorKind::AlreadyExists, relpath));
+        }
+        files.insert(path, Some(Bytes::copy_from_slice(content)));
+        Ok(())
+    }
+
+    fn list_dir(&self, relpath: &str) -> transport::Result<ListDir> {
+        let files = self.files.lock().unwrap();
+        let dir = self.full_path(relpath);
+        if !dir.is_empty() && files.get(&dir) != Some(&None) {
+            return Err(self.error(ErrorKind::NotFound, relpath));
+        }
+        let mut list = ListDir::default();
+        for (path, content) in files.iter() {
+            let name = if dir.is_empty() {
+                Some(path.as_str())
+            } else {
+                path.strip_prefix(&dir).and_then(|p| p.strip_prefix('/'))
+            };
+            match (name, content) {
+                (Some(name), _) if name.is_empty() || name.contains('/') => (),
+                (Some(name), Some(_)) => list.files.push(name.to_owned()),
+                (Some(name), None) => list.dirs.push(name.to_owned()),
+                (None, _) => (),
+            }
+        }
+        Ok(list)
+    }
+
+    fn create_dir(&self, relpath: &str) -> transport::Result<()> {
+        let path = self.full_path(relpath);
+        if !path.is_empty() {
+            self.files.lock().unwrap().entry(path).or_insert(None);
+        }
+        Ok(())
+    }
+
+    fn metadata(&self, relpath: &str) -> transport::Result<Metadata> {
+        match self.files.lock().unwrap().get(&self.full_path(relpath)) {
+            Some(Some(content)) => Ok(Metadata {
+                len: content.len() as u64,
+                kind: Kind::File,
+                modified: OffsetDateTime::now_utc(),
+            }),
+            _ => Err(self.error(ErrorKind::NotFound, relpath)),
+        }
+    }
+
+    fn remove_file(&self, relpath: &str) -> transport::Result<()> {
+        match self.files.lock().unwrap().remove(&self.full_path(relpath)) {
+            Some(_) => Ok(()),
+            None => Err(self.error(ErrorKind::NotFound, relpath)),
+        }
+    }
+
+    fn remove_dir_all(&self, relpath: &str) -> transport::Result<()> {
+        let dir = self.full_path(relpath);
+        let prefix = format!("{dir}/");
+        self.files
+            .lock()
+            .unwrap()
+            .retain(|path, _| *path != dir && !path.starts_with(&prefix));
+        Ok(())
+    }
+
+    fn chdir(&self, relpath: &str) -> Arc<dyn Protocol> {
+        Arc::new(MapProtocol {
+            files: Arc::clone(&self.files),
+            prefix: self.full_path(relpath),
+            url: self.url.join(&format!("{relpath}/")).unwrap(),
+            available_space: self.available_space,
+        })
+    }
+
+    fn url(&self) -> &Url {
+        &self.url
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        self.available_space
+    }
+}
+
+#[test]
+fn backup_and_restore_with_custom_protocol() {
+    let src = TreeFixture::new();
+    src.create_file_with_contents("hello", b"hello world\n");
+    src.create_dir("subdir");
+    src.create_file_of_length_with_prefix("subdir/big", 10_000, b"big");
+    let files = Files::default();
+    let transport = || {
+        Transport::from_protocol(Arc::new(MapProtocol {
+            files: files.clone(),
+            prefix: String::new(),
+            url: Url::parse("map:///").unwrap(),
+            available_space: None,
+        }))
+    };
+    let options = BackupOptions {
+        max_block_size: 4096,
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    backup(
+        &Archive::create(transport()).unwrap(),
+        src.path(),
+        &options,
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    assert!(files.lock().unwrap().contains_key("b0000/BANDTAIL"));
+
+    let archive = Archive::open(transport()).unwrap();
+    archive
+        .validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    monitor.assert_no_errors();
+    let dest = TempDir::new().unwrap();
+    restore(
+        &archive,
+        dest.path(),
+        &RestoreOptions::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(fs::read(dest.child("hello")).unwrap(), b"hello world\n");
+    assert_eq!(fs::read(dest.child("subdir/big")).unwrap().len(), 10_000);
+}
+
+/// Back up a small tree into a map archive with the given amount of space available.
+fn backup_with_available_space(available_space: u64, ignore_space_check: bool) {
+    let src = TreeFixture::new();
+    src.create_file_with_contents("hello", b"hello world\n");
+    let archive = Archive::create(Transport::from_protocol(Arc::new(MapProtocol {
+        files: Files::default(),
+        prefix: String::new(),
+        url: Url::parse("map:///").unwrap(),
+        available_space: Some(available_space),
+    })))
+    .unwrap();
+    let options = BackupOptions {
+        ignore_space_check,
+        ..Default::default()
+    };
+    let monitor = TestMonitor::arc();
+    backup(&archive, src.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+}
+
+#[test]
+#[traced_test]
+fn backup_warns_when_archive_space_is_low() {
+    backup_with_available_space(10 << 20, false);
+    assert!(logs_contain("Only 10 MB available for the archive"));
+}
+
+#[test]
+#[traced_test]
+fn backup_does_not_warn_with_enough_space() {
+    backup_with_available_space(LOW_SPACE_WARNING_BYTES, false);
+    assert!(!logs_contain("may run out of space"));
+}
+
+#[test]
+#[traced_test]
+fn ignore_space_check_suppresses_low_space_warning() {
+    backup_with_available_space(10 << 20, true);
+    assert!(!logs_contain("may run out of space"));
+}